@@ -0,0 +1,121 @@
+use std::{io, path::Path};
+
+/// Placeholders of the form `{name}` in `template` that are not in `known`,
+/// with their position in characters starting at 1. Shell variables written
+/// `${name}` are not placeholders.
+pub(crate) fn unknown_placeholders<'a>(template: &'a str, known: &[&str]) -> Vec<(usize, &'a str)> {
+    let mut unknown = Vec::new();
+    let mut rest = template;
+    while let Some(end) = rest.find('}') {
+        // Innermost braces, templates may be JSON objects.
+        if let Some(start) = rest[..end].rfind('{') {
+            let candidate = &rest[start..=end];
+            let name = &candidate[1..candidate.len() - 1];
+            if !name.is_empty()
+                && !rest[..start].ends_with('$')
+                && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+                && !known.contains(&candidate)
+            {
+                let offset = template.len() - rest.len() + start;
+                unknown.push((template[..offset].chars().count() + 1, candidate));
+            }
+        }
+        rest = &rest[end + 1..];
+    }
+    unknown
+}
+
+/// Problems found when checking a configuration file.
+pub(crate) struct Diagnostics {
+    errors: Vec<String>,
+}
+
+impl Diagnostics {
+    pub(crate) fn new() -> Self {
+        Self { errors: Vec::new() }
+    }
+
+    pub(crate) fn error(&mut self, msg: String) {
+        self.errors.push(msg);
+    }
+
+    pub(crate) fn check_dir(&mut self, what: &str, path: &Path) {
+        if !path.is_dir() {
+            self.error(format!("{what} {path:?} is not an existing directory"));
+        }
+    }
+
+    pub(crate) fn check_placeholders(&mut self, what: &str, template: &str, known: &[&str]) {
+        for (position, placeholder) in unknown_placeholders(template, known) {
+            self.error(format!(
+                "{what} uses unknown placeholder {placeholder} at character {position} of \
+                 {template:?}"
+            ));
+        }
+    }
+
+    /// Error listing the problems found, if any, without printing them.
+    pub(crate) fn into_result(self) -> io::Result<()> {
+        if self.errors.is_empty() {
+            return Ok(());
+        }
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid configuration: {}", self.errors.join("; ")),
+        ))
+    }
+
+    /// Print diagnostics, erroring if any problem has been found.
+    pub(crate) fn conclude(self) -> io::Result<()> {
+        if self.errors.is_empty() {
+            println!("configuration is valid");
+            return Ok(());
+        }
+        for error in &self.errors {
+            eprintln!("- {error}");
+        }
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("found {} problem(s) in configuration", self.errors.len()),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn placeholders_all_known() {
+        let known = ["{foo}", "{bar}"];
+        assert!(unknown_placeholders("{foo}/{bar}.txt", &known).is_empty());
+    }
+
+    #[test]
+    fn placeholders_one_unknown() {
+        let known = ["{foo}"];
+        let unknown = unknown_placeholders("{foo}/{fooo}.txt", &known);
+        assert_eq!(unknown, [(7, "{fooo}")]);
+    }
+
+    #[test]
+    fn placeholders_ignore_non_identifiers() {
+        let known = ["{foo}"];
+        assert!(unknown_placeholders("awk '{ print $1 }' {foo}", &known).is_empty());
+    }
+
+    #[test]
+    fn placeholders_ignore_shell_variables() {
+        let known = ["{foo}"];
+        let unknown = unknown_placeholders("${HOME}/{foo}-${USER}-{bar}", &known);
+        assert_eq!(unknown, [(23, "{bar}")]);
+    }
+
+    #[test]
+    fn placeholders_in_json() {
+        let known = ["{foo}"];
+        let unknown =
+            unknown_placeholders(r#"{"a": {"b": "{foo}", "c": "{baz}"}, "d": {}}"#, &known);
+        assert_eq!(unknown, [(28, "{baz}")]);
+    }
+}
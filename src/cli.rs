@@ -1,16 +1,23 @@
 use std::{
     fs, io,
     path::{Path, PathBuf},
+    time::Duration,
 };
 
 use clap::{Parser, Subcommand};
 use serde::{Deserialize, Serialize};
 
+#[cfg(windows)]
+use crate::win_service::{self, ServiceKind};
 use crate::{
     client,
+    hashing::{self, DEFAULT_SAMPLE_BYTES, HashAlgorithm},
     server::{
-        self,
-        query::{self, Query},
+        self, control,
+        database::{ProcessStatus, PruneFilter},
+        export::ExportFormat,
+        manifest::Selection,
+        query::{self, Query, QueryConfig},
     },
 };
 
@@ -39,6 +46,39 @@ enum Commands {
         #[command(subcommand)]
         cmd: QueryCmd,
     },
+    /// Print the digests of a file as computed by the pipeline
+    Hash {
+        /// File to hash
+        path: PathBuf,
+        /// Hash function, as set by `hash_algorithm`
+        #[arg(long, value_enum, default_value_t)]
+        algorithm: HashAlgorithm,
+        /// Bytes read per sample by shallow hashes, as set by
+        /// `shallow_hash_bytes`
+        #[arg(long, value_parser = parse_size, default_value_t = DEFAULT_SAMPLE_BYTES)]
+        sample_bytes: u64,
+        /// File name used in shallow hashes instead of the one of `path`, e.g.
+        /// to hash the copy of a file on the server
+        #[arg(long)]
+        name: Option<String>,
+    },
+    /// Measure performance on the local hardware
+    Bench {
+        #[command(subcommand)]
+        cmd: BenchCmd,
+    },
+}
+
+#[derive(Subcommand)]
+enum BenchCmd {
+    /// Measure hashing throughput with the various hashing options
+    Hash {
+        /// File to hash, preferably as large as typical files
+        path: PathBuf,
+        /// Hash function used for shallow and concurrent hashes
+        #[arg(long, value_enum, default_value_t)]
+        algorithm: HashAlgorithm,
+    },
 }
 
 #[derive(Subcommand)]
@@ -53,11 +93,35 @@ enum ClientCmd {
         /// Configuration file
         config: PathBuf,
     },
+    /// Check configuration file, reporting any problem found
+    Check {
+        /// Configuration file
+        config: PathBuf,
+    },
     /// List files that would be processed in watched directory
     WatchedFiles {
         /// Configuration file
         config: PathBuf,
     },
+    /// Install pipeline client as a Windows service
+    #[cfg(windows)]
+    InstallService {
+        /// Configuration file
+        config: PathBuf,
+        /// Name of the service
+        #[arg(long, default_value = "pipeline-client")]
+        name: String,
+    },
+    /// Run pipeline client as a Windows service, meant for the service manager
+    #[cfg(windows)]
+    #[command(hide = true)]
+    RunAsService {
+        /// Configuration file
+        config: PathBuf,
+        /// Name of the service
+        #[arg(long)]
+        name: String,
+    },
     /// Print configuration example
     Config {
         /// Print configuration to this file, otherwise stdout
@@ -75,6 +139,11 @@ enum ServerCmd {
         /// Configuration file
         config: PathBuf,
     },
+    /// Check configuration file, reporting any problem found
+    Check {
+        /// Configuration file
+        config: PathBuf,
+    },
     /// Print configuration example
     Config {
         /// Print configuration to this file, otherwise stdout
@@ -87,11 +156,219 @@ enum ServerCmd {
         /// Also remove `Done` tasks instead of only `ToPrune` ones
         #[arg(long)]
         done: bool,
+        /// Remove tasks with this status instead of `ToPrune` ones, can be
+        /// repeated
+        #[arg(long)]
+        status: Vec<MarkStatus>,
+        /// Only remove files from this client
+        #[arg(long)]
+        client: Option<String>,
+        /// Only remove files with this tag
+        #[arg(long)]
+        tag: Option<String>,
+        /// Only remove files announced at least this long ago, e.g. `30d`,
+        /// `12h`, `90m` or `3600s`
+        #[arg(long, value_parser = parse_duration)]
+        older_than: Option<Duration>,
+        /// Only remove the oldest files until this much space is available on
+        /// the incoming volume, e.g. `500GiB`
+        #[arg(long, value_parser = parse_size)]
+        target_free: Option<u64>,
+        /// Only list the files that would be removed
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Create all buckets, e.g. to set permissions, and the database
+    #[command(alias = "create-buckets")]
+    Init {
+        /// Configuration file
+        config: PathBuf,
+    },
+    /// Live view of the activity of a running server
+    Top {
+        /// Configuration file
+        config: PathBuf,
+    },
+    /// Manage the server database
+    Db {
+        #[command(subcommand)]
+        cmd: DbCmd,
+    },
+    /// Re-hash received files, reporting missing, corrupted and orphan files
+    Verify {
+        /// Configuration file
+        config: PathBuf,
+        /// Only verify this directory, relative to the incoming directory,
+        /// e.g. a bucket
+        #[arg(long)]
+        directory: Option<PathBuf>,
+    },
+    /// List files in the incoming directory that are not in the database, and
+    /// remove them with `--delete`. The server must be stopped.
+    Gc {
+        /// Configuration file
+        config: PathBuf,
+        /// Move files to this directory
+        #[arg(long, conflicts_with = "delete")]
+        quarantine: Option<PathBuf>,
+        /// Remove files, they are only listed otherwise
+        #[arg(long)]
+        delete: bool,
     },
-    /// Convenience to create all buckets, e.g. to set permissions
-    CreateBuckets {
+    /// Print a JSON manifest of files with their origins, history and
+    /// processing attempts
+    Manifest {
         /// Configuration file
         config: PathBuf,
+        /// Hash of the file
+        #[arg(required_unless_present = "since", conflicts_with = "since")]
+        hash: Option<String>,
+        /// Include all files announced since this date (UTC), e.g.
+        /// `2025-01-31` or `2025-01-31 12:00:00`
+        #[arg(long, value_parser = parse_date)]
+        since: Option<String>,
+    },
+    /// Find files whose name, client path, client name or tags contain a
+    /// pattern
+    Search {
+        /// Configuration file
+        config: PathBuf,
+        /// Text to look for, `*` matches any sequence of characters and `?`
+        /// any single character
+        pattern: String,
+        /// Maximum number of files to show, the most recent first
+        #[arg(long, default_value_t = 100)]
+        limit: u32,
+    },
+    /// Report files received per day and client, and processing durations
+    /// and failures
+    Stats {
+        /// Configuration file
+        config: PathBuf,
+        /// Only account for files received and processed since this date
+        /// (UTC), e.g. `2025-01-31` or `2025-01-31 12:00:00`
+        #[arg(long, value_parser = parse_date)]
+        since: Option<String>,
+    },
+    /// Mirror the database and files of the primary server set in the
+    /// `[replication]` section, to take over if it fails
+    Standby {
+        /// Configuration file
+        config: PathBuf,
+    },
+    /// List processing clients, whether they are connected and when they last
+    /// delivered a file
+    Clients {
+        /// Configuration file
+        config: PathBuf,
+        /// Only list clients that did not deliver any file for this long, e.g.
+        /// `1h`
+        #[arg(long, value_parser = parse_duration)]
+        silent_for: Option<Duration>,
+    },
+    /// Attach a tag to a file in the pipeline
+    Tag {
+        /// Configuration file
+        config: PathBuf,
+        /// Hash of the file
+        hash: String,
+        tag: String,
+        /// Detach the tag instead
+        #[arg(long)]
+        remove: bool,
+    },
+    /// Print the steps a processing group runs on a local file, with
+    /// placeholders replaced, and optionally run them on a copy of the file
+    TestProcessing {
+        /// Configuration file
+        config: PathBuf,
+        /// File to process
+        file: PathBuf,
+        /// Processing group, required if there are several
+        #[arg(long)]
+        group: Option<String>,
+        /// Name of the client sending the file
+        #[arg(long, default_value = "test")]
+        client: String,
+        /// Metadata attached to the file, as `key=value`
+        #[arg(long = "meta", value_parser = parse_key_value)]
+        metadata: Vec<(String, String)>,
+        /// Run the steps on a copy of the file. Only `{server_path}` refers to
+        /// the copy, other effects of the steps are real
+        #[arg(long)]
+        execute: bool,
+        /// Run the steps without asking for confirmation
+        #[arg(long, requires = "execute")]
+        yes: bool,
+    },
+    /// Move a file pruned to the `archive_directory` back to the pipeline,
+    /// marked as done
+    Restore {
+        /// Configuration file
+        config: PathBuf,
+        /// Hash of the file
+        hash: String,
+    },
+    /// Show the history of a file in the pipeline
+    Audit {
+        /// Configuration file
+        config: PathBuf,
+        /// Hash of the file
+        hash: String,
+    },
+    /// Install pipeline server as a Windows service
+    #[cfg(windows)]
+    InstallService {
+        /// Configuration file
+        config: PathBuf,
+        /// Name of the service
+        #[arg(long, default_value = "pipeline-server")]
+        name: String,
+    },
+    /// Run pipeline server as a Windows service, meant for the service manager
+    #[cfg(windows)]
+    #[command(hide = true)]
+    RunAsService {
+        /// Configuration file
+        config: PathBuf,
+        /// Name of the service
+        #[arg(long)]
+        name: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum DbCmd {
+    /// Check integrity of the database and reclaim unused space
+    Maintain {
+        /// Configuration file
+        config: PathBuf,
+    },
+    /// Copy the database while the server may be running
+    Backup {
+        /// Configuration file
+        config: PathBuf,
+        /// Destination file, or directory in which to create a timestamped
+        /// backup
+        dest: PathBuf,
+    },
+    /// Print the content of the database
+    Export {
+        /// Configuration file
+        config: PathBuf,
+        #[arg(long, value_enum, default_value_t = ExportFormat::Json)]
+        format: ExportFormat,
+        /// Only export this table
+        #[arg(long)]
+        table: Option<String>,
+    },
+    /// Load a JSON export into an empty database, e.g. to move the server to
+    /// another host
+    Import {
+        /// Configuration file
+        config: PathBuf,
+        /// File produced by `db export --format json`
+        path: PathBuf,
     },
 }
 
@@ -99,17 +376,47 @@ enum ServerCmd {
 enum QueryCmd {
     /// List files in pipeline and their status
     List {
-        /// Configuration file
-        config: PathBuf,
+        /// Configuration file, not needed with `--address`
+        #[arg(long, required_unless_present = "address")]
+        config: Option<PathBuf>,
+        /// Address of the server, instead of the one in the configuration
+        #[arg(long, conflicts_with = "config")]
+        address: Option<String>,
+        /// Only list files with this status
+        #[arg(long)]
+        status: Option<ProcessStatus>,
+        /// Only list files with this tag
+        #[arg(long)]
+        tag: Option<String>,
+    },
+    /// Process failed and abandoned files again, all of them unless a hash or
+    /// tag is given
+    Retry {
+        /// Configuration file, not needed with `--address`
+        #[arg(long, required_unless_present = "address")]
+        config: Option<PathBuf>,
+        /// Hash of the file
+        hash: Option<String>,
+        /// Address of the server, instead of the one in the configuration
+        #[arg(long, conflicts_with = "config")]
+        address: Option<String>,
+        /// Only retry files with this tag
+        #[arg(long)]
+        tag: Option<String>,
     },
     /// Change the status of a file in the pipeline
     Mark {
-        /// Configuration file
-        config: PathBuf,
+        /// Configuration file, not needed with `--address`
+        #[arg(long, required_unless_present = "address")]
+        config: Option<PathBuf>,
         /// Hash of the processed file to update
         hash: String,
-        /// Desired status to set
-        status: MarkStatus,
+        /// Desired status to set, or `pin` to keep the file in the pipeline
+        /// regardless of its status and `unpin` to let it be pruned again
+        mark: Mark,
+        /// Address of the server, instead of the one in the configuration
+        #[arg(long, conflicts_with = "config")]
+        address: Option<String>,
     },
     /// Mark "done" tasks as "to-prune"
     PruneDone {
@@ -118,8 +425,53 @@ enum QueryCmd {
     },
     /// Check status of server
     Status {
-        /// Configuration file
-        config: PathBuf,
+        /// Configuration file, not needed with `--address`
+        #[arg(long, required_unless_present = "address")]
+        config: Option<PathBuf>,
+        /// Address of the server, instead of the one in the configuration
+        #[arg(long, conflicts_with = "config")]
+        address: Option<String>,
+    },
+    /// Stop accepting new files and launching processing, both unless one is
+    /// given
+    Pause {
+        /// Configuration file, not needed with `--address`
+        #[arg(required_unless_present = "address")]
+        config: Option<PathBuf>,
+        /// Address of the server, instead of the one in the configuration
+        #[arg(long, conflicts_with = "config")]
+        address: Option<String>,
+        /// Stop accepting new files, clients announce them again later
+        #[arg(long)]
+        accepting: bool,
+        /// Stop launching processing, files in progress are not interrupted
+        #[arg(long)]
+        processing: bool,
+    },
+    /// Resume what `pause` stopped, everything unless one is given
+    Resume {
+        /// Configuration file, not needed with `--address`
+        #[arg(required_unless_present = "address")]
+        config: Option<PathBuf>,
+        /// Address of the server, instead of the one in the configuration
+        #[arg(long, conflicts_with = "config")]
+        address: Option<String>,
+        /// Accept new files again
+        #[arg(long)]
+        accepting: bool,
+        /// Launch processing again
+        #[arg(long)]
+        processing: bool,
+    },
+    /// Stop accepting new files, finish processing the ones in progress and
+    /// stop the server
+    Drain {
+        /// Configuration file, not needed with `--address`
+        #[arg(required_unless_present = "address")]
+        config: Option<PathBuf>,
+        /// Address of the server, instead of the one in the configuration
+        #[arg(long, conflicts_with = "config")]
+        address: Option<String>,
     },
     /// Print configuration example
     Config {
@@ -128,13 +480,107 @@ enum QueryCmd {
     },
 }
 
-#[derive(clap::ValueEnum, Serialize, Deserialize, Copy, Clone, Debug)]
+#[derive(clap::ValueEnum, Serialize, Deserialize, Copy, Clone, PartialEq, Eq, Debug)]
 pub(crate) enum MarkStatus {
     Done,
     Failed,
+    Abandoned,
     ToPrune,
 }
 
+/// What `query mark` applies to a file.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug)]
+pub(crate) enum Mark {
+    Status(MarkStatus),
+    /// Never prune the file, whatever its status.
+    Pin,
+    Unpin,
+}
+
+impl clap::ValueEnum for Mark {
+    fn value_variants<'a>() -> &'a [Self] {
+        &[
+            Self::Status(MarkStatus::Done),
+            Self::Status(MarkStatus::Failed),
+            Self::Status(MarkStatus::Abandoned),
+            Self::Status(MarkStatus::ToPrune),
+            Self::Pin,
+            Self::Unpin,
+        ]
+    }
+
+    fn to_possible_value(&self) -> Option<clap::builder::PossibleValue> {
+        match self {
+            Self::Status(status) => status.to_possible_value(),
+            Self::Pin => Some(clap::builder::PossibleValue::new("pin")),
+            Self::Unpin => Some(clap::builder::PossibleValue::new("unpin")),
+        }
+    }
+}
+
+/// Parse a duration made of a number and a unit among `s`, `m`, `h` and `d`.
+fn parse_duration(value: &str) -> Result<Duration, String> {
+    let unit_secs = match value.chars().last() {
+        Some('s') => 1,
+        Some('m') => 60,
+        Some('h') => 3600,
+        Some('d') => 86400,
+        _ => return Err("expected a unit among s, m, h and d".to_owned()),
+    };
+    let number: u64 = value[..value.len() - 1]
+        .parse()
+        .map_err(|err| format!("invalid number: {err}"))?;
+    number
+        .checked_mul(unit_secs)
+        .map(Duration::from_secs)
+        .ok_or_else(|| format!("duration {value:?} is too long"))
+}
+
+/// Parse a size in bytes, with an optional unit such as `kB`, `MiB` or `GiB`.
+fn parse_size(value: &str) -> Result<u64, String> {
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let multiplier: u64 = match unit.trim() {
+        "" | "B" => 1,
+        "kB" => 1000,
+        "MB" => 1000u64.pow(2),
+        "GB" => 1000u64.pow(3),
+        "TB" => 1000u64.pow(4),
+        "KiB" | "kiB" => 1024,
+        "MiB" => 1024u64.pow(2),
+        "GiB" => 1024u64.pow(3),
+        "TiB" => 1024u64.pow(4),
+        unit => return Err(format!("unknown unit {unit:?}")),
+    };
+    let number: u64 = number
+        .parse()
+        .map_err(|err| format!("invalid number: {err}"))?;
+    number
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("size {value:?} is too large"))
+}
+
+/// Parse a date with an optional time, formatted as dates in the database.
+fn parse_key_value(value: &str) -> Result<(String, String), String> {
+    value
+        .split_once('=')
+        .map(|(key, value)| (key.to_owned(), value.to_owned()))
+        .ok_or_else(|| format!("expected key=value, got {value:?}"))
+}
+
+fn parse_date(value: &str) -> Result<String, String> {
+    let format = "%Y-%m-%d %H:%M:%S";
+    let datetime = chrono::NaiveDateTime::parse_from_str(value, format)
+        .or_else(|_| {
+            chrono::NaiveDate::parse_from_str(value, "%Y-%m-%d")
+                .map(|d| d.and_time(Default::default()))
+        })
+        .map_err(|err| format!("expected YYYY-MM-DD [HH:MM:SS]: {err}"))?;
+    Ok(datetime.format(format).to_string())
+}
+
 fn conf_from_toml<T: for<'a> Deserialize<'a>>(path: &Path) -> io::Result<T> {
     let content = fs::read(path)?;
     match toml::from_slice(&content) {
@@ -149,7 +595,7 @@ fn conf_from_toml<T: for<'a> Deserialize<'a>>(path: &Path) -> io::Result<T> {
     }
 }
 
-fn read_conf_and_chdir<T: for<'a> Deserialize<'a>>(path: &Path) -> io::Result<T> {
+pub(crate) fn read_conf_and_chdir<T: for<'a> Deserialize<'a>>(path: &Path) -> io::Result<T> {
     let config = conf_from_toml(path)?;
     let work_dir = path
         .parent()
@@ -164,9 +610,18 @@ async fn client_cli(cmd: ClientCmd) -> io::Result<()> {
     match cmd {
         ClientCmd::Start { config } => client::main(read_conf_and_chdir(&config)?, false).await,
         ClientCmd::StartOnce { config } => client::main(read_conf_and_chdir(&config)?, true).await,
+        ClientCmd::Check { config } => client::check::main(read_conf_and_chdir(&config)?),
         ClientCmd::WatchedFiles { config } => {
             client::watch::main(read_conf_and_chdir(&config)?).await
         }
+        #[cfg(windows)]
+        ClientCmd::InstallService { config, name } => {
+            win_service::install(ServiceKind::Client, &name, &config)
+        }
+        #[cfg(windows)]
+        ClientCmd::RunAsService { config, name } => {
+            win_service::run(ServiceKind::Client, name, config)
+        }
         ClientCmd::Config { path, ssh_tunnel } => {
             let content: &str = if ssh_tunnel {
                 client::TUNNEL_TOML_CONF.as_ref()
@@ -185,6 +640,7 @@ async fn client_cli(cmd: ClientCmd) -> io::Result<()> {
 async fn server_cli(cmd: ServerCmd) -> io::Result<()> {
     match cmd {
         ServerCmd::Start { config } => server::main(read_conf_and_chdir(&config)?).await,
+        ServerCmd::Check { config } => server::check::main(read_conf_and_chdir(&config)?).await,
         ServerCmd::Config { path } => {
             let content = server::DEFAULT_TOML_CONF;
             match path {
@@ -193,38 +649,222 @@ async fn server_cli(cmd: ServerCmd) -> io::Result<()> {
             }
             Ok(())
         }
-        ServerCmd::Clean { config, done } => {
-            server::clean::main(read_conf_and_chdir(&config)?, done).await
+        ServerCmd::Clean {
+            config,
+            done,
+            mut status,
+            client,
+            tag,
+            older_than,
+            target_free,
+            dry_run,
+        } => {
+            if status.is_empty() {
+                status.push(MarkStatus::ToPrune);
+            }
+            if done {
+                status.push(MarkStatus::Done);
+            }
+            let filter = PruneFilter {
+                client,
+                older_than,
+                include_pinned: false,
+                tag,
+            };
+            let config = read_conf_and_chdir(&config)?;
+            server::clean::main(config, status, filter, target_free, dry_run).await
         }
-        ServerCmd::CreateBuckets { config } => {
+        ServerCmd::Init { config } => {
             server::create_buckets::main(read_conf_and_chdir(&config)?).await
         }
+        ServerCmd::Top { config } => server::top::main(read_conf_and_chdir(&config)?).await,
+        ServerCmd::Db { cmd } => db_cli(cmd).await,
+        ServerCmd::Verify { config, directory } => {
+            server::verify::main(read_conf_and_chdir(&config)?, directory).await
+        }
+        ServerCmd::Gc {
+            config,
+            quarantine,
+            delete,
+        } => {
+            use server::gc::Collect;
+            let collect = match quarantine {
+                // Relative to the current directory, before moving to the one
+                // of the configuration file.
+                Some(quarantine) => Collect::Quarantine(std::path::absolute(quarantine)?),
+                None if delete => Collect::Delete,
+                None => Collect::List,
+            };
+            server::gc::main(read_conf_and_chdir(&config)?, collect).await
+        }
+        ServerCmd::Manifest {
+            config,
+            hash,
+            since,
+        } => {
+            let selection = match hash {
+                Some(hash) => Selection::Hash(hash),
+                None => Selection::Since(since.expect("clap requires a hash or a date")),
+            };
+            server::manifest::main(read_conf_and_chdir(&config)?, selection).await
+        }
+        ServerCmd::Search {
+            config,
+            pattern,
+            limit,
+        } => server::search::main(read_conf_and_chdir(&config)?, &pattern, limit).await,
+        ServerCmd::Stats { config, since } => {
+            let since = since.unwrap_or_default();
+            server::stats::main(read_conf_and_chdir(&config)?, &since).await
+        }
+        ServerCmd::Standby { config } => {
+            server::replication::standby(read_conf_and_chdir(&config)?).await
+        }
+        ServerCmd::Clients { config, silent_for } => {
+            server::clients::main(read_conf_and_chdir(&config)?, silent_for).await
+        }
+        ServerCmd::Tag {
+            config,
+            hash,
+            tag,
+            remove,
+        } => server::audit::tag(read_conf_and_chdir(&config)?, &hash, &tag, remove).await,
+        ServerCmd::TestProcessing {
+            config,
+            file,
+            group,
+            client,
+            metadata,
+            execute,
+            yes,
+        } => {
+            // Relative to the current directory, before moving to the one of
+            // the configuration file.
+            let file = std::path::absolute(file)?;
+            let config = read_conf_and_chdir(&config)?;
+            let metadata = metadata.into_iter().collect();
+            let execute = execute.then_some(yes);
+            server::dry_run::main(config, &file, group, client, metadata, execute).await
+        }
+        ServerCmd::Restore { config, hash } => {
+            server::archive::restore(read_conf_and_chdir(&config)?, &hash).await
+        }
+        ServerCmd::Audit { config, hash } => {
+            server::audit::main(read_conf_and_chdir(&config)?, &hash).await
+        }
+        #[cfg(windows)]
+        ServerCmd::InstallService { config, name } => {
+            win_service::install(ServiceKind::Server, &name, &config)
+        }
+        #[cfg(windows)]
+        ServerCmd::RunAsService { config, name } => {
+            win_service::run(ServiceKind::Server, name, config)
+        }
+    }
+}
+
+async fn db_cli(cmd: DbCmd) -> io::Result<()> {
+    match cmd {
+        DbCmd::Maintain { config } => {
+            server::maintenance::main(read_conf_and_chdir(&config)?).await
+        }
+        DbCmd::Backup { config, dest } => {
+            // Relative to the current directory, before moving to the one of
+            // the configuration file.
+            let dest = std::path::absolute(dest)?;
+            server::maintenance::backup(read_conf_and_chdir(&config)?, &dest).await
+        }
+        DbCmd::Export {
+            config,
+            format,
+            table,
+        } => server::export::export(read_conf_and_chdir(&config)?, format, table).await,
+        DbCmd::Import { config, path } => {
+            // Relative to the current directory, before moving to the one of
+            // the configuration file.
+            let path = std::path::absolute(path)?;
+            server::export::import(read_conf_and_chdir(&config)?, &path).await
+        }
+    }
+}
+
+/// Read the query configuration, or reach the server directly at `address`.
+fn query_conf(config: Option<PathBuf>, address: Option<String>) -> io::Result<QueryConfig> {
+    match (config, address) {
+        (_, Some(address)) => Ok(QueryConfig::direct(address)),
+        (Some(config), None) => read_conf_and_chdir(&config),
+        (None, None) => unreachable!("clap requires either a configuration or an address"),
     }
 }
 
 async fn query_cli(cmd: QueryCmd) -> io::Result<()> {
     match cmd {
-        QueryCmd::List { config } => {
-            let config = read_conf_and_chdir(&config)?;
-            query::main(config, Query::List).await
+        QueryCmd::List {
+            config,
+            address,
+            status,
+            tag,
+        } => {
+            let config = query_conf(config, address)?;
+            query::main(config, Query::List { status, tag }).await
+        }
+        QueryCmd::Retry {
+            config,
+            hash,
+            address,
+            tag,
+        } => {
+            let config = query_conf(config, address)?;
+            query::main(config, Query::Retry { hash, tag }).await
         }
         QueryCmd::Mark {
             config,
             hash,
-            status,
+            mark,
+            address,
         } => {
-            let config = read_conf_and_chdir(&config)?;
-            let query = Query::Mark { hash, status };
+            let config = query_conf(config, address)?;
+            let query = Query::Mark { hash, mark };
             query::main(config, query).await
         }
         QueryCmd::PruneDone { config } => {
             let config = read_conf_and_chdir(&config)?;
             query::main(config, Query::PruneDone).await
         }
-        QueryCmd::Status { config } => {
-            let config = read_conf_and_chdir(&config)?;
+        QueryCmd::Status { config, address } => {
+            let config = query_conf(config, address)?;
             query::main(config, Query::Status).await
         }
+        QueryCmd::Pause {
+            config,
+            address,
+            accepting,
+            processing,
+        } => {
+            let both = !accepting && !processing;
+            let command = control::Command::Pause {
+                accepting: accepting || both,
+                processing: processing || both,
+            };
+            query::main(query_conf(config, address)?, Query::Control(command)).await
+        }
+        QueryCmd::Resume {
+            config,
+            address,
+            accepting,
+            processing,
+        } => {
+            let both = !accepting && !processing;
+            let command = control::Command::Resume {
+                accepting: accepting || both,
+                processing: processing || both,
+            };
+            query::main(query_conf(config, address)?, Query::Control(command)).await
+        }
+        QueryCmd::Drain { config, address } => {
+            let query = Query::Control(control::Command::Drain);
+            query::main(query_conf(config, address)?, query).await
+        }
         QueryCmd::Config { path } => {
             let content = query::QUERY_TOML_CONF;
             match path {
@@ -242,6 +882,15 @@ pub async fn main() -> io::Result<()> {
         Commands::Client { cmd } => client_cli(cmd).await,
         Commands::Server { cmd } => server_cli(cmd).await,
         Commands::Query { cmd } => query_cli(cmd).await,
+        Commands::Hash {
+            path,
+            algorithm,
+            sample_bytes,
+            name,
+        } => hashing::main(&path, name, algorithm, sample_bytes),
+        Commands::Bench {
+            cmd: BenchCmd::Hash { path, algorithm },
+        } => hashing::bench::main(&path, algorithm),
     }
 }
 
@@ -255,4 +904,70 @@ mod tests {
     fn verify_cli() {
         Cli::command().debug_assert();
     }
+
+    #[test]
+    fn query_hash_with_or_without_config() {
+        let parse = |args: &[&str]| {
+            let args = ["pipeline", "query"].iter().chain(args);
+            match Cli::try_parse_from(args).map(|cli| cli.command) {
+                Ok(Commands::Query {
+                    cmd: QueryCmd::Mark { config, hash, .. },
+                }) => Some((config, hash)),
+                _ => None,
+            }
+        };
+        let hash = "0123abcd".to_owned();
+        assert_eq!(
+            parse(&["mark", "--address", "localhost:1", "0123abcd", "done"]),
+            Some((None, hash.clone()))
+        );
+        assert_eq!(
+            parse(&["mark", "--config", "q.toml", "0123abcd", "pin"]),
+            Some((Some(PathBuf::from("q.toml")), hash))
+        );
+        assert_eq!(parse(&["mark", "q.toml", "0123abcd", "done"]), None);
+        assert_eq!(parse(&["mark", "0123abcd", "done"]), None);
+
+        for cmd in ["list", "status", "retry"] {
+            let parses = |args: &[&str]| {
+                let args = ["pipeline", "query", cmd]
+                    .into_iter()
+                    .chain(args.iter().copied());
+                Cli::try_parse_from(args).is_ok()
+            };
+            assert!(parses(&["--config", "q.toml"]), "{cmd} --config");
+            assert!(parses(&["--address", "localhost:1"]), "{cmd} --address");
+            assert!(!parses(&[]), "{cmd} without server");
+        }
+        let positional = ["pipeline", "query", "list", "q.toml"];
+        assert!(Cli::try_parse_from(positional).is_err());
+    }
+
+    #[test]
+    fn parse_durations() {
+        assert_eq!(parse_duration("90m"), Ok(Duration::from_secs(5400)));
+        assert_eq!(parse_duration("30d"), Ok(Duration::from_secs(30 * 86400)));
+        assert!(parse_duration("30").is_err());
+        assert!(parse_duration("d").is_err());
+        assert!(parse_duration(&format!("{}d", u64::MAX / 1000)).is_err());
+    }
+
+    #[test]
+    fn parse_sizes() {
+        assert_eq!(parse_size("500GiB"), Ok(500 * 1024u64.pow(3)));
+        assert_eq!(parse_size("2 MB"), Ok(2_000_000));
+        assert_eq!(parse_size("42"), Ok(42));
+        assert!(parse_size("3 parsecs").is_err());
+        assert!(parse_size("20000000TiB").is_err());
+    }
+
+    #[test]
+    fn parse_dates() {
+        assert_eq!(parse_date("2025-01-31").unwrap(), "2025-01-31 00:00:00");
+        assert_eq!(
+            parse_date("2025-01-31 12:30:00").unwrap(),
+            "2025-01-31 12:30:00"
+        );
+        assert!(parse_date("31/01/2025").is_err());
+    }
 }
@@ -1,42 +1,246 @@
+pub(crate) mod check;
+pub(crate) mod hash_cache;
 pub(crate) mod watch;
 
 use std::{
-    collections::HashSet,
+    collections::{BTreeMap, HashMap},
+    ffi::OsStr,
     io,
-    path::PathBuf,
+    path::{Path, PathBuf},
     process::ExitStatus,
-    sync::{Arc, LazyLock},
+    sync::{
+        self as std_sync, Arc, LazyLock,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, SystemTime},
 };
 
 use crate::{
-    FileSpec, Receipt, assemble_path, custom_serde,
-    framed_io::{ReadFramedJson, WriteFramedJson, json_channel},
-    handshake::{self, RequestPayload},
+    ClientMessage, ClientRequest, ConfigSource, FileSpec, Receipt, Reconciliation, ServerReply,
+    assemble_path, custom_serde, format_size,
+    framed_io::{ReadFramedJson, WriteFramedJson, default_max_frame_length, json_channel},
+    handshake::{self, Hashing, RequestPayload},
+    hashing::{FileDigest, HashAlgorithm, HashMode, ReadMode, ReadOptions},
     replace_os_strings,
     server_route::ServerRoute,
+    systemd,
 };
 use futures_util::TryStreamExt;
 use futures_util::sink::SinkExt;
-use log::{debug, info, warn};
-use serde::Deserialize;
+use hash_cache::HashCache;
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Deserializer};
 use tokio::{
     fs,
+    io::AsyncWrite,
     net::tcp::{OwnedReadHalf, OwnedWriteHalf},
     process::Command,
-    sync::Mutex,
+    sync::{Mutex, Semaphore},
+    task::JoinSet,
+    time::Instant,
 };
 
-type Db = Arc<Mutex<HashSet<PathBuf>>>;
-type ToServer<W> = Arc<Mutex<WriteFramedJson<FileSpec, W>>>;
+/// Files announced to the server and not confirmed yet, with the number of
+/// times each has been sent again after an error.
+type Db = Arc<Mutex<HashMap<PathBuf, u32>>>;
+type ToServer<W> = Arc<Mutex<Outbox<W>>>;
+/// Time until which announcements are paused, see [`Receipt::SlowDown`] and
+/// [`Receipt::QuotaExceeded`].
+type Pause = Arc<Mutex<Instant>>;
 
+/// Id of the next request sent to the server, see [`ClientRequest`]. Ids
+/// start from the time the client started, in microseconds, so that they keep
+/// increasing when the client restarts.
+static NEXT_REQUEST_ID: LazyLock<AtomicU64> = LazyLock::new(|| {
+    let since_epoch = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    AtomicU64::new(since_epoch.as_micros() as u64)
+});
+
+/// Delay after which a loop that reports no progress is considered stuck, see
+/// [`Liveness`].
+const STALL_AFTER: Duration = Duration::from_secs(600);
+
+/// Progress of a loop of the client, the systemd watchdog is only fed while
+/// all loops make progress.
+#[derive(Clone, Default)]
+pub(super) struct Liveness(Arc<std_sync::Mutex<Option<Instant>>>);
+
+impl Liveness {
+    /// Record progress, more is expected within `next` and [`STALL_AFTER`].
+    pub(super) fn progress(&self, next: Duration) {
+        *self.0.lock().unwrap() = Some(Instant::now() + next + STALL_AFTER);
+    }
+
+    /// Stop expecting progress, e.g. while the loop is not running.
+    fn idle(&self) {
+        *self.0.lock().unwrap() = None;
+    }
+
+    fn is_alive(&self) -> bool {
+        self.0
+            .lock()
+            .unwrap()
+            .is_none_or(|deadline| Instant::now() <= deadline)
+    }
+}
+
+/// Sending half of the connection to the server, numbering requests.
+struct Outbox<W> {
+    sink: WriteFramedJson<ClientRequest, W>,
+    /// Id of the latest request about each file.
+    latest: HashMap<PathBuf, u64>,
+    /// Files of [`ClientMessage::Reconcile`] requests not answered yet.
+    reconciling: HashMap<u64, Vec<FileSpec>>,
+    /// Files the server is still receiving, see [`CopyProgress`].
+    incomplete: HashMap<PathBuf, CopyProgress>,
+}
+
+/// Progress of a copy the server reported incomplete.
+#[derive(Default)]
+struct CopyProgress {
+    received_bytes: u64,
+    /// Times the copy was reported incomplete.
+    checks: u32,
+    /// Times in a row the copy was reported without progress.
+    stalled: u32,
+}
+
+impl<W: AsyncWrite + Unpin> Outbox<W> {
+    fn new(sink: WriteFramedJson<ClientRequest, W>) -> Self {
+        Self {
+            sink,
+            latest: HashMap::new(),
+            reconciling: HashMap::new(),
+            incomplete: HashMap::new(),
+        }
+    }
+
+    /// Send `message` to the server, returning the id of the request.
+    async fn send(&mut self, message: ClientMessage) -> io::Result<u64> {
+        let id = NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed);
+        match &message {
+            ClientMessage::Announce(spec) | ClientMessage::FullHash { spec, .. } => {
+                self.latest.insert(spec.relative_path(), id);
+            }
+            ClientMessage::Reconcile(files) => {
+                self.reconciling.insert(id, files.clone());
+            }
+            ClientMessage::Ping => {}
+        }
+        let sent = self.sink.send(ClientRequest { id, message }).await;
+        if sent.is_err() {
+            self.reconciling.remove(&id);
+        }
+        sent.map(|()| id)
+    }
+
+    /// Files of the reconciliation request `id`, answered by the server.
+    fn reconciled(&mut self, id: u64) -> Option<Vec<FileSpec>> {
+        self.reconciling.remove(&id)
+    }
+
+    /// Whether a receipt about `spec` answers the latest request about it.
+    /// Receipts sent without request are only current if no request about
+    /// `spec` awaits an answer.
+    fn is_current(&self, spec: &FileSpec, in_reply_to: Option<u64>) -> bool {
+        let latest = self.latest.get(&spec.relative_path());
+        match in_reply_to {
+            Some(id) => latest.is_none_or(|latest| *latest == id),
+            None => latest.is_none(),
+        }
+    }
+
+    /// Record that the server received `received_bytes` of `spec` so far.
+    fn incomplete(&mut self, spec: &FileSpec, received_bytes: u64) -> &CopyProgress {
+        let progress = self.incomplete.entry(spec.relative_path()).or_default();
+        if received_bytes > progress.received_bytes || progress.checks == 0 {
+            progress.received_bytes = received_bytes;
+            progress.stalled = 0;
+        }
+        progress.checks += 1;
+        progress.stalled += 1;
+        progress
+    }
+
+    fn forget(&mut self, spec: &FileSpec) {
+        let path = spec.relative_path();
+        self.latest.remove(&path);
+        self.incomplete.remove(&path);
+    }
+}
+
+/// Configuration of the client, refused when deserialized if it uses unknown
+/// placeholders.
 #[derive(Deserialize, Debug)]
+#[serde(remote = "Self")]
 pub(crate) struct Config {
     name: String,
     copy_to_server: CopyToServer,
     server: ServerRoute,
+    #[serde(
+        default = "default_ping_every_secs",
+        deserialize_with = "custom_serde::at_least_one"
+    )]
+    ping_every_secs: u64,
+    #[serde(default = "default_max_frame_length")]
+    max_frame_length: usize,
+    #[serde(default = "default_max_resends")]
+    max_resends: u32,
+    #[serde(default = "default_resend_backoff_secs")]
+    resend_backoff_secs: u64,
+    on_give_up: Option<Vec<String>>,
+    on_received: Option<Vec<String>>,
+    on_hash_mismatch: Option<Vec<String>>,
+    on_processed: Option<Vec<String>>,
+    #[serde(default = "default_hook_timeout_secs")]
+    hook_timeout_secs: u64,
+    #[serde(
+        default = "default_max_concurrent_copies",
+        deserialize_with = "custom_serde::at_least_one"
+    )]
+    max_concurrent_copies: usize,
+    #[serde(default = "default_progress_every_secs")]
+    progress_every_secs: u64,
+    #[serde(default)]
+    after_confirmation: AfterConfirmation,
+    #[serde(default)]
+    wait_for_processing: bool,
+    #[serde(default)]
+    verify_copy: bool,
+    verify_copy_directory: Option<PathBuf>,
     watching: Watching,
 }
 
+fn default_ping_every_secs() -> u64 {
+    60
+}
+
+fn default_hook_timeout_secs() -> u64 {
+    60
+}
+
+fn default_max_concurrent_copies() -> usize {
+    4
+}
+
+fn default_max_examined_files() -> usize {
+    256
+}
+
+fn default_progress_every_secs() -> u64 {
+    30
+}
+
+fn default_max_resends() -> u32 {
+    5
+}
+
+fn default_resend_backoff_secs() -> u64 {
+    1
+}
+
 #[derive(Deserialize, Debug)]
 #[serde(untagged)]
 enum CopyToServer {
@@ -46,6 +250,15 @@ enum CopyToServer {
 }
 
 impl CopyToServer {
+    /// Directory where copied files are visible from the client, if known.
+    fn destination(&self) -> Option<&Path> {
+        match self {
+            CopyToServer::Move { move_in_same_fs_to } => Some(move_in_same_fs_to),
+            CopyToServer::Copy { destination } => Some(destination),
+            CopyToServer::Command(_) => None,
+        }
+    }
+
     fn requires_cleanup(&self) -> bool {
         match self {
             CopyToServer::Move { .. } => false,
@@ -55,6 +268,16 @@ impl CopyToServer {
     }
 }
 
+/// What to do with files copied to the server once it confirmed their reception.
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+enum AfterConfirmation {
+    #[default]
+    Delete,
+    /// Move files to this directory, mirroring their path in the watched one.
+    MoveTo(PathBuf),
+}
+
 pub(crate) static DEFAULT_TOML_CONF: LazyLock<String> = LazyLock::new(|| {
     format!(
         include_str!("client/default.toml"),
@@ -73,8 +296,36 @@ pub(crate) static TUNNEL_TOML_CONF: LazyLock<String> = LazyLock::new(|| {
 struct Watching {
     directory: PathBuf,
     refresh_every_secs: u64,
+    #[serde(default)]
+    full_scan_every_refreshes: u32,
+    #[serde(default)]
+    stable_across_scans: bool,
+    #[serde(default)]
+    skip_locked_files: bool,
+    #[serde(default)]
+    skip_hidden: bool,
+    #[serde(default)]
+    exclude_directories: Vec<String>,
+    max_depth: Option<usize>,
+    #[serde(default)]
+    follow_symlinks: bool,
     max_concurrent_hashes: usize,
+    #[serde(
+        default = "default_max_examined_files",
+        deserialize_with = "custom_serde::at_least_one"
+    )]
+    max_examined_files: usize,
     heartbeat_every_refreshes: u32,
+    #[serde(default = "crate::hashing::default_sample_bytes")]
+    shallow_hash_bytes: u64,
+    #[serde(default)]
+    hash_algorithm: HashAlgorithm,
+    #[serde(default)]
+    tree_hash_threads: usize,
+    #[serde(default)]
+    hash_read_mode: ReadMode,
+    metadata_sidecar: Option<String>,
+    hash_cache: Option<PathBuf>,
     #[serde(deserialize_with = "custom_serde::vec_at_least_one")]
     groups: Vec<WatchingGroup>,
 }
@@ -85,6 +336,12 @@ struct WatchingGroup {
     processing: String,
     last_modif_secs: u64,
     full_hash: bool,
+    #[serde(default)]
+    sampled_hash: bool,
+    #[serde(default)]
+    metadata: BTreeMap<String, String>,
+    #[serde(default)]
+    companion_extensions: Vec<String>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -100,6 +357,18 @@ fn default_max_depth() -> usize {
     usize::MAX
 }
 
+impl WatchingGroup {
+    fn hash_mode(&self, sample_bytes: u64) -> HashMode {
+        if self.full_hash {
+            HashMode::Full
+        } else if self.sampled_hash {
+            HashMode::Sampled(sample_bytes)
+        } else {
+            HashMode::Shallow(sample_bytes)
+        }
+    }
+}
+
 impl Watching {
     fn min_depth(&self) -> usize {
         self.groups
@@ -110,19 +379,67 @@ impl Watching {
     }
 
     fn max_depth(&self) -> usize {
-        self.groups
+        let groups_max = self
+            .groups
             .iter()
             .map(|g| g.filters.max_depth)
             .max()
-            .unwrap_or(default_max_depth())
+            .unwrap_or(default_max_depth());
+        self.max_depth.map_or(groups_max, |max| max.min(groups_max))
+    }
+}
+
+impl<'de> Deserialize<'de> for Config {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let config = Config::deserialize(deserializer)?;
+        check::placeholders_at_load(&config).map_err(serde::de::Error::custom)?;
+        Ok(config)
     }
 }
 
 impl Config {
+    /// Whether files are only cleaned up once the server processed them,
+    /// rather than as soon as it received them.
+    fn waits_for_processing(&self) -> bool {
+        self.wait_for_processing && self.copy_to_server.requires_cleanup()
+    }
+
+    /// Whether a file sent `resends` times after an error should be given up.
+    fn gave_up(&self, resends: u32) -> bool {
+        resends > self.max_resends
+    }
+
+    /// Delay before sending a file for the `resends`-th time, doubling each time.
+    fn resend_delay(&self, resends: u32) -> Duration {
+        let factor = 2_u64.saturating_pow(resends.saturating_sub(1));
+        Duration::from_secs(self.resend_backoff_secs.saturating_mul(factor))
+    }
+
+    /// How full hashes read files. Watched files may still be written, so
+    /// they are never mapped in memory, see [`ReadOptions::unmapped`].
+    fn read_options(&self) -> ReadOptions {
+        ReadOptions {
+            mode: self.watching.hash_read_mode,
+            tree_hash_threads: self.watching.tree_hash_threads,
+        }
+    }
+
     fn watched_path(&self, spec: &FileSpec) -> PathBuf {
         assemble_path(&self.watching.directory, spec.relative_path())
     }
 
+    /// Directory where the copies on the server can be checked from the client.
+    fn verify_copy_directory(&self) -> Option<&Path> {
+        self.verify_copy_directory
+            .as_deref()
+            .or(self.copy_to_server.destination())
+    }
+
+    fn watched_companion_path(&self, spec: &FileSpec) -> Option<PathBuf> {
+        let rel_path = spec.companion_relative_path()?;
+        Some(assemble_path(&self.watching.directory, rel_path))
+    }
+
     pub(crate) fn processing_groups(&self) -> Vec<String> {
         self.watching
             .groups
@@ -131,47 +448,370 @@ impl Config {
             .cloned()
             .collect()
     }
+
+    /// Hashing parameters of the processing groups, checked by the server.
+    fn hashing(&self) -> Hashing {
+        let watching = &self.watching;
+        let mut modes = Vec::new();
+        for group in &watching.groups {
+            let mode = (
+                group.processing.clone(),
+                group.hash_mode(watching.shallow_hash_bytes),
+            );
+            if !modes.contains(&mode) {
+                modes.push(mode);
+            }
+        }
+        Hashing {
+            algorithm: watching.hash_algorithm,
+            modes,
+        }
+    }
+}
+
+/// Clean up after a file the server received.
+async fn forget_received(spec: FileSpec, db: &Db, conf: &Config) {
+    if conf.copy_to_server.requires_cleanup() {
+        if let Some(rel_path) = spec.companion_relative_path()
+            && let Err(err) = clean_up(&rel_path, conf).await
+        {
+            warn!("error when cleaning up {rel_path:?}: {err}");
+        }
+        let path = conf.watched_path(&spec);
+        if let Err(err) = clean_up(&spec.relative_path(), conf).await {
+            warn!("error when cleaning up {path:?}: {err}");
+            return;
+        }
+    }
+    db.lock().await.remove(&spec.relative_path());
+}
+
+/// Delete or archive the file at `rel_path` in the watched directory.
+async fn clean_up(rel_path: &Path, conf: &Config) -> io::Result<()> {
+    let path = assemble_path(&conf.watching.directory, rel_path);
+    let path = path.as_path();
+    match &conf.after_confirmation {
+        AfterConfirmation::Delete => fs::remove_file(path).await,
+        AfterConfirmation::MoveTo(archive) => {
+            let destination = assemble_path(archive, rel_path);
+            if let Some(parent) = destination.parent() {
+                fs::create_dir_all(parent).await?;
+            }
+            debug!("archiving {path:?} to {destination:?}");
+            match fs::rename(path, &destination).await {
+                Err(err) if err.kind() == io::ErrorKind::CrossesDevices => {
+                    move_by_copy(path, &destination).await
+                }
+                res => res,
+            }
+        }
+    }
+}
+
+/// Pause announcements for at least `until_secs`, returning whether they were
+/// not paused already.
+async fn pause_for(pause: &Pause, until_secs: u64) -> bool {
+    let resume_at = Instant::now() + Duration::from_secs(until_secs);
+    let mut pause = pause.lock().await;
+    let was_paused = *pause > Instant::now();
+    *pause = (*pause).max(resume_at);
+    !was_paused
 }
 
 async fn listen_to_server(
-    mut from_server: ReadFramedJson<Receipt, OwnedReadHalf>,
+    mut from_server: ReadFramedJson<ServerReply, OwnedReadHalf>,
     to_server: ToServer<OwnedWriteHalf>,
     db: Db,
+    pause: Pause,
+    copies: Arc<Semaphore>,
+    liveness: Liveness,
     conf: Arc<Config>,
 ) -> io::Result<()> {
-    while let Some(msg) = from_server.try_next().await? {
-        match msg {
+    // Pongs are expected every `ping_every_secs`, allow for a few missed ones
+    // before considering the connection dead.
+    let timeout = Duration::from_secs(3 * conf.ping_every_secs);
+    liveness.progress(timeout);
+    // Tasks sending to the server, a failure means the connection is lost.
+    // They are aborted when the connection is dropped.
+    let mut tasks = JoinSet::new();
+    loop {
+        let next = tokio::time::timeout(timeout, from_server.try_next());
+        tokio::pin!(next);
+        let msg = loop {
+            tokio::select! {
+                msg = &mut next => break msg,
+                Some(res) = tasks.join_next() => res??,
+            }
+        };
+        let Ok(msg) = msg else {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "no message from server, connection presumably lost",
+            ));
+        };
+        liveness.progress(timeout);
+        let Some(ServerReply {
+            in_reply_to,
+            receipt,
+        }) = msg?
+        else {
+            break;
+        };
+        if let Some(spec) = receipt.file_to_act_on()
+            && !to_server.lock().await.is_current(spec, in_reply_to)
+        {
+            debug!(
+                "ignoring {} receipt for superseded request about {spec:?}",
+                receipt.name()
+            );
+            continue;
+        }
+        if let Receipt::Processed(spec) | Receipt::ProcessingFailed { spec, .. } = &receipt
+            && conf.waits_for_processing()
+            && !db.lock().await.contains_key(&spec.relative_path())
+        {
+            debug!(
+                "ignoring {} receipt for {spec:?}, not sent by this client",
+                receipt.name()
+            );
+            continue;
+        }
+        match receipt {
             Receipt::Expecting {
                 spec,
                 server_rel_path,
             } => {
                 debug!("server awaiting {spec:?}, sending according to `copy_to_server`");
-                send_file_to_server(to_server.clone(), spec, server_rel_path, conf.clone()).await;
+                tasks.spawn(send_file_to_server(
+                    to_server.clone(),
+                    spec,
+                    server_rel_path,
+                    copies.clone(),
+                    conf.clone(),
+                ));
             }
             Receipt::Received(spec) => {
                 debug!("server confirmed reception of {spec:?}");
-                if conf.copy_to_server.requires_cleanup() {
-                    let path = conf.watched_path(&spec);
-                    if let Err(err) = fs::remove_file(&path).await {
-                        warn!("error when removing {path:?}: {err}");
-                        continue;
-                    }
+                to_server.lock().await.forget(&spec);
+                let forget = !conf.waits_for_processing();
+                tokio::spawn(confirmed(
+                    Hook::Received,
+                    spec,
+                    forget,
+                    db.clone(),
+                    conf.clone(),
+                ));
+            }
+            Receipt::Processed(spec) => {
+                info!("server processed {spec:?}");
+                let forget = conf.waits_for_processing();
+                tokio::spawn(confirmed(
+                    Hook::Processed,
+                    spec,
+                    forget,
+                    db.clone(),
+                    conf.clone(),
+                ));
+            }
+            Receipt::ProcessingFailed {
+                spec,
+                error,
+                abandoned,
+            } => {
+                if abandoned {
+                    error!("server abandoned processing of {spec:?}: '{error}'");
+                } else {
+                    warn!("server failed processing {spec:?}, it will retry: '{error}'");
                 }
-                db.lock().await.remove(&spec.relative_path());
+            }
+            Receipt::RequestFullHash {
+                spec,
+                server_rel_path,
+            } => {
+                debug!("server requests full hash of {spec:?}");
+                tokio::spawn(send_full_hash(
+                    to_server.clone(),
+                    spec,
+                    server_rel_path,
+                    conf.clone(),
+                ));
             }
             Receipt::DifferentHash(spec) => {
                 warn!(
                     "server does not have expected hash for {spec:?}, forgetting it in case of TOCTOU condition"
                 );
+                let (hook_spec, hook_conf) = (spec.clone(), conf.clone());
+                tokio::spawn(async move {
+                    run_hook(Hook::HashMismatch, &hook_spec, &hook_conf).await;
+                });
                 db.lock().await.remove(&spec.relative_path());
             }
+            Receipt::ShallowCollision(spec) => {
+                warn!(
+                    "{spec:?} collides with another file on the server, announcing its full hash"
+                );
+                tokio::spawn(announce_full_hash(
+                    to_server.clone(),
+                    spec,
+                    db.clone(),
+                    conf.clone(),
+                ));
+            }
+            Receipt::Incomplete {
+                spec,
+                received_bytes,
+            } => {
+                // Copies may take long, only those that stop progressing are
+                // given up.
+                let (checks, stalled) = {
+                    let mut to_server = to_server.lock().await;
+                    let progress = to_server.incomplete(&spec, received_bytes);
+                    (progress.checks, progress.stalled)
+                };
+                let error = format!(
+                    "incomplete, got {received_bytes} of {} bytes",
+                    spec.size_bytes
+                );
+                if conf.gave_up(stalled) {
+                    error!(
+                        "server says '{error}' for {spec:?}, giving up after {} announcements without progress",
+                        conf.max_resends
+                    );
+                    db.lock()
+                        .await
+                        .insert(spec.relative_path(), conf.max_resends + 1);
+                    tokio::spawn(notify_give_up(spec, error, conf.clone()));
+                    continue;
+                }
+                let delay = conf.resend_delay(checks.min(conf.max_resends));
+                info!(
+                    "copy of {spec:?} still in progress ({error}), announcing it again in {delay:?}"
+                );
+                let to_server = to_server.clone();
+                tasks.spawn(async move {
+                    tokio::time::sleep(delay).await;
+                    to_server
+                        .lock()
+                        .await
+                        .send(ClientMessage::Announce(Box::new(spec)))
+                        .await?;
+                    Ok(())
+                });
+            }
             Receipt::Error {
                 spec,
                 server_rel_path,
                 error,
             } => {
-                warn!("server says '{error}' for {spec:?}, resending");
-                send_file_to_server(to_server.clone(), spec, server_rel_path, conf.clone()).await;
+                let resends = {
+                    let mut db = db.lock().await;
+                    let resends = db.entry(spec.relative_path()).or_default();
+                    *resends += 1;
+                    *resends
+                };
+                if conf.gave_up(resends) {
+                    error!(
+                        "server says '{error}' for {spec:?}, giving up after {} resends",
+                        conf.max_resends
+                    );
+                    tokio::spawn(notify_give_up(spec, error, conf.clone()));
+                    continue;
+                }
+                let delay = conf.resend_delay(resends);
+                warn!(
+                    "server says '{error}' for {spec:?}, resending in {delay:?} ({resends}/{})",
+                    conf.max_resends
+                );
+                let to_server = to_server.clone();
+                let copies = copies.clone();
+                let conf = conf.clone();
+                tasks.spawn(async move {
+                    tokio::time::sleep(delay).await;
+                    send_file_to_server(to_server, spec, server_rel_path, copies, conf).await
+                });
+            }
+            Receipt::QuotaExceeded { spec, until_secs } => {
+                debug!("quota exceeded on server, {spec:?} will be announced again later");
+                if pause_for(&pause, until_secs).await {
+                    warn!(
+                        "quota exceeded on server, pausing announcements for {until_secs} s \
+                        until files are pruned"
+                    );
+                }
+                db.lock().await.remove(&spec.relative_path());
+            }
+            Receipt::LowDiskSpace(spec) => {
+                warn!("server is low on disk space, {spec:?} will be announced again later");
+                db.lock().await.remove(&spec.relative_path());
+            }
+            Receipt::AwaitedElsewhere(spec) => {
+                debug!("{spec:?} is awaited from another client, will be announced again later");
+                db.lock().await.remove(&spec.relative_path());
+            }
+            Receipt::SlowDown { spec, until_secs } => {
+                debug!("server is saturated, {spec:?} will be announced again later");
+                if pause_for(&pause, until_secs).await {
+                    warn!("server is saturated, pausing announcements for {until_secs} s");
+                }
+                db.lock().await.remove(&spec.relative_path());
+            }
+            Receipt::Reconciled(states) => {
+                let files = match in_reply_to {
+                    Some(id) => to_server.lock().await.reconciled(id),
+                    None => None,
+                };
+                let Some(files) = files.filter(|files| files.len() == states.len()) else {
+                    warn!("ignoring reconciliation that does not answer a request");
+                    continue;
+                };
+                let received = states
+                    .iter()
+                    .filter(|state| {
+                        matches!(state, Reconciliation::Received | Reconciliation::Processed)
+                    })
+                    .count();
+                info!(
+                    "server already received {received} of {} files found when connecting",
+                    files.len()
+                );
+                let mut to_announce = Vec::new();
+                for (spec, state) in files.into_iter().zip(states) {
+                    let db = db.clone();
+                    let conf = conf.clone();
+                    match state {
+                        Reconciliation::Received => {
+                            if conf.waits_for_processing() {
+                                debug!("waiting for server to process {spec:?}");
+                            }
+                            let forget = !conf.waits_for_processing();
+                            tokio::spawn(confirmed(Hook::Received, spec, forget, db, conf));
+                        }
+                        Reconciliation::Processed => {
+                            tokio::spawn(confirmed(Hook::Processed, spec, true, db, conf));
+                        }
+                        Reconciliation::Resumed => {
+                            debug!("server already resumed transfer of {spec:?}");
+                        }
+                        Reconciliation::Unknown | Reconciliation::Pending => to_announce.push(spec),
+                    }
+                }
+                // Announced aside so that receipts keep being read while
+                // announcements are paused.
+                let to_server = to_server.clone();
+                let pause = pause.clone();
+                tasks.spawn(async move {
+                    for spec in to_announce {
+                        watch::announce(&to_server, &pause, spec).await?;
+                    }
+                    Ok(())
+                });
+            }
+            Receipt::Pong => debug!("received pong from server"),
+            Receipt::ProtocolError(error) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("server reported protocol error '{error}'"),
+                ));
             }
         }
     }
@@ -206,27 +846,122 @@ impl From<io::Result<()>> for CopyOutcome {
     }
 }
 
-async fn send_file_to_server(
-    to_server: ToServer<OwnedWriteHalf>,
-    spec: FileSpec,
-    server_rel_path: String,
-    conf: Arc<Config>,
+/// Placeholders available in the `on_give_up` command.
+pub(crate) const GIVE_UP_PLACEHOLDERS: [&str; 2] = ["{client_path}", "{error}"];
+
+/// Run the `on_give_up` command, if any, for a file that won't be sent again.
+async fn notify_give_up(spec: FileSpec, error: String, conf: Arc<Config>) {
+    let Some(items) = &conf.on_give_up else {
+        return;
+    };
+    let path = conf.watched_path(&spec);
+    let replacements = [
+        ("{client_path}", path.as_os_str()),
+        ("{error}", error.as_ref()),
+    ];
+    run_hook_command("on_give_up", items, &replacements, &conf).await;
+}
+
+/// Placeholders available in the commands of a [`Hook`].
+pub(crate) const HOOK_PLACEHOLDERS: [&str; 2] = ["{client_path}", "{hash}"];
+
+/// Commands run when the server sends some receipts about a file.
+#[derive(Clone, Copy)]
+pub(crate) enum Hook {
+    Received,
+    HashMismatch,
+    Processed,
+}
+
+impl Hook {
+    pub(crate) const ALL: [Hook; 3] = [Hook::Received, Hook::HashMismatch, Hook::Processed];
+
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Hook::Received => "on_received",
+            Hook::HashMismatch => "on_hash_mismatch",
+            Hook::Processed => "on_processed",
+        }
+    }
+
+    pub(crate) fn command(self, conf: &Config) -> Option<&Vec<String>> {
+        match self {
+            Hook::Received => conf.on_received.as_ref(),
+            Hook::HashMismatch => conf.on_hash_mismatch.as_ref(),
+            Hook::Processed => conf.on_processed.as_ref(),
+        }
+    }
+}
+
+/// Run the command of `hook`, if any, for `spec`.
+async fn run_hook(hook: Hook, spec: &FileSpec, conf: &Config) {
+    let Some(items) = hook.command(conf) else {
+        return;
+    };
+    let path = conf.watched_path(spec);
+    let replacements = [
+        ("{client_path}", path.as_os_str()),
+        ("{hash}", spec.hash().as_ref()),
+    ];
+    run_hook_command(hook.name(), items, &replacements, conf).await;
+}
+
+/// Run `hook` for a file the server confirmed, then clean it up if `forget`.
+/// The hook runs first as it may still need the file.
+async fn confirmed(hook: Hook, spec: FileSpec, forget: bool, db: Db, conf: Arc<Config>) {
+    run_hook(hook, &spec, &conf).await;
+    if forget {
+        forget_received(spec, &db, &conf).await;
+    }
+}
+
+async fn run_hook_command(
+    name: &str,
+    items: &[String],
+    replacements: &[(&str, &OsStr)],
+    conf: &Config,
 ) {
-    let from = conf.watched_path(&spec);
-    let outcome = match &conf.copy_to_server {
+    let status = Command::new(&items[0])
+        .args(
+            items[1..]
+                .iter()
+                .map(|a| replace_os_strings(a, replacements.iter().copied())),
+        )
+        .kill_on_drop(true)
+        .status();
+    let timeout = Duration::from_secs(conf.hook_timeout_secs);
+    match tokio::time::timeout(timeout, status).await {
+        Ok(Ok(status)) if status.success() => {}
+        Ok(Ok(status)) => warn!("`{name}` command failed with status {:?}", status.code()),
+        Ok(Err(err)) => warn!("cannot run `{name}` command: {err}"),
+        Err(_) => warn!("`{name}` command killed after running for {timeout:?}"),
+    }
+}
+
+/// Placeholders available in the `copy_to_server` command.
+pub(crate) const COPY_PLACEHOLDERS: [&str; 2] = ["{server_filename}", "{client_path}"];
+
+async fn copy_to_server(conf: &Config, from: &Path, server_rel_path: &str) -> CopyOutcome {
+    match &conf.copy_to_server {
         CopyToServer::Move { move_in_same_fs_to } => {
-            info!("move {spec:?} to server via `fs::rename`");
+            info!("move {from:?} to server via `fs::rename`");
             let destination = assemble_path(move_in_same_fs_to, server_rel_path);
-            fs::rename(from, destination).await.into()
+            match fs::rename(from, &destination).await {
+                Err(err) if err.kind() == io::ErrorKind::CrossesDevices => {
+                    warn!("cannot move {from:?} to another filesystem, copying it instead");
+                    move_by_copy(from, &destination).await.into()
+                }
+                res => res.into(),
+            }
         }
         CopyToServer::Copy { destination } => {
-            info!("copying {spec:?} to server via `fs::copy`");
+            info!("copying {from:?} to server via `fs::copy`");
             let destination = assemble_path(destination, server_rel_path);
-            fs::copy(from, destination).await.map(|_| ()).into()
+            copy_file(from, &destination).await.into()
         }
         CopyToServer::Command(items) => {
-            info!("copying {spec:?} to server with `{}`", &items[0]);
-            let rel_path = assemble_path(&server_rel_path, "");
+            info!("copying {from:?} to server with `{}`", &items[0]);
+            let rel_path = assemble_path(server_rel_path, "");
             Command::new(&items[0])
                 .args(items[1..].iter().map(|a| {
                     replace_os_strings(
@@ -244,16 +979,192 @@ async fn send_file_to_server(
                 .await
                 .into()
         }
+    }
+}
+
+/// Copy `from` to `to`, sharing their data with a reflink when the filesystem
+/// supports it (e.g. btrfs or XFS). The fallback `fs::copy` already relies on
+/// `copy_file_range` on Linux and clones files on APFS.
+async fn copy_file(from: &Path, to: &Path) -> io::Result<()> {
+    #[cfg(target_os = "linux")]
+    {
+        let (src, dst) = (from.to_owned(), to.to_owned());
+        match tokio::task::spawn_blocking(move || reflink(&src, &dst)).await? {
+            Ok(()) => {
+                debug!("reflinked {from:?} to {to:?}");
+                return Ok(());
+            }
+            Err(err) => debug!("cannot reflink {from:?}: {err}, copying it"),
+        }
+    }
+    fs::copy(from, to).await.map(|_| ())
+}
+
+#[cfg(target_os = "linux")]
+fn reflink(from: &Path, to: &Path) -> io::Result<()> {
+    let src = std::fs::File::open(from)?;
+    let dst = std::fs::File::create(to)?;
+    rustix::fs::ioctl_ficlone(&dst, &src).map_err(io::Error::from)
+}
+
+/// Copy `from` to `to` then delete it, checking the size of the copy first.
+async fn move_by_copy(from: &Path, to: &Path) -> io::Result<()> {
+    let copied = fs::copy(from, to).await?;
+    let size = fs::metadata(from).await?.len();
+    if copied != size || fs::metadata(to).await?.len() != size {
+        return Err(io::Error::other(format!(
+            "copy of {from:?} to {to:?} is incomplete"
+        )));
+    }
+    fs::remove_file(from).await
+}
+
+async fn send_full_hash(
+    to_server: ToServer<OwnedWriteHalf>,
+    spec: FileSpec,
+    server_rel_path: String,
+    conf: Arc<Config>,
+) {
+    // The file might already have been moved to the server.
+    let path = Some(conf.watched_path(&spec))
+        .filter(|p| p.exists())
+        .or_else(|| {
+            let path = assemble_path(conf.verify_copy_directory()?, &server_rel_path);
+            path.exists().then_some(path)
+        });
+    let hash = match path {
+        Some(path) => {
+            let algorithm = conf.watching.hash_algorithm;
+            let read = conf.read_options().unmapped();
+            tokio::task::spawn_blocking(move || {
+                FileDigest::new(&path, HashMode::Full, algorithm, read)
+            })
+            .await
+            .map_err(io::Error::from)
+            .and_then(|hash| hash)
+            .inspect_err(|err| warn!("cannot compute full hash of {spec:?}: {err}"))
+            .ok()
+            .map(|digest| digest.hash().to_owned())
+        }
+        None => None,
+    };
+    let msg = ClientMessage::FullHash {
+        spec: Box::new(spec),
+        hash,
     };
+    if let Err(err) = to_server.lock().await.send(msg).await {
+        warn!("failed to send full hash to server: {err}");
+    }
+}
+
+/// Announce `spec` again, identified by its full hash.
+async fn announce_full_hash(
+    to_server: ToServer<OwnedWriteHalf>,
+    mut spec: FileSpec,
+    db: Db,
+    conf: Arc<Config>,
+) {
+    let path = conf.watched_path(&spec);
+    let algorithm = conf.watching.hash_algorithm;
+    let read = conf.read_options().unmapped();
+    let digest = tokio::task::spawn_blocking(move || {
+        FileDigest::new(&path, HashMode::Full, algorithm, read)
+    })
+    .await
+    .map_err(io::Error::from)
+    .and_then(|digest| digest);
+    match digest {
+        Ok(digest) => {
+            spec.sha256_digest = digest;
+            let msg = ClientMessage::Announce(Box::new(spec));
+            if let Err(err) = to_server.lock().await.send(msg).await {
+                warn!("failed to announce full hash to server: {err}");
+            }
+        }
+        Err(err) => {
+            warn!("cannot compute full hash of {spec:?}, will be announced again later: {err}");
+            db.lock().await.remove(&spec.relative_path());
+        }
+    }
+}
+
+/// Check the size, and hash if full, of the copy of `spec` on the server.
+async fn verify_copy(conf: &Config, spec: &FileSpec, server_rel_path: &str) -> io::Result<()> {
+    let Some(directory) = conf.verify_copy_directory() else {
+        warn!("cannot verify copy of {spec:?}, set `verify_copy_directory`");
+        return Ok(());
+    };
+    let copy = assemble_path(directory, server_rel_path);
+    let size = fs::metadata(&copy).await?.len();
+    if size != spec.size_bytes {
+        return Err(io::Error::other(format!(
+            "copy has {size} bytes instead of {}",
+            spec.size_bytes
+        )));
+    }
+    if spec.sha256_digest.is_full() {
+        let copied_spec = spec.clone();
+        let algorithm = conf.watching.hash_algorithm;
+        let read = conf.read_options();
+        let digest = tokio::task::spawn_blocking(move || {
+            FileDigest::with_spec(&copy, &copied_spec, algorithm, read)
+        })
+        .await??;
+        if digest.hash() != spec.hash() {
+            return Err(io::Error::other("copy does not have the expected hash"));
+        }
+    }
+    debug!("verified copy of {spec:?}");
+    Ok(())
+}
+
+async fn send_file_to_server(
+    to_server: ToServer<OwnedWriteHalf>,
+    spec: FileSpec,
+    server_rel_path: String,
+    copies: Arc<Semaphore>,
+    conf: Arc<Config>,
+) -> io::Result<()> {
+    let _permit = copies.acquire().await.unwrap();
+    // The companion is sent first so that it is already present on the server
+    // when the file is announced.
+    let mut outcome = CopyOutcome::Ok;
+    if let Some(from) = conf.watched_companion_path(&spec)
+        && let Some(suffix) = spec.companion_suffix()
+    {
+        outcome = copy_to_server(&conf, &from, &(server_rel_path.clone() + &suffix)).await;
+    }
+    let started = Instant::now();
+    if let CopyOutcome::Ok = outcome {
+        let from = conf.watched_path(&spec);
+        let copy = copy_to_server(&conf, &from, &server_rel_path);
+        outcome = match conf.verify_copy_directory() {
+            Some(dir) if conf.progress_every_secs > 0 => {
+                let copy_path = assemble_path(dir, &server_rel_path);
+                report_progress(copy, &copy_path, spec.size_bytes, &conf).await
+            }
+            _ => copy.await,
+        };
+    }
+    let elapsed = started.elapsed();
+    if let CopyOutcome::Ok = outcome
+        && conf.verify_copy
+    {
+        outcome = verify_copy(&conf, &spec, &server_rel_path).await.into();
+    }
     match outcome {
         CopyOutcome::Ok => {
-            debug!("copy of {spec:?} completed successfully");
+            let rate = spec.size_bytes as f64 / elapsed.as_secs_f64().max(1e-3);
+            info!(
+                "copied {spec:?} in {:.1} s ({}/s)",
+                elapsed.as_secs_f64(),
+                format_size(rate as u64)
+            );
             to_server
                 .lock()
                 .await
-                .send(spec)
-                .await
-                .expect("couldn't send request to server");
+                .send(ClientMessage::Announce(Box::new(spec)))
+                .await?;
         }
         CopyOutcome::ErrCommand(status) => warn!(
             "copy of {spec:?} to server failed with status {:?}",
@@ -261,39 +1172,281 @@ async fn send_file_to_server(
         ),
         CopyOutcome::Err(err) => warn!("copy of {spec:?} to server failed '{err}'"),
     }
+    Ok(())
 }
 
-pub(crate) async fn main(config: Config, once: bool) -> io::Result<()> {
-    let mut stream = config.server.connect().await;
+/// Wait for `copy` to complete, periodically logging how much of the
+/// `size_bytes` of the file have been written to `copy_path`.
+async fn report_progress(
+    copy: impl Future<Output = CopyOutcome>,
+    copy_path: &Path,
+    size_bytes: u64,
+    conf: &Config,
+) -> CopyOutcome {
+    let period = Duration::from_secs(conf.progress_every_secs);
+    let started = Instant::now();
+    let mut interval = tokio::time::interval_at(started + period, period);
+    tokio::pin!(copy);
+    loop {
+        tokio::select! {
+            outcome = &mut copy => return outcome,
+            _ = interval.tick() => {
+                let copied = fs::metadata(copy_path).await.map_or(0, |m| m.len());
+                let rate = copied as f64 / started.elapsed().as_secs_f64();
+                let percent = 100 * copied / size_bytes.max(1);
+                info!(
+                    "copied {} of {} ({percent}%) to {copy_path:?} at {}/s",
+                    format_size(copied),
+                    format_size(size_bytes),
+                    format_size(rate as u64),
+                );
+            }
+        }
+    }
+}
 
-    let payload = RequestPayload::ProcessingClient {
-        groups: config.processing_groups(),
+async fn ping_server(to_server: ToServer<OwnedWriteHalf>, conf: Arc<Config>) -> io::Result<()> {
+    let mut interval = tokio::time::interval(Duration::from_secs(conf.ping_every_secs));
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        debug!("sending ping to server");
+        to_server.lock().await.send(ClientMessage::Ping).await?;
+    }
+}
+
+pub(crate) async fn main(config: Config, once: bool) -> io::Result<()> {
+    let hash_cache = match &config.watching.hash_cache {
+        Some(file) => HashCache::load(file.clone()),
+        None => HashCache::default(),
     };
-    if !handshake::client_side(&mut stream, payload).await? {
-        return Ok(());
+    let liveness = [Liveness::default(), Liveness::default()];
+    tokio::select!(
+        res = run_with_reconnect(Arc::new(config), Arc::new(hash_cache), liveness.clone(), once) => res,
+        res = systemd::watchdog(|| {
+            let alive = liveness.iter().all(Liveness::is_alive);
+            async move { alive }
+        }) => res,
+    )
+}
+
+/// Connect to the server and send files to it, connecting again whenever the
+/// connection is lost. `liveness` tracks the progress of the connection and
+/// of the scans of the watched directory.
+async fn run_with_reconnect(
+    config: Arc<Config>,
+    hash_cache: Arc<HashCache>,
+    liveness: [Liveness; 2],
+    once: bool,
+) -> io::Result<()> {
+    let db = Arc::new(Mutex::new(HashMap::new()));
+    let copies = Arc::new(Semaphore::new(config.max_concurrent_copies));
+    let [connection, scan] = liveness;
+    loop {
+        // Waiting for the server is not a sign of the client being stuck.
+        connection.idle();
+        scan.idle();
+        let mut stream = config.server.connect().await;
+
+        let payload = RequestPayload::ProcessingClient {
+            name: config.name.clone(),
+            groups: config.processing_groups(),
+            hashing: config.hashing(),
+        };
+        match handshake::client_side(&mut stream, payload).await {
+            Ok(true) => {}
+            Ok(false) => return Ok(()),
+            Err(err) => {
+                warn!("handshake with server failed: {err}, reconnecting in 3s");
+                tokio::time::sleep(Duration::from_secs(3)).await;
+                continue;
+            }
+        }
+        systemd::notify_ready();
+
+        let (from_server, to_server) =
+            json_channel::<ServerReply, ClientRequest, _, _, _>(stream, config.max_frame_length);
+
+        let to_server = Arc::new(Mutex::new(Outbox::new(to_server)));
+        // Files announced during a previous connection but not confirmed yet
+        // are simply announced again, unless they were given up.
+        db.lock()
+            .await
+            .retain(|_, resends| config.gave_up(*resends));
+        let pause = Arc::new(Mutex::new(Instant::now()));
+
+        let listen = tokio::spawn(listen_to_server(
+            from_server,
+            to_server.clone(),
+            db.clone(),
+            pause.clone(),
+            copies.clone(),
+            connection.clone(),
+            config.clone(),
+        ));
+        let abort_listen = listen.abort_handle();
+
+        let res = tokio::select!(
+            handle = listen => handle.unwrap(),
+            res = ping_server(to_server.clone(), config.clone()) => res,
+            res = watch::watch_dir(to_server, db.clone(), pause, config.clone(), hash_cache.clone(), scan.clone(), once) => res,
+        );
+        abort_listen.abort();
+
+        match res {
+            Ok(()) => break Ok(()),
+            Err(err) => warn!("lost connection to server: {err}, reconnecting"),
+        }
     }
+}
 
-    let (from_server, to_server) = json_channel::<Receipt, FileSpec, _, _, _>(stream);
+/// Pipeline client, watching a directory and sending files to a server.
+pub struct Client {
+    config: Config,
+    once: bool,
+}
 
-    let to_server = Arc::new(Mutex::new(to_server));
-    let db = Arc::new(Mutex::new(HashSet::new()));
-    let config = Arc::new(config);
+impl Client {
+    /// Start building a client from the default configuration.
+    pub fn builder() -> ClientBuilder {
+        ClientBuilder {
+            source: ConfigSource::Toml(DEFAULT_TOML_CONF.clone()),
+            name: None,
+            server_address: None,
+            watched_directory: None,
+            once: false,
+        }
+    }
 
-    tokio::select!(
-        handle = tokio::spawn(listen_to_server(from_server, to_server.clone(), db.clone(), config.clone())) => handle.unwrap(),
-        res = watch::watch_dir(to_server, db, config, once) => res,
-    )
+    /// Run the client until it stops or an unrecoverable error occurs.
+    pub async fn run(self) -> io::Result<()> {
+        main(self.config, self.once).await
+    }
+}
+
+/// Builder for [`Client`].
+///
+/// Relative paths in the configuration are resolved with respect to the
+/// current working directory.
+pub struct ClientBuilder {
+    source: ConfigSource,
+    name: Option<String>,
+    server_address: Option<String>,
+    watched_directory: Option<PathBuf>,
+    once: bool,
+}
+
+impl ClientBuilder {
+    /// Use the given TOML configuration instead of the default one.
+    pub fn config_toml(mut self, content: impl Into<String>) -> Self {
+        self.source = ConfigSource::Toml(content.into());
+        self
+    }
+
+    /// Read the TOML configuration from a file instead of using the default one.
+    pub fn config_file(mut self, path: impl AsRef<Path>) -> Self {
+        self.source = ConfigSource::File(path.as_ref().to_owned());
+        self
+    }
+
+    /// Set the client name.
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Connect directly to the server at this address.
+    pub fn server_address(mut self, address: impl Into<String>) -> Self {
+        self.server_address = Some(address.into());
+        self
+    }
+
+    /// Set the directory to watch for new files.
+    pub fn watched_directory(mut self, directory: impl AsRef<Path>) -> Self {
+        self.watched_directory = Some(directory.as_ref().to_owned());
+        self
+    }
+
+    /// Stop as soon as no new files are found.
+    pub fn once(mut self, once: bool) -> Self {
+        self.once = once;
+        self
+    }
+
+    /// Read the configuration and apply the overrides set on this builder.
+    pub fn build(self) -> io::Result<Client> {
+        let mut config: Config = self.source.parse()?;
+        if let Some(name) = self.name {
+            config.name = name;
+        }
+        if let Some(address) = self.server_address {
+            config.server = ServerRoute::Direct { address };
+        }
+        if let Some(directory) = self.watched_directory {
+            config.watching.directory = directory;
+        }
+        // Archived files would be found and sent again.
+        if let AfterConfirmation::MoveTo(archive) = &config.after_confirmation
+            && std::path::absolute(archive)?
+                .starts_with(std::path::absolute(&config.watching.directory)?)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "`after_confirmation` archive cannot be in the watched directory",
+            ));
+        }
+        Ok(Client {
+            config,
+            once: self.once,
+        })
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
 
+    #[test]
+    fn build_default_client() {
+        let client = Client::builder().name("foo").build().unwrap();
+        assert_eq!(client.config.name, "foo");
+    }
+
     #[test]
     fn read_default_config() {
         assert!(toml::from_slice::<Config>(DEFAULT_TOML_CONF.as_bytes()).is_ok());
     }
 
+    #[test]
+    fn refuse_unknown_placeholders() {
+        let config = |hook: &str| {
+            let toml = DEFAULT_TOML_CONF.replace(
+                "# on_received = [\"logger\", \"pipeline sent {client_path}\"]",
+                &format!("on_received = [\"sh\", \"-c\", {hook:?}]"),
+            );
+            toml::from_str::<Config>(&toml)
+        };
+        assert!(config("echo {client_path} >> ${HOME}/sent").is_ok());
+        let err = config("echo {client_pth}").unwrap_err().to_string();
+        assert!(err.contains("unknown placeholder {client_pth}"), "{err}");
+    }
+
+    #[test]
+    fn refuse_archive_in_watched_directory() {
+        let client = |archive: &str| {
+            Client::builder()
+                .config_toml(DEFAULT_TOML_CONF.replace(
+                    "after_confirmation = \"delete\"",
+                    &format!("after_confirmation = {{ move_to = {archive:?} }}"),
+                ))
+                .watched_directory("/data/watched")
+                .build()
+        };
+        assert!(client("/data/archive").is_ok());
+        assert!(client("/data/watched/archive").is_err());
+        assert!(client("/data/watched").is_err());
+    }
+
     #[test]
     fn read_tunnel_config() {
         assert!(toml::from_slice::<Config>(TUNNEL_TOML_CONF.as_bytes()).is_ok());
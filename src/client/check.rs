@@ -0,0 +1,95 @@
+use std::{io, path::Path};
+
+use crate::{
+    check::Diagnostics,
+    client::{
+        AfterConfirmation, COPY_PLACEHOLDERS, Config, CopyToServer, GIVE_UP_PLACEHOLDERS,
+        HOOK_PLACEHOLDERS, Hook,
+    },
+};
+
+/// Check the placeholders of all commands, which would otherwise stay
+/// literal at runtime.
+fn check_placeholders(diag: &mut Diagnostics, config: &Config) {
+    if let CopyToServer::Command(items) = &config.copy_to_server {
+        for item in items {
+            diag.check_placeholders("`copy_to_server`", item, &COPY_PLACEHOLDERS);
+        }
+    }
+    let hooks = Hook::ALL
+        .iter()
+        .map(|hook| (hook.name(), hook.command(config), &HOOK_PLACEHOLDERS));
+    let hooks = [(
+        "on_give_up",
+        config.on_give_up.as_ref(),
+        &GIVE_UP_PLACEHOLDERS,
+    )]
+    .into_iter()
+    .chain(hooks);
+    for (name, command, known) in hooks {
+        for item in command.into_iter().flatten() {
+            diag.check_placeholders(&format!("`{name}`"), item, known);
+        }
+    }
+}
+
+/// Refuse a configuration using unknown placeholders when loading it.
+pub(crate) fn placeholders_at_load(config: &Config) -> io::Result<()> {
+    let mut diag = Diagnostics::new();
+    check_placeholders(&mut diag, config);
+    diag.into_result()
+}
+
+pub(crate) fn main(config: Config) -> io::Result<()> {
+    let mut diag = Diagnostics::new();
+
+    diag.check_dir("watched directory", &config.watching.directory);
+
+    match &config.copy_to_server {
+        CopyToServer::Move { move_in_same_fs_to } => {
+            diag.check_dir("`move_in_same_fs_to` destination", move_in_same_fs_to)
+        }
+        CopyToServer::Copy { destination } => {
+            diag.check_dir("`copy_to_server` destination", destination)
+        }
+        CopyToServer::Command(items) if items.is_empty() => {
+            diag.error("`copy_to_server` command is empty".to_owned())
+        }
+        CopyToServer::Command(_) => {}
+    }
+    check_placeholders(&mut diag, &config);
+
+    if let Some(parent) = config
+        .watching
+        .hash_cache
+        .as_deref()
+        .and_then(Path::parent)
+        .filter(|p| !p.as_os_str().is_empty())
+    {
+        diag.check_dir("directory of `hash_cache`", parent);
+    }
+
+    if let AfterConfirmation::MoveTo(archive) = &config.after_confirmation {
+        diag.check_dir("`after_confirmation` archive", archive);
+    }
+
+    check_hook(&mut diag, "on_give_up", config.on_give_up.as_ref());
+    for hook in Hook::ALL {
+        check_hook(&mut diag, hook.name(), hook.command(&config));
+    }
+
+    if config.verify_copy {
+        match config.verify_copy_directory() {
+            Some(dir) => diag.check_dir("`verify_copy_directory`", dir),
+            None => diag.error("`verify_copy` requires `verify_copy_directory`".to_owned()),
+        }
+    }
+
+    diag.conclude()
+}
+
+fn check_hook(diag: &mut Diagnostics, name: &str, command: Option<&Vec<String>>) {
+    if command.is_some_and(Vec::is_empty) {
+        diag.error(format!("`{name}` command is empty"));
+    }
+}
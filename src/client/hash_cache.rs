@@ -0,0 +1,157 @@
+use std::{
+    collections::HashMap,
+    fs::Metadata,
+    io,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::SystemTime,
+};
+
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::hashing::{FileDigest, HashAlgorithm, HashMode, ReadOptions};
+
+#[derive(Serialize, Deserialize)]
+struct CachedDigest {
+    size_bytes: u64,
+    modified: SystemTime,
+    #[serde(default)]
+    algorithm: HashAlgorithm,
+    digest: FileDigest,
+}
+
+#[derive(Default)]
+struct Entries {
+    digests: HashMap<PathBuf, CachedDigest>,
+    /// Whether digests changed since they were last saved.
+    dirty: bool,
+}
+
+/// Digests of files computed during previous scans, reused as long as the
+/// size and modification time of files are unchanged.
+#[derive(Default)]
+pub(crate) struct HashCache {
+    /// File the cache is persisted in, if any.
+    file: Option<PathBuf>,
+    entries: Mutex<Entries>,
+}
+
+impl HashCache {
+    /// Cache persisted in `file`, starting empty if it cannot be read.
+    pub(crate) fn load(file: PathBuf) -> Self {
+        let digests = match std::fs::read(&file) {
+            Ok(content) => serde_json::from_slice(&content).unwrap_or_else(|err| {
+                warn!("ignoring invalid hash cache {file:?}: {err}");
+                HashMap::new()
+            }),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(err) => {
+                warn!("cannot read hash cache {file:?}: {err}");
+                HashMap::new()
+            }
+        };
+        debug!("loaded {} digests from hash cache {file:?}", digests.len());
+        Self {
+            file: Some(file),
+            entries: Mutex::new(Entries {
+                digests,
+                dirty: false,
+            }),
+        }
+    }
+
+    /// Digest of `path`, only computed if not already cached.
+    pub(crate) fn digest(
+        &self,
+        path: &Path,
+        stat: &Metadata,
+        mode: HashMode,
+        algorithm: HashAlgorithm,
+        read: ReadOptions,
+    ) -> io::Result<FileDigest> {
+        let modified = stat.modified()?;
+        if let Some(cached) = self.entries.lock().unwrap().digests.get(path)
+            && cached.size_bytes == stat.len()
+            && cached.modified == modified
+            && cached.digest.mode() == mode
+            && cached.algorithm == algorithm
+        {
+            debug!("reusing cached digest of {path:?}");
+            return Ok(cached.digest.clone());
+        }
+        let digest = FileDigest::new(path, mode, algorithm, read)?;
+        let mut entries = self.entries.lock().unwrap();
+        entries.digests.insert(
+            path.to_owned(),
+            CachedDigest {
+                size_bytes: stat.len(),
+                modified,
+                algorithm,
+                digest: digest.clone(),
+            },
+        );
+        entries.dirty = true;
+        Ok(digest)
+    }
+
+    /// Persist the cache if it changed, forgetting files that disappeared.
+    pub(crate) fn save(&self) -> io::Result<()> {
+        let Some(file) = &self.file else {
+            return Ok(());
+        };
+        let mut entries = self.entries.lock().unwrap();
+        if !entries.dirty {
+            return Ok(());
+        }
+        entries.digests.retain(|path, _| path.exists());
+        let content = serde_json::to_vec(&entries.digests).map_err(io::Error::other)?;
+        let mut tmp = file.as_os_str().to_owned();
+        tmp.push(".tmp");
+        std::fs::write(&tmp, content)?;
+        std::fs::rename(&tmp, file)?;
+        entries.dirty = false;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn cached_digest_survives_reload() {
+        let dir = std::env::temp_dir().join(format!("pipeline-cache-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("file.dat");
+        std::fs::write(&path, "content").unwrap();
+        let cache_file = dir.join("cache.json");
+        let stat = path.metadata().unwrap();
+
+        let sha256 = HashAlgorithm::Sha256;
+        let read = ReadOptions::default();
+        let cache = HashCache::load(cache_file.clone());
+        let digest = cache
+            .digest(&path, &stat, HashMode::Full, sha256, read)
+            .unwrap();
+        cache.save().unwrap();
+
+        // Change the content behind the back of the cache.
+        std::fs::write(&path, "CONTENT").unwrap();
+        let cache = HashCache::load(cache_file);
+        assert_eq!(
+            cache
+                .digest(&path, &stat, HashMode::Full, sha256, read)
+                .unwrap(),
+            digest
+        );
+        let stat = path.metadata().unwrap();
+        assert_eq!(
+            cache
+                .digest(&path, &stat, HashMode::Shallow(4), sha256, read)
+                .unwrap(),
+            FileDigest::new(&path, HashMode::Shallow(4), sha256, read).unwrap(),
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
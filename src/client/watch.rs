@@ -1,31 +1,149 @@
 use std::{
-    collections::HashSet,
+    collections::{HashMap, HashSet},
+    fs::Metadata,
     io,
     path::{Path, PathBuf},
-    sync::Arc,
-    time::Duration,
+    sync::{self as std_sync, Arc},
+    time::{Duration, SystemTime},
 };
 
-use futures_util::SinkExt;
-use log::{debug, info};
+use log::{debug, info, warn};
 use tokio::{
     io::AsyncWrite,
     net::tcp::OwnedWriteHalf,
     sync::{Mutex, Semaphore},
-    task::yield_now,
+    task::{JoinSet, yield_now},
     time::Instant,
 };
 use walkdir::{DirEntry, WalkDir};
 
 use crate::{
-    FileInfo, FileSpec,
-    client::{Config, Db, ToServer, WatchingFilters, WatchingGroup},
-    framed_io::framed_json_sink,
+    ClientMessage, FileInfo, FileSpec,
+    client::{
+        Config, Db, Liveness, Outbox, Pause, ToServer, Watching, WatchingFilters, WatchingGroup,
+        hash_cache::HashCache,
+    },
+    decode_name, encode_name,
+    framed_io::{framed_json_sink, is_frame_too_long},
 };
 
+/// Maximum number of files in a [`ClientMessage::Reconcile`] message.
+const RECONCILE_CHUNK: usize = 1000;
+
+/// Files found during the first scan after connecting to the server, which
+/// are reconciled in bulk rather than announced one by one.
+type Candidates = Arc<Mutex<Vec<FileSpec>>>;
+
+/// Size and modification time of files during the previous and current scans,
+/// see `stable_across_scans`.
+#[derive(Default)]
+struct Stability {
+    previous: HashMap<PathBuf, (u64, SystemTime)>,
+    current: HashMap<PathBuf, (u64, SystemTime)>,
+    /// Whether some files changed during the current scan.
+    changed: bool,
+}
+
+impl Stability {
+    /// Whether `path` is unchanged since the previous scan.
+    fn is_stable(&mut self, path: &Path, metadata: &Metadata) -> io::Result<bool> {
+        let state = (metadata.len(), metadata.modified()?);
+        let stable = self.previous.get(path) == Some(&state);
+        self.current.insert(path.to_owned(), state);
+        self.changed |= !stable;
+        Ok(stable)
+    }
+
+    /// Forget files that were not seen during the scan that just ended,
+    /// returning whether some files changed.
+    fn end_scan(&mut self) -> bool {
+        self.previous = std::mem::take(&mut self.current);
+        std::mem::take(&mut self.changed)
+    }
+}
+
+/// Directories seen during the previous and current scans, to only look at
+/// files in directories that changed in between full scans, see
+/// `full_scan_every_refreshes`.
+#[derive(Default)]
+struct Directories {
+    previous: HashMap<PathBuf, SystemTime>,
+    current: HashMap<PathBuf, SystemTime>,
+    /// Directories holding files that were not ready during the previous scan.
+    busy: HashSet<PathBuf>,
+    busy_now: HashSet<PathBuf>,
+    scans_since_full: u32,
+}
+
+impl Directories {
+    /// Whether the next scan should look at all files, `full_scan_every`
+    /// refreshes or when `forced`.
+    fn start_scan(&mut self, full_scan_every: u32, forced: bool) -> bool {
+        let full = forced || self.scans_since_full + 1 >= full_scan_every;
+        self.scans_since_full = if full { 0 } else { self.scans_since_full + 1 };
+        full
+    }
+
+    /// Whether files in `dir` can be skipped as nothing changed since the
+    /// previous scan.
+    fn is_quiet(&mut self, dir: &Path, metadata: &Metadata, full_scan: bool) -> bool {
+        let Ok(modified) = metadata.modified() else {
+            return false;
+        };
+        let quiet =
+            !full_scan && !self.busy.contains(dir) && self.previous.get(dir) == Some(&modified);
+        self.current.insert(dir.to_owned(), modified);
+        quiet
+    }
+
+    fn end_scan(&mut self) {
+        self.previous = std::mem::take(&mut self.current);
+        self.busy = std::mem::take(&mut self.busy_now);
+    }
+}
+
+/// State carried over from one scan of the watched directory to the next.
+#[derive(Clone)]
+struct ScanHistory {
+    hash_cache: Arc<HashCache>,
+    stability: Arc<std_sync::Mutex<Stability>>,
+    directories: Arc<std_sync::Mutex<Directories>>,
+    /// Progress of scans, reported for each file looked at.
+    liveness: Liveness,
+}
+
+impl ScanHistory {
+    fn new(hash_cache: Arc<HashCache>, liveness: Liveness) -> Self {
+        Self {
+            hash_cache,
+            stability: Default::default(),
+            directories: Default::default(),
+            liveness,
+        }
+    }
+
+    /// Record that `path` is not ready yet, its directory should be looked at
+    /// during the next scan.
+    fn not_ready(&self, path: &Path) {
+        if let Some(dir) = path.parent() {
+            let mut directories = self.directories.lock().unwrap();
+            directories.busy_now.insert(dir.to_owned());
+        }
+    }
+
+    /// Returns whether some files are not announced yet as they changed.
+    fn end_scan(&self) -> bool {
+        if let Err(err) = self.hash_cache.save() {
+            warn!("failed to save hash cache: {err}");
+        }
+        self.directories.lock().unwrap().end_scan();
+        self.stability.lock().unwrap().end_scan()
+    }
+}
+
 enum Validation {
-    /// File belongs to group and is ready
-    Ok,
+    /// File belongs to group and is ready, with its companion file if any
+    Ok(Option<String>),
     /// File belongs to group but has been modified recently
     TooRecent,
     /// File doesn't belong to group, try next one
@@ -42,28 +160,97 @@ impl WatchingFilters {
     }
 }
 
+impl Watching {
+    /// Whether `entry` should be ignored, without looking into it if it is a
+    /// directory.
+    fn is_excluded(&self, entry: &DirEntry) -> bool {
+        if entry.depth() == 0 {
+            return false;
+        }
+        if self.skip_hidden && is_hidden(entry) {
+            return true;
+        }
+        // Metadata sidecars are sent along with the file they describe.
+        if let Some(suffix) = &self.metadata_sidecar
+            && !entry.file_type().is_dir()
+            && entry
+                .file_name()
+                .as_encoded_bytes()
+                .ends_with(suffix.as_bytes())
+        {
+            return true;
+        }
+        entry.file_type().is_dir()
+            && self
+                .exclude_directories
+                .iter()
+                .any(|name| entry.file_name() == name.as_str())
+    }
+}
+
+fn is_hidden(entry: &DirEntry) -> bool {
+    #[cfg(windows)]
+    {
+        use std::os::windows::fs::MetadataExt;
+        const FILE_ATTRIBUTE_HIDDEN: u32 = 0x2;
+        if entry
+            .metadata()
+            .is_ok_and(|m| m.file_attributes() & FILE_ATTRIBUTE_HIDDEN != 0)
+        {
+            return true;
+        }
+    }
+    entry.file_name().as_encoded_bytes().starts_with(b".")
+}
+
 impl WatchingGroup {
-    fn validate(&self, entry: &DirEntry) -> io::Result<Validation> {
-        if self.filters.pass(entry) {
-            if let Ok(last_modif) = entry.metadata()?.modified()?.elapsed()
-                && last_modif > Duration::from_secs(self.last_modif_secs)
-            {
-                Ok(Validation::Ok)
-            } else {
-                Ok(Validation::TooRecent)
+    fn is_old_enough(&self, metadata: &Metadata) -> io::Result<bool> {
+        Ok(metadata
+            .modified()?
+            .elapsed()
+            .is_ok_and(|last_modif| last_modif > Duration::from_secs(self.last_modif_secs)))
+    }
+
+    /// Name of the first companion of `path` that exists and is ready.
+    fn find_companion(&self, path: &Path) -> io::Result<Option<String>> {
+        for ext in &self.companion_extensions {
+            let companion = path.with_extension(ext);
+            match companion.metadata() {
+                Ok(metadata) if self.is_old_enough(&metadata)? => {
+                    return Ok(companion.file_name().map(encode_name));
+                }
+                Ok(_) => {}
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                Err(err) => return Err(err),
             }
-        } else {
-            Ok(Validation::TryNextGroup)
+        }
+        Ok(None)
+    }
+
+    fn validate(&self, entry: &DirEntry) -> io::Result<Validation> {
+        if !self.filters.pass(entry) {
+            return Ok(Validation::TryNextGroup);
+        }
+        if !self.is_old_enough(&entry.metadata()?)? {
+            return Ok(Validation::TooRecent);
+        }
+        if self.companion_extensions.is_empty() {
+            return Ok(Validation::Ok(None));
+        }
+        match self.find_companion(entry.path())? {
+            Some(companion) => Ok(Validation::Ok(Some(companion))),
+            None => Ok(Validation::TooRecent),
         }
     }
 }
 
 async fn insert_path(db: &Db, path: &Path) -> bool {
     let mut db = db.lock().await;
-    if db.contains(path) {
+    if db.contains_key(path) {
         false
     } else {
-        db.insert(path.to_owned())
+        db.insert(path.to_owned(), 0);
+        true
     }
 }
 
@@ -72,44 +259,72 @@ async fn file_info_if_new(
     entry: &DirEntry,
     db: &Db,
     conf: &Config,
+    history: &ScanHistory,
 ) -> io::Result<Option<FileInfo>> {
     for group in &conf.watching.groups {
         match group.validate(entry)? {
-            Validation::Ok => {
+            Validation::Ok(companion) => {
                 let relative_path = entry
                     .path()
                     .strip_prefix(root)
                     .expect("root should be parent of path");
 
-                // Extra sanitation: check that the file name and relative
-                // path can be represented as UTF8 strings, to safely send
-                // over the network.
-                let Some(filename) = entry.file_name().to_str() else {
-                    return Ok(None);
-                };
-
-                let segments: Option<Vec<&str>> = relative_path
+                // Names that are not valid UTF-8 are percent-encoded to be
+                // sent over the network, the client should be able to find
+                // the file again from them.
+                let filename = encode_name(entry.file_name());
+                let segments: Vec<String> = relative_path
                     .parent()
                     .unwrap()
                     .iter()
-                    .map(|segment| segment.to_str())
+                    .map(encode_name)
+                    .collect();
+                let decoded: PathBuf = segments
+                    .iter()
+                    .chain([&filename])
+                    .map(|segment| decode_name(segment))
                     .collect();
-                let Some(segments) = segments else {
+                if decoded != relative_path {
+                    warn!("skipping {relative_path:?} as its name cannot be represented");
                     return Ok(None);
-                };
+                }
+
+                if conf.watching.stable_across_scans
+                    && !history
+                        .stability
+                        .lock()
+                        .unwrap()
+                        .is_stable(entry.path(), &entry.metadata()?)?
+                {
+                    debug!("{:?} changed since previous scan", entry.path());
+                    history.not_ready(entry.path());
+                    return Ok(None);
+                }
+
+                if conf.watching.skip_locked_files && is_locked(entry.path())? {
+                    debug!("{:?} is held by another process", entry.path());
+                    history.not_ready(entry.path());
+                    return Ok(None);
+                }
 
                 if insert_path(db, relative_path).await {
                     let info = FileInfo {
-                        filename: filename.to_owned(),
+                        filename,
                         relpath: segments.join("/"),
                         processing: group.processing.clone(),
-                        full_hash: group.full_hash,
+                        hash_mode: group.hash_mode(conf.watching.shallow_hash_bytes),
+                        hash_algorithm: conf.watching.hash_algorithm,
+                        read: conf.read_options().unmapped(),
+                        metadata: group.metadata.clone(),
+                        metadata_sidecar: conf.watching.metadata_sidecar.clone(),
+                        companion,
                     };
                     return Ok(Some(info));
                 }
                 return Ok(None);
             }
             Validation::TooRecent => {
+                history.not_ready(entry.path());
                 return Ok(None);
             }
             Validation::TryNextGroup => {}
@@ -118,81 +333,239 @@ async fn file_info_if_new(
     Ok(None)
 }
 
-async fn examine_file<W: AsyncWrite + Unpin>(
+/// Whether another process holds `path`, see `skip_locked_files`.
+fn is_locked(path: &Path) -> io::Result<bool> {
+    #[cfg(windows)]
+    {
+        use std::os::windows::fs::OpenOptionsExt;
+        const ERROR_SHARING_VIOLATION: i32 = 32;
+        const ERROR_LOCK_VIOLATION: i32 = 33;
+        let opened = std::fs::OpenOptions::new()
+            .read(true)
+            .share_mode(0)
+            .open(path);
+        match opened {
+            Ok(_) => Ok(false),
+            Err(err)
+                if matches!(
+                    err.raw_os_error(),
+                    Some(ERROR_SHARING_VIOLATION | ERROR_LOCK_VIOLATION)
+                ) =>
+            {
+                Ok(true)
+            }
+            Err(err) => Err(err),
+        }
+    }
+    #[cfg(not(windows))]
+    {
+        // The lock is released when the file is closed.
+        match std::fs::File::open(path)?.try_lock() {
+            Ok(()) => Ok(false),
+            Err(std::fs::TryLockError::WouldBlock) => Ok(true),
+            Err(std::fs::TryLockError::Error(err)) => Err(err),
+        }
+    }
+}
+
+async fn examine_file(
     root: PathBuf,
     entry: DirEntry,
-    to_server: ToServer<W>,
     db: Db,
     conf: Arc<Config>,
+    history: ScanHistory,
     semaphore: Arc<Semaphore>,
-) -> io::Result<bool> {
+) -> Option<FileSpec> {
     debug!("examining {:?}", entry.path());
-    if let Ok(Some(info)) = file_info_if_new(&root, &entry, &db, &conf).await
-        && let Ok(spec) = {
-            let permit = semaphore.acquire_owned().await.unwrap();
-            tokio::task::spawn_blocking(move || {
-                let spec = FileSpec::new(conf.name.clone(), entry.path(), info);
-                drop(permit);
-                spec
-            })
-            .await
-            .unwrap()
+    let info = file_info_if_new(&root, &entry, &db, &conf, &history)
+        .await
+        .ok()??;
+    let permit = semaphore.acquire_owned().await.unwrap();
+    let spec = tokio::task::spawn_blocking(move || {
+        let spec = FileSpec::new(conf.name.clone(), entry.path(), info, &history.hash_cache);
+        drop(permit);
+        spec.inspect_err(|err| warn!("cannot read {:?}: {err}", entry.path()))
+    })
+    .await
+    .unwrap()
+    .ok()?;
+    debug!("found file to process {spec:?}");
+    Some(spec)
+}
+
+/// Wait until announcements are no longer paused by the server.
+async fn wait_for_resume(pause: &Pause) {
+    loop {
+        let resume_at = *pause.lock().await;
+        if resume_at <= Instant::now() {
+            break;
         }
-    {
-        debug!("found file to process {spec:?}");
-        to_server.lock().await.send(spec).await?;
-        Ok(true)
-    } else {
-        Ok(false)
+        tokio::time::sleep_until(resume_at).await;
+    }
+}
+
+/// Announce `spec` to the server, returning whether it was sent.
+pub(super) async fn announce<W: AsyncWrite + Unpin>(
+    to_server: &ToServer<W>,
+    pause: &Pause,
+    spec: FileSpec,
+) -> io::Result<bool> {
+    wait_for_resume(pause).await;
+    let rel_path = spec.relative_path();
+    let sent = to_server
+        .lock()
+        .await
+        .send(ClientMessage::Announce(Box::new(spec)))
+        .await;
+    match sent {
+        Ok(_) => Ok(true),
+        Err(err) if is_frame_too_long(&err) => {
+            warn!("skipping {rel_path:?}: {err}, consider increasing `max_frame_length`");
+            Ok(false)
+        }
+        Err(err) => Err(err),
     }
 }
 
 fn filter_dir_entry(entry: Result<DirEntry, walkdir::Error>) -> Option<DirEntry> {
-    let entry = entry.ok()?;
-    // Only consider paths that are valid UTF8 strings to be able to send them safely through
-    // the network.
-    entry.file_name().to_str()?;
-    Some(entry)
+    entry
+        .inspect_err(|err| {
+            if err.loop_ancestor().is_some() {
+                warn!("skipping symbolic link loop: {err}");
+            }
+        })
+        .ok()
 }
 
 async fn recurse_through_files<W: AsyncWrite + Unpin + Send + 'static>(
     root: PathBuf,
     to_server: ToServer<W>,
     db: Db,
+    pause: Pause,
     conf: Arc<Config>,
+    history: ScanHistory,
+    reconcile: bool,
 ) -> io::Result<u64> {
-    let mut examined_files = Vec::with_capacity(32);
+    let mut examined_files = JoinSet::new();
     let semaphore = Arc::new(Semaphore::new(conf.watching.max_concurrent_hashes));
+    let candidates = reconcile.then(Candidates::default);
     let mut found_files = 0;
+    let full_scan = history
+        .directories
+        .lock()
+        .unwrap()
+        .start_scan(conf.watching.full_scan_every_refreshes, reconcile);
+    let mut quiet_dirs = HashSet::new();
     let walker = WalkDir::new(&root)
         .min_depth(conf.watching.min_depth())
         .max_depth(conf.watching.max_depth())
+        .follow_links(conf.watching.follow_symlinks)
         .into_iter()
-        .filter_map(filter_dir_entry)
-        .filter(|e| e.file_type().is_file());
+        .filter_entry(|e| !conf.watching.is_excluded(e))
+        .filter_map(filter_dir_entry);
     for entry in walker {
+        history.liveness.progress(Duration::ZERO);
+        // Wait for files being examined before looking at more of them.
+        while examined_files.len() >= conf.watching.max_examined_files {
+            if let Some(found) = examined_files.join_next().await
+                && found??
+            {
+                found_files += 1;
+            }
+            history.liveness.progress(Duration::ZERO);
+            if let Some(candidates) = &candidates {
+                let mut candidates = candidates.lock().await;
+                if candidates.len() >= RECONCILE_CHUNK {
+                    let chunk = candidates.drain(..).collect();
+                    drop(candidates);
+                    send_candidates(&to_server, &pause, chunk).await?;
+                }
+            }
+        }
+        if entry.file_type().is_dir() {
+            let quiet = entry.metadata().is_ok_and(|metadata| {
+                let mut directories = history.directories.lock().unwrap();
+                directories.is_quiet(entry.path(), &metadata, full_scan)
+            });
+            if quiet {
+                quiet_dirs.insert(entry.into_path());
+            }
+            continue;
+        }
+        if !entry.file_type().is_file()
+            || entry
+                .path()
+                .parent()
+                .is_some_and(|dir| quiet_dirs.contains(dir))
+        {
+            continue;
+        }
         let root = root.clone();
         let to_server = to_server.clone();
         let db = db.clone();
+        let pause = pause.clone();
         let conf = conf.clone();
+        let history = history.clone();
         let semaphore = semaphore.clone();
-        examined_files.push(tokio::spawn(async move {
-            examine_file(root, entry, to_server, db, conf, semaphore).await
-        }));
+        let candidates = candidates.clone();
+        examined_files.spawn(async move {
+            let Some(spec) = examine_file(root, entry, db, conf, history, semaphore).await else {
+                return Ok(false);
+            };
+            match candidates {
+                Some(candidates) => {
+                    candidates.lock().await.push(spec);
+                    Ok(true)
+                }
+                None => announce(&to_server, &pause, spec).await,
+            }
+        });
         yield_now().await;
     }
-    for f in examined_files {
-        if f.await?? {
+    while let Some(found) = examined_files.join_next().await {
+        if found?? {
             found_files += 1;
         }
+        history.liveness.progress(Duration::ZERO);
+    }
+    if let Some(candidates) = candidates {
+        let candidates = candidates.lock().await.drain(..).collect();
+        send_candidates(&to_server, &pause, candidates).await?;
     }
     Ok(found_files)
 }
 
+async fn send_candidates<W: AsyncWrite + Unpin>(
+    to_server: &ToServer<W>,
+    pause: &Pause,
+    mut candidates: Vec<FileSpec>,
+) -> io::Result<()> {
+    while !candidates.is_empty() {
+        wait_for_resume(pause).await;
+        let rest = candidates.split_off(candidates.len().min(RECONCILE_CHUNK));
+        debug!("reconciling {} files with server", candidates.len());
+        let msg = ClientMessage::Reconcile(candidates.clone());
+        let sent = to_server.lock().await.send(msg).await;
+        match sent {
+            Ok(_) => {}
+            Err(err) if is_frame_too_long(&err) => {
+                warn!("cannot reconcile files in bulk: {err}, announcing them one by one");
+                for spec in candidates {
+                    announce(to_server, pause, spec).await?;
+                }
+            }
+            Err(err) => return Err(err),
+        }
+        candidates = rest;
+    }
+    Ok(())
+}
+
 struct HeartBeat {
     nfiles: u64,
     nrefreshes: u32,
     emit_every_refreshes: u32,
+    longest_scan: Duration,
     timer: Instant,
 }
 
@@ -202,12 +575,14 @@ impl HeartBeat {
             nfiles: 0,
             nrefreshes: 0,
             emit_every_refreshes,
+            longest_scan: Duration::ZERO,
             timer: Instant::now(),
         }
     }
 
-    fn refresh(&mut self, n_new_files: u64) {
+    fn refresh(&mut self, n_new_files: u64, scan_duration: Duration) {
         self.nfiles += n_new_files;
+        self.longest_scan = self.longest_scan.max(scan_duration);
         if self.emit_every_refreshes > 0 {
             self.nrefreshes = (self.nrefreshes + 1) % self.emit_every_refreshes;
             if self.nrefreshes == 0 {
@@ -220,48 +595,91 @@ impl HeartBeat {
         let elapsed = self.timer.elapsed();
         self.timer = Instant::now();
         info!(
-            "found {} new files to process since last heartbeat ({:.0} s ago)",
+            "found {} new files to process since last heartbeat ({:.0} s ago), longest scan took {:.1} s",
             self.nfiles,
             elapsed.as_secs_f64(),
+            self.longest_scan.as_secs_f64(),
         );
         self.nfiles = 0;
+        self.longest_scan = Duration::ZERO;
     }
 }
 
 pub(super) async fn watch_dir(
     to_server: ToServer<OwnedWriteHalf>,
     db: Db,
+    pause: Pause,
     conf: Arc<Config>,
+    hash_cache: Arc<HashCache>,
+    liveness: Liveness,
     once: bool,
 ) -> io::Result<()> {
     info!("watching {:?} for new files", &conf.watching.directory);
-    let mut interval = tokio::time::interval(Duration::from_secs(conf.watching.refresh_every_secs));
-    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let refresh_every = Duration::from_secs(conf.watching.refresh_every_secs);
     let root = conf.watching.directory.canonicalize()?;
     let mut heart_beat = HeartBeat::new(conf.watching.heartbeat_every_refreshes);
+    let history = ScanHistory::new(hash_cache, liveness);
+    // Files found when connecting were possibly announced before a restart.
+    let mut first_scan = true;
     loop {
-        interval.tick().await;
         debug!("going through files in {root:?}");
-        let nfiles =
-            recurse_through_files(root.clone(), to_server.clone(), db.clone(), conf.clone())
-                .await?;
-        heart_beat.refresh(nfiles);
-        if once && nfiles == 0 && db.lock().await.is_empty() {
+        let scan_start = Instant::now();
+        let nfiles = recurse_through_files(
+            root.clone(),
+            to_server.clone(),
+            db.clone(),
+            pause.clone(),
+            conf.clone(),
+            history.clone(),
+            first_scan,
+        )
+        .await?;
+        first_scan = false;
+        let unstable_files = history.end_scan();
+        let scan_duration = scan_start.elapsed();
+        debug!("scan of {root:?} took {:.3} s", scan_duration.as_secs_f64());
+        heart_beat.refresh(nfiles, scan_duration);
+        let all_done = db
+            .lock()
+            .await
+            .values()
+            .all(|resends| conf.gave_up(*resends));
+        if once && nfiles == 0 && all_done && !unstable_files {
             heart_beat.emit();
             info!("stopping as in `start-once` mode and no new file has been found");
             break Ok(());
         }
+        // Pause at least as long as the scan took, so that scanning large
+        // trees back to back does not keep the client busy.
+        let pause_for = refresh_every
+            .saturating_sub(scan_duration)
+            .max(scan_duration);
+        if scan_duration > refresh_every {
+            debug!("scan took longer than `refresh_every_secs`, pausing for {pause_for:?}");
+        }
+        history.liveness.progress(pause_for);
+        tokio::time::sleep(pause_for).await;
     }
 }
 
 pub(crate) async fn main(config: Config) -> io::Result<()> {
     let config = Arc::new(config);
-    let db = Arc::new(Mutex::new(HashSet::new()));
+    let db = Arc::new(Mutex::new(HashMap::new()));
     let root = config.watching.directory.canonicalize()?;
-    let to_server = framed_json_sink();
-    let to_server = Arc::new(Mutex::new(to_server));
+    let to_server = Arc::new(Mutex::new(Outbox::new(framed_json_sink())));
     let timer = Instant::now();
-    recurse_through_files(root, to_server, db.clone(), config.clone()).await?;
+    let history = ScanHistory::new(Arc::new(HashCache::default()), Liveness::default());
+    let pause = Arc::new(Mutex::new(Instant::now()));
+    recurse_through_files(
+        root,
+        to_server,
+        db.clone(),
+        pause,
+        config.clone(),
+        history,
+        false,
+    )
+    .await?;
     let duration = timer.elapsed();
     println!(
         "watched-files: found {} files to process in {:?}, took {:.3} s",
@@ -271,3 +689,29 @@ pub(crate) async fn main(config: Config) -> io::Result<()> {
     );
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::client::DEFAULT_TOML_CONF;
+
+    #[test]
+    fn metadata_sidecars_are_excluded() {
+        let dir = std::env::temp_dir().join(format!("pipeline-sidecar-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("file.dat"), "content").unwrap();
+        std::fs::write(dir.join("file.dat.meta.toml"), "operator = \"jdoe\"").unwrap();
+
+        let mut conf: Config = toml::from_slice(DEFAULT_TOML_CONF.as_bytes()).unwrap();
+        conf.watching.metadata_sidecar = Some(".meta.toml".to_owned());
+        let mut names: Vec<_> = WalkDir::new(&dir)
+            .min_depth(1)
+            .into_iter()
+            .filter_entry(|entry| !conf.watching.is_excluded(entry))
+            .map(|entry| entry.unwrap().file_name().to_owned())
+            .collect();
+        names.sort();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(names, ["file.dat"]);
+    }
+}
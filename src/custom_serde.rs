@@ -1,6 +1,7 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, fmt, path::PathBuf};
 
 use serde::{Deserialize, Deserializer};
+use zeroize::Zeroizing;
 
 pub(crate) fn vec_at_least_one<'de, D, T>(de: D) -> Result<Vec<T>, D::Error>
 where
@@ -14,6 +15,18 @@ where
     Ok(vec)
 }
 
+pub(crate) fn at_least_one<'de, D, T>(de: D) -> Result<T, D::Error>
+where
+    T: Deserialize<'de> + Default + PartialEq,
+    D: Deserializer<'de>,
+{
+    let value = T::deserialize(de)?;
+    if value == T::default() {
+        return Err(serde::de::Error::custom("value should be at least 1"));
+    }
+    Ok(value)
+}
+
 pub(crate) fn map_at_least_one<'de, D, T>(de: D) -> Result<HashMap<String, T>, D::Error>
 where
     T: Deserialize<'de>,
@@ -25,3 +38,93 @@ where
     }
     Ok(map)
 }
+
+/// Sensitive value, either given inline or read from the environment or a file
+/// when the configuration is loaded.
+///
+/// In TOML, this is either a string, `{ env = "VAR" }` or `{ file = "path" }`.
+#[derive(Clone)]
+pub(crate) struct Secret(Zeroizing<String>);
+
+impl Secret {
+    pub(crate) fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Secret(***)")
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SecretSource {
+    Inline(String),
+    Env { env: String },
+    File { file: PathBuf },
+}
+
+impl<'de> Deserialize<'de> for Secret {
+    fn deserialize<D: Deserializer<'de>>(de: D) -> Result<Self, D::Error> {
+        let value = match SecretSource::deserialize(de)? {
+            SecretSource::Inline(value) => value,
+            SecretSource::Env { env } => std::env::var(&env).map_err(|err| {
+                serde::de::Error::custom(format!("cannot read secret from ${env}: {err}"))
+            })?,
+            SecretSource::File { file } => {
+                let mut value = std::fs::read_to_string(&file).map_err(|err| {
+                    serde::de::Error::custom(format!("cannot read secret from {file:?}: {err}"))
+                })?;
+                let len = value.trim_end_matches(['\r', '\n']).len();
+                value.truncate(len);
+                value
+            }
+        };
+        Ok(Secret(Zeroizing::new(value)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[derive(Deserialize)]
+    struct WithSecret {
+        secret: Secret,
+    }
+
+    #[derive(Deserialize)]
+    struct WithCount {
+        #[serde(deserialize_with = "at_least_one")]
+        count: usize,
+    }
+
+    #[test]
+    fn zero_count_is_refused() {
+        assert!(toml::from_str::<WithCount>("count = 0").is_err());
+        let conf: WithCount = toml::from_str("count = 2").unwrap();
+        assert_eq!(conf.count, 2);
+    }
+
+    #[test]
+    fn inline_secret() {
+        let conf: WithSecret = toml::from_str("secret = \"hunter2\"").unwrap();
+        assert_eq!(conf.secret.expose(), "hunter2");
+    }
+
+    #[test]
+    fn missing_env_secret() {
+        let conf = toml::from_str::<WithSecret>(
+            "secret = { env = \"PIPELINE_TEST_SURELY_UNDEFINED_VARIABLE\" }",
+        );
+        assert!(conf.is_err());
+    }
+
+    #[test]
+    fn secret_is_redacted() {
+        let conf: WithSecret = toml::from_str("secret = \"hunter2\"").unwrap();
+        assert!(!format!("{:?}", conf.secret).contains("hunter2"));
+    }
+}
@@ -1,12 +1,34 @@
+use serde::{Serialize, de::DeserializeOwned};
 use tokio::{
-    io::{self, Sink},
+    io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, Sink},
     net::{
         TcpStream,
         tcp::{OwnedReadHalf, OwnedWriteHalf, ReadHalf, WriteHalf},
     },
 };
 use tokio_serde::{SymmetricallyFramed, formats::SymmetricalJson};
-use tokio_util::codec::{FramedRead, FramedWrite, LengthDelimitedCodec};
+use tokio_util::codec::{
+    FramedRead, FramedWrite, LengthDelimitedCodec, length_delimited::LengthDelimitedCodecError,
+};
+
+/// Maximum length of frames exchanged when not otherwise configured.
+pub(crate) const DEFAULT_MAX_FRAME_LENGTH: usize = 8 * 1024 * 1024;
+
+pub(crate) fn default_max_frame_length() -> usize {
+    DEFAULT_MAX_FRAME_LENGTH
+}
+
+/// Whether an IO error stems from a frame exceeding the maximum frame length.
+pub(crate) fn is_frame_too_long(err: &io::Error) -> bool {
+    err.get_ref()
+        .is_some_and(|e| e.is::<LengthDelimitedCodecError>())
+}
+
+fn codec(max_frame_length: usize) -> LengthDelimitedCodec {
+    LengthDelimitedCodec::builder()
+        .max_frame_length(max_frame_length)
+        .new_codec()
+}
 
 pub(crate) type ReadFramedJson<T, R> =
     SymmetricallyFramed<FramedRead<R, LengthDelimitedCodec>, T, SymmetricalJson<T>>;
@@ -14,9 +36,12 @@ pub(crate) type ReadFramedJson<T, R> =
 pub(crate) type WriteFramedJson<T, W> =
     SymmetricallyFramed<FramedWrite<W, LengthDelimitedCodec>, T, SymmetricalJson<T>>;
 
-fn framed_json_writer<T, W>(writer: W) -> WriteFramedJson<T, W> {
+pub(crate) fn framed_json_writer<T, W>(
+    writer: W,
+    max_frame_length: usize,
+) -> WriteFramedJson<T, W> {
     tokio_serde::SymmetricallyFramed::new(
-        FramedWrite::new(writer, LengthDelimitedCodec::new()),
+        FramedWrite::new(writer, codec(max_frame_length)),
         SymmetricalJson::<T>::default(),
     )
 }
@@ -40,21 +65,70 @@ impl<'a> Splittable<ReadHalf<'a>, WriteHalf<'a>> for &'a mut TcpStream {
     }
 }
 
+/// Split a stream into framed JSON read and write halves.
+///
+/// Frames longer than `max_frame_length` bytes are rejected with an error for
+/// which [`is_frame_too_long`] is true.
 pub(crate) fn json_channel<T, U, R, W, S>(
     stream: S,
+    max_frame_length: usize,
 ) -> (ReadFramedJson<T, R>, WriteFramedJson<U, W>)
 where
     S: Splittable<R, W>,
 {
     let (socket_r, socket_w) = stream.split();
     let read_half = tokio_serde::SymmetricallyFramed::new(
-        FramedRead::new(socket_r, LengthDelimitedCodec::new()),
+        FramedRead::new(socket_r, codec(max_frame_length)),
         SymmetricalJson::<T>::default(),
     );
-    let write_half = framed_json_writer(socket_w);
+    let write_half = framed_json_writer(socket_w, max_frame_length);
     (read_half, write_half)
 }
 
+/// Read a single JSON frame without buffering any data past it, unlike a
+/// [`FramedRead`] which would lose what it read ahead once dropped.
+///
+/// Returns `None` if the stream is closed before a frame starts.
+pub(crate) async fn read_json_frame<T, R>(
+    reader: &mut R,
+    max_frame_length: usize,
+) -> io::Result<Option<T>>
+where
+    T: DeserializeOwned,
+    R: AsyncRead + Unpin,
+{
+    let mut header = [0; 4];
+    match reader.read_exact(&mut header).await {
+        Ok(_) => {}
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err),
+    }
+    let length = u32::from_be_bytes(header) as usize;
+    if length > max_frame_length {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("frame of {length} bytes exceeds maximum of {max_frame_length}"),
+        ));
+    }
+    let mut frame = vec![0; length];
+    reader.read_exact(&mut frame).await?;
+    Ok(Some(serde_json::from_slice(&frame)?))
+}
+
+/// Write a single JSON frame, readable with [`read_json_frame`] or a framed
+/// JSON reader.
+pub(crate) async fn write_json_frame<T, W>(writer: &mut W, value: &T) -> io::Result<()>
+where
+    T: Serialize,
+    W: AsyncWrite + Unpin,
+{
+    let frame = serde_json::to_vec(value)?;
+    let length = u32::try_from(frame.len()).map_err(io::Error::other)?;
+    writer.write_all(&length.to_be_bytes()).await?;
+    writer.write_all(&frame).await?;
+    writer.flush().await
+}
+
 pub(crate) fn framed_json_sink<T>() -> WriteFramedJson<T, Sink> {
-    framed_json_writer(io::sink())
+    framed_json_writer(io::sink(), DEFAULT_MAX_FRAME_LENGTH)
 }
@@ -6,9 +6,13 @@ use serde::{Deserialize, Serialize};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
 use crate::{
-    cli::MarkStatus,
-    framed_io::{Splittable, json_channel},
-    server,
+    cli::Mark,
+    framed_io::{
+        DEFAULT_MAX_FRAME_LENGTH, Splittable, framed_json_writer, json_channel, read_json_frame,
+    },
+    hashing::{HashAlgorithm, HashMode},
+    server::{self, control},
+    server_route::CONNECT_TIMEOUT,
 };
 
 static VERSION: &str = env!("CARGO_PKG_VERSION");
@@ -21,11 +25,36 @@ struct Request {
 
 #[derive(Serialize, Deserialize, Debug)]
 pub(crate) enum RequestPayload {
-    ProcessingClient { groups: Vec<String> },
-    Mark { hash: String, status: MarkStatus },
+    ProcessingClient {
+        name: String,
+        groups: Vec<String>,
+        hashing: Hashing,
+    },
+    Mark {
+        hash: String,
+        mark: Mark,
+    },
     List,
+    /// Retry failed and abandoned files, optionally only `hash` or those with
+    /// `tag`.
+    Retry {
+        hash: Option<String>,
+        tag: Option<String>,
+    },
     PruneDone,
     Status,
+    Control(control::Command),
+    Top,
+    /// Standby server replicating this one.
+    Standby,
+}
+
+/// How a processing client hashes files, which the server must be able to verify.
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct Hashing {
+    pub(crate) algorithm: HashAlgorithm,
+    /// Mode used for each processing group.
+    pub(crate) modes: Vec<(String, HashMode)>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -33,6 +62,9 @@ enum Answer {
     Ok,
     DifferentVersion(String),
     UnknownGroups(Vec<String>),
+    ForbiddenGroups(Vec<String>),
+    InvalidName,
+    HashingMismatch(String),
 }
 
 pub(crate) enum HandshakeOutcome {
@@ -42,11 +74,23 @@ pub(crate) enum HandshakeOutcome {
 }
 
 pub(crate) enum ClientKind {
-    Processing,
-    Mark { hash: String, status: MarkStatus },
+    Processing {
+        name: String,
+    },
+    Mark {
+        hash: String,
+        mark: Mark,
+    },
     List,
+    Retry {
+        hash: Option<String>,
+        tag: Option<String>,
+    },
     PruneDone,
     Status,
+    Control(control::Command),
+    Top,
+    Standby,
 }
 
 pub(crate) async fn server_side<R, W, S>(
@@ -58,7 +102,8 @@ where
     R: AsyncReadExt + Unpin,
     W: AsyncWriteExt + Unpin,
 {
-    let (mut from_client, mut to_client) = json_channel::<Request, Answer, _, _, _>(stream);
+    let (mut from_client, mut to_client) =
+        json_channel::<Request, Answer, _, _, _>(stream, DEFAULT_MAX_FRAME_LENGTH);
 
     if let Some(msg) = from_client.try_next().await? {
         if msg.version != VERSION {
@@ -68,29 +113,50 @@ where
             return Ok(HandshakeOutcome::Denied);
         }
         match msg.payload {
-            RequestPayload::ProcessingClient { groups } => {
-                let unknown_groups: Vec<_> = groups
+            RequestPayload::ProcessingClient {
+                name,
+                groups,
+                hashing,
+            } => {
+                let (unknown_groups, groups): (Vec<_>, Vec<_>) =
+                    groups.into_iter().partition(|g| !config.is_proc_group(g));
+                let forbidden_groups: Vec<_> = groups
                     .into_iter()
-                    .filter(|g| !config.is_proc_group(g))
+                    .filter(|g| !config.is_allowed_group(g, &name))
                     .collect();
-                if unknown_groups.is_empty() {
-                    to_client.send(Answer::Ok).await?;
-                    Ok(HandshakeOutcome::Success(ClientKind::Processing))
-                } else {
+                if !unknown_groups.is_empty() {
                     to_client
                         .send(Answer::UnknownGroups(unknown_groups))
                         .await?;
                     Ok(HandshakeOutcome::Denied)
+                } else if !forbidden_groups.is_empty() {
+                    to_client
+                        .send(Answer::ForbiddenGroups(forbidden_groups))
+                        .await?;
+                    Ok(HandshakeOutcome::Denied)
+                } else if !config.is_valid_client_name(&name) {
+                    to_client.send(Answer::InvalidName).await?;
+                    Ok(HandshakeOutcome::Denied)
+                } else if let Err(mismatch) = config.check_hashing(&name, &hashing) {
+                    to_client.send(Answer::HashingMismatch(mismatch)).await?;
+                    Ok(HandshakeOutcome::Denied)
+                } else {
+                    to_client.send(Answer::Ok).await?;
+                    Ok(HandshakeOutcome::Success(ClientKind::Processing { name }))
                 }
             }
-            RequestPayload::Mark { hash, status } => {
+            RequestPayload::Mark { hash, mark } => {
                 to_client.send(Answer::Ok).await?;
-                Ok(HandshakeOutcome::Success(ClientKind::Mark { hash, status }))
+                Ok(HandshakeOutcome::Success(ClientKind::Mark { hash, mark }))
             }
             RequestPayload::List => {
                 to_client.send(Answer::Ok).await?;
                 Ok(HandshakeOutcome::Success(ClientKind::List))
             }
+            RequestPayload::Retry { hash, tag } => {
+                to_client.send(Answer::Ok).await?;
+                Ok(HandshakeOutcome::Success(ClientKind::Retry { hash, tag }))
+            }
             RequestPayload::PruneDone => {
                 to_client.send(Answer::Ok).await?;
                 Ok(HandshakeOutcome::Success(ClientKind::PruneDone))
@@ -99,6 +165,18 @@ where
                 to_client.send(Answer::Ok).await?;
                 Ok(HandshakeOutcome::Success(ClientKind::Status))
             }
+            RequestPayload::Control(command) => {
+                to_client.send(Answer::Ok).await?;
+                Ok(HandshakeOutcome::Success(ClientKind::Control(command)))
+            }
+            RequestPayload::Top => {
+                to_client.send(Answer::Ok).await?;
+                Ok(HandshakeOutcome::Success(ClientKind::Top))
+            }
+            RequestPayload::Standby => {
+                to_client.send(Answer::Ok).await?;
+                Ok(HandshakeOutcome::Success(ClientKind::Standby))
+            }
         }
     } else {
         Ok(HandshakeOutcome::ClosedConnection)
@@ -111,16 +189,32 @@ where
     R: AsyncReadExt + Unpin,
     W: AsyncWriteExt + Unpin,
 {
-    let (mut from_server, mut to_server) = json_channel::<Answer, Request, _, _, _>(stream);
-
-    to_server
-        .send(Request {
-            version: VERSION.to_owned(),
-            payload,
-        })
-        .await?;
+    let (mut from_server, to_server) = stream.split();
+    let mut to_server = framed_json_writer::<Request, _>(to_server, DEFAULT_MAX_FRAME_LENGTH);
 
-    if let Some(msg) = from_server.try_next().await? {
+    let exchange = async {
+        to_server
+            .send(Request {
+                version: VERSION.to_owned(),
+                payload,
+            })
+            .await?;
+        // The server may send more frames right after its answer, they must
+        // be left in the stream for the caller.
+        read_json_frame(&mut from_server, DEFAULT_MAX_FRAME_LENGTH).await
+    };
+    let answer = tokio::time::timeout(CONNECT_TIMEOUT, exchange)
+        .await
+        .map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!(
+                    "no answer to handshake within {}s",
+                    CONNECT_TIMEOUT.as_secs()
+                ),
+            )
+        })??;
+    if let Some(msg) = answer {
         match msg {
             Answer::Ok => Ok(true),
             Answer::DifferentVersion(version) => {
@@ -131,6 +225,18 @@ where
                 error!("server reported unknown groups {items:?}");
                 Ok(false)
             }
+            Answer::ForbiddenGroups(items) => {
+                error!("server does not allow this client to use groups {items:?}");
+                Ok(false)
+            }
+            Answer::InvalidName => {
+                error!("server cannot use the name of this client as a directory name");
+                Ok(false)
+            }
+            Answer::HashingMismatch(mismatch) => {
+                error!("server cannot verify hashes of this client: {mismatch}");
+                Ok(false)
+            }
         }
     } else {
         warn!("server closed connection");
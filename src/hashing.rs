@@ -1,92 +1,531 @@
+pub(crate) mod bench;
+
 use std::{
-    ffi::OsStr,
-    io::{self, Read},
+    fs::File,
+    io::{self, Read, Seek, SeekFrom},
     path::Path,
+    sync::{
+        Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
 };
 
-use digest_io::IoWrapper;
 use log::debug;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 
-use crate::FileSpec;
+use crate::{FileSpec, encode_name};
+
+/// Default number of bytes read per sample by shallow hashes.
+pub(crate) const DEFAULT_SAMPLE_BYTES: u64 = 1024 * 1024;
+
+/// Largest number of bytes per sample of shallow hashes. Samples are read in
+/// memory, larger ones are refused.
+pub(crate) const MAX_SAMPLE_BYTES: u64 = 64 * 1024 * 1024;
+
+pub(crate) fn default_sample_bytes() -> u64 {
+    DEFAULT_SAMPLE_BYTES
+}
+
+/// Size of the chunks hashed in parallel by [`HashAlgorithm::Sha256Tree`].
+const TREE_CHUNK_BYTES: u64 = 64 * 1024 * 1024;
+
+/// How the content of files is read to compute full hashes.
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ReadMode {
+    /// Through the page cache, with a regular buffer.
+    #[default]
+    Buffered,
+    /// By mapping files in memory.
+    Mmap,
+    /// Bypassing the page cache with large aligned reads, only on Linux.
+    Direct,
+}
+
+/// How full hashes read files, from the configuration.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ReadOptions {
+    pub(crate) mode: ReadMode,
+    /// Number of threads hashing the chunks of each `sha256_tree` hash, all
+    /// available cores if 0.
+    pub(crate) tree_hash_threads: usize,
+}
+
+impl ReadOptions {
+    /// Options to read files that may still be written, e.g. watched files or
+    /// copies being received. These are never mapped in memory: accessing a
+    /// mapping past the end of a file truncated meanwhile raises `SIGBUS`.
+    pub(crate) fn unmapped(self) -> Self {
+        let mode = match self.mode {
+            ReadMode::Mmap => ReadMode::Buffered,
+            mode => mode,
+        };
+        Self { mode, ..self }
+    }
+
+    fn tree_hash_threads(&self) -> usize {
+        match self.tree_hash_threads {
+            0 => std::thread::available_parallelism().map_or(1, |n| n.get()),
+            threads => threads,
+        }
+    }
+}
+
+/// Size of the buffer used with [`ReadMode::Direct`].
+#[cfg(target_os = "linux")]
+const DIRECT_BUFFER_BYTES: usize = 8 * 1024 * 1024;
+
+/// Alignment of offsets and buffers required by `O_DIRECT`.
+#[cfg(target_os = "linux")]
+const DIRECT_ALIGNMENT: usize = 4096;
+
+/// Hash function computing digests, clients and the server must use the same.
+#[derive(clap::ValueEnum, Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum HashAlgorithm {
+    #[default]
+    Sha256,
+    Blake3,
+    /// SHA-256 of the SHA-256 digests of consecutive chunks of the file, which
+    /// are hashed in parallel. Shallow hashes are the same as with SHA-256.
+    Sha256Tree,
+}
+
+enum Hasher {
+    Sha256(Sha256),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl Hasher {
+    fn new(algorithm: HashAlgorithm) -> Self {
+        match algorithm {
+            HashAlgorithm::Sha256 | HashAlgorithm::Sha256Tree => Self::Sha256(Sha256::new()),
+            HashAlgorithm::Blake3 => Self::Blake3(Box::default()),
+        }
+    }
+
+    fn update(&mut self, data: impl AsRef<[u8]>) {
+        match self {
+            Self::Sha256(hasher) => hasher.update(data),
+            Self::Blake3(hasher) => {
+                hasher.update(data.as_ref());
+            }
+        }
+    }
+
+    fn finalize(self) -> Vec<u8> {
+        match self {
+            Self::Sha256(hasher) => hasher.finalize().to_vec(),
+            Self::Blake3(hasher) => hasher.finalize().as_bytes().to_vec(),
+        }
+    }
+}
+
+/// Feed up to `len` bytes of `path` starting at `offset` to `hasher`.
+fn hash_content(
+    path: &Path,
+    offset: u64,
+    len: u64,
+    mode: ReadMode,
+    hasher: &mut Hasher,
+) -> io::Result<()> {
+    match mode {
+        ReadMode::Buffered => {}
+        ReadMode::Mmap => return hash_mapped(path, offset, len, hasher),
+        ReadMode::Direct =>
+        {
+            #[cfg(target_os = "linux")]
+            match open_direct(path) {
+                Ok(file) => return hash_direct(file, offset, len, hasher),
+                Err(err) => {
+                    debug!("cannot open {path:?} with O_DIRECT: {err}, reading it buffered")
+                }
+            }
+        }
+    }
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::Start(offset))?;
+    let mut reader = io::BufReader::new(file.take(len));
+    io::copy(&mut reader, hasher)?;
+    Ok(())
+}
+
+fn hash_mapped(path: &Path, offset: u64, len: u64, hasher: &mut Hasher) -> io::Result<()> {
+    let file = File::open(path)?;
+    if file.metadata()?.len() <= offset {
+        return Ok(());
+    }
+    // SAFETY: only files that are no longer written are mapped, see
+    // `ReadOptions::unmapped`. Truncating the file while it is mapped would
+    // raise `SIGBUS` when reading the missing pages.
+    let map = unsafe { memmap2::MmapOptions::new().offset(offset).map(&file)? };
+    let end = map.len().min(usize::try_from(len).unwrap_or(usize::MAX));
+    hasher.update(&map[..end]);
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn open_direct(path: &Path) -> io::Result<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+    std::fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(rustix::fs::OFlags::DIRECT.bits() as i32)
+        .open(path)
+}
+
+/// Read `file` opened with `O_DIRECT`, `offset` must be aligned.
+#[cfg(target_os = "linux")]
+fn hash_direct(mut file: File, offset: u64, len: u64, hasher: &mut Hasher) -> io::Result<()> {
+    let mut storage = vec![0; DIRECT_BUFFER_BYTES + DIRECT_ALIGNMENT];
+    let start = storage.as_ptr().align_offset(DIRECT_ALIGNMENT);
+    let buffer = &mut storage[start..start + DIRECT_BUFFER_BYTES];
+    file.seek(SeekFrom::Start(offset))?;
+    let mut remaining = len;
+    while remaining > 0 {
+        let read_bytes = file.read(buffer)?;
+        if read_bytes == 0 {
+            break;
+        }
+        let used = (read_bytes as u64).min(remaining);
+        hasher.update(&buffer[..used as usize]);
+        remaining -= used;
+    }
+    Ok(())
+}
+
+/// Hash of `path` with [`HashAlgorithm::Sha256Tree`].
+fn tree_hash(path: &Path, read: ReadOptions) -> io::Result<Vec<u8>> {
+    let chunks = path.metadata()?.len().div_ceil(TREE_CHUNK_BYTES);
+    let next_chunk = AtomicU64::new(0);
+    let digests = Mutex::new(vec![Vec::new(); chunks as usize]);
+    let hash_chunks = || -> io::Result<()> {
+        loop {
+            let chunk = next_chunk.fetch_add(1, Ordering::Relaxed);
+            if chunk >= chunks {
+                return Ok(());
+            }
+            let mut hasher = Hasher::new(HashAlgorithm::Sha256);
+            hash_content(
+                path,
+                chunk * TREE_CHUNK_BYTES,
+                TREE_CHUNK_BYTES,
+                read.mode,
+                &mut hasher,
+            )?;
+            digests.lock().unwrap()[chunk as usize] = hasher.finalize();
+        }
+    };
+    let threads = read.tree_hash_threads().min(chunks as usize).max(1);
+    std::thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads).map(|_| scope.spawn(hash_chunks)).collect();
+        workers
+            .into_iter()
+            .try_for_each(|worker| worker.join().expect("hashing thread panicked"))
+    })?;
+    let mut hasher = Hasher::new(HashAlgorithm::Sha256);
+    for digest in digests.into_inner().unwrap() {
+        hasher.update(digest);
+    }
+    Ok(hasher.finalize())
+}
+
+impl io::Write for Hasher {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub(crate) enum FileDigest {
-    Shallow(String),
+    /// Hash of the name, size and first `sample_bytes` of the file.
+    Shallow {
+        hash: String,
+        sample_bytes: u64,
+    },
+    /// Like [`FileDigest::Shallow`], also sampling the middle and the tail.
+    Sampled {
+        hash: String,
+        sample_bytes: u64,
+    },
     Full(String),
 }
 
+/// Which content of a file is hashed, see [`FileDigest`]. Shallow modes
+/// carry the number of bytes read per sample.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum HashMode {
+    Shallow(u64),
+    Sampled(u64),
+    Full,
+}
+
+/// Read up to `len` bytes of `file` from `offset`.
+fn read_chunk(file: &mut File, offset: u64, len: u64) -> io::Result<Vec<u8>> {
+    file.seek(SeekFrom::Start(offset))?;
+    let mut data = Vec::with_capacity(len.min(MAX_SAMPLE_BYTES) as usize);
+    file.take(len).read_to_end(&mut data)?;
+    Ok(data)
+}
+
 impl FileDigest {
-    pub(crate) fn new(path: &Path, full: bool) -> io::Result<Self> {
-        if full {
-            Self::new_helper(path, full, "", 0)
+    pub(crate) fn new(
+        path: &Path,
+        mode: HashMode,
+        algorithm: HashAlgorithm,
+        read: ReadOptions,
+    ) -> io::Result<Self> {
+        if mode == HashMode::Full {
+            Self::new_helper(path, mode, algorithm, read, "", 0)
         } else {
-            let name = path
-                .file_name()
-                .map(OsStr::to_str)
-                .expect("failed to get filename")
-                .expect("failed to convert filename to utf8");
+            let name = encode_name(path.file_name().expect("failed to get filename"));
             let size = path.metadata()?.len();
-            Self::new_helper(path, full, name, size)
+            Self::new_helper(path, mode, algorithm, read, &name, size)
         }
     }
 
-    fn new_helper(path: &Path, full: bool, name: &str, size: u64) -> io::Result<Self> {
-        if full {
-            debug!("computing full hash for {path:?}");
-        } else {
-            debug!("computing shallow hash for {path:?}, with name={name} and size={size}");
-        }
-        let mut hasher = Sha256::new();
-        let mut file = std::fs::File::open(path)?;
-        let hash = if full {
-            let mut hasher = IoWrapper(hasher);
-            let mut reader = io::BufReader::new(file);
-            io::copy(&mut reader, &mut hasher)?;
-            hasher.0.finalize()
-        } else {
+    fn new_helper(
+        path: &Path,
+        mode: HashMode,
+        algorithm: HashAlgorithm,
+        read: ReadOptions,
+        name: &str,
+        size: u64,
+    ) -> io::Result<Self> {
+        match mode {
+            HashMode::Full => debug!("computing full hash for {path:?}"),
+            HashMode::Shallow(_) | HashMode::Sampled(_) => {
+                debug!("computing {mode:?} hash for {path:?}, with name={name} and size={size}")
+            }
+        }
+        if let HashMode::Shallow(sample) | HashMode::Sampled(sample) = mode
+            && sample > MAX_SAMPLE_BYTES
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("samples of {sample} bytes exceed the maximum of {MAX_SAMPLE_BYTES}"),
+            ));
+        }
+        let mut hasher = Hasher::new(algorithm);
+        let mut file = File::open(path)?;
+        let hash = if let HashMode::Shallow(sample) | HashMode::Sampled(sample) = mode {
             hasher.update(name);
             hasher.update(size.to_le_bytes());
-            let mut data = vec![0; 1024 * 1024];
-            let mut idx = 0;
-            while let read_bytes = file.read(&mut data[idx..])?
-                && read_bytes != 0
-            {
-                idx += read_bytes;
-            }
+            // The first sample is padded with zeros for files smaller than it.
+            let mut data = read_chunk(&mut file, 0, sample)?;
+            data.resize(sample as usize, 0);
             hasher.update(data);
+            if matches!(mode, HashMode::Sampled(_)) && size > sample {
+                let middle = (size / 2).saturating_sub(sample / 2).max(sample);
+                hasher.update(read_chunk(&mut file, middle, sample)?);
+                let tail = size.saturating_sub(sample).max(sample);
+                hasher.update(read_chunk(&mut file, tail, sample)?);
+            }
+            hasher.finalize()
+        } else if algorithm == HashAlgorithm::Sha256Tree {
+            tree_hash(path, read)?
+        } else {
+            hash_content(path, 0, u64::MAX, read.mode, &mut hasher)?;
             hasher.finalize()
         };
         let hash = hex::encode(hash);
-        if full {
-            Ok(Self::Full(hash))
-        } else {
-            Ok(Self::Shallow(hash))
+        match mode {
+            HashMode::Shallow(sample_bytes) => Ok(Self::Shallow { hash, sample_bytes }),
+            HashMode::Sampled(sample_bytes) => Ok(Self::Sampled { hash, sample_bytes }),
+            HashMode::Full => Ok(Self::Full(hash)),
         }
     }
 
-    pub(crate) fn with_spec(path: &Path, spec: &FileSpec) -> io::Result<Self> {
-        match &spec.sha256_digest {
-            Self::Shallow(_) => {
+    pub(crate) fn with_spec(
+        path: &Path,
+        spec: &FileSpec,
+        algorithm: HashAlgorithm,
+        read: ReadOptions,
+    ) -> io::Result<Self> {
+        match spec.sha256_digest.mode() {
+            HashMode::Full => Self::new_helper(path, HashMode::Full, algorithm, read, "", 0),
+            mode => {
                 let size = path.metadata()?.len();
-                Self::new_helper(path, false, &spec.filename, size)
+                Self::new_helper(path, mode, algorithm, read, &spec.filename, size)
             }
-            Self::Full(_) => Self::new_helper(path, true, "", 0),
+        }
+    }
+
+    pub(crate) fn mode(&self) -> HashMode {
+        match self {
+            FileDigest::Shallow { sample_bytes, .. } => HashMode::Shallow(*sample_bytes),
+            FileDigest::Sampled { sample_bytes, .. } => HashMode::Sampled(*sample_bytes),
+            FileDigest::Full(_) => HashMode::Full,
         }
     }
 
     pub(crate) fn hash(&self) -> &str {
         match self {
-            FileDigest::Shallow(h) => h,
-            FileDigest::Full(h) => h,
+            FileDigest::Shallow { hash, .. } => hash,
+            FileDigest::Sampled { hash, .. } => hash,
+            FileDigest::Full(hash) => hash,
         }
     }
 
-    pub(crate) fn is_full(&self) -> bool {
+    /// Number of bytes read per sample, `None` for full hashes.
+    pub(crate) fn sample_bytes(&self) -> Option<u64> {
         match self {
-            FileDigest::Shallow(_) => false,
-            FileDigest::Full(_) => true,
+            FileDigest::Shallow { sample_bytes, .. } | FileDigest::Sampled { sample_bytes, .. } => {
+                Some(*sample_bytes)
+            }
+            FileDigest::Full(_) => None,
         }
     }
+
+    pub(crate) fn is_full(&self) -> bool {
+        self.mode() == HashMode::Full
+    }
+}
+
+/// Print the digests of `path` in all modes, as computed by clients and by the
+/// server. `name` replaces the file name in shallow hashes, e.g. to hash a copy
+/// of the file on the server.
+pub(crate) fn main(
+    path: &Path,
+    name: Option<String>,
+    algorithm: HashAlgorithm,
+    sample_bytes: u64,
+) -> io::Result<()> {
+    let name =
+        name.unwrap_or_else(|| encode_name(path.file_name().expect("failed to get filename")));
+    let size = path.metadata()?.len();
+    println!("algorithm: {algorithm:?}");
+    println!("name:      {name}");
+    println!("size:      {size} bytes");
+    println!("samples:   {sample_bytes} bytes");
+    for (label, mode) in [
+        ("shallow", HashMode::Shallow(sample_bytes)),
+        ("sampled", HashMode::Sampled(sample_bytes)),
+        ("full", HashMode::Full),
+    ] {
+        let digest =
+            FileDigest::new_helper(path, mode, algorithm, ReadOptions::default(), &name, size)?;
+        println!("{:<10} {}", format!("{label}:"), digest.hash());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sampled_hash_sees_tail() {
+        let dir = std::env::temp_dir().join(format!("pipeline-hash-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("file.dat");
+        let mut content = vec![0u8; 5 * DEFAULT_SAMPLE_BYTES as usize];
+        std::fs::write(&path, &content).unwrap();
+        let shallow_mode = HashMode::Shallow(DEFAULT_SAMPLE_BYTES);
+        let sampled_mode = HashMode::Sampled(DEFAULT_SAMPLE_BYTES);
+        let sha256 = HashAlgorithm::Sha256;
+        let shallow = FileDigest::new(&path, shallow_mode, sha256, ReadOptions::default()).unwrap();
+        let sampled = FileDigest::new(&path, sampled_mode, sha256, ReadOptions::default()).unwrap();
+
+        *content.last_mut().unwrap() = 1;
+        std::fs::write(&path, &content).unwrap();
+        assert_eq!(
+            FileDigest::new(&path, shallow_mode, sha256, ReadOptions::default()).unwrap(),
+            shallow
+        );
+        assert_ne!(
+            FileDigest::new(&path, sampled_mode, sha256, ReadOptions::default()).unwrap(),
+            sampled
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn oversized_samples_are_refused() {
+        let dir = std::env::temp_dir().join(format!("pipeline-sample-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("file.dat");
+        std::fs::write(&path, "content").unwrap();
+        let sha256 = HashAlgorithm::Sha256;
+        let small =
+            FileDigest::new(&path, HashMode::Shallow(16), sha256, ReadOptions::default()).unwrap();
+        // Padding the first sample keeps hashes of small files unchanged.
+        let mut expected = Sha256::new();
+        expected.update("file.dat");
+        expected.update(7u64.to_le_bytes());
+        expected.update(b"content\0\0\0\0\0\0\0\0\0");
+        assert_eq!(small.hash(), hex::encode(expected.finalize()));
+        let huge = HashMode::Shallow(u64::MAX);
+        let err = FileDigest::new(&path, huge, sha256, ReadOptions::default()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn tree_hash_of_chunks() {
+        let dir = std::env::temp_dir().join(format!("pipeline-tree-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("file.dat");
+        let content: Vec<u8> = (0..TREE_CHUNK_BYTES + 10).map(|i| i as u8).collect();
+        std::fs::write(&path, &content).unwrap();
+        let digest = FileDigest::new(
+            &path,
+            HashMode::Full,
+            HashAlgorithm::Sha256Tree,
+            ReadOptions::default(),
+        )
+        .unwrap();
+        let mut expected = Sha256::new();
+        for chunk in content.chunks(TREE_CHUNK_BYTES as usize) {
+            expected.update(Sha256::digest(chunk));
+        }
+        assert_eq!(digest.hash(), hex::encode(expected.finalize()));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn read_modes_agree() {
+        let dir = std::env::temp_dir().join(format!("pipeline-read-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("file.dat");
+        let content: Vec<u8> = (0..3 * 4096 + 10).map(|i| i as u8).collect();
+        std::fs::write(&path, content).unwrap();
+        let full = |mode| {
+            let read = ReadOptions {
+                mode,
+                tree_hash_threads: 0,
+            };
+            FileDigest::new(&path, HashMode::Full, HashAlgorithm::Sha256, read).unwrap()
+        };
+        let buffered = full(ReadMode::Buffered);
+        assert_eq!(full(ReadMode::Mmap), buffered);
+        assert_eq!(full(ReadMode::Direct), buffered);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn blake3_full_hash() {
+        let dir = std::env::temp_dir().join(format!("pipeline-blake3-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("file.dat");
+        std::fs::write(&path, "abc").unwrap();
+        let digest = FileDigest::new(
+            &path,
+            HashMode::Full,
+            HashAlgorithm::Blake3,
+            ReadOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            digest.hash(),
+            "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85"
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
 }
@@ -0,0 +1,106 @@
+use std::{
+    io,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    format_size,
+    hashing::{FileDigest, HashAlgorithm, HashMode, ReadMode, ReadOptions},
+};
+
+/// Time taken to hash `path` with `mode`.
+fn time_hash(
+    path: &Path,
+    mode: HashMode,
+    algorithm: HashAlgorithm,
+    read: ReadOptions,
+) -> io::Result<Duration> {
+    let start = Instant::now();
+    FileDigest::new(path, mode, algorithm, read)?;
+    Ok(start.elapsed())
+}
+
+fn throughput(bytes: u64, elapsed: Duration) -> String {
+    let per_sec = bytes as f64 / elapsed.as_secs_f64().max(f64::EPSILON);
+    format!("{}/s", format_size(per_sec as u64))
+}
+
+/// Thread counts to try, powers of two up to the number of available cores.
+fn thread_counts() -> Vec<usize> {
+    let cores = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let mut counts: Vec<_> = (0..)
+        .map(|exp| 1 << exp)
+        .take_while(|&n| n < cores)
+        .collect();
+    counts.push(cores);
+    counts
+}
+
+/// Measure how fast `path` is hashed with the various hashing options, to
+/// help choosing them for the local hardware.
+pub(crate) fn main(path: &Path, algorithm: HashAlgorithm) -> io::Result<()> {
+    let size = path.metadata()?.len();
+    println!("hashing {path:?} ({})", format_size(size));
+    println!("the file may be read from the page cache after the first run");
+
+    println!("\nshallow hashes with {algorithm:?}, by `shallow_hash_bytes`");
+    for sample_bytes in [64 * 1024, 1024 * 1024, 16 * 1024 * 1024] {
+        let read = ReadOptions::default();
+        let shallow = time_hash(path, HashMode::Shallow(sample_bytes), algorithm, read)?;
+        let sampled = time_hash(path, HashMode::Sampled(sample_bytes), algorithm, read)?;
+        println!(
+            "  {:>10}: shallow {:.1} ms, sampled {:.1} ms",
+            format_size(sample_bytes),
+            shallow.as_secs_f64() * 1e3,
+            sampled.as_secs_f64() * 1e3,
+        );
+    }
+
+    println!("\nfull hashes, by `hash_algorithm` and `hash_read_mode`");
+    for full_algorithm in [HashAlgorithm::Sha256, HashAlgorithm::Blake3] {
+        for read_mode in [ReadMode::Buffered, ReadMode::Mmap, ReadMode::Direct] {
+            let read = ReadOptions {
+                mode: read_mode,
+                tree_hash_threads: 0,
+            };
+            let elapsed = time_hash(path, HashMode::Full, full_algorithm, read)?;
+            println!(
+                "  {full_algorithm:?}, {read_mode:?}: {}",
+                throughput(size, elapsed)
+            );
+        }
+    }
+
+    println!("\nfull Sha256Tree hashes, by `tree_hash_threads`");
+    for threads in thread_counts() {
+        let read = ReadOptions {
+            mode: ReadMode::Buffered,
+            tree_hash_threads: threads,
+        };
+        let elapsed = time_hash(path, HashMode::Full, HashAlgorithm::Sha256Tree, read)?;
+        println!("  {threads:>3} threads: {}", throughput(size, elapsed));
+    }
+
+    println!("\nconcurrent full hashes with {algorithm:?}, by `max_concurrent_hashes`");
+    for concurrent in thread_counts() {
+        let start = Instant::now();
+        std::thread::scope(|scope| {
+            let workers: Vec<_> = (0..concurrent)
+                .map(|_| {
+                    scope.spawn(|| {
+                        FileDigest::new(path, HashMode::Full, algorithm, ReadOptions::default())
+                    })
+                })
+                .collect();
+            workers
+                .into_iter()
+                .try_for_each(|worker| worker.join().expect("hashing thread panicked").map(drop))
+        })?;
+        println!(
+            "  {concurrent:>3} at once: {} in total",
+            throughput(size * concurrent as u64, start.elapsed())
+        );
+    }
+    Ok(())
+}
@@ -1,3 +1,4 @@
+mod check;
 pub mod cli;
 mod client;
 mod custom_serde;
@@ -6,16 +7,29 @@ mod handshake;
 mod hashing;
 mod server;
 mod server_route;
+mod systemd;
+#[cfg(windows)]
+mod win_service;
+
+pub use client::{Client, ClientBuilder};
+pub use server::{ProcessingStep, Server, ServerBuilder, StepContext};
 
 use bstr::{ByteSlice, ByteVec};
-use serde::{Deserialize, Serialize};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
 use std::{
+    collections::BTreeMap,
     ffi::{OsStr, OsString},
+    fmt::Write,
     io,
     path::{Path, PathBuf},
+    time::SystemTime,
 };
 
-use crate::hashing::FileDigest;
+use crate::{
+    client::hash_cache::HashCache,
+    hashing::{FileDigest, HashAlgorithm, HashMode, ReadOptions},
+};
 
 /// Join paths while ensuring the use of platform-specific delimiters
 fn assemble_path<P1: AsRef<Path>, P2: AsRef<Path>>(dir: P1, relative: P2) -> PathBuf {
@@ -25,6 +39,107 @@ fn assemble_path<P1: AsRef<Path>, P2: AsRef<Path>>(dir: P1, relative: P2) -> Pat
     path
 }
 
+/// Represent a file name as a string that can be sent over the network.
+/// Names that are not valid UTF-8 are percent-encoded, `%` included.
+fn encode_name(name: &OsStr) -> String {
+    if let Some(name) = name.to_str() {
+        return name.to_owned();
+    }
+    let bytes = name.as_encoded_bytes();
+    let mut encoded = String::with_capacity(bytes.len());
+    for chunk in bytes.utf8_chunks() {
+        for c in chunk.valid().chars() {
+            match c {
+                '%' => encoded.push_str("%25"),
+                c => encoded.push(c),
+            }
+        }
+        for byte in chunk.invalid() {
+            write!(encoded, "%{byte:02X}").expect("writing to a string cannot fail");
+        }
+    }
+    encoded
+}
+
+/// Name encoded by [`encode_name`]. Names are only decoded if that gives back
+/// a name that is not valid UTF-8, they are otherwise returned unchanged as
+/// are names that cannot be represented on this platform. Valid names with
+/// percent-encoded invalid UTF-8, e.g. `caf%E9`, are hence ambiguous.
+fn decode_name(name: &str) -> OsString {
+    let bytes = name.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let byte = bytes
+            .get(i + 1..i + 3)
+            .filter(|_| bytes[i] == b'%')
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match byte {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    if std::str::from_utf8(&decoded).is_ok() {
+        return name.into();
+    }
+    decoded
+        .into_os_string()
+        .ok()
+        .filter(|decoded| encode_name(decoded) == name)
+        .unwrap_or_else(|| name.into())
+}
+
+/// Source of a configuration for the library builders.
+enum ConfigSource {
+    Toml(String),
+    File(PathBuf),
+}
+
+impl ConfigSource {
+    fn parse<T: DeserializeOwned>(&self) -> io::Result<T> {
+        let content = match self {
+            ConfigSource::Toml(content) => content.clone(),
+            ConfigSource::File(path) => std::fs::read_to_string(path)?,
+        };
+        toml::from_str(&content)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))
+    }
+}
+
+/// Format a size in bytes with a binary unit, e.g. `1.5 MiB`.
+fn format_size(size: u64) -> String {
+    const GIBI: u64 = 1024u64.pow(3);
+    const MEBI: u64 = 1024u64.pow(2);
+    const KIBI: u64 = 1024u64.pow(1);
+    let (size, unit) = if size > GIBI {
+        (size as f64 / GIBI as f64, "GiB")
+    } else if size > MEBI {
+        (size as f64 / MEBI as f64, "MiB")
+    } else if size > KIBI {
+        (size as f64 / KIBI as f64, "kiB")
+    } else {
+        (size as f64, "B")
+    };
+    if unit == "B" {
+        format!("{size:.0} {unit}")
+    } else {
+        format!("{size:.1} {unit}")
+    }
+}
+
+/// Format a time as `YYYY-MM-DD HH:MM:SS` in UTC, as SQLite's `datetime`.
+fn format_utc(time: SystemTime) -> String {
+    DateTime::<Utc>::from(time)
+        .format("%Y-%m-%d %H:%M:%S")
+        .to_string()
+}
+
 fn replace_os_strings<'a, I>(arg: &str, replacements: I) -> OsString
 where
     I: Iterator<Item = (&'a str, &'a OsStr)>,
@@ -44,25 +159,74 @@ struct FileSpec {
     filename: String,
     processing: String,
     sha256_digest: FileDigest,
+    size_bytes: u64,
+    /// Last modification time, formatted as `YYYY-MM-DD HH:MM:SS` in UTC.
+    modified_utc: String,
+    /// Arbitrary key/value pairs attached by the client.
+    metadata: BTreeMap<String, String>,
+    /// Name of a companion file in the same directory, transferred along.
+    companion: Option<String>,
 }
 
 struct FileInfo {
     filename: String,
     relpath: String,
     processing: String,
-    full_hash: bool,
+    hash_mode: HashMode,
+    hash_algorithm: HashAlgorithm,
+    read: ReadOptions,
+    metadata: BTreeMap<String, String>,
+    /// Suffix of a TOML file next to the file, holding additional metadata.
+    metadata_sidecar: Option<String>,
+    companion: Option<String>,
+}
+
+/// Read metadata from the sidecar of `path`, if it exists.
+fn read_metadata_sidecar(path: &Path, suffix: &str) -> io::Result<BTreeMap<String, String>> {
+    let mut sidecar = path.as_os_str().to_owned();
+    sidecar.push(suffix);
+    match std::fs::read_to_string(&sidecar) {
+        Ok(content) => toml::from_str(&content).map_err(|err| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid metadata in {sidecar:?}: {err}"),
+            )
+        }),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+        Err(err) => Err(err),
+    }
 }
 
 impl FileSpec {
-    fn new<S: Into<String>>(client: S, client_path: &Path, info: FileInfo) -> io::Result<Self> {
+    fn new<S: Into<String>>(
+        client: S,
+        client_path: &Path,
+        info: FileInfo,
+        hash_cache: &HashCache,
+    ) -> io::Result<Self> {
         let client = client.into();
-        let sha256_digest = FileDigest::new(client_path, info.full_hash)?;
+        let stat = client_path.metadata()?;
+        let mut metadata = info.metadata;
+        if let Some(suffix) = &info.metadata_sidecar {
+            metadata.extend(read_metadata_sidecar(client_path, suffix)?);
+        }
+        let sha256_digest = hash_cache.digest(
+            client_path,
+            &stat,
+            info.hash_mode,
+            info.hash_algorithm,
+            info.read,
+        )?;
         Ok(FileSpec {
             client,
             path: info.relpath,
             filename: info.filename,
             processing: info.processing,
             sha256_digest,
+            size_bytes: stat.len(),
+            modified_utc: format_utc(stat.modified()?),
+            metadata,
+            companion: info.companion,
         })
     }
 
@@ -74,18 +238,103 @@ impl FileSpec {
         assemble_path(&self.path, "")
     }
 
+    /// Path of the file on the client, relative to the watched directory.
     fn relative_path(&self) -> PathBuf {
-        let mut path = self.relative_directory();
-        path.push(&self.filename);
+        let mut path = self.client_relative_directory();
+        path.push(decode_name(&self.filename));
         path
     }
 
+    fn companion_relative_path(&self) -> Option<PathBuf> {
+        let mut path = self.client_relative_directory();
+        path.push(decode_name(self.companion.as_ref()?));
+        Some(path)
+    }
+
+    /// Directory of the file on the client, with its original (possibly not
+    /// UTF-8) name, unlike [`FileSpec::relative_directory`].
+    fn client_relative_directory(&self) -> PathBuf {
+        self.path
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .map(decode_name)
+            .collect()
+    }
+
+    /// Extension of the companion of the file, e.g. `xml`.
+    fn companion_extension(&self) -> Option<&str> {
+        let companion: &Path = self.companion.as_ref()?.as_ref();
+        companion.extension()?.to_str()
+    }
+
+    /// Suffix to append to the path of the file to get that of its companion
+    /// on the server, e.g. `.5f3c09a1.xml`. The same content may be sent from
+    /// several places with different companions, the suffix identifies the
+    /// origin of the file so that they are all kept.
+    fn companion_suffix(&self) -> Option<String> {
+        use sha2::{Digest, Sha256};
+
+        let ext = self.companion_extension()?;
+        let origin = Sha256::new()
+            .chain_update(&self.client)
+            .chain_update([0])
+            .chain_update(&self.path)
+            .chain_update([0])
+            .chain_update(&self.filename)
+            .finalize();
+        Some(format!(".{}.{ext}", hex::encode(&origin[..4])))
+    }
+
     fn file_stem(&self) -> &OsStr {
         let path: &Path = self.filename.as_ref();
         path.file_stem().unwrap()
     }
 }
 
+/// Message sent by a processing client to the server.
+#[derive(Serialize, Deserialize, Debug)]
+enum ClientMessage {
+    /// File ready to be processed.
+    Announce(Box<FileSpec>),
+    /// Full hash of a file, answering [`Receipt::RequestFullHash`]. This is
+    /// `None` if the client cannot compute it.
+    FullHash {
+        spec: Box<FileSpec>,
+        hash: Option<String>,
+    },
+    /// Files found when (re)connecting, the server answers with a single
+    /// [`Receipt::Reconciled`] instead of handling announcements one by one.
+    Reconcile(Vec<FileSpec>),
+    /// Heartbeat, the server answers with [`Receipt::Pong`].
+    Ping,
+}
+
+impl ClientMessage {
+    /// Files the message is about.
+    fn files(&self) -> &[FileSpec] {
+        match self {
+            Self::Announce(spec) | Self::FullHash { spec, .. } => std::slice::from_ref(spec),
+            Self::Reconcile(files) => files,
+            Self::Ping => &[],
+        }
+    }
+}
+
+/// [`ClientMessage`] numbered by the client, ids increase monotonically
+/// during the lifetime of the client so that stale receipts can be detected.
+#[derive(Serialize, Deserialize, Debug)]
+struct ClientRequest {
+    id: u64,
+    message: ClientMessage,
+}
+
+/// [`Receipt`] with the id of the [`ClientRequest`] it answers, if any.
+#[derive(Serialize, Deserialize, Debug)]
+struct ServerReply {
+    in_reply_to: Option<u64>,
+    receipt: Receipt,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 enum Receipt {
     Expecting {
@@ -93,24 +342,145 @@ enum Receipt {
         server_rel_path: String,
     },
     Received(FileSpec),
+    /// The server finished processing the file, sent unsolicited once its
+    /// processing is done.
+    Processed(FileSpec),
+    /// Processing of the file failed, sent unsolicited. It is retried later
+    /// unless `abandoned`.
+    ProcessingFailed {
+        spec: FileSpec,
+        error: String,
+        abandoned: bool,
+    },
+    /// The shallow hash matches but the server wants the full hash to be
+    /// confirmed before processing, see [`ClientMessage::FullHash`].
+    RequestFullHash {
+        spec: FileSpec,
+        server_rel_path: String,
+    },
     DifferentHash(FileSpec),
+    /// Another file with the same shallow hash was received, the client should
+    /// announce the file again with its full hash.
+    ShallowCollision(FileSpec),
+    /// The file on the server is smaller than announced, presumably because
+    /// its copy is still in progress. The client should announce it again
+    /// later rather than send it again.
+    Incomplete {
+        spec: FileSpec,
+        received_bytes: u64,
+    },
     Error {
         spec: FileSpec,
         server_rel_path: String,
         error: String,
     },
+    /// The client exceeded its disk quota on the server, the client should
+    /// announce `spec` again and pause its announcements for `until_secs`
+    /// seconds, until files are pruned.
+    QuotaExceeded {
+        spec: FileSpec,
+        until_secs: u64,
+    },
+    /// The server is low on disk space, the file should be announced again
+    /// later.
+    LowDiskSpace(FileSpec),
+    /// The same content is awaited from another client, the file should be
+    /// announced again later.
+    AwaitedElsewhere(FileSpec),
+    /// The server is saturated, the client should announce `spec` again and
+    /// pause its announcements for `until_secs` seconds.
+    SlowDown {
+        spec: FileSpec,
+        until_secs: u64,
+    },
+    /// What the server has of the files of a [`ClientMessage::Reconcile`], in
+    /// the same order. Files are not repeated to keep the reply within the
+    /// frame length the request fitted in.
+    Reconciled(Vec<Reconciliation>),
+    Pong,
+    /// The server received an invalid message and closes the connection.
+    ProtocolError(String),
+}
+
+/// State of a file on the server, see [`ClientMessage::Reconcile`].
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+enum Reconciliation {
+    /// The server does not know the file, it should be announced.
+    Unknown,
+    /// The file was announced but not received yet, or may collide with
+    /// another file with the same shallow hash, it should be announced again.
+    Pending,
+    /// The file was announced during a previous connection and the server
+    /// already sent an [`Receipt::Expecting`] for it again.
+    Resumed,
+    /// The server already received the file.
+    Received,
+    /// The server already received and processed the file.
+    Processed,
 }
 
 impl Receipt {
     fn continue_processing(&self) -> bool {
         matches!(self, Self::Received(_))
     }
+
+    /// File the client should act upon, by sending it or its hash.
+    fn file_to_act_on(&self) -> Option<&FileSpec> {
+        match self {
+            Self::Expecting { spec, .. }
+            | Self::Error { spec, .. }
+            | Self::RequestFullHash { spec, .. }
+            | Self::ShallowCollision(spec)
+            | Self::Incomplete { spec, .. } => Some(spec),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Expecting { .. } => "Expecting",
+            Self::Received(_) => "Received",
+            Self::Processed(_) => "Processed",
+            Self::ProcessingFailed { .. } => "ProcessingFailed",
+            Self::RequestFullHash { .. } => "RequestFullHash",
+            Self::DifferentHash(_) => "DifferentHash",
+            Self::ShallowCollision(_) => "ShallowCollision",
+            Self::Incomplete { .. } => "Incomplete",
+            Self::Error { .. } => "Error",
+            Self::QuotaExceeded { .. } => "QuotaExceeded",
+            Self::LowDiskSpace(_) => "LowDiskSpace",
+            Self::AwaitedElsewhere(_) => "AwaitedElsewhere",
+            Self::SlowDown { .. } => "SlowDown",
+            Self::Reconciled(_) => "Reconciled",
+            Self::Pong => "Pong",
+            Self::ProtocolError(_) => "ProtocolError",
+        }
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
 
+    #[test]
+    fn encode_names() {
+        assert_eq!(encode_name("file.dat".as_ref()), "file.dat");
+        assert_eq!(encode_name("100%.dat".as_ref()), "100%.dat");
+        assert_eq!(encode_name("100%25.dat".as_ref()), "100%25.dat");
+        assert_eq!(decode_name("100%25.dat"), "100%25.dat");
+        assert_eq!(decode_name("100%.dat"), "100%.dat");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn encode_non_utf8_name() {
+        use std::os::unix::ffi::OsStrExt;
+        let name = OsStr::from_bytes(b"caf\xe9 %.dat");
+        let encoded = encode_name(name);
+        assert_eq!(encoded, "caf%E9 %25.dat");
+        assert_eq!(decode_name(&encoded), name);
+    }
+
     #[test]
     fn replace_osstr_nothing_to_replace() {
         let arg = "foo-file_path";
@@ -135,6 +505,12 @@ mod test {
         assert_eq!(out, "hello world");
     }
 
+    #[test]
+    fn format_utc_as_sqlite() {
+        let time = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_700_000_000);
+        assert_eq!(format_utc(time), "2023-11-14 22:13:20");
+    }
+
     #[test]
     fn assemble_path_subdirs() {
         let path1 = "foo/bar";
@@ -161,4 +537,32 @@ mod test {
         let expected: PathBuf = ["foo", "bar"].iter().collect();
         assert_eq!(out, expected);
     }
+
+    #[test]
+    fn companion_suffix_per_origin() {
+        let spec = FileSpec {
+            client: "lab".to_owned(),
+            path: "runs/1".to_owned(),
+            filename: "image.mrc".to_owned(),
+            processing: "main".to_owned(),
+            sha256_digest: hashing::FileDigest::Full("0123abcd".to_owned()),
+            size_bytes: 0,
+            modified_utc: String::new(),
+            metadata: BTreeMap::new(),
+            companion: Some("image.xml".to_owned()),
+        };
+        let suffix = spec.companion_suffix().unwrap();
+        assert!(suffix.starts_with('.') && suffix.ends_with(".xml"));
+        assert_eq!(spec.companion_suffix(), Some(suffix.clone()));
+        let elsewhere = FileSpec {
+            path: "runs/2".to_owned(),
+            ..spec.clone()
+        };
+        assert_ne!(elsewhere.companion_suffix(), Some(suffix));
+        let alone = FileSpec {
+            companion: None,
+            ..spec
+        };
+        assert_eq!(alone.companion_suffix(), None);
+    }
 }
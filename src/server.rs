@@ -1,48 +1,178 @@
+pub(crate) mod archive;
+pub(crate) mod audit;
+pub(crate) mod check;
 pub(crate) mod clean;
+pub(crate) mod clients;
+pub(crate) mod control;
 pub(crate) mod create_buckets;
+mod crypt;
+mod dashboard;
 pub(crate) mod database;
+pub(crate) mod dry_run;
+mod encryption;
+pub(crate) mod export;
+pub(crate) mod gc;
+mod hours;
+mod jobs;
+pub(crate) mod maintenance;
+pub(crate) mod manifest;
 mod processing;
 pub(crate) mod query;
+pub(crate) mod replication;
+mod scheduler;
+pub(crate) mod search;
+mod spawn;
+pub(crate) mod stats;
+pub(crate) mod top;
+pub(crate) mod verify;
+
+pub use processing::{ProcessingStep, StepContext};
 
 use std::{
-    collections::HashMap,
+    collections::{BTreeSet, HashMap, HashSet},
+    ffi::OsStr,
     io,
-    net::SocketAddr,
+    net::{IpAddr, SocketAddr},
     path::{Path, PathBuf},
-    sync::Arc,
+    sync::{self as std_sync, Arc},
     time::Duration,
 };
 
 use crate::{
-    FileSpec, Receipt, assemble_path, custom_serde,
-    framed_io::{Splittable, WriteFramedJson, json_channel},
+    ClientMessage, ClientRequest, ConfigSource, FileSpec, Receipt, Reconciliation, ServerReply,
+    assemble_path, custom_serde, format_utc,
+    framed_io::{
+        Splittable, WriteFramedJson, default_max_frame_length, is_frame_too_long, json_channel,
+    },
+    handshake::Hashing,
     handshake::{self, ClientKind, HandshakeOutcome},
-    hashing::FileDigest,
-    server::clean::clean_tasks_with_status,
+    hashing::{self, FileDigest, HashAlgorithm, HashMode, ReadMode, ReadOptions},
+    server::{
+        clean::clean_tasks_with_status,
+        control::{Busy, Controls},
+        scheduler::{Pools, Resources, Scheduler, Slot},
+    },
+    systemd,
 };
-use database::{Database, ProcessStatus};
-use futures_util::{SinkExt, TryStreamExt};
+use database::{Database, ProcessStatus, PruneFilter, SERVER_ACTOR};
+use futures_util::{SinkExt, TryStreamExt, future::BoxFuture};
 use log::{debug, error, info, warn};
-use serde::Deserialize;
+use serde::{Deserialize, Deserializer};
 use tokio::{
     io::AsyncReadExt,
     io::AsyncWriteExt,
     net::{TcpListener, TcpStream},
-    sync::{Mutex, Semaphore},
+    sync::{Mutex, OwnedSemaphorePermit, Semaphore, mpsc},
     time::MissedTickBehavior,
 };
 
+/// Configuration of the server, refused when deserialized if it uses unknown
+/// placeholders or resources.
 #[derive(Deserialize, Debug, PartialEq, Eq)]
+#[serde(remote = "Self")]
 pub(crate) struct Config {
     incoming_directory: PathBuf,
     unix_mode: Option<u32>,
     #[serde(deserialize_with = "custom_serde::map_at_least_one")]
     processing: HashMap<String, ProcessingGroup>,
     retry_tasks_every_secs: u64,
+    /// Daily windows outside of which files are not processed, processing is
+    /// allowed anytime if empty.
+    #[serde(default)]
+    processing_hours: Vec<hours::TimeWindow>,
+    /// Processing attempts after which a failing file is abandoned, 0 for
+    /// no limit.
+    #[serde(default)]
+    max_attempts: u32,
+    /// Time after which files announced but never delivered expire, 0 to
+    /// wait forever.
+    #[serde(default)]
+    await_ttl_secs: u64,
     prune_every_secs: u64,
+    #[serde(default)]
+    jobs: Vec<jobs::ScheduledJob>,
+    #[serde(
+        default = "default_client_timeout_secs",
+        deserialize_with = "custom_serde::at_least_one"
+    )]
+    client_timeout_secs: u64,
+    #[serde(default = "default_max_frame_length")]
+    max_frame_length: usize,
+    #[serde(default)]
+    quota_bytes: HashMap<String, u64>,
+    quarantine_directory: Option<PathBuf>,
+    /// Directory where pruned files are moved instead of being deleted.
+    archive_directory: Option<PathBuf>,
+    /// Directory where the output of external commands is logged, per file
+    /// and step.
+    step_logs_directory: Option<PathBuf>,
+    encryption: Option<encryption::EncryptionConfig>,
+    manifest_key_file: Option<PathBuf>,
+    /// Address to serve the web dashboard on, if any.
+    dashboard_address: Option<String>,
+    /// Metadata keys attached as `key=value` tags to the files announced with
+    /// them.
+    #[serde(default)]
+    tag_metadata: Vec<String>,
+    #[serde(default)]
+    min_free_bytes: u64,
+    #[serde(default)]
+    target_free_bytes: u64,
+    #[serde(default)]
+    paranoid: bool,
+    #[serde(default = "crate::hashing::default_sample_bytes")]
+    shallow_hash_bytes: u64,
+    #[serde(default)]
+    hash_algorithm: HashAlgorithm,
+    #[serde(default)]
+    tree_hash_threads: usize,
+    #[serde(default)]
+    hash_read_mode: ReadMode,
+    #[serde(default)]
+    on_shallow_collision: ShallowCollisions,
+    #[serde(default)]
+    namespace_by_client: bool,
+    #[serde(default)]
+    preserve_extension: bool,
+    #[serde(default)]
+    storage_layout: StorageLayout,
     server: ServerAddress,
     concurrency: Concurrency,
     database: DatabaseConfig,
+    #[serde(default)]
+    replication: ReplicationConfig,
+    #[serde(skip)]
+    plugins: processing::Plugins,
+}
+
+/// What to do when a file announced with a shallow hash matches a file
+/// received from another client, path or name.
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum ShallowCollisions {
+    /// Assume both files are identical.
+    Trust,
+    /// Assume both files are identical, but log and audit the suspected collision.
+    #[default]
+    Log,
+    /// Compare the full hashes of both files, the new file is announced again
+    /// with its full hash if they differ.
+    FullHash,
+}
+
+/// How files are laid out in the incoming directory.
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum StorageLayout {
+    /// `ab/cd/abcd...`, named after their hash.
+    #[default]
+    HashBuckets,
+    /// `client/path/filename`, mirroring their path on the client.
+    ClientPaths,
+}
+
+fn default_client_timeout_secs() -> u64 {
+    300
 }
 
 #[derive(Deserialize, Debug, PartialEq, Eq)]
@@ -54,17 +184,142 @@ pub(crate) struct ServerAddress {
 struct ProcessingGroup {
     processing: processing::Processing,
     after_processing: processing::AfterProcessing,
+    batch: Option<processing::Batch>,
+    /// Outputs of another group fed to this one.
+    chained_from: Option<processing::ChainedFrom>,
+    /// Clients allowed to use this group, all if empty.
+    #[serde(default)]
+    clients: Vec<String>,
+    #[serde(default)]
+    require_full_hash: bool,
 }
 
 #[derive(Deserialize, Debug, PartialEq, Eq)]
 struct Concurrency {
     max_hashes: usize,
     max_processing: usize,
+    #[serde(default = "default_max_queued_files")]
+    max_queued_files: usize,
+    #[serde(default = "default_slow_down_secs")]
+    slow_down_secs: u64,
+    /// Hand processing slots out in turn across clients rather than in order
+    /// of arrival, see [`Scheduler`].
+    #[serde(default = "default_fair_share")]
+    fair_share: bool,
+    /// Priority of files from each client for processing slots.
+    #[serde(default)]
+    client_priority: HashMap<String, i64>,
+    /// Priority added by each tag of a file for processing slots.
+    #[serde(default)]
+    tag_priority: HashMap<String, i64>,
+    /// Size of each pool of resources that processing steps may require.
+    #[serde(default)]
+    resources: HashMap<String, u32>,
+}
+
+/// Semaphores shared by all processing clients, see [`Concurrency`], and the
+/// runtime controls that can pause them.
+#[derive(Clone)]
+struct Semaphores {
+    hash: Arc<Semaphore>,
+    proc: Scheduler,
+    queue: Arc<Semaphore>,
+    pools: Pools,
+    controls: Controls,
+}
+
+impl Semaphores {
+    fn new(concurrency: &Concurrency, controls: Controls) -> Self {
+        Self {
+            hash: Arc::new(Semaphore::new(concurrency.max_hashes)),
+            proc: Scheduler::new(concurrency.max_processing, concurrency.fair_share),
+            queue: Arc::new(Semaphore::new(concurrency.max_queued_files)),
+            pools: Pools::new(&concurrency.resources),
+            controls,
+        }
+    }
+}
+
+fn default_max_queued_files() -> usize {
+    1000
+}
+
+fn default_fair_share() -> bool {
+    true
+}
+
+fn default_slow_down_secs() -> u64 {
+    10
 }
 
 #[derive(Deserialize, Debug, PartialEq, Eq)]
 struct DatabaseConfig {
     wal: bool,
+    #[serde(default = "default_busy_timeout_secs")]
+    busy_timeout_secs: u64,
+    #[serde(default = "default_max_connections")]
+    max_connections: u32,
+    /// Interval between automatic backups of the database, 0 to disable them.
+    #[serde(default)]
+    backup_every_secs: u64,
+    #[serde(default = "default_backup_directory")]
+    backup_directory: PathBuf,
+    /// Number of automatic backups to keep, older ones are removed.
+    #[serde(default = "default_backup_keep")]
+    backup_keep: usize,
+}
+
+/// Replication of a primary server to hot-standby servers.
+#[derive(Deserialize, Debug, PartialEq, Eq)]
+struct ReplicationConfig {
+    /// Addresses of the standby servers allowed to replicate this server.
+    #[serde(default)]
+    standbys: Vec<IpAddr>,
+    /// Address of the primary server replicated by `pipeline server standby`.
+    primary: Option<String>,
+    #[serde(
+        default = "default_replicate_every_secs",
+        deserialize_with = "custom_serde::at_least_one"
+    )]
+    every_secs: u64,
+}
+
+impl Default for ReplicationConfig {
+    fn default() -> Self {
+        Self {
+            standbys: Vec::new(),
+            primary: None,
+            every_secs: default_replicate_every_secs(),
+        }
+    }
+}
+
+fn default_replicate_every_secs() -> u64 {
+    60
+}
+
+fn default_backup_directory() -> PathBuf {
+    PathBuf::from("./server/backups")
+}
+
+fn default_backup_keep() -> usize {
+    7
+}
+
+fn default_busy_timeout_secs() -> u64 {
+    5
+}
+
+fn default_max_connections() -> u32 {
+    4
+}
+
+impl<'de> Deserialize<'de> for Config {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let config = Config::deserialize(deserializer)?;
+        check::at_load(&config).map_err(serde::de::Error::custom)?;
+        Ok(config)
+    }
 }
 
 impl Config {
@@ -72,11 +327,21 @@ impl Config {
         assemble_path(&self.incoming_directory, relative)
     }
 
-    pub(crate) fn path_of(&self, file: &FileSpec) -> PathBuf {
-        let rel_path = rel_path(file, self);
+    /// Path of a file stored at `rel_path`, as recorded in the database when
+    /// the file was claimed, see [`claim_storage_path`].
+    pub(crate) fn stored_path(&self, rel_path: &str) -> PathBuf {
         self.incoming_path(rel_path)
     }
 
+    /// How full hashes read files, see [`ReadOptions::unmapped`] for copies
+    /// that clients may still be writing.
+    pub(crate) fn read_options(&self) -> ReadOptions {
+        ReadOptions {
+            mode: self.hash_read_mode,
+            tree_hash_threads: self.tree_hash_threads,
+        }
+    }
+
     pub(crate) async fn create_dir_async(&self, path: impl AsRef<Path>) -> io::Result<()> {
         use tokio::fs;
 
@@ -107,34 +372,332 @@ impl Config {
         Ok(())
     }
 
+    /// Whether accepting `file` would leave less than `min_free_bytes` on the
+    /// volume of `incoming_directory`.
+    fn low_disk_space(&self, file: &FileSpec) -> bool {
+        if self.min_free_bytes == 0 {
+            return false;
+        }
+        match fs4::available_space(&self.incoming_directory) {
+            Ok(available) => available < self.min_free_bytes + file.size_bytes,
+            Err(err) => {
+                warn!("failed to check free space in incoming directory: {err}");
+                false
+            }
+        }
+    }
+
+    /// Priority of `file` for processing slots, that of its client plus those
+    /// of its tags.
+    async fn priority(&self, file: &FileSpec, db: &Database) -> i64 {
+        let conc = &self.concurrency;
+        let mut priority = conc.client_priority.get(&file.client).copied().unwrap_or(0);
+        if !conc.tag_priority.is_empty() {
+            match db.file(file.hash()).await {
+                Ok(Some(in_db)) => {
+                    let tags = in_db.tag_list();
+                    priority += tags
+                        .iter()
+                        .filter_map(|tag| conc.tag_priority.get(tag))
+                        .sum::<i64>();
+                }
+                Ok(None) => {}
+                Err(err) => warn!("failed to read tags of {file:?} in db: {err}"),
+            }
+        }
+        priority
+    }
+
+    /// Tags of `file` derived from its metadata, see `tag_metadata`.
+    fn metadata_tags(&self, file: &FileSpec) -> Vec<String> {
+        self.tag_metadata
+            .iter()
+            .filter_map(|key| Some(format!("{key}={}", file.metadata.get(key)?)))
+            .collect()
+    }
+
     pub(crate) fn is_proc_group(&self, name: &str) -> bool {
         self.processing.contains_key(name)
     }
+
+    /// Clients mentioned in the configuration, either with a quota or a
+    /// priority, or as allowed to use a processing group.
+    pub(crate) fn known_clients(&self) -> BTreeSet<&str> {
+        let allowed = self.processing.values().flat_map(|g| &g.clients);
+        self.quota_bytes
+            .keys()
+            .chain(self.concurrency.client_priority.keys())
+            .chain(allowed)
+            .map(String::as_str)
+            .collect()
+    }
+
+    /// Whether `client` may send files to the processing group `name`.
+    pub(crate) fn is_allowed_group(&self, name: &str, client: &str) -> bool {
+        self.processing
+            .get(name)
+            .is_some_and(|g| g.clients.is_empty() || g.clients.iter().any(|c| c == client))
+    }
+
+    /// Check that `client` may send `spec`, returning why not otherwise.
+    fn check_sent_file(&self, client: &str, spec: &FileSpec) -> Result<(), String> {
+        if spec.client != client {
+            return Err(format!("file sent by {client} as {}", spec.client));
+        }
+        if !self.is_allowed_group(&spec.processing, client) {
+            return Err(format!(
+                "{client} may not send files to processing group {}",
+                spec.processing
+            ));
+        }
+        if let Some(bytes) = spec.sha256_digest.sample_bytes()
+            && bytes > hashing::MAX_SAMPLE_BYTES
+        {
+            return Err(format!(
+                "samples of {bytes} bytes exceed the maximum of {}",
+                hashing::MAX_SAMPLE_BYTES
+            ));
+        }
+        // Clients are checked when connecting, but may send any digest.
+        if !spec.sha256_digest.is_full()
+            && self
+                .processing
+                .get(&spec.processing)
+                .is_some_and(|group| group.require_full_hash)
+        {
+            return Err(format!(
+                "group {} requires full hashes, got {:?}",
+                spec.processing,
+                spec.sha256_digest.mode()
+            ));
+        }
+        Ok(())
+    }
+
+    /// Check that the server can verify hashes computed by `client` as
+    /// described by `hashing`, returning the mismatch otherwise.
+    pub(crate) fn check_hashing(&self, client: &str, hashing: &Hashing) -> Result<(), String> {
+        if hashing.algorithm != self.hash_algorithm {
+            return Err(format!(
+                "client uses {:?} while server expects {:?}",
+                hashing.algorithm, self.hash_algorithm
+            ));
+        }
+        for (group, mode) in &hashing.modes {
+            if *mode != HashMode::Full
+                && self
+                    .processing
+                    .get(group)
+                    .is_some_and(|g| g.require_full_hash)
+            {
+                return Err(format!(
+                    "group {group} requires full hashes, client uses {mode:?}"
+                ));
+            }
+            if let HashMode::Shallow(bytes) | HashMode::Sampled(bytes) = mode
+                && *bytes < self.shallow_hash_bytes
+            {
+                warn!(
+                    "client {client} samples {bytes} bytes for group {group}, less than `shallow_hash_bytes`, full hashes will be requested for all its files"
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether `client` can be used as a directory name in the incoming
+    /// directory, always true if files are not stored per client.
+    pub(crate) fn is_valid_client_name(&self, client: &str) -> bool {
+        !self.stores_per_client() || is_safe_segment(client)
+    }
+
+    /// Whether incoming files are stored in a directory per client.
+    pub(crate) fn stores_per_client(&self) -> bool {
+        self.namespace_by_client || self.storage_layout == StorageLayout::ClientPaths
+    }
+}
+
+/// Whether `segment` can be used as a single path component.
+fn is_safe_segment(segment: &str) -> bool {
+    !matches!(segment, "" | "." | "..") && !segment.contains(['/', '\\', ':'])
 }
 
 pub(crate) static DEFAULT_TOML_CONF: &str = include_str!("server/default.toml");
 
-fn rel_path(spec: &FileSpec, config: &Config) -> String {
+/// Path of the companion of `file` stored at `path` on the server, if it has
+/// one.
+pub(crate) fn companion_path_of(path: &Path, file: &FileSpec) -> Option<PathBuf> {
+    let mut path = path.as_os_str().to_owned();
+    path.push(file.companion_suffix()?);
+    Some(path.into())
+}
+
+/// Path of `spec` relative to the incoming directory in a bucket named after
+/// its hash.
+fn bucket_path(spec: &FileSpec, config: &Config) -> (String, String) {
     let hash = spec.hash();
-    let bucket = hash[0..2].to_owned() + "/" + &hash[2..4];
+    let mut bucket = hash[0..2].to_owned() + "/" + &hash[2..4];
+    if config.namespace_by_client {
+        bucket = format!("{}/{bucket}", spec.client);
+    }
+    (bucket, blob_name(spec, config))
+}
+
+/// Reserve a path for the new file `spec` in the incoming directory following
+/// `storage_layout`, creating its directory. The path, relative to the
+/// incoming directory, is recorded in the database as the location of the file
+/// does not depend on the configuration or the files present afterwards.
+fn claim_storage_path(spec: &FileSpec, config: &Config) -> String {
+    if config.storage_layout == StorageLayout::ClientPaths
+        && let Some(rel_path) = claim_client_path(spec, config)
+    {
+        return rel_path;
+    }
+    let (bucket, name) = bucket_path(spec, config);
     match config.create_dir_sync(config.incoming_path(&bucket)) {
-        Ok(_) => bucket + "/" + hash,
+        Ok(_) => bucket + "/" + &name,
         Err(err) => {
             warn!("failed to create {bucket}: {err}");
-            hash.to_owned()
+            name
+        }
+    }
+}
+
+/// Free the path reserved by [`claim_storage_path`] for a file that was
+/// refused after all.
+fn release_storage_path(rel_path: &str, config: &Config) {
+    if config.storage_layout != StorageLayout::ClientPaths {
+        return;
+    }
+    let path = config.incoming_path(rel_path);
+    if path.metadata().is_ok_and(|stat| stat.len() == 0)
+        && let Err(err) = std::fs::remove_file(&path)
+    {
+        warn!("failed to release {path:?}: {err}");
+    }
+}
+
+/// Storage path of a file announced by an older version of pipeline, which
+/// did not record it. Files with the `client_paths` layout were stored with
+/// their name suffixed by their hash if that path exists.
+fn legacy_storage_path(spec: &FileSpec, config: &Config) -> String {
+    if config.storage_layout == StorageLayout::ClientPaths
+        && let Some((plain, suffixed)) = client_rel_paths(spec)
+    {
+        return if config.incoming_path(&suffixed).exists() {
+            suffixed
+        } else {
+            plain
+        };
+    }
+    let (bucket, name) = bucket_path(spec, config);
+    if config.incoming_path(&bucket).is_dir() {
+        bucket + "/" + &name
+    } else {
+        name
+    }
+}
+
+/// Paths of `spec` with the `client_paths` layout: `client/path/filename`,
+/// and `client/path/stem.hash.ext` used if the former is taken when the file
+/// is announced, see [`claim_client_path`]. This is `None` if the path of the
+/// file cannot be mirrored safely.
+fn client_rel_paths(spec: &FileSpec) -> Option<(String, String)> {
+    let segments: Vec<&str> = spec
+        .path
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .collect();
+    if !is_safe_segment(&spec.client)
+        || !is_safe_segment(&spec.filename)
+        || !segments.iter().all(|segment| is_safe_segment(segment))
+    {
+        debug!("cannot mirror path of {spec:?}, storing it in a bucket");
+        return None;
+    }
+    let dir = [spec.client.as_str()]
+        .into_iter()
+        .chain(segments)
+        .collect::<Vec<_>>()
+        .join("/");
+    let name = Path::new(&spec.filename);
+    let stem = name.file_stem().and_then(OsStr::to_str).unwrap_or_default();
+    let mut suffixed = format!("{dir}/{stem}.{}", &spec.hash()[..12]);
+    if let Some(ext) = name.extension().and_then(OsStr::to_str) {
+        suffixed = format!("{suffixed}.{ext}");
+    }
+    Some((format!("{dir}/{}", spec.filename), suffixed))
+}
+
+/// Reserve the path of a new file with the `client_paths` layout, falling
+/// back to its name suffixed by its hash if another file already uses it.
+/// This is `None` if the file is to be stored in a bucket instead.
+fn claim_client_path(spec: &FileSpec, config: &Config) -> Option<String> {
+    let (plain, suffixed) = client_rel_paths(spec)?;
+    if let Some((dir, _)) = plain.rsplit_once('/')
+        && let Err(err) = config.create_dir_sync(config.incoming_path(dir))
+    {
+        warn!("failed to create {dir}: {err}");
+        return None;
+    }
+    let claim = |rel_path: &str| {
+        std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(config.incoming_path(rel_path))
+    };
+    match claim(&plain) {
+        Ok(_) => Some(plain),
+        Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {
+            info!("{plain} is already taken, storing {spec:?} as {suffixed}");
+            if let Err(err) = std::fs::File::create(config.incoming_path(&suffixed)) {
+                warn!("failed to reserve {suffixed}: {err}");
+            }
+            Some(suffixed)
         }
+        Err(err) => {
+            warn!("failed to reserve {plain}: {err}");
+            Some(plain)
+        }
+    }
+}
+
+/// Path of `file` relative to the incoming directory, recorded in the
+/// database when it was claimed. This is `None` if it was not claimed.
+async fn storage_path(file: &FileSpec, db: &Database) -> Option<String> {
+    loop {
+        match db.storage_path(file.hash()).await {
+            Ok(path) => break path,
+            Err(err) => warn!("failed to read storage path of {file:?} in db: {err}"),
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+}
+
+/// Name of `spec` in the incoming directory, its hash followed by its
+/// extension if `preserve_extension` is set.
+fn blob_name(spec: &FileSpec, config: &Config) -> String {
+    let hash = spec.hash();
+    let extension = Path::new(&spec.filename)
+        .extension()
+        .and_then(OsStr::to_str)
+        .filter(|ext| ext.chars().all(|c| c.is_ascii_alphanumeric()));
+    match extension {
+        Some(ext) if config.preserve_extension => format!("{hash}.{ext}"),
+        _ => hash.to_owned(),
     }
 }
 
 async fn processing_pipeline<W: AsyncWriteExt + Unpin>(
     file: FileSpec,
-    channel: Arc<Mutex<WriteFramedJson<Receipt, W>>>,
+    channel: ReplyTo<W>,
     config: Arc<Config>,
     db: Database,
-    sem_hash: Arc<Semaphore>,
-    sem_proc: Arc<Semaphore>,
+    sems: Semaphores,
+    connected: Connected,
+    queued: OwnedSemaphorePermit,
 ) {
-    let server_path = config.path_of(&file);
+    let busy = sems.controls.busy();
 
     let in_db = loop {
         match db.contains(file.hash()).await {
@@ -144,7 +707,7 @@ async fn processing_pipeline<W: AsyncWriteExt + Unpin>(
         tokio::time::sleep(Duration::from_secs(1)).await;
     };
 
-    let await_first_arrival = if in_db {
+    let status = if in_db {
         let status = loop {
             match db.status(file.hash()).await {
                 Ok(status) => break status,
@@ -152,28 +715,117 @@ async fn processing_pipeline<W: AsyncWriteExt + Unpin>(
             }
             tokio::time::sleep(Duration::from_secs(1)).await;
         };
-        matches!(status, ProcessStatus::AwaitFromClient)
+        Some(status)
     } else {
-        false
+        None
     };
+    let await_first_arrival = status.is_some_and(ProcessStatus::awaits_arrival);
+
+    // Known files are stored where they were claimed when first announced.
+    let rel_path = if in_db {
+        storage_path(&file, &db).await.unwrap_or_default()
+    } else {
+        String::new()
+    };
+    let server_path = config.stored_path(&rel_path);
+
+    if await_first_arrival && config.stores_per_client() {
+        let (owner, path, filename) = loop {
+            match db.origin_of(file.hash()).await {
+                Ok(origin) => break origin,
+                Err(err) => warn!("failed to check client of {file:?} in db: {err}"),
+            }
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        };
+        let elsewhere = owner != file.client
+            || (config.storage_layout == StorageLayout::ClientPaths
+                && (path != file.path || filename != file.filename));
+        if elsewhere {
+            // The blob is stored in the namespace of the client that
+            // announced it first, the file is known once it arrives there.
+            debug!("{file:?} is awaited from {owner}, deferring");
+            send_receipt(
+                Receipt::AwaitedElsewhere(file.clone()),
+                &file,
+                &channel,
+                &db,
+            )
+            .await;
+            return;
+        }
+    }
+
+    let compare_full_hash =
+        in_db && suspect_shallow_collision(&file, await_first_arrival, &config, &db).await;
+    if compare_full_hash && await_first_arrival {
+        // Full hashes can only be compared once the other file arrived.
+        debug!("{file:?} may collide with a file awaited from elsewhere, deferring");
+        send_receipt(
+            Receipt::AwaitedElsewhere(file.clone()),
+            &file,
+            &channel,
+            &db,
+        )
+        .await;
+        return;
+    }
 
-    let receipt = if in_db && !await_first_arrival {
+    if in_db && !await_first_arrival && !compare_full_hash {
+        // Same content sent again, possibly by another client or from another
+        // path. Only its origin is recorded as the blob is addressed by hash.
+        debug!("{file:?} already received, recording its origin");
+        if let Err(err) = db.add_origin(&file).await {
+            warn!("failed to record origin of {file:?} in db: {err}");
+        }
+    }
+
+    let receipt = if compare_full_hash {
+        Receipt::RequestFullHash {
+            spec: file.clone(),
+            server_rel_path: rel_path,
+        }
+    } else if in_db && !await_first_arrival {
         Receipt::Received(file.clone())
+    } else if let Some(received_bytes) = incomplete_copy(&server_path, &file)
+        && in_db
+    {
+        warn!(
+            "{file:?} is incomplete, got {received_bytes} of {} bytes",
+            file.size_bytes
+        );
+        Receipt::Incomplete {
+            spec: file.clone(),
+            received_bytes,
+        }
     } else if in_db {
+        set_status(&db, &file, ProcessStatus::Verifying).await;
         let hash = {
-            let _permit = sem_hash.acquire().await.unwrap();
-            FileDigest::with_spec(&server_path, &file)
+            let _permit = sems.hash.acquire().await.unwrap();
+            let read = config.read_options().unmapped();
+            FileDigest::with_spec(&server_path, &file, config.hash_algorithm, read)
         };
         match hash {
             Ok(received_hash) => {
                 if file.sha256_digest == received_hash {
                     debug!("{file:?} found");
-                    Receipt::Received(file.clone())
+                    let small_sample = received_hash
+                        .sample_bytes()
+                        .is_some_and(|bytes| bytes < config.shallow_hash_bytes);
+                    if (config.paranoid && !received_hash.is_full()) || small_sample {
+                        Receipt::RequestFullHash {
+                            spec: file.clone(),
+                            server_rel_path: rel_path,
+                        }
+                    } else {
+                        Receipt::Received(file.clone())
+                    }
                 } else {
                     warn!(
                         "{file:?} does not have expected hash, got {}",
                         received_hash.hash()
                     );
+                    let actual = received_hash.hash();
+                    quarantine(&file, &server_path, file.hash(), actual, &config, &db).await;
                     Receipt::DifferentHash(file.clone())
                 }
             }
@@ -181,148 +833,1226 @@ async fn processing_pipeline<W: AsyncWriteExt + Unpin>(
                 warn!("{file:?} not found {err:?}");
                 Receipt::Error {
                     spec: file.clone(),
-                    server_rel_path: rel_path(&file, &config),
+                    server_rel_path: rel_path,
                     error: err.to_string(),
                 }
             }
         }
-    } else {
-        while let Err(err) = db.insert_new(&file).await {
-            warn!("failed to insert {file:?} in db: {err}");
-            tokio::time::sleep(Duration::from_secs(1)).await;
+    } else if !sems.controls.accepts_files() {
+        info!("not accepting new files, deferring {file:?}");
+        Receipt::SlowDown {
+            spec: file.clone(),
+            until_secs: config.concurrency.slow_down_secs,
+        }
+    } else if config.low_disk_space(&file) {
+        error!(
+            "less than {} bytes available in incoming directory, deferring {file:?}",
+            config.min_free_bytes
+        );
+        Receipt::LowDiskSpace(file.clone())
+    } else if let Some(rel_path) = insert_new(&file, &config, &db).await {
+        for tag in config.metadata_tags(&file) {
+            if let Err(err) = db.tag(file.hash(), &tag, &file.client).await {
+                warn!("failed to tag {file:?} with {tag} in db: {err}");
+            }
         }
         Receipt::Expecting {
             spec: file.clone(),
-            server_rel_path: rel_path(&file, &config),
+            server_rel_path: rel_path,
+        }
+    } else {
+        error!(
+            "client {} exceeded its quota, refusing {file:?} until files are pruned",
+            file.client
+        );
+        Receipt::QuotaExceeded {
+            spec: file.clone(),
+            until_secs: config.prune_every_secs,
         }
     };
 
-    let continue_processing = receipt.continue_processing();
-    channel.lock().await.send(receipt).await.unwrap();
+    let already_processed = matches!(
+        status,
+        Some(
+            ProcessStatus::Queued
+                | ProcessStatus::Processing
+                | ProcessStatus::Done
+                | ProcessStatus::ToPrune
+        )
+    );
+    if matches!(receipt, Receipt::Received(_)) {
+        record_arrival(&db, &file).await;
+    }
+    let continue_processing = receipt.continue_processing() && !already_processed;
+    // Update the status first as the client may act on the receipt at once.
+    if let Some(next_status) = status_after(&receipt, continue_processing) {
+        set_status(&db, &file, next_status).await;
+    }
+    let processed = matches!(receipt, Receipt::Received(_))
+        && matches!(status, Some(ProcessStatus::Done | ProcessStatus::ToPrune));
+    send_receipt(receipt, &file, &channel, &db).await;
+    if processed {
+        send_receipt(Receipt::Processed(file.clone()), &file, &channel, &db).await;
+    }
     if !continue_processing {
         return;
     }
 
-    let permit_proc = sem_proc.acquire().await.unwrap();
-    process_file(file, config, db).await;
-    drop(permit_proc);
+    // Queued files are recorded in the database, they no longer count as in
+    // flight while waiting for a processing slot.
+    drop(queued);
+    process_when_scheduled(file, config, db, connected, sems, busy).await;
 }
 
-async fn process_file(file: FileSpec, config: Arc<Config>, db: Database) {
-    let status = loop {
-        match db.status(file.hash()).await {
-            Ok(status) => break status,
-            Err(err) => warn!("failed to check status of {file:?} in db: {err}"),
-        }
-        tokio::time::sleep(Duration::from_secs(1)).await;
-    };
-    if matches!(status, ProcessStatus::Processing) {
-        debug!("{file:?} is already being processed");
+/// Move the copy of `file` at `from` that has hash `actual` instead of
+/// `expected` to the `quarantine_directory`, if set, next to a JSON file
+/// describing it. This keeps evidence of the corruption instead of
+/// overwriting it when the file is sent again.
+async fn quarantine(
+    file: &FileSpec,
+    from: &Path,
+    expected: &str,
+    actual: &str,
+    config: &Config,
+    db: &Database,
+) {
+    let Some(directory) = &config.quarantine_directory else {
         return;
+    };
+    let name = format!(
+        "{}-{}",
+        file.hash(),
+        chrono::DateTime::<chrono::Utc>::from(std::time::SystemTime::now())
+            .format("%Y%m%dT%H%M%S%3fZ")
+    );
+    let destination = directory.join(&name);
+    let report_path = directory.join(format!("{name}.json"));
+    let report = serde_json::json!({
+        "expected_hash": expected,
+        "actual_hash": actual,
+        "file": file,
+    });
+    let from = from.to_owned();
+    let moved = {
+        let destination = destination.clone();
+        tokio::task::spawn_blocking(move || {
+            gc::move_file(&from, &destination)?;
+            let report = serde_json::to_vec_pretty(&report).map_err(io::Error::other)?;
+            std::fs::write(report_path, report)
+        })
+        .await
+        .map_err(io::Error::from)
+        .and_then(|moved| moved)
+    };
+    match moved {
+        Ok(()) => {
+            warn!("moved copy of {file:?} to {destination:?}");
+            let event = format!("copy with hash {actual} quarantined as {name}");
+            if let Err(err) = db.audit(file.hash(), SERVER_ACTOR, &event).await {
+                warn!("failed to record quarantine of {file:?} in db: {err}");
+            }
+        }
+        Err(err) => warn!("failed to quarantine copy of {file:?}: {err}"),
     }
+}
 
-    info!("starting processing for {file:?}");
-
-    while let Err(err) = db
-        .update_status(file.hash(), ProcessStatus::Processing)
-        .await
-    {
-        warn!("failed to update status of {file:?} in db: {err}");
-        tokio::time::sleep(Duration::from_secs(1)).await;
+/// Whether the full hash of `file` should be compared with the one of the
+/// file received with the same shallow hash from another origin, following
+/// `on_shallow_collision`, `awaited` if that file did not arrive yet. Suspected
+/// collisions are otherwise only logged.
+async fn suspect_shallow_collision(
+    file: &FileSpec,
+    awaited: bool,
+    config: &Config,
+    db: &Database,
+) -> bool {
+    if file.sha256_digest.is_full() || config.on_shallow_collision == ShallowCollisions::Trust {
+        return false;
+    }
+    let known_origin = db.has_origin(file).await.unwrap_or_else(|err| {
+        warn!("failed to check origins of {file:?} in db: {err}");
+        true
+    });
+    if known_origin {
+        return false;
     }
+    if config.on_shallow_collision == ShallowCollisions::FullHash {
+        if awaited {
+            return true;
+        }
+        let stored = storage_path(file, db).await;
+        if stored.is_some_and(|rel_path| config.stored_path(&rel_path).exists()) {
+            return true;
+        }
+        warn!("cannot compare full hash of {file:?}, the received file was already pruned");
+    }
+    warn!(
+        "{file:?} has the same shallow hash as a file received from elsewhere, assuming they are identical"
+    );
+    let event = format!(
+        "suspected shallow hash collision with {}/{}",
+        file.path, file.filename
+    );
+    if let Err(err) = db.audit(file.hash(), &file.client, &event).await {
+        warn!("failed to record collision of {file:?} in db: {err}");
+    }
+    false
+}
 
-    let Some(proc_group) = config.processing.get(&file.processing) else {
-        // When establishing a connection with client, the handshake verifies that all processing
-        // groups in the client config are known by the server so this is unreachable.
-        error!(
-            "unknown group name {}. This should have been caught during the handshake, please raise a bug report",
-            file.processing,
-        );
-        return;
-    };
+/// Where to send the receipts answering a request of a processing client.
+struct ReplyTo<W> {
+    channel: Arc<Mutex<WriteFramedJson<ServerReply, W>>>,
+    /// Id of the answered [`ClientRequest`], if any.
+    request: Option<u64>,
+    /// Full hashes requested over the same connection.
+    full_hashes: FullHashRequests,
+}
 
-    let status = match proc_group.processing.run(&file, &config).await {
-        Ok(()) => {
-            info!("processing of {file:?} completed successfully");
-            proc_group.after_processing.run(&file, &config, &db).await
+impl<W: AsyncWriteExt + Unpin> ReplyTo<W> {
+    async fn send(&self, receipt: Receipt) -> io::Result<()> {
+        if let Receipt::RequestFullHash { spec, .. } = &receipt {
+            self.full_hashes.request(spec);
         }
-        Err(err) => {
-            warn!("processing of {file:?} failed: '{err}'");
-            Some(ProcessStatus::Failed)
+        let reply = ServerReply {
+            in_reply_to: self.request,
+            receipt,
+        };
+        self.channel.lock().await.send(reply).await
+    }
+}
+
+/// Receipts pushed to connected processing clients, by client name.
+///
+/// This tells clients about the outcome of processing, which happens long
+/// after their request was answered.
+#[derive(Clone, Default)]
+struct Connected(Arc<std_sync::Mutex<HashMap<String, mpsc::UnboundedSender<Receipt>>>>);
+
+impl Connected {
+    /// Forward receipts pushed to `client` through `channel`, as long as the
+    /// returned [`Registration`] is alive.
+    fn register<W>(&self, client: &str, channel: ReplyTo<W>) -> Registration
+    where
+        W: AsyncWriteExt + Unpin + Send + 'static,
+    {
+        let (sender, mut pushed) = mpsc::unbounded_channel::<Receipt>();
+        let forward = tokio::spawn(async move {
+            while let Some(receipt) = pushed.recv().await {
+                if let Err(err) = channel.send(receipt).await {
+                    warn!("failed to push receipt to client: {err}");
+                    break;
+                }
+            }
+        });
+        self.0
+            .lock()
+            .unwrap()
+            .insert(client.to_owned(), sender.clone());
+        Registration {
+            connected: self.clone(),
+            client: client.to_owned(),
+            sender,
+            forward: forward.abort_handle(),
         }
-    };
+    }
 
-    if let Some(status) = status {
-        debug!("marking {file:?} as {status:?}");
-        while let Err(err) = db.update_status(file.hash(), status).await {
-            warn!("failed to update status of {file:?} in db: {err}");
-            tokio::time::sleep(Duration::from_secs(1)).await;
+    /// Push `receipt` about `file` to its client, if it is connected.
+    async fn push(&self, file: &FileSpec, receipt: Receipt, db: &Database) {
+        let event = format!("pushed {} receipt to {}", receipt.name(), file.client);
+        let sent = match self.0.lock().unwrap().get(&file.client) {
+            Some(sender) => sender.send(receipt).is_ok(),
+            None => false,
+        };
+        if !sent {
+            debug!(
+                "{} is not connected, not telling it about {file:?}",
+                file.client
+            );
+            return;
+        }
+        if let Err(err) = db.audit(file.hash(), SERVER_ACTOR, &event).await {
+            warn!("failed to record receipt for {file:?} in audit log: {err}");
         }
     }
 }
 
-async fn listen_to_processing_client<R, W, S>(
-    stream: S,
-    addr: SocketAddr,
-    config: Arc<Config>,
-    db: Database,
-    sem_hash: Arc<Semaphore>,
-    sem_proc: Arc<Semaphore>,
-) -> io::Result<()>
-where
-    S: Splittable<R, W>,
-    R: AsyncReadExt + Unpin,
-    W: AsyncWriteExt + Unpin + Send + 'static,
-{
-    let (mut from_client, to_client) = json_channel::<FileSpec, Receipt, _, _, _>(stream);
-    let to_client = Arc::new(Mutex::new(to_client));
+/// Files for which a [`Receipt::RequestFullHash`] was sent to a client over
+/// its connection, and not answered yet.
+#[derive(Clone, Default)]
+struct FullHashRequests(Arc<std_sync::Mutex<HashSet<(String, String, String)>>>);
 
-    while let Some(msg) = from_client.try_next().await? {
-        debug!("received request from {addr:?}: {msg:?}");
-        tokio::spawn(processing_pipeline(
-            msg,
-            to_client.clone(),
-            config.clone(),
-            db.clone(),
-            sem_hash.clone(),
-            sem_proc.clone(),
-        ));
+impl FullHashRequests {
+    fn key(file: &FileSpec) -> (String, String, String) {
+        (
+            file.hash().to_owned(),
+            file.path.clone(),
+            file.filename.clone(),
+        )
     }
 
-    info!("client {addr:?} closed connection");
-    Ok(())
+    fn request(&self, file: &FileSpec) {
+        self.0.lock().unwrap().insert(Self::key(file));
+    }
+
+    /// Whether the full hash of `file` was requested, it is then not pending
+    /// anymore.
+    fn answer(&self, file: &FileSpec) -> bool {
+        self.0.lock().unwrap().remove(&Self::key(file))
+    }
 }
 
-async fn handle_client(
-    mut stream: TcpStream,
-    addr: SocketAddr,
-    config: Arc<Config>,
-    db: Database,
-    sem_hash: Arc<Semaphore>,
-    sem_proc: Arc<Semaphore>,
-) -> io::Result<()> {
-    debug!("got connection request from {addr:?}");
+/// Connection of a client in [`Connected`], removed when dropped.
+struct Registration {
+    connected: Connected,
+    client: String,
+    sender: mpsc::UnboundedSender<Receipt>,
+    forward: tokio::task::AbortHandle,
+}
 
-    match handshake::server_side(&mut stream, &config).await {
-        Ok(HandshakeOutcome::Success(ClientKind::Processing)) => {
-            info!("handshake with processing client {addr:?} was successful");
-            listen_to_processing_client(stream, addr, config, db, sem_hash, sem_proc).await
-        }
-        Ok(HandshakeOutcome::Success(ClientKind::Mark { hash, status })) => {
-            info!("received mark request from {addr:?}");
-            query::process_mark_query(db, hash, status).await
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.forward.abort();
+        let mut clients = self.connected.0.lock().unwrap();
+        // The client may already have reconnected.
+        if clients
+            .get(&self.client)
+            .is_some_and(|sender| sender.same_channel(&self.sender))
+        {
+            clients.remove(&self.client);
         }
-        Ok(HandshakeOutcome::Success(ClientKind::List)) => {
-            info!("received list request from {addr:?}");
-            query::process_list_query(stream, db).await
-        }
-        Ok(HandshakeOutcome::Success(ClientKind::PruneDone)) => {
+    }
+}
+
+async fn send_receipt<W: AsyncWriteExt + Unpin>(
+    receipt: Receipt,
+    file: &FileSpec,
+    channel: &ReplyTo<W>,
+    db: &Database,
+) {
+    let event = format!("sent {} receipt to {}", receipt.name(), file.client);
+    if let Err(err) = db.audit(file.hash(), SERVER_ACTOR, &event).await {
+        warn!("failed to record receipt for {file:?} in audit log: {err}");
+    }
+    // The client may have disconnected, it announces pending files again
+    // when reconnecting.
+    if let Err(err) = channel.send(receipt).await {
+        warn!(
+            "failed to send receipt for {file:?} to {}: {err}",
+            file.client
+        );
+    }
+}
+
+/// Ask `client_name` again for the files it announced during a previous
+/// connection but never delivered, returning their hashes.
+async fn resume_pending<W: AsyncWriteExt + Unpin>(
+    client_name: &str,
+    channel: &ReplyTo<W>,
+    config: &Config,
+    db: &Database,
+) -> HashSet<String> {
+    let filter = PruneFilter {
+        client: Some(client_name.to_owned()),
+        older_than: None,
+        include_pinned: true,
+        tag: None,
+    };
+    let statuses = [ProcessStatus::AwaitFromClient, ProcessStatus::Receiving];
+    let pending = match db.oldest_tasks(&statuses, &filter).await {
+        Ok(pending) => pending,
+        Err(err) => {
+            warn!("failed to read database for files pending from {client_name}: {err}");
+            return HashSet::new();
+        }
+    };
+    if !pending.is_empty() {
+        info!(
+            "resuming {} files pending from {client_name}",
+            pending.len()
+        );
+    }
+    let mut resumed = HashSet::with_capacity(pending.len());
+    for (status, file) in pending
+        .into_iter()
+        .map(|row| (row.status, FileSpec::from(row)))
+    {
+        let Some(rel_path) = storage_path(&file, db).await else {
+            warn!("no storage path recorded for {file:?}, not resuming it");
+            continue;
+        };
+        if status == ProcessStatus::Receiving
+            && !is_stale_copy(&config.stored_path(&rel_path), &file, config)
+        {
+            // The client announces it again once its copy is over.
+            debug!("copy of {file:?} may still be in progress, not resuming it");
+            continue;
+        }
+        set_status(db, &file, ProcessStatus::Receiving).await;
+        let receipt = Receipt::Expecting {
+            spec: file.clone(),
+            server_rel_path: rel_path,
+        };
+        send_receipt(receipt, &file, channel, db).await;
+        resumed.insert(file.hash().to_owned());
+    }
+    resumed
+}
+
+/// Whether the copy of `file` at `path` stopped progressing: it is missing, or
+/// incomplete and was not written to for `client_timeout_secs`.
+fn is_stale_copy(path: &Path, file: &FileSpec, config: &Config) -> bool {
+    let Ok(meta) = path.metadata() else {
+        return true;
+    };
+    let idle = meta
+        .modified()
+        .ok()
+        .and_then(|modified| modified.elapsed().ok())
+        .is_some_and(|idle| idle.as_secs() >= config.client_timeout_secs);
+    meta.len() < file.size_bytes && idle
+}
+
+/// Size of the copy of `file` at `path` if it is smaller than expected.
+fn incomplete_copy(path: &Path, file: &FileSpec) -> Option<u64> {
+    let received_bytes = path.metadata().ok()?.len();
+    (received_bytes < file.size_bytes).then_some(received_bytes)
+}
+
+/// Status of a file once `receipt` is sent to its client, if it changes.
+fn status_after(receipt: &Receipt, continue_processing: bool) -> Option<ProcessStatus> {
+    match receipt {
+        Receipt::Expecting { .. } | Receipt::Error { .. } => Some(ProcessStatus::Receiving),
+        Receipt::DifferentHash(_) => Some(ProcessStatus::AwaitFromClient),
+        Receipt::Received(_) if continue_processing => Some(ProcessStatus::Queued),
+        _ => None,
+    }
+}
+
+/// Claim a storage path for the new `file` and insert it in the database,
+/// returning that path unless its client would exceed its quota.
+async fn insert_new(file: &FileSpec, config: &Config, db: &Database) -> Option<String> {
+    let quota = config.quota_bytes.get(&file.client).copied();
+    let rel_path = claim_storage_path(file, config);
+    let inserted = loop {
+        match db.insert_new(file, &rel_path, quota).await {
+            Ok(inserted) => break inserted,
+            Err(err) => warn!("failed to insert {file:?} in db: {err}"),
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    };
+    if inserted {
+        Some(rel_path)
+    } else {
+        release_storage_path(&rel_path, config);
+        None
+    }
+}
+
+/// Record that `file` arrived from its client and was verified.
+async fn record_arrival(db: &Database, file: &FileSpec) {
+    if let Err(err) = db.record_arrival(file).await {
+        warn!("failed to record arrival of {file:?} in db: {err}");
+    }
+}
+
+async fn set_status(db: &Database, file: &FileSpec, status: ProcessStatus) {
+    while let Err(err) = db.update_status(file.hash(), status, SERVER_ACTOR).await {
+        warn!("failed to update status of {file:?} in db: {err}");
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+}
+
+/// Tell the client which of `files` are already known, in a single receipt.
+async fn reconcile<W: AsyncWriteExt + Unpin>(
+    files: Vec<FileSpec>,
+    channel: ReplyTo<W>,
+    config: Arc<Config>,
+    db: Database,
+    resumed: Arc<HashSet<String>>,
+) {
+    let hashes: Vec<&str> = files.iter().map(FileSpec::hash).collect();
+    let statuses = loop {
+        match db.statuses(&hashes).await {
+            Ok(statuses) => break statuses,
+            Err(err) => warn!(
+                "failed to check status of {} files in db: {err}",
+                files.len()
+            ),
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    };
+    debug!(
+        "reconciling {} files, {} already known",
+        files.len(),
+        statuses.len()
+    );
+
+    let mut reconciled = Vec::with_capacity(files.len());
+    for file in files {
+        let state = match statuses.get(file.hash()) {
+            None => Reconciliation::Unknown,
+            Some(_) if resumed.contains(file.hash()) => Reconciliation::Resumed,
+            Some(status) if status.awaits_arrival() => Reconciliation::Pending,
+            // Announced again to compare full hashes, see `processing_pipeline`.
+            Some(_) if suspect_shallow_collision(&file, false, &config, &db).await => {
+                Reconciliation::Pending
+            }
+            Some(status) => {
+                if let Err(err) = db.add_origin(&file).await {
+                    warn!("failed to record origin of {file:?} in db: {err}");
+                }
+                if matches!(status, ProcessStatus::Done | ProcessStatus::ToPrune) {
+                    Reconciliation::Processed
+                } else {
+                    Reconciliation::Received
+                }
+            }
+        };
+        reconciled.push(state);
+    }
+    let sent = channel.send(Receipt::Reconciled(reconciled)).await;
+    if let Err(err) = sent {
+        warn!("failed to send reconciliation to client: {err}");
+    }
+}
+
+/// Compare the full hash computed by the client, after a
+/// [`Receipt::RequestFullHash`], with the one of the file on the server. A
+/// client that cannot compute the hash, e.g. as its file is gone, gets an
+/// error in return.
+///
+/// If the file was already received, this checks a suspected shallow hash
+/// collision: `file` is only another origin of the received file if the full
+/// hashes match, and should be announced again with its full hash otherwise.
+async fn confirm_full_hash<W: AsyncWriteExt + Unpin>(
+    file: FileSpec,
+    client_hash: Option<String>,
+    channel: ReplyTo<W>,
+    config: Arc<Config>,
+    db: Database,
+    sems: Semaphores,
+    connected: Connected,
+) {
+    let busy = sems.controls.busy();
+    let status = loop {
+        match db.status(file.hash()).await {
+            Ok(status) => break status,
+            Err(err) => warn!("failed to check status of {file:?} in db: {err}"),
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    };
+    let collision_check = status != ProcessStatus::Verifying;
+    let rel_path = storage_path(&file, &db).await.unwrap_or_default();
+    let server_path = config.stored_path(&rel_path);
+
+    let receipt = match client_hash {
+        Some(client_hash) => {
+            let hash = {
+                let _permit = sems.hash.acquire().await.unwrap();
+                // Received files are only encrypted once verified.
+                encryption::plaintext(&config, &db, file.hash(), server_path.clone())
+                    .await
+                    .and_then(|plain| {
+                        let read = config.read_options().unmapped();
+                        FileDigest::new(plain.path(), HashMode::Full, config.hash_algorithm, read)
+                    })
+            };
+            match hash {
+                Ok(hash) if hash.hash() == client_hash => {
+                    debug!("full hash of {file:?} confirmed");
+                    Receipt::Received(file.clone())
+                }
+                Ok(_) if collision_check => {
+                    error!("{file:?} collides with the file received with the same shallow hash");
+                    let event = format!(
+                        "shallow hash collision with {}/{}",
+                        file.path, file.filename
+                    );
+                    if let Err(err) = db.audit(file.hash(), &file.client, &event).await {
+                        warn!("failed to record collision of {file:?} in db: {err}");
+                    }
+                    Receipt::ShallowCollision(file.clone())
+                }
+                Ok(hash) => {
+                    warn!(
+                        "{file:?} does not have expected full hash {client_hash}, got {}",
+                        hash.hash()
+                    );
+                    let actual = hash.hash();
+                    quarantine(&file, &server_path, &client_hash, actual, &config, &db).await;
+                    Receipt::DifferentHash(file.clone())
+                }
+                Err(err) => {
+                    warn!("{file:?} not found {err:?}");
+                    Receipt::Error {
+                        spec: file.clone(),
+                        server_rel_path: rel_path,
+                        error: err.to_string(),
+                    }
+                }
+            }
+        }
+        None => {
+            warn!("client cannot compute full hash of {file:?}");
+            Receipt::Error {
+                spec: file.clone(),
+                server_rel_path: rel_path,
+                error: "full hash was requested but not computed".to_owned(),
+            }
+        }
+    };
+
+    let received = matches!(receipt, Receipt::Received(_));
+    if received {
+        record_arrival(&db, &file).await;
+    }
+    if collision_check {
+        if received && let Err(err) = db.add_origin(&file).await {
+            warn!("failed to record origin of {file:?} in db: {err}");
+        }
+        let processed = received && matches!(status, ProcessStatus::Done | ProcessStatus::ToPrune);
+        send_receipt(receipt, &file, &channel, &db).await;
+        if processed {
+            send_receipt(Receipt::Processed(file.clone()), &file, &channel, &db).await;
+        }
+        return;
+    }
+
+    let continue_processing = receipt.continue_processing();
+    // Update the status first as the client may act on the receipt at once.
+    if let Some(next_status) = status_after(&receipt, continue_processing) {
+        set_status(&db, &file, next_status).await;
+    }
+    send_receipt(receipt, &file, &channel, &db).await;
+    if !continue_processing {
+        return;
+    }
+
+    process_when_scheduled(file, config, db, connected, sems, busy).await;
+}
+
+/// Process `file` once a processing slot is available for it. Outside of
+/// processing hours, the file is left in the database to be picked up when
+/// they start, see [`resume_when_open`]. The future is boxed as processing may
+/// in turn schedule files of chained groups.
+fn process_when_scheduled(
+    file: FileSpec,
+    config: Arc<Config>,
+    db: Database,
+    connected: Connected,
+    sems: Semaphores,
+    busy: Busy,
+) -> BoxFuture<'static, ()> {
+    Box::pin(async move {
+        // Encrypted as soon as accepted rather than once processed.
+        if let Some(rel_path) = storage_path(&file, &db).await {
+            let path = config.stored_path(&rel_path);
+            encryption::encrypt_at_rest(&config, &db, file.hash(), path).await;
+        }
+        if !hours::is_open(&config.processing_hours) {
+            debug!("outside of processing hours, {file:?} stays queued");
+            return;
+        }
+        let priority = config.priority(&file, &db).await;
+        debug!("{file:?} waits for a processing slot with priority {priority}");
+        let slot = sems.proc.acquire(priority, &file.client).await;
+        process_file(file, config, db, connected, sems, busy, slot).await;
+    })
+}
+
+/// Process `file` holding the processing `slot`, which stays counted as in
+/// progress by `busy` until done.
+async fn process_file(
+    file: FileSpec,
+    config: Arc<Config>,
+    db: Database,
+    connected: Connected,
+    sems: Semaphores,
+    busy: Busy,
+    slot: Slot,
+) {
+    let Some(rel_path) = storage_path(&file, &db).await else {
+        error!("no storage path recorded for {file:?}, cannot process it");
+        return;
+    };
+    let server_path = config.stored_path(&rel_path);
+    // Files resumed at startup may not have been encrypted yet.
+    encryption::encrypt_at_rest(&config, &db, file.hash(), server_path.clone()).await;
+    busy.controls().processing_allowed().await;
+    // Processing hours may have ended while waiting for a slot.
+    if !hours::is_open(&config.processing_hours) {
+        debug!("outside of processing hours, {file:?} stays queued");
+        return;
+    }
+    let status = loop {
+        match db.status(file.hash()).await {
+            Ok(status) => break status,
+            Err(err) => warn!("failed to check status of {file:?} in db: {err}"),
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    };
+    // Files picked up from the database may also be scheduled already.
+    if !matches!(status, ProcessStatus::Queued | ProcessStatus::Failed) {
+        debug!("{file:?} is already being processed or was processed");
+        return;
+    }
+
+    info!("starting processing for {file:?}");
+    set_status(&db, &file, ProcessStatus::Processing).await;
+
+    let Some(proc_group) = config.processing.get(&file.processing) else {
+        // When establishing a connection with client, the handshake verifies that all processing
+        // groups in the client config are known by the server so this is unreachable.
+        error!(
+            "unknown group name {}. This should have been caught during the handshake, please raise a bug report",
+            file.processing,
+        );
+        return;
+    };
+
+    let attempt = match db.start_attempt(file.hash()).await {
+        Ok(id) => Some(id),
+        Err(err) => {
+            warn!("failed to record processing attempt of {file:?}: {err}");
+            None
+        }
+    };
+    let mut step_secs = Vec::new();
+    let mut resources = Resources::new(&sems.pools, Some(slot));
+    let result = proc_group
+        .processing
+        .run(
+            &file,
+            &server_path,
+            &config,
+            &db,
+            &mut resources,
+            &mut step_secs,
+        )
+        .await;
+    if let Some(id) = attempt
+        && let Err(err) = db.end_attempt(id, result.as_ref().err(), &step_secs).await
+    {
+        warn!("failed to record end of processing attempt of {file:?}: {err}");
+    }
+    let mut error = result.as_ref().err().map(ToString::to_string);
+
+    let status = match result {
+        Ok(Some(group)) if config.processing.contains_key(&group) => {
+            info!("{file:?} routed to processing group {group}");
+            let routed = FileSpec {
+                processing: group,
+                ..file.clone()
+            };
+            match db
+                .route(routed.hash(), &routed.processing, SERVER_ACTOR)
+                .await
+            {
+                Ok(()) => {
+                    set_status(&db, &routed, ProcessStatus::Queued).await;
+                    tokio::spawn(process_when_scheduled(
+                        routed,
+                        config.clone(),
+                        db.clone(),
+                        connected.clone(),
+                        sems.clone(),
+                        sems.controls.busy(),
+                    ));
+                    return;
+                }
+                Err(err) => {
+                    warn!("failed to route {file:?} in db: {err}");
+                    error = Some(format!("failed to route to group {}", routed.processing));
+                    Some(ProcessStatus::Failed)
+                }
+            }
+        }
+        Ok(Some(group)) => {
+            warn!("{file:?} routed to unknown processing group {group}");
+            error = Some(format!("routed to unknown group {group}"));
+            Some(ProcessStatus::Failed)
+        }
+        Ok(None) => {
+            info!("processing of {file:?} completed successfully");
+            if let Some(batch) = &proc_group.batch {
+                let name = batch.name_of(&file, &server_path);
+                add_to_batch(&file, batch, name, config.clone(), db.clone(), sems.clone()).await;
+            }
+            for chained in feed_chained_groups(&file, &server_path, &config, &db).await {
+                tokio::spawn(process_when_scheduled(
+                    chained,
+                    config.clone(),
+                    db.clone(),
+                    connected.clone(),
+                    sems.clone(),
+                    sems.controls.busy(),
+                ));
+            }
+            proc_group
+                .after_processing
+                .run(&file, &server_path, &config, &db)
+                .await
+        }
+        Err(err) => {
+            warn!("processing of {file:?} failed: '{err}'");
+            Some(ProcessStatus::Failed)
+        }
+    };
+    let status = match status {
+        Some(ProcessStatus::Failed) if config.max_attempts > 0 => {
+            match db.attempt_count(file.hash()).await {
+                Ok(n) if n >= config.max_attempts => {
+                    error!("{file:?} failed {n} times, abandoning it");
+                    Some(ProcessStatus::Abandoned)
+                }
+                Ok(_) => status,
+                Err(err) => {
+                    warn!("failed to read attempt count of {file:?} in db: {err}");
+                    status
+                }
+            }
+        }
+        _ => status,
+    };
+
+    if let Some(status) = status {
+        debug!("marking {file:?} as {status:?}");
+        while let Err(err) = db.update_status(file.hash(), status, SERVER_ACTOR).await {
+            warn!("failed to update status of {file:?} in db: {err}");
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+    }
+    let receipt = match status {
+        Some(ProcessStatus::Failed | ProcessStatus::Abandoned) => Receipt::ProcessingFailed {
+            spec: file.clone(),
+            error: error.unwrap_or_else(|| "failed to move processed file".to_owned()),
+            abandoned: status == Some(ProcessStatus::Abandoned),
+        },
+        _ => Receipt::Processed(file.clone()),
+    };
+    // Chained files were not sent by their client, which has nothing to do
+    // with their receipts.
+    if !file.metadata.contains_key(CHAINED_FROM_KEY) {
+        connected.push(&file, receipt, &db).await;
+    }
+}
+
+/// Metadata key set to the hash of the upstream file on chained files.
+const CHAINED_FROM_KEY: &str = "chained_from";
+
+/// Queue the outputs of the processing of `file` in the groups chained to its
+/// own, returning the newly queued files.
+async fn feed_chained_groups(
+    file: &FileSpec,
+    server_path: &Path,
+    config: &Config,
+    db: &Database,
+) -> Vec<FileSpec> {
+    let mut queued = Vec::new();
+    for (group, proc_group) in &config.processing {
+        let Some(chained_from) = &proc_group.chained_from else {
+            continue;
+        };
+        if chained_from.group != file.processing {
+            continue;
+        }
+        for path in chained_from.output_paths(file, server_path) {
+            match chain_output(file, group, &path, config, db).await {
+                Ok(Some(spec)) => queued.push(spec),
+                Ok(None) => {}
+                Err(err) => warn!("failed to feed {path:?} to group {group}: {err}"),
+            }
+        }
+    }
+    queued
+}
+
+/// Add the output `path` of the processing of `file` to the pipeline as a file
+/// of `group`, from the same client and directory. Outputs already in the
+/// pipeline are skipped.
+async fn chain_output(
+    file: &FileSpec,
+    group: &str,
+    path: &Path,
+    config: &Config,
+    db: &Database,
+) -> io::Result<Option<FileSpec>> {
+    let stat = path.metadata()?;
+    let algorithm = config.hash_algorithm;
+    let read = config.read_options();
+    let owned_path = path.to_owned();
+    let digest = tokio::task::spawn_blocking(move || {
+        FileDigest::new(&owned_path, HashMode::Full, algorithm, read)
+    })
+    .await??;
+    let mut metadata = file.metadata.clone();
+    metadata.insert(CHAINED_FROM_KEY.to_owned(), file.hash().to_owned());
+    let spec = FileSpec {
+        client: file.client.clone(),
+        path: file.path.clone(),
+        filename: crate::encode_name(path.file_name().unwrap_or_default()),
+        processing: group.to_owned(),
+        sha256_digest: digest,
+        size_bytes: stat.len(),
+        modified_utc: format_utc(stat.modified()?),
+        metadata,
+        companion: None,
+    };
+    if db.contains(spec.hash()).await.map_err(io::Error::other)? {
+        debug!("{spec:?} is already in the pipeline");
+        return Ok(None);
+    }
+    let rel_path = claim_storage_path(&spec, config);
+    if let Err(err) = db.insert_new(&spec, &rel_path, None).await {
+        release_storage_path(&rel_path, config);
+        return Err(io::Error::other(err));
+    }
+    for tag in config.metadata_tags(&spec) {
+        if let Err(err) = db.tag(spec.hash(), &tag, &spec.client).await {
+            warn!("failed to tag {spec:?} with {tag} in db: {err}");
+        }
+    }
+    let dest = config.stored_path(&rel_path);
+    let linked = async {
+        if let Some(parent) = dest.parent() {
+            config.create_dir_async(parent).await?;
+        }
+        // Replaces the path reserved above, if any.
+        let _ = tokio::fs::remove_file(&dest).await;
+        if tokio::fs::hard_link(path, &dest).await.is_err() {
+            tokio::fs::copy(path, &dest).await?;
+        }
+        io::Result::Ok(())
+    };
+    if let Err(err) = linked.await {
+        if let Err(err) = db.remove(spec.hash()).await {
+            warn!("failed to remove {spec:?} from db: {err}");
+        }
+        return Err(err);
+    }
+    db.update_status(spec.hash(), ProcessStatus::Queued, SERVER_ACTOR)
+        .await
+        .map_err(io::Error::other)?;
+    info!("fed output {path:?} of {file:?} to group {group}");
+    Ok(Some(spec))
+}
+
+/// Record `file` as member of its batch, processing the batch once complete.
+async fn add_to_batch(
+    file: &FileSpec,
+    batch: &processing::Batch,
+    name: String,
+    config: Arc<Config>,
+    db: Database,
+    sems: Semaphores,
+) {
+    let complete = loop {
+        match db
+            .add_to_batch(&file.processing, &name, file.hash(), batch.size)
+            .await
+        {
+            Ok(complete) => break complete,
+            Err(err) => warn!("failed to add {file:?} to batch {name:?} in db: {err}"),
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    };
+    if complete {
+        let busy = sems.controls.busy();
+        tokio::spawn(process_batch(
+            file.processing.clone(),
+            name,
+            config,
+            db,
+            sems,
+            busy,
+        ));
+    }
+}
+
+/// Process the batch `name` of `group`, already marked as `Processing`, once
+/// a processing slot is available for it.
+async fn process_batch(
+    group: String,
+    name: String,
+    config: Arc<Config>,
+    db: Database,
+    sems: Semaphores,
+    busy: Busy,
+) {
+    let Some(batch) = config.processing.get(&group).and_then(|g| g.batch.as_ref()) else {
+        warn!("batch {name:?} belongs to unknown processing group {group}");
+        return;
+    };
+    let _permit_proc = sems.proc.acquire(0, SERVER_ACTOR).await;
+    // Processing may be paused while waiting for processing hours.
+    loop {
+        busy.controls().processing_allowed().await;
+        if hours::is_open(&config.processing_hours) {
+            break;
+        }
+        hours::wait_until_open(&config.processing_hours).await;
+    }
+    info!("starting processing of batch {name:?} of group {group}");
+    let status = match run_batch(batch, &group, &name, &config, &db).await {
+        Ok(()) => {
+            info!("processing of batch {name:?} completed successfully");
+            ProcessStatus::Done
+        }
+        Err(err) => {
+            warn!("processing of batch {name:?} failed: '{err}'");
+            ProcessStatus::Failed
+        }
+    };
+    while let Err(err) = db.update_batch_status(&group, &name, status).await {
+        warn!("failed to update status of batch {name:?} in db: {err}");
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+}
+
+async fn run_batch(
+    batch: &processing::Batch,
+    group: &str,
+    name: &str,
+    config: &Config,
+    db: &Database,
+) -> io::Result<()> {
+    let members = db
+        .batch_members(group, name)
+        .await
+        .map_err(io::Error::other)?;
+    let mut plaintexts = Vec::with_capacity(members.len());
+    for member in members {
+        let path = config.stored_path(&member.storage_path);
+        plaintexts.push(encryption::plaintext(config, db, &member.hash, path).await?);
+    }
+    let paths: Vec<_> = plaintexts.iter().map(|p| p.path().to_owned()).collect();
+    batch.run(name, &paths).await
+}
+
+async fn listen_to_processing_client<R, W, S>(
+    stream: S,
+    addr: SocketAddr,
+    config: Arc<Config>,
+    db: Database,
+    client_name: String,
+    sems: Semaphores,
+    connected: Connected,
+) -> io::Result<()>
+where
+    S: Splittable<R, W>,
+    R: AsyncReadExt + Unpin,
+    W: AsyncWriteExt + Unpin + Send + 'static,
+{
+    let (mut from_client, to_client) =
+        json_channel::<ClientRequest, ServerReply, _, _, _>(stream, config.max_frame_length);
+    let to_client = Arc::new(Mutex::new(to_client));
+    let timeout = Duration::from_secs(config.client_timeout_secs);
+    let full_hashes = FullHashRequests::default();
+    let unsolicited = ReplyTo {
+        channel: to_client.clone(),
+        request: None,
+        full_hashes: full_hashes.clone(),
+    };
+    let _registration = connected.register(
+        &client_name,
+        ReplyTo {
+            channel: to_client.clone(),
+            request: None,
+            full_hashes: full_hashes.clone(),
+        },
+    );
+    let resumed = Arc::new(resume_pending(&client_name, &unsolicited, &config, &db).await);
+    // Requests are numbered in increasing order, anything else is a duplicate.
+    // Requests about files are checked against the database as duplicates may
+    // be delivered again over a later connection.
+    let mut last_id = None;
+
+    loop {
+        let Ok(msg) = tokio::time::timeout(timeout, from_client.try_next()).await else {
+            warn!("no message from {addr:?} in {timeout:?}, closing connection");
+            return Ok(());
+        };
+        let msg = match msg {
+            Ok(Some(msg)) => msg,
+            Ok(None) => break,
+            Err(err) if is_frame_too_long(&err) => {
+                warn!("message from {addr:?} exceeds `max_frame_length`, closing connection");
+                let error = format!(
+                    "message exceeds max_frame_length of {} bytes",
+                    config.max_frame_length
+                );
+                unsolicited.send(Receipt::ProtocolError(error)).await?;
+                return Ok(());
+            }
+            Err(err) => return Err(err),
+        };
+        debug!("received request from {addr:?}: {msg:?}");
+        let ClientRequest { id, message } = msg;
+        if last_id.is_some_and(|last| id <= last) {
+            warn!("ignoring duplicate request {id} from {addr:?}");
+            continue;
+        }
+        last_id = Some(id);
+        if let ClientMessage::Announce(spec) | ClientMessage::FullHash { spec, .. } = &message {
+            match db.claim_request(&client_name, spec.hash(), id).await {
+                Ok(true) => {}
+                Ok(false) => {
+                    warn!(
+                        "ignoring request {id} from {addr:?}, a later one about {spec:?} was handled"
+                    );
+                    continue;
+                }
+                Err(err) => warn!("failed to record request {id} from {addr:?} in db: {err}"),
+            }
+        }
+        let reply_to = ReplyTo {
+            channel: to_client.clone(),
+            request: Some(id),
+            full_hashes: full_hashes.clone(),
+        };
+        // Groups a client may use were checked during the handshake, but
+        // any file in a message could claim another group or client.
+        let refused = message
+            .files()
+            .iter()
+            .find_map(|spec| config.check_sent_file(&client_name, spec).err());
+        if let Some(error) = refused {
+            warn!("{addr:?} sent an invalid request: {error}, closing connection");
+            reply_to.send(Receipt::ProtocolError(error)).await?;
+            return Ok(());
+        }
+        match message {
+            ClientMessage::Announce(spec) => {
+                let Ok(queued) = sems.queue.clone().try_acquire_owned() else {
+                    warn!("too many files in flight, asking {addr:?} to slow down");
+                    let receipt = Receipt::SlowDown {
+                        spec: *spec,
+                        until_secs: config.concurrency.slow_down_secs,
+                    };
+                    reply_to.send(receipt).await?;
+                    continue;
+                };
+                tokio::spawn(processing_pipeline(
+                    *spec,
+                    reply_to,
+                    config.clone(),
+                    db.clone(),
+                    sems.clone(),
+                    connected.clone(),
+                    queued,
+                ));
+            }
+            ClientMessage::FullHash { spec, hash } => {
+                if !full_hashes.answer(&spec) {
+                    warn!("ignoring full hash of {spec:?} from {addr:?}, it was not requested");
+                    continue;
+                }
+                tokio::spawn(confirm_full_hash(
+                    *spec,
+                    hash,
+                    reply_to,
+                    config.clone(),
+                    db.clone(),
+                    sems.clone(),
+                    connected.clone(),
+                ));
+            }
+            ClientMessage::Reconcile(files) => {
+                tokio::spawn(reconcile(
+                    files,
+                    reply_to,
+                    config.clone(),
+                    db.clone(),
+                    resumed.clone(),
+                ));
+            }
+            ClientMessage::Ping => {
+                if let Err(err) = db.client_seen(&client_name).await {
+                    warn!("failed to record ping of {client_name} in db: {err}");
+                }
+                reply_to.send(Receipt::Pong).await?;
+            }
+        }
+    }
+
+    info!("client {addr:?} closed connection");
+    Ok(())
+}
+
+async fn handle_client(
+    mut stream: TcpStream,
+    addr: SocketAddr,
+    config: Arc<Config>,
+    db: Database,
+    sems: Semaphores,
+    connected: Connected,
+) -> io::Result<()> {
+    debug!("got connection request from {addr:?}");
+
+    match handshake::server_side(&mut stream, &config).await {
+        Ok(HandshakeOutcome::Success(ClientKind::Processing { name })) => {
+            info!("handshake with processing client {name} at {addr:?} was successful");
+            if let Err(err) = db.client_connected(&name, &addr.to_string()).await {
+                warn!("failed to record connection of {name} in db: {err}");
+            }
+            let res = listen_to_processing_client(
+                stream,
+                addr,
+                config,
+                db.clone(),
+                name.clone(),
+                sems,
+                connected,
+            )
+            .await;
+            if let Err(err) = db.client_disconnected(&name).await {
+                warn!("failed to record disconnection of {name} in db: {err}");
+            }
+            res
+        }
+        Ok(HandshakeOutcome::Success(ClientKind::Mark { hash, mark })) => {
+            info!("received mark request from {addr:?}");
+            query::process_mark_query(db, hash, mark, addr).await
+        }
+        Ok(HandshakeOutcome::Success(ClientKind::List)) => {
+            info!("received list request from {addr:?}");
+            query::process_list_query(stream, db, config.max_frame_length).await
+        }
+        Ok(HandshakeOutcome::Success(ClientKind::Retry { hash, tag })) => {
+            info!("received retry request from {addr:?}");
+            query::process_retry_query(db, hash, tag, addr).await
+        }
+        Ok(HandshakeOutcome::Success(ClientKind::PruneDone)) => {
             info!("received request to prune 'done' tasks from {addr:?}");
-            query::process_prune_done_query(db).await
+            query::process_prune_done_query(db, addr).await
         }
         Ok(HandshakeOutcome::Success(ClientKind::Status)) => {
             info!("received status request from {addr:?}");
-            Ok(())
+            query::send_state(stream, sems.controls.state(), config.max_frame_length).await
+        }
+        Ok(HandshakeOutcome::Success(ClientKind::Control(command))) => {
+            // Anyone reaching the port could otherwise stop the server.
+            if !from_this_host(&stream, addr) {
+                warn!("refusing control request from {addr:?}, not from this host");
+                return stream.shutdown().await;
+            }
+            info!("received control request from {addr:?}");
+            let state = sems.controls.apply(command);
+            query::send_state(stream, state, config.max_frame_length).await
+        }
+        Ok(HandshakeOutcome::Success(ClientKind::Top)) => {
+            debug!("received top request from {addr:?}");
+            query::process_top_query(stream, db, config.max_frame_length).await
+        }
+        Ok(HandshakeOutcome::Success(ClientKind::Standby)) => {
+            if !config.replication.standbys.contains(&addr.ip()) {
+                warn!("{addr:?} is not an allowed standby server, closing connection");
+                return stream.shutdown().await;
+            }
+            info!("standby server {addr:?} is replicating this server");
+            let res = replication::serve_standby(stream, &config, &db).await;
+            info!("standby server {addr:?} stopped replicating");
+            res
         }
         Ok(HandshakeOutcome::Denied) => {
             warn!("handshake with {addr:?} was not successful, closing connection");
@@ -340,12 +2070,26 @@ async fn handle_client(
     }
 }
 
-async fn listen_to_clients(config: Arc<Config>, db: Database) -> io::Result<()> {
+/// Whether the peer at `addr` runs on this host, connected through the
+/// loopback interface or to one of the addresses of this host.
+fn from_this_host(stream: &TcpStream, addr: SocketAddr) -> bool {
+    let peer = addr.ip().to_canonical();
+    peer.is_loopback()
+        || stream
+            .local_addr()
+            .is_ok_and(|local| local.ip().to_canonical() == peer)
+}
+
+async fn listen_to_clients(
+    config: Arc<Config>,
+    db: Database,
+    connected: Connected,
+    sems: Semaphores,
+) -> io::Result<()> {
     let listener = TcpListener::bind(&config.server.address).await?;
-    let sem_hash = Arc::new(Semaphore::new(config.concurrency.max_hashes));
-    let sem_proc = Arc::new(Semaphore::new(config.concurrency.max_processing));
 
     info!("listening on {:?}", listener.local_addr());
+    systemd::notify_ready();
 
     loop {
         let (socket, addr) = listener.accept().await?;
@@ -354,30 +2098,137 @@ async fn listen_to_clients(config: Arc<Config>, db: Database) -> io::Result<()>
             addr,
             config.clone(),
             db.clone(),
-            sem_hash.clone(),
-            sem_proc.clone(),
+            sems.clone(),
+            connected.clone(),
         ));
     }
 }
 
-async fn restart_failed_tasks(config: Arc<Config>, db: Database) -> io::Result<()> {
+/// Schedule the processing of files left queued in the database.
+async fn resume_queued(
+    config: &Arc<Config>,
+    db: &Database,
+    connected: &Connected,
+    sems: &Semaphores,
+) {
+    match db.tasks_with_status(ProcessStatus::Queued).await {
+        Ok(queued) => {
+            for spec in queued.into_iter().map(FileSpec::from) {
+                info!("resuming queued {spec:?}");
+                tokio::spawn(process_when_scheduled(
+                    spec,
+                    config.clone(),
+                    db.clone(),
+                    connected.clone(),
+                    sems.clone(),
+                    sems.controls.busy(),
+                ));
+            }
+        }
+        Err(err) => warn!("failed to read database for queued tasks: {err}"),
+    }
+}
+
+/// Resume the files queued outside of processing hours whenever they start.
+async fn resume_when_open(
+    config: Arc<Config>,
+    db: Database,
+    connected: Connected,
+    sems: Semaphores,
+) -> io::Result<()> {
+    loop {
+        hours::wait_until_closed(&config.processing_hours).await;
+        hours::wait_until_open(&config.processing_hours).await;
+        info!("processing hours started");
+        resume_queued(&config, &db, &connected, &sems).await;
+    }
+}
+
+async fn restart_failed_tasks(
+    config: Arc<Config>,
+    db: Database,
+    connected: Connected,
+    sems: Semaphores,
+) -> io::Result<()> {
+    let controls = &sems.controls;
+    // Files still queued were waiting for a processing slot when the server
+    // stopped, nothing else would pick them up.
+    resume_queued(&config, &db, &connected, &sems).await;
+    // Batches being processed when the server stopped.
+    match db.batches_with_status(ProcessStatus::Processing).await {
+        Ok(batches) => {
+            for (group, name) in batches {
+                info!("resuming processing of batch {name:?} of group {group}");
+                tokio::spawn(process_batch(
+                    group,
+                    name,
+                    config.clone(),
+                    db.clone(),
+                    sems.clone(),
+                    controls.busy(),
+                ));
+            }
+        }
+        Err(err) => warn!("failed to read database for batches in progress: {err}"),
+    }
+
     let mut interval = tokio::time::interval(Duration::from_secs(config.retry_tasks_every_secs));
     interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
     loop {
         interval.tick().await;
+        if !controls.retries_files() {
+            debug!("processing paused or draining, not restarting failed tasks");
+            continue;
+        }
+        if !hours::is_open(&config.processing_hours) {
+            debug!("outside of processing hours, not restarting failed tasks");
+            continue;
+        }
         debug!("looking for failed tasks to restart");
         let failed = db.tasks_with_status(ProcessStatus::Failed).await;
         match failed {
             Ok(failed) => {
                 for spec in failed.into_iter().map(FileSpec::from) {
                     info!("restarting previously failed {spec:?}");
-                    tokio::spawn(process_file(spec, config.clone(), db.clone()));
+                    tokio::spawn(process_when_scheduled(
+                        spec,
+                        config.clone(),
+                        db.clone(),
+                        connected.clone(),
+                        sems.clone(),
+                        controls.busy(),
+                    ));
                 }
             }
             Err(err) => {
                 warn!("failed to read database for failed tasks: {err}");
             }
         }
+        let failed = db.batches_with_status(ProcessStatus::Failed).await;
+        match failed {
+            Ok(failed) => {
+                for (group, name) in failed {
+                    match db.retry_batch(&group, &name).await {
+                        Ok(true) => {}
+                        Ok(false) => continue,
+                        Err(err) => {
+                            warn!("failed to mark batch {name:?} for retry in db: {err}");
+                            continue;
+                        }
+                    }
+                    info!("restarting previously failed batch {name:?} of group {group}");
+                    tokio::spawn(process_batch(
+                        group,
+                        name,
+                        config.clone(),
+                        db.clone(),
+                        sems.clone(),
+                        controls.busy(),
+                    ));
+                }
+            }
+            Err(err) => warn!("failed to read database for failed batches: {err}"),
+        }
     }
 }
 
@@ -386,30 +2237,594 @@ async fn prune_tasks(config: Arc<Config>, db: Database) -> io::Result<()> {
     interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
     loop {
         interval.tick().await;
-        let summary =
-            clean_tasks_with_status(config.clone(), db.clone(), ProcessStatus::ToPrune).await;
-        debug!("{summary}");
+        prune_once(&config, &db).await;
+    }
+}
+
+/// Prune `ToPrune` tasks, expire stale ones and prune files to free space if
+/// needed.
+async fn prune_once(config: &Arc<Config>, db: &Database) {
+    let summary = clean_tasks_with_status(
+        config.clone(),
+        db.clone(),
+        ProcessStatus::ToPrune,
+        &PruneFilter::default(),
+    )
+    .await;
+    debug!("{summary}");
+
+    if config.await_ttl_secs > 0 {
+        clean::expire_stale(config, db).await;
     }
+
+    if config.target_free_bytes > 0 {
+        let statuses = [ProcessStatus::ToPrune, ProcessStatus::Done];
+        let pruned = clean::prune_until_free(
+            config,
+            db,
+            &statuses,
+            &PruneFilter::default(),
+            config.target_free_bytes,
+            false,
+        )
+        .await;
+        match pruned {
+            Ok(summary) => debug!("to free space: {summary}"),
+            Err(err) => warn!("failed to prune files to free space: {err}"),
+        }
+    }
+}
+
+/// Record the size of files announced before sizes were, from their copy on
+/// the server.
+async fn backfill_sizes(config: &Config, db: &Database) -> io::Result<()> {
+    for file in db.unsized_files().await.map_err(io::Error::other)? {
+        // Files not stored (anymore) keep an unknown size.
+        let Ok(stat) = tokio::fs::metadata(config.stored_path(&file.storage_path)).await else {
+            continue;
+        };
+        if stat.len() > 0 {
+            db.set_size(&file.hash, stat.len())
+                .await
+                .map_err(io::Error::other)?;
+        }
+    }
+    Ok(())
+}
+
+/// Record the storage path of files announced by an older version of
+/// pipeline, see [`legacy_storage_path`].
+async fn backfill_storage_paths(config: &Config, db: &Database) -> io::Result<()> {
+    for file in db.unclaimed_files().await.map_err(io::Error::other)? {
+        let hash = file.hash.clone();
+        let rel_path = legacy_storage_path(&FileSpec::from(file), config);
+        db.set_storage_path(&hash, &rel_path)
+            .await
+            .map_err(io::Error::other)?;
+    }
+    Ok(())
 }
 
 pub(crate) async fn main(config: Config) -> io::Result<()> {
     let config = Arc::new(config);
 
-    let db = Database::create_if_missing(config.database.wal)
+    let db = Database::create_if_missing(&config.database)
         .await
         .expect("failed to create database");
+    if let Err(err) = db.reset_client_connections().await {
+        warn!("failed to reset client connections in db: {err}");
+    }
+    if let Err(err) = backfill_storage_paths(&config, &db).await {
+        warn!("failed to record storage paths of older files: {err}");
+    }
+    if let Err(err) = backfill_sizes(&config, &db).await {
+        warn!("failed to record sizes of older files: {err}");
+    }
+
+    let connected = Connected::default();
+    let sems = Semaphores::new(&config.concurrency, Controls::default());
 
     tokio::select!(
-        listen = listen_to_clients(config.clone(), db.clone()) => listen,
-        retry = restart_failed_tasks(config.clone(), db.clone()) => retry,
-        prune = prune_tasks(config, db) => prune,
+        listen = listen_to_clients(
+            config.clone(),
+            db.clone(),
+            connected.clone(),
+            sems.clone(),
+        ) => listen,
+        retry = restart_failed_tasks(
+            config.clone(),
+            db.clone(),
+            connected.clone(),
+            sems.clone(),
+        ) => retry,
+        resume = resume_when_open(
+            config.clone(),
+            db.clone(),
+            connected,
+            sems.clone(),
+        ) => resume,
+        () = sems.controls.drained() => {
+            info!("all files in progress are done, stopping drained server");
+            Ok(())
+        },
+        backup = maintenance::backup_periodically(config.clone(), db.clone()) => backup,
+        jobs = jobs::run_scheduled(config.clone(), db.clone()) => jobs,
+        dashboard = dashboard::serve(config.clone(), db.clone()) => dashboard,
+        prune = prune_tasks(config, db.clone()) => prune,
+        watchdog = systemd::watchdog(|| {
+            let db = db.clone();
+            async move { db.count().await.is_ok() }
+        }) => watchdog,
     )
 }
 
+/// Pipeline server, receiving files from clients and processing them.
+pub struct Server {
+    config: Config,
+}
+
+impl Server {
+    /// Start building a server from the default configuration.
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder {
+            source: ConfigSource::Toml(DEFAULT_TOML_CONF.to_owned()),
+            address: None,
+            incoming_directory: None,
+            plugins: processing::Plugins::default(),
+        }
+    }
+
+    /// Run the server until an unrecoverable error occurs.
+    pub async fn run(self) -> io::Result<()> {
+        main(self.config).await
+    }
+}
+
+/// Builder for [`Server`].
+///
+/// Relative paths in the configuration, as well as the database, are resolved
+/// with respect to the current working directory.
+pub struct ServerBuilder {
+    source: ConfigSource,
+    address: Option<String>,
+    incoming_directory: Option<PathBuf>,
+    plugins: processing::Plugins,
+}
+
+impl ServerBuilder {
+    /// Use the given TOML configuration instead of the default one.
+    pub fn config_toml(mut self, content: impl Into<String>) -> Self {
+        self.source = ConfigSource::Toml(content.into());
+        self
+    }
+
+    /// Read the TOML configuration from a file instead of using the default one.
+    pub fn config_file(mut self, path: impl AsRef<Path>) -> Self {
+        self.source = ConfigSource::File(path.as_ref().to_owned());
+        self
+    }
+
+    /// Listen to clients on this address.
+    pub fn address(mut self, address: impl Into<String>) -> Self {
+        self.address = Some(address.into());
+        self
+    }
+
+    /// Set the directory where clients send files.
+    pub fn incoming_directory(mut self, directory: impl AsRef<Path>) -> Self {
+        self.incoming_directory = Some(directory.as_ref().to_owned());
+        self
+    }
+
+    /// Register a custom processing step.
+    pub fn step(mut self, step: impl ProcessingStep + 'static) -> Self {
+        self.plugins.register(Arc::new(step));
+        self
+    }
+
+    /// Read the configuration and apply the overrides set on this builder.
+    pub fn build(self) -> io::Result<Server> {
+        let mut config: Config = self.source.parse()?;
+        config.plugins = self.plugins;
+        for group in config.processing.values() {
+            if let Some(name) = group
+                .processing
+                .plugins()
+                .find(|p| !config.plugins.contains(p))
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("plugin step `{name}` is not registered"),
+                ));
+            }
+        }
+        if let Some(address) = self.address {
+            config.server.address = address;
+        }
+        if let Some(directory) = self.incoming_directory {
+            config.incoming_directory = directory;
+        }
+        Ok(Server { config })
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
 
+    #[test]
+    fn build_default_server() {
+        let server = Server::builder().address("0.0.0.0:4321").build().unwrap();
+        assert_eq!(server.config.server.address, "0.0.0.0:4321");
+    }
+
+    struct DummyStep;
+
+    impl ProcessingStep for DummyStep {
+        fn name(&self) -> &str {
+            "dummy"
+        }
+
+        fn run<'a>(
+            &'a self,
+            _ctx: &'a StepContext<'a>,
+        ) -> futures_util::future::BoxFuture<'a, io::Result<()>> {
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    #[test]
+    fn build_server_with_plugin() {
+        let toml = DEFAULT_TOML_CONF.replace(
+            "processing = [\n",
+            "processing = [\n    { plugin = \"dummy\" },\n",
+        );
+        assert!(Server::builder().config_toml(&toml).build().is_err());
+        let builder = Server::builder().config_toml(toml).step(DummyStep);
+        assert!(builder.build().is_ok());
+    }
+
+    #[test]
+    fn claim_client_paths() {
+        let dir = std::env::temp_dir().join(format!("pipeline-claim-{}", std::process::id()));
+        let toml = DEFAULT_TOML_CONF
+            .replace("./server/buckets", &dir.to_string_lossy())
+            .replace("\"hash_buckets\"", "\"client_paths\"");
+        let config: Config = toml::from_str(&toml).unwrap();
+        let spec = |hash: &str| FileSpec {
+            client: "lab".to_owned(),
+            path: "runs/1".to_owned(),
+            filename: "scan.dat".to_owned(),
+            processing: "main".to_owned(),
+            sha256_digest: FileDigest::Full(hash.repeat(16)),
+            size_bytes: 42,
+            modified_utc: String::new(),
+            metadata: Default::default(),
+            companion: None,
+        };
+        let first = claim_storage_path(&spec("a"), &config);
+        assert_eq!(first, "lab/runs/1/scan.dat");
+        let second = claim_storage_path(&spec("b"), &config);
+        assert_eq!(second, "lab/runs/1/scan.bbbbbbbbbbbb.dat");
+        assert_eq!(legacy_storage_path(&spec("b"), &config), second);
+        release_storage_path(&second, &config);
+        assert!(!config.stored_path(&second).exists());
+        assert_eq!(legacy_storage_path(&spec("b"), &config), first);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// Processing steps of the `main` group in the default configuration.
+    pub(super) const MAIN_STEPS: &str = r#"processing = [
+    { create_directory = "./server/{client_relative_directory}" },
+    [ "cp", "{server_path}", "./server/{client_relative_directory}/{client_file_stem}.out" ],
+]"#;
+
+    /// Default configuration with `replacements` applied, storing files in a
+    /// fresh directory named after `name`. `./server/buckets` stands for that
+    /// directory, in replacements too. Panics if a replacement matches
+    /// nothing, so that editing the default configuration cannot silently
+    /// turn a test into one of the default configuration.
+    pub(super) fn config_in(name: &str, replacements: &[(&str, &str)]) -> (Config, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("pipeline-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut toml = DEFAULT_TOML_CONF.to_owned();
+        let dir_str = dir.to_string_lossy();
+        for (from, to) in replacements
+            .iter()
+            .chain([&("./server/buckets", &*dir_str)])
+        {
+            assert!(
+                toml.contains(from),
+                "{from:?} not found in the default configuration"
+            );
+            toml = toml.replace(from, to);
+        }
+        (toml::from_str(&toml).unwrap(), dir)
+    }
+
+    /// File `name` with `content` sent by the client `lab` to the group
+    /// `main`, stored under its name in the incoming directory.
+    pub(super) fn stored_file(config: &Config, name: &str, content: &str) -> FileSpec {
+        let path = config.stored_path(name);
+        std::fs::write(&path, content).unwrap();
+        let digest = FileDigest::new(
+            &path,
+            HashMode::Full,
+            config.hash_algorithm,
+            ReadOptions::default(),
+        )
+        .unwrap();
+        FileSpec {
+            client: "lab".to_owned(),
+            path: String::new(),
+            filename: name.to_owned(),
+            processing: "main".to_owned(),
+            sha256_digest: digest,
+            size_bytes: content.len() as u64,
+            modified_utc: String::new(),
+            metadata: Default::default(),
+            companion: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn refuse_files_over_quota() {
+        let (config, dir) = config_in("quota", &[("# client_name = 100_000_000_000", "lab = 10")]);
+        let db = Database::in_memory().await.unwrap();
+        let first = stored_file(&config, "first.dat", "123456");
+        let second = stored_file(&config, "second.dat", "abcdef");
+        let other = FileSpec {
+            client: "other".to_owned(),
+            ..second.clone()
+        };
+        assert!(insert_new(&first, &config, &db).await.is_some());
+        assert!(insert_new(&second, &config, &db).await.is_none());
+        assert!(!db.contains(second.hash()).await.unwrap());
+        // Clients without quota are not limited.
+        assert!(insert_new(&other, &config, &db).await.is_some());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn process_complete_batches() {
+        let (config, dir) = config_in(
+            "batch",
+            &[
+                ("# [processing.main.batch]", "[processing.main.batch]"),
+                (
+                    "# key = \"{client_relative_directory}\"",
+                    "key = \"{client_name}\"",
+                ),
+                ("# size = 10", "size = 2"),
+                (
+                    "# processing = [ \"process_batch\", \"{batch_name}\", \"{batch_members}\" ]",
+                    "processing = [ \"sh\", \"-c\", 'cat \"$@\" > \"$(dirname \"$1\")/$0.out\"', \"{batch_name}\", \"{batch_members}\" ]",
+                ),
+            ],
+        );
+        let db = Database::in_memory().await.unwrap();
+        let batch = config.processing["main"].batch.as_ref().unwrap();
+        let mut complete = Vec::new();
+        for (name, content) in [("a.dat", "first\n"), ("b.dat", "second\n")] {
+            let file = stored_file(&config, name, content);
+            db.insert_new(&file, name, None).await.unwrap();
+            let batch_name = batch.name_of(&file, &config.stored_path(name));
+            assert_eq!(batch_name, "lab");
+            complete.push(
+                db.add_to_batch("main", "lab", file.hash(), batch.size)
+                    .await
+                    .unwrap(),
+            );
+        }
+        assert_eq!(complete, [false, true]);
+
+        run_batch(batch, "main", "lab", &config, &db).await.unwrap();
+        let out = std::fs::read_to_string(dir.join("lab.out")).unwrap();
+        assert_eq!(out, "first\nsecond\n");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn process_encrypted_blobs() {
+        let (config, dir) = config_in(
+            "encrypted",
+            &[
+                ("# [encryption]", "[encryption]"),
+                (
+                    "# key_file = \"./server/blob.key\"",
+                    "key_file = \"./server/buckets/blob.key\"",
+                ),
+                (
+                    "# decrypt_directory = \"/tmp\"",
+                    "decrypt_directory = \"./server/buckets/plain\"",
+                ),
+                (
+                    MAIN_STEPS,
+                    r#"processing = [ [ "cp", "{server_path}", "./server/buckets/out.dat" ] ]"#,
+                ),
+            ],
+        );
+        std::fs::write(dir.join("blob.key"), "07".repeat(32)).unwrap();
+        // Client files that look like encrypted blobs are encrypted too.
+        let content = "PLENC002 secret scan";
+        let file = stored_file(&config, "scan.dat", content);
+        let path = config.stored_path("scan.dat");
+        let db = Database::in_memory().await.unwrap();
+        db.insert_new(&file, "scan.dat", None).await.unwrap();
+        encryption::encrypt_at_rest(&config, &db, file.hash(), path.clone()).await;
+        assert!(db.is_encrypted(file.hash()).await.unwrap());
+        let blob = std::fs::read(&path).unwrap();
+        assert!(!blob.ends_with(content.as_bytes()));
+        // Blobs are only encrypted once.
+        encryption::encrypt_at_rest(&config, &db, file.hash(), path.clone()).await;
+        assert_eq!(std::fs::read(&path).unwrap(), blob);
+
+        let pools = Pools::new(&config.concurrency.resources);
+        let mut resources = Resources::new(&pools, None);
+        let processing = &config.processing["main"].processing;
+        let mut step_secs = Vec::new();
+        let run = processing.run(&file, &path, &config, &db, &mut resources, &mut step_secs);
+        assert!(matches!(run.await, Ok(None)));
+        let out = std::fs::read_to_string(dir.join("out.dat")).unwrap();
+        assert_eq!(out, content);
+        // The decrypted copy is removed once processing completes.
+        let copies = std::fs::read_dir(dir.join("plain")).unwrap().count();
+        assert_eq!(copies, 0);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn steps_wait_for_their_resources() {
+        let (config, dir) = config_in(
+            "resources",
+            &[
+                ("# gpu = 2", "gpu = 1"),
+                (
+                    MAIN_STEPS,
+                    r#"processing = [ { run = [ "cp", "{server_path}", "{server_path}.out" ], resources = { gpu = 1 } } ]"#,
+                ),
+            ],
+        );
+        let file = stored_file(&config, "scan.dat", "scan");
+        let path = config.stored_path("scan.dat");
+        let pools = Pools::new(&config.concurrency.resources);
+        let gpu = std::collections::BTreeMap::from([("gpu".to_owned(), 1)]);
+        let held = Resources::new(&pools, None).acquire(&gpu).await.unwrap();
+
+        let db = Database::in_memory().await.unwrap();
+        let mut resources = Resources::new(&pools, None);
+        let mut step_secs = Vec::new();
+        let processing = &config.processing["main"].processing;
+        let run = processing.run(&file, &path, &config, &db, &mut resources, &mut step_secs);
+        tokio::pin!(run);
+        let timeout = Duration::from_millis(200);
+        assert!(tokio::time::timeout(timeout, &mut run).await.is_err());
+        assert!(!dir.join("scan.dat.out").exists());
+        drop(held);
+        assert!(matches!(run.await, Ok(None)));
+        assert_eq!(
+            std::fs::read_to_string(dir.join("scan.dat.out")).unwrap(),
+            "scan"
+        );
+        // Released once the step completed.
+        let mut resources = Resources::new(&pools, None);
+        assert!(
+            tokio::time::timeout(timeout, resources.acquire(&gpu))
+                .await
+                .is_ok()
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn refuse_unknown_placeholders_or_resources() {
+        let config = |step: &str| {
+            let toml = DEFAULT_TOML_CONF.replace(
+                "    [ \"cp\", \"{server_path}\", \"./server/{client_relative_directory}/{client_file_stem}.out\" ],",
+                step,
+            );
+            toml::from_str::<Config>(&toml).map_err(|err| err.to_string())
+        };
+        assert!(config("[ \"sh\", \"-c\", \"cp {server_path} ${HOME}\" ],").is_ok());
+        let err = config("[ \"cp\", \"{server_pth}\", \"out\" ],").unwrap_err();
+        assert!(err.contains("unknown placeholder {server_pth}"), "{err}");
+        let err =
+            config("{ run = [ \"cp\", \"{server_path}\", \"out\" ], resources = { gpu = 1 } },")
+                .unwrap_err();
+        assert!(err.contains("unknown resource `gpu`"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn feed_outputs_to_chained_groups() {
+        let (config, dir) = config_in(
+            "chained",
+            &[(
+                "# outputs = [ \"./server/{client_relative_directory}/{client_file_stem}.out\" ]",
+                r#"[processing.picking]
+processing = "pass"
+after_processing = { mark_as = "Done" }
+[processing.picking.chained_from]
+group = "main"
+outputs = [ "{server_path}.out" ]"#,
+            )],
+        );
+        let db = Database::in_memory().await.unwrap();
+        let file = stored_file(&config, "scan.dat", "scan");
+        db.insert_new(&file, "scan.dat", None).await.unwrap();
+        let server_path = config.stored_path("scan.dat");
+        let feed = || feed_chained_groups(&file, &server_path, &config, &db);
+        assert!(feed().await.is_empty());
+
+        std::fs::write(dir.join("scan.dat.out"), "picked").unwrap();
+        let chained = feed().await;
+        let [output] = &chained[..] else {
+            panic!("expected one chained file, got {chained:?}");
+        };
+        assert_eq!(output.processing, "picking");
+        assert_eq!(output.filename, "scan.dat.out");
+        assert_eq!(output.metadata[CHAINED_FROM_KEY], file.hash());
+        let stored = db.file(output.hash()).await.unwrap().unwrap();
+        assert_eq!(stored.status, ProcessStatus::Queued);
+        let content = std::fs::read_to_string(config.stored_path(&stored.storage_path));
+        assert_eq!(content.unwrap(), "picked");
+        // Outputs already in the pipeline are not fed again.
+        assert!(feed().await.is_empty());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn restrict_group_to_clients() {
+        let toml = DEFAULT_TOML_CONF
+            .replace("namespace_by_client = false", "namespace_by_client = true")
+            .replace("clients = []", "clients = [\"lab\"]");
+        let config: Config = toml::from_str(&toml).unwrap();
+        assert!(config.is_allowed_group("main", "lab"));
+        assert!(!config.is_allowed_group("main", "other"));
+        assert!(config.is_valid_client_name("lab"));
+        assert!(!config.is_valid_client_name("../lab"));
+
+        let spec = FileSpec {
+            client: "lab".to_owned(),
+            path: String::new(),
+            filename: "file.dat".to_owned(),
+            processing: "main".to_owned(),
+            sha256_digest: FileDigest::Shallow {
+                hash: String::new(),
+                sample_bytes: hashing::DEFAULT_SAMPLE_BYTES,
+            },
+            size_bytes: 0,
+            modified_utc: String::new(),
+            metadata: Default::default(),
+            companion: None,
+        };
+        assert!(config.check_sent_file("lab", &spec).is_ok());
+        assert!(config.check_sent_file("other", &spec).is_err());
+        let other = FileSpec {
+            client: "other".to_owned(),
+            ..spec.clone()
+        };
+        assert!(config.check_sent_file("other", &other).is_err());
+        let huge = FileSpec {
+            sha256_digest: FileDigest::Shallow {
+                hash: String::new(),
+                sample_bytes: u64::MAX,
+            },
+            ..spec.clone()
+        };
+        assert!(config.check_sent_file("lab", &huge).is_err());
+
+        let toml = toml.replace("require_full_hash = false", "require_full_hash = true");
+        let config: Config = toml::from_str(&toml).unwrap();
+        assert!(config.check_sent_file("lab", &spec).is_err());
+        let full = FileSpec {
+            sha256_digest: FileDigest::Full(String::new()),
+            ..spec
+        };
+        assert!(config.check_sent_file("lab", &full).is_ok());
+    }
+
     #[test]
     fn read_default_config() {
         assert!(toml::from_slice::<Config>(DEFAULT_TOML_CONF.as_bytes()).is_ok());
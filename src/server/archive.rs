@@ -0,0 +1,150 @@
+use std::{io, path::Path};
+
+use crate::{
+    FileSpec,
+    server::{
+        Config, claim_storage_path, companion_path_of,
+        database::{Database, ProcessStatus},
+        gc::move_file,
+    },
+};
+
+/// Move `spec` and its companion to `directory`, under its hash so that
+/// archived files never overwrite each other, and record their location to
+/// restore them later.
+pub(super) async fn archive(
+    spec: &FileSpec,
+    config: &Config,
+    db: &Database,
+    directory: &Path,
+) -> io::Result<()> {
+    let Some(rel_path) = db
+        .storage_path(spec.hash())
+        .await
+        .map_err(io::Error::other)?
+    else {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no storage path recorded for {spec:?}"),
+        ));
+    };
+    let file_name = Path::new(&rel_path).file_name().unwrap_or_default();
+    let location = std::path::absolute(directory.join(spec.hash()).join(file_name))?;
+    let from = config.stored_path(&rel_path);
+    let companion = companion_path_of(&from, spec);
+    let to = location.clone();
+    let companion_to = spec
+        .companion_suffix()
+        .map(|suffix| with_suffix(&location, &suffix));
+    tokio::task::spawn_blocking(move || {
+        move_file(&from, &to)?;
+        if let (Some(from), Some(to)) = (companion, companion_to) {
+            move_file(&from, &to)?;
+        }
+        io::Result::Ok(())
+    })
+    .await??;
+    db.archive(spec, &location.to_string_lossy())
+        .await
+        .map_err(io::Error::other)
+}
+
+/// Whether `directory` is on the same volume as the incoming directory, in
+/// which case archiving files frees no space. `directory` may not exist yet.
+pub(super) fn on_incoming_volume(config: &Config, directory: &Path) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        let device = |path: &Path| path.metadata().map(|meta| meta.dev());
+        let Some(existing) = directory.ancestors().find(|dir| dir.exists()) else {
+            return false;
+        };
+        matches!(
+            (device(&config.incoming_directory), device(existing)),
+            (Ok(incoming), Ok(archive)) if incoming == archive
+        )
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = (config, directory);
+        false
+    }
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> std::path::PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    path.into()
+}
+
+/// Move the archived file `hash` back to the incoming directory, marked as
+/// `Done`.
+pub(crate) async fn restore(config: Config, hash: &str) -> io::Result<()> {
+    let db = Database::open_existing(&config.database)
+        .await
+        .map_err(io::Error::other)?;
+    if db.contains(hash).await.map_err(io::Error::other)? {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{hash} is already in the pipeline"),
+        ));
+    }
+    let Some((spec, location)) = db.archived(hash).await.map_err(io::Error::other)? else {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{hash} is not archived"),
+        ));
+    };
+    let location = Path::new(&location);
+    if !location.is_file() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("archived copy {location:?} of {hash} is missing"),
+        ));
+    }
+
+    let rel_path = claim_storage_path(&spec, &config);
+    let dest = config.stored_path(&rel_path);
+    move_file(location, &dest)?;
+    if let (Some(suffix), Some(companion)) =
+        (spec.companion_suffix(), companion_path_of(&dest, &spec))
+    {
+        move_file(&with_suffix(location, &suffix), &companion)?;
+    }
+
+    let actor = "restore command";
+    db.insert_new(&spec, &rel_path, None)
+        .await
+        .map_err(io::Error::other)?;
+    db.update_status(hash, ProcessStatus::Done, actor)
+        .await
+        .map_err(io::Error::other)?;
+    db.unarchive(hash).await.map_err(io::Error::other)?;
+    db.audit(hash, actor, &format!("restored from {location:?}"))
+        .await
+        .map_err(io::Error::other)?;
+    println!("restored {hash} to {dest:?}");
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::server::DEFAULT_TOML_CONF;
+
+    #[cfg(unix)]
+    #[test]
+    fn archive_next_to_incoming_directory() {
+        let dir = std::env::temp_dir().join(format!("pipeline-archive-{}", std::process::id()));
+        let incoming = dir.join("incoming");
+        std::fs::create_dir_all(&incoming).unwrap();
+        let toml = DEFAULT_TOML_CONF.replace("./server/buckets", &incoming.to_string_lossy());
+        let config: Config = toml::from_str(&toml).unwrap();
+        assert!(on_incoming_volume(
+            &config,
+            &dir.join("archive/not/created")
+        ));
+        assert!(!on_incoming_volume(&config, Path::new("/proc")));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,70 @@
+use std::io;
+
+use tabled::{Table, settings::Style};
+
+use crate::server::{Config, database::Database};
+
+pub(crate) async fn main(config: Config, hash: &str) -> io::Result<()> {
+    let db = Database::open_existing(&config.database)
+        .await
+        .map_err(io::Error::other)?;
+
+    let history = db.history(hash).await.map_err(io::Error::other)?;
+    if history.is_empty() {
+        println!("no record of {hash}");
+        return Ok(());
+    }
+    print_table(Table::new(&history));
+
+    let attempts = db.attempts(hash).await.map_err(io::Error::other)?;
+    if !attempts.is_empty() {
+        println!("\nprocessing attempts:");
+        print_table(Table::new(&attempts));
+    }
+    Ok(())
+}
+
+/// Attach `tag` to the file `hash`, or detach it if `remove`.
+pub(crate) async fn tag(config: Config, hash: &str, tag: &str, remove: bool) -> io::Result<()> {
+    if tag.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "tags cannot be empty",
+        ));
+    }
+    let db = Database::open_existing(&config.database)
+        .await
+        .map_err(io::Error::other)?;
+    if !db.contains(hash).await.map_err(io::Error::other)? {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{hash} is not in the pipeline"),
+        ));
+    }
+    let actor = "tag command";
+    let changed = if remove {
+        db.untag(hash, tag, actor).await
+    } else {
+        db.tag(hash, tag, actor).await
+    }
+    .map_err(io::Error::other)?;
+    if !changed {
+        let state = if remove {
+            "does not have"
+        } else {
+            "already has"
+        };
+        println!("{hash} {state} tag {tag}");
+    }
+    Ok(())
+}
+
+fn print_table(mut table: Table) {
+    table.with(
+        Style::markdown()
+            .remove_vertical()
+            .remove_left()
+            .remove_right(),
+    );
+    println!("{table}");
+}
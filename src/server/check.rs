@@ -0,0 +1,157 @@
+use std::io;
+
+use crate::{
+    check::Diagnostics,
+    server::{
+        Config, Database,
+        processing::{BATCH_PLACEHOLDERS, PLACEHOLDERS},
+        spawn::lookup_user,
+    },
+};
+
+/// Check the placeholders of all templates, which would otherwise stay
+/// literal at runtime.
+fn check_placeholders(diag: &mut Diagnostics, config: &Config) {
+    let mut groups: Vec<_> = config.processing.iter().collect();
+    groups.sort_by_key(|(name, _)| *name);
+    for (name, group) in groups {
+        let what = format!("processing group `{name}`");
+        let templates = group
+            .processing
+            .templates()
+            .into_iter()
+            .chain(group.after_processing.templates());
+        for template in templates {
+            diag.check_placeholders(&what, template, &PLACEHOLDERS);
+        }
+        if let Some(batch) = &group.batch {
+            diag.check_placeholders(&what, &batch.key, &PLACEHOLDERS);
+            for arg in &batch.processing {
+                diag.check_placeholders(&what, arg, &BATCH_PLACEHOLDERS);
+            }
+        }
+        if let Some(chained_from) = &group.chained_from {
+            for output in &chained_from.outputs {
+                diag.check_placeholders(&what, output, &PLACEHOLDERS);
+            }
+        }
+    }
+}
+
+/// Check that the resources required by processing steps exist and are large
+/// enough, steps would otherwise fail every time they run.
+fn check_resources(diag: &mut Diagnostics, config: &Config) {
+    let mut groups: Vec<_> = config.processing.iter().collect();
+    groups.sort_by_key(|(name, _)| *name);
+    for (name, group) in groups {
+        let what = format!("processing group `{name}`");
+        for (name, amount) in group.processing.resources() {
+            match config.concurrency.resources.get(name) {
+                None => diag.error(format!("{what} requires unknown resource `{name}`")),
+                Some(&size) if amount > size => diag.error(format!(
+                    "{what} requires {amount} `{name}` but only {size} are available"
+                )),
+                Some(_) => {}
+            }
+        }
+    }
+}
+
+/// Refuse a configuration using unknown placeholders or resources when
+/// loading it.
+pub(crate) fn at_load(config: &Config) -> io::Result<()> {
+    let mut diag = Diagnostics::new();
+    check_placeholders(&mut diag, config);
+    check_resources(&mut diag, config);
+    diag.into_result()
+}
+
+pub(crate) async fn main(config: Config) -> io::Result<()> {
+    let mut diag = Diagnostics::new();
+
+    diag.check_dir("incoming directory", &config.incoming_directory);
+    if let Some(encryption) = &config.encryption {
+        if let Err(err) = encryption.key() {
+            diag.error(format!("cannot load encryption key: {err}"));
+        }
+        diag.check_dir("decrypt directory", &encryption.decrypt_directory);
+    }
+
+    check_placeholders(&mut diag, &config);
+    check_resources(&mut diag, &config);
+    let mut groups: Vec<_> = config.processing.iter().collect();
+    groups.sort_by_key(|(name, _)| *name);
+    for (name, group) in groups {
+        let what = format!("processing group `{name}`");
+        if group.batch.as_ref().is_some_and(|batch| batch.size == 0) {
+            diag.error(format!("{what} has a batch size of 0"));
+        }
+        if !cfg!(feature = "lua") && group.processing.uses_lua() {
+            diag.error(format!(
+                "{what} uses a Lua script, but pipeline was built without the `lua` feature"
+            ));
+        }
+        for options in group.processing.spawn_options() {
+            if let Some(user) = &options.user
+                && let Err(err) = lookup_user(user)
+            {
+                diag.error(format!("{what} runs a command as `{user}`: {err}"));
+            }
+            if let Some(nice) = options.nice
+                && !(-20..=19).contains(&nice)
+            {
+                diag.error(format!(
+                    "{what} has a niceness of {nice}, expected -20 to 19"
+                ));
+            }
+            if options.cpu_percent == Some(0) || options.memory_max_bytes == Some(0) {
+                diag.error(format!("{what} limits a command to no CPU or no memory"));
+            }
+        }
+        if let Some(chained_from) = &group.chained_from {
+            if !config.processing.contains_key(&chained_from.group) {
+                diag.error(format!(
+                    "{what} is chained from unknown group `{}`",
+                    chained_from.group
+                ));
+            } else if chains_back_to(&config, name) {
+                diag.error(format!("{what} is chained from its own outputs"));
+            }
+        }
+        for plugin in group.processing.plugins() {
+            diag.error(format!(
+                "{what} uses plugin step `{plugin}`, only available when embedding pipeline"
+            ));
+        }
+    }
+
+    match Database::open_existing(&config.database).await {
+        Ok(db) => match db.count().await {
+            Ok(n) => println!("database is accessible, with {n} files in pipeline"),
+            Err(err) => diag.error(format!("cannot read database: {err}")),
+        },
+        Err(err) => diag.error(format!("cannot open database: {err}")),
+    }
+
+    diag.conclude()
+}
+
+/// Whether following the `chained_from` groups upstream of `group` leads back
+/// to it.
+fn chains_back_to(config: &Config, group: &str) -> bool {
+    let mut current = group;
+    for _ in 0..config.processing.len() {
+        let Some(upstream) = config
+            .processing
+            .get(current)
+            .and_then(|g| g.chained_from.as_ref())
+        else {
+            return false;
+        };
+        if upstream.group == group {
+            return true;
+        }
+        current = &upstream.group;
+    }
+    false
+}
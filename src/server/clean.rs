@@ -1,45 +1,32 @@
-use std::{fmt::Display, fs::Metadata, io, sync::Arc};
+use std::{fmt::Display, fs::Metadata, io, path::Path, sync::Arc, time::Duration};
 
-use log::{debug, warn};
+use log::{debug, info, warn};
+use tabled::{Table, Tabled, settings::Style};
 
 use crate::{
     FileSpec,
+    cli::MarkStatus,
+    format_size,
     server::{
-        Config,
-        database::{Database, ProcessStatus},
+        Config, archive, companion_path_of,
+        database::{Database, FileInPipeline, ProcessStatus, PruneFilter, SERVER_ACTOR},
+        processing::remove_step_logs,
     },
 };
 
-fn format_size(size: u64) -> String {
-    const GIBI: u64 = 1024u64.pow(3);
-    const MEBI: u64 = 1024u64.pow(2);
-    const KIBI: u64 = 1024u64.pow(1);
-    let (size, unit) = if size > GIBI {
-        (size as f64 / GIBI as f64, "GiB")
-    } else if size > MEBI {
-        (size as f64 / MEBI as f64, "MiB")
-    } else if size > KIBI {
-        (size as f64 / KIBI as f64, "kiB")
-    } else {
-        (size as f64, "B")
-    };
-    if unit == "B" {
-        format!("{size:.0} {unit}")
-    } else {
-        format!("{size:.1} {unit}")
-    }
-}
-
 pub(super) struct CleanSummary {
     nfiles: u32,
     total_size: u64,
+    /// Whether files are moved to the `archive_directory` rather than deleted.
+    archived: bool,
 }
 
 impl CleanSummary {
-    fn new() -> CleanSummary {
+    fn new(archived: bool) -> CleanSummary {
         CleanSummary {
             nfiles: 0,
             total_size: 0,
+            archived,
         }
     }
 
@@ -52,23 +39,66 @@ impl CleanSummary {
 impl Display for CleanSummary {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let fmt_size = format_size(self.total_size);
-        write!(f, "deleted {} files ({fmt_size})", self.nfiles)
+        let action = if self.archived { "archived" } else { "deleted" };
+        write!(f, "{action} {} files ({fmt_size})", self.nfiles)
     }
 }
 
-pub(super) async fn clean_spec(spec: FileSpec, config: &Config, db: &Database) -> Option<Metadata> {
+/// Prune `file`, moving it to `archive_to` if set rather than deleting it.
+pub(super) async fn clean_file(
+    file: FileInPipeline,
+    config: &Config,
+    db: &Database,
+    archive_to: Option<&Path>,
+) -> Option<Metadata> {
+    let server_path = config.stored_path(&file.storage_path);
+    let spec = FileSpec::from(file);
     debug!("pruning {spec:?}");
     let mut meta = None;
-    let server_path = config.path_of(&spec);
     match tokio::fs::metadata(&server_path).await {
         Ok(m) => meta = Some(m),
         Err(err) => warn!("error gathering metadata for {spec:?}: {err}"),
     }
-    if let Err(err) = tokio::fs::remove_file(&server_path).await {
-        warn!("error pruning {spec:?}: {err}")
+    // Files already moved away, e.g. by `move_to_and_prune`, are not archived.
+    if let Some(directory) = archive_to
+        && meta.is_some()
+    {
+        if let Err(err) = archive::archive(&spec, config, db, directory).await {
+            // Kept in the pipeline rather than lost.
+            warn!("error archiving {spec:?}: {err}");
+            return None;
+        }
+    } else {
+        if let Err(err) = tokio::fs::remove_file(&server_path).await {
+            warn!("error pruning {spec:?}: {err}")
+        }
+        if let Some(companion) = companion_path_of(&server_path, &spec)
+            && let Err(err) = tokio::fs::remove_file(&companion).await
+        {
+            warn!("error pruning companion of {spec:?}: {err}")
+        }
+    }
+    // Companions sent along the same content from other places.
+    match db.companion_origins(Some(spec.hash())).await {
+        Ok(origins) => {
+            let main = companion_path_of(&server_path, &spec);
+            for companion in origins
+                .iter()
+                .filter_map(|origin| companion_path_of(&server_path, &origin.spec_of(&spec)))
+                .filter(|companion| Some(companion) != main.as_ref())
+            {
+                if let Err(err) = tokio::fs::remove_file(&companion).await
+                    && err.kind() != io::ErrorKind::NotFound
+                {
+                    warn!("error pruning companion {companion:?}: {err}")
+                }
+            }
+        }
+        Err(err) => warn!("failed to list companions of {spec:?} in db: {err}"),
     }
-    if let Err(err) = db.remove(spec.hash()).await {
-        warn!("error when removing {spec:?} from db: {err}")
+    match db.remove(spec.hash()).await {
+        Ok(()) => remove_step_logs(config, spec.hash()).await,
+        Err(err) => warn!("error when removing {spec:?} from db: {err}"),
     }
     meta
 }
@@ -77,14 +107,16 @@ pub(super) async fn clean_tasks_with_status(
     config: Arc<Config>,
     db: Database,
     status: ProcessStatus,
+    filter: &PruneFilter,
 ) -> CleanSummary {
     debug!("looking for tasks to prune");
-    let mut summary = CleanSummary::new();
-    let to_prune = db.tasks_with_status(status).await;
+    let archive_to = config.archive_directory.as_deref();
+    let mut summary = CleanSummary::new(archive_to.is_some());
+    let to_prune = db.tasks_to_prune(status, filter).await;
     match to_prune {
         Ok(to_prune) => {
-            for spec in to_prune.into_iter().map(FileSpec::from) {
-                if let Some(meta) = clean_spec(spec, &config, &db).await {
+            for file in to_prune {
+                if let Some(meta) = clean_file(file, &config, &db, archive_to).await {
                     summary.add(meta);
                 }
             }
@@ -94,21 +126,190 @@ pub(super) async fn clean_tasks_with_status(
     summary
 }
 
-pub(crate) async fn main(config: Config, include_done: bool) -> io::Result<()> {
-    let db = Database::create_if_missing(config.database.wal)
+/// Mark files announced more than `await_ttl_secs` ago and still not
+/// delivered as expired, removing what may have been partially received.
+pub(super) async fn expire_stale(config: &Config, db: &Database) {
+    let filter = PruneFilter {
+        client: None,
+        older_than: Some(Duration::from_secs(config.await_ttl_secs)),
+        include_pinned: false,
+        tag: None,
+    };
+    let statuses = [
+        ProcessStatus::AwaitFromClient,
+        ProcessStatus::Receiving,
+        ProcessStatus::Verifying,
+    ];
+    let stale = match db.oldest_tasks(&statuses, &filter).await {
+        Ok(stale) => stale,
+        Err(err) => {
+            warn!("error when querying db for stale tasks: {err}");
+            return;
+        }
+    };
+    for file in stale {
+        let server_path = config.stored_path(&file.storage_path);
+        let spec = FileSpec::from(file);
+        info!("{spec:?} was never delivered, marking it as expired");
+        match tokio::fs::remove_file(&server_path).await {
+            Ok(()) => debug!("removed partial copy of {spec:?}"),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => warn!("error removing partial copy of {spec:?}: {err}"),
+        }
+        if let Err(err) = db
+            .update_status(spec.hash(), ProcessStatus::Expired, SERVER_ACTOR)
+            .await
+        {
+            warn!("error when marking {spec:?} as expired: {err}");
+        }
+    }
+}
+
+/// Prune the oldest files with any of `statuses` until at least
+/// `target_free` bytes are available on the volume of the incoming directory.
+pub(super) async fn prune_until_free(
+    config: &Config,
+    db: &Database,
+    statuses: &[ProcessStatus],
+    filter: &PruneFilter,
+    target_free: u64,
+    dry_run: bool,
+) -> io::Result<CleanSummary> {
+    // Archiving to the same volume would not free any space.
+    let archive_to = config
+        .archive_directory
+        .as_deref()
+        .filter(|dir| !archive::on_incoming_volume(config, dir));
+    if archive_to.is_none() && config.archive_directory.is_some() {
+        debug!("archive directory is on the incoming volume, deleting files to free space");
+    }
+    let mut summary = CleanSummary::new(archive_to.is_some());
+    let mut available = fs4::available_space(&config.incoming_directory)?;
+    if available >= target_free {
+        return Ok(summary);
+    }
+    let candidates = db
+        .oldest_tasks(statuses, filter)
+        .await
+        .map_err(io::Error::other)?;
+    for file in candidates {
+        if dry_run {
+            // Assume deleting a file frees its size.
+            let size_bytes = FileSpec::from(file).size_bytes;
+            summary.nfiles += 1;
+            summary.total_size += size_bytes;
+            available += size_bytes;
+        } else {
+            if let Some(meta) = clean_file(file, config, db, archive_to).await {
+                summary.add(meta);
+            }
+            available = fs4::available_space(&config.incoming_directory)?;
+        }
+        if available >= target_free {
+            break;
+        }
+    }
+    if available < target_free {
+        warn!(
+            "only {} available after pruning, below target of {}",
+            format_size(available),
+            format_size(target_free)
+        );
+    }
+    Ok(summary)
+}
+
+fn format_age(secs: i64) -> String {
+    format!("{}d {:02}h", secs / 86400, (secs / 3600) % 24)
+}
+
+#[derive(Tabled)]
+struct PruneRow {
+    hash: String,
+    client: String,
+    size: String,
+    age: String,
+}
+
+/// List the files with `status` that would be pruned, returning their total
+/// size.
+async fn list_prune_candidates(
+    db: &Database,
+    status: ProcessStatus,
+    filter: &PruneFilter,
+) -> io::Result<u64> {
+    let candidates = db
+        .prune_candidates(status, filter)
         .await
-        .expect("failed to create database");
+        .map_err(io::Error::other)?;
+    let total_size = candidates.iter().map(|c| c.size_bytes as u64).sum();
+    let nfiles = candidates.len();
+    if nfiles > 0 {
+        let rows = candidates.into_iter().map(|c| PruneRow {
+            hash: c.hash,
+            client: c.client,
+            size: format_size(c.size_bytes as u64),
+            age: format_age(c.age_secs),
+        });
+        let mut table = Table::new(rows);
+        table.with(
+            Style::markdown()
+                .remove_vertical()
+                .remove_left()
+                .remove_right(),
+        );
+        println!("{table}");
+    }
+    println!(
+        "{status:?} files: would delete {nfiles} files ({})\n",
+        format_size(total_size)
+    );
+    Ok(total_size)
+}
 
-    let config = Arc::new(config);
+pub(crate) async fn main(
+    config: Config,
+    statuses: Vec<MarkStatus>,
+    filter: PruneFilter,
+    target_free: Option<u64>,
+    dry_run: bool,
+) -> io::Result<()> {
+    let db = Database::open_existing(&config.database)
+        .await
+        .map_err(io::Error::other)?;
 
-    if include_done {
+    if let Some(target_free) = target_free {
+        let statuses: Vec<_> = statuses.into_iter().map(ProcessStatus::from).collect();
         let summary =
-            clean_tasks_with_status(config.clone(), db.clone(), ProcessStatus::Done).await;
-        println!("Done files: {summary}")
+            prune_until_free(&config, &db, &statuses, &filter, target_free, dry_run).await?;
+        if dry_run {
+            println!(
+                "would delete {} files ({}) to reach {} of free space",
+                summary.nfiles,
+                format_size(summary.total_size),
+                format_size(target_free)
+            );
+        } else {
+            println!("{summary}");
+        }
+        return Ok(());
     }
 
-    let summary = clean_tasks_with_status(config, db, ProcessStatus::ToPrune).await;
-    println!("ToPrune files: {summary}");
+    if dry_run {
+        let mut total_size = 0;
+        for status in statuses {
+            total_size += list_prune_candidates(&db, status.into(), &filter).await?;
+        }
+        println!("total: {}", format_size(total_size));
+        return Ok(());
+    }
+
+    let config = Arc::new(config);
+
+    for status in statuses.into_iter().map(ProcessStatus::from) {
+        let summary = clean_tasks_with_status(config.clone(), db.clone(), status, &filter).await;
+        println!("{status:?} files: {summary}");
+    }
 
     Ok(())
 }
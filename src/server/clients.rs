@@ -0,0 +1,54 @@
+use std::{io, time::Duration};
+
+use tabled::{Table, Tabled, settings::Style};
+
+use crate::server::{Config, database::Database};
+
+#[derive(Tabled)]
+struct ClientRow {
+    client: String,
+    connected: bool,
+    address: String,
+    connected_utc: String,
+    last_seen_utc: String,
+    last_delivery_utc: String,
+    silent_for: String,
+}
+
+fn format_silence(secs: Option<i64>) -> String {
+    match secs {
+        Some(secs) => format!("{}:{:02}:{:02}", secs / 3600, (secs / 60) % 60, secs % 60),
+        None => "never delivered".to_owned(),
+    }
+}
+
+/// List processing clients known to the server, only those that did not
+/// deliver any file for `silent_for` if given.
+pub(crate) async fn main(config: Config, silent_for: Option<Duration>) -> io::Result<()> {
+    let db = Database::open_existing(&config.database)
+        .await
+        .map_err(io::Error::other)?;
+
+    let mut clients = db.clients().await.map_err(io::Error::other)?;
+    if let Some(silent_for) = silent_for {
+        let secs = silent_for.as_secs() as i64;
+        clients.retain(|client| client.silent_secs.is_none_or(|silent| silent >= secs));
+    }
+    let mut table = Table::new(clients.into_iter().map(|client| ClientRow {
+        client: client.name,
+        connected: client.connected,
+        address: client.address,
+        connected_utc: client.connected_utc,
+        last_seen_utc: client.last_seen_utc,
+        last_delivery_utc: client.last_delivery_utc.unwrap_or_default(),
+        silent_for: format_silence(client.silent_secs),
+    }));
+    table.with(
+        Style::markdown()
+            .remove_vertical()
+            .remove_left()
+            .remove_right(),
+    );
+    println!("{table}");
+    Ok(())
+}
@@ -0,0 +1,125 @@
+use std::{fmt, sync::Arc};
+
+use log::info;
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+
+/// Runtime control requested by an administrator with `query pause`,
+/// `query resume` or `query drain`.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub(crate) enum Command {
+    Pause {
+        accepting: bool,
+        processing: bool,
+    },
+    Resume {
+        accepting: bool,
+        processing: bool,
+    },
+    /// Stop accepting files, finish the work in progress and stop the server.
+    Drain,
+}
+
+/// Runtime state of the server, reported by `query status`.
+#[derive(Serialize, Deserialize, Clone, Copy, Default, Debug)]
+pub(crate) struct State {
+    accepting_paused: bool,
+    processing_paused: bool,
+    draining: bool,
+    /// Files being verified or processed.
+    busy: usize,
+}
+
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let paused = |paused| if paused { "paused" } else { "running" };
+        writeln!(f, "accepting files: {}", paused(self.accepting_paused))?;
+        writeln!(f, "processing: {}", paused(self.processing_paused))?;
+        writeln!(f, "draining: {}", if self.draining { "yes" } else { "no" })?;
+        write!(f, "files in progress: {}", self.busy)
+    }
+}
+
+/// Shared handle on the runtime state of the server.
+#[derive(Clone, Default)]
+pub(super) struct Controls(Arc<watch::Sender<State>>);
+
+impl Controls {
+    pub(super) fn state(&self) -> State {
+        *self.0.borrow()
+    }
+
+    pub(super) fn apply(&self, command: Command) -> State {
+        info!("applying control command {command:?}");
+        self.0.send_modify(|state| match command {
+            Command::Pause {
+                accepting,
+                processing,
+            } => {
+                state.accepting_paused |= accepting;
+                state.processing_paused |= processing;
+            }
+            Command::Resume {
+                accepting,
+                processing,
+            } => {
+                state.accepting_paused &= !accepting;
+                state.processing_paused &= !processing;
+            }
+            Command::Drain => {
+                // Work in progress could not be finished otherwise.
+                state.processing_paused = false;
+                state.draining = true;
+            }
+        });
+        self.state()
+    }
+
+    /// Whether files announced by clients should be accepted.
+    pub(super) fn accepts_files(&self) -> bool {
+        let state = self.0.borrow();
+        !state.accepting_paused && !state.draining
+    }
+
+    /// Whether failed files should be processed again.
+    pub(super) fn retries_files(&self) -> bool {
+        let state = self.0.borrow();
+        !state.processing_paused && !state.draining
+    }
+
+    /// Wait until processing is not paused.
+    pub(super) async fn processing_allowed(&self) {
+        let mut state = self.0.subscribe();
+        // The sender lives as long as `self`, this cannot fail.
+        _ = state.wait_for(|state| !state.processing_paused).await;
+    }
+
+    /// Count a file as in progress until the returned guard is dropped.
+    pub(super) fn busy(&self) -> Busy {
+        self.0.send_modify(|state| state.busy += 1);
+        Busy(self.clone())
+    }
+
+    /// Wait until the server is draining and no file is in progress.
+    pub(super) async fn drained(&self) {
+        let mut state = self.0.subscribe();
+        _ = state
+            .wait_for(|state| state.draining && state.busy == 0)
+            .await;
+    }
+}
+
+/// See [`Controls::busy`].
+pub(super) struct Busy(Controls);
+
+impl Busy {
+    pub(super) fn controls(&self) -> &Controls {
+        &self.0
+    }
+}
+
+impl Drop for Busy {
+    fn drop(&mut self) {
+        self.0.0.send_modify(|state| state.busy -= 1);
+    }
+}
@@ -2,28 +2,61 @@ use std::sync::Arc;
 
 use tokio::io;
 
-use crate::server::Config;
+use crate::server::{Config, StorageLayout, database::Database};
 
 pub(crate) async fn main(config: Config) -> io::Result<()> {
     let config = Arc::new(config);
-    let mut handles = Vec::with_capacity(256);
-
-    for i in 0..256 {
-        let conf = config.clone();
-        let handle = tokio::spawn(async move {
-            for j in 0..256 {
-                let bucket = conf.incoming_path(format!("{i:02x}/{j:02x}"));
-                conf.create_dir_async(bucket)
-                    .await
-                    .expect("cannot create {bucket}");
-            }
-        });
-        handles.push(handle);
-    }
 
-    for handle in handles {
-        handle.await?;
+    if config.storage_layout == StorageLayout::ClientPaths {
+        println!("no bucket to create with the `client_paths` layout");
+    } else {
+        create_buckets(&config).await?;
     }
 
+    Database::create_if_missing(&config.database)
+        .await
+        .expect("failed to create database");
+    println!("database is ready");
+
+    Ok(())
+}
+
+async fn create_buckets(config: &Arc<Config>) -> io::Result<()> {
+    // With namespaces, buckets can only be created for the clients known
+    // in advance, those of other clients are created as files arrive.
+    let namespaces: Vec<String> = if config.namespace_by_client {
+        config
+            .known_clients()
+            .into_iter()
+            .map(|client| format!("{client}/"))
+            .collect()
+    } else {
+        vec![String::new()]
+    };
+
+    for namespace in &namespaces {
+        let mut handles = Vec::with_capacity(256);
+        for i in 0..256 {
+            let conf = config.clone();
+            let namespace = namespace.clone();
+            let handle = tokio::spawn(async move {
+                for j in 0..256 {
+                    let bucket = conf.incoming_path(format!("{namespace}{i:02x}/{j:02x}"));
+                    conf.create_dir_async(bucket)
+                        .await
+                        .expect("cannot create {bucket}");
+                }
+            });
+            handles.push(handle);
+        }
+
+        for handle in handles {
+            handle.await?;
+        }
+        println!(
+            "created buckets in {:?}",
+            config.incoming_path(namespace.as_str())
+        );
+    }
     Ok(())
 }
@@ -0,0 +1,181 @@
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    process::Stdio,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use log::warn;
+use tokio::process::Command;
+
+/// Start of files encrypted with age, other files are assumed to be OpenPGP.
+const AGE_MAGIC: &[u8] = b"age-encryption.org/";
+
+/// Create `path` readable by its owner only, decrypted files are plaintext.
+fn create_private(path: &Path) -> io::Result<File> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)
+}
+
+/// Whether `path` starts with the age magic bytes.
+fn is_age(path: &Path) -> io::Result<bool> {
+    let mut magic = [0; AGE_MAGIC.len()];
+    match File::open(path)?.read_exact(&mut magic) {
+        Ok(()) => Ok(magic == AGE_MAGIC),
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+fn age_error(err: impl std::fmt::Display) -> io::Error {
+    io::Error::other(format!("age: {err}"))
+}
+
+/// Encrypt `from` to `to` for `recipient`, an age public key (`age1...`) or
+/// a key known to GPG otherwise.
+pub(super) async fn encrypt(from: PathBuf, to: PathBuf, recipient: String) -> io::Result<()> {
+    if !recipient.starts_with("age1") {
+        let mut gpg = Command::new("gpg");
+        gpg.stdout(Stdio::null())
+            .args(["--batch", "--yes", "--trust-model", "always", "--recipient"])
+            .arg(&recipient)
+            .arg("--output")
+            .arg(&to)
+            .arg("--encrypt")
+            .arg(&from);
+        return run_gpg(gpg).await;
+    }
+    tokio::task::spawn_blocking(move || {
+        let recipient: age::x25519::Recipient = recipient.parse().map_err(age_error)?;
+        let encryptor =
+            age::Encryptor::with_recipients(std::iter::once(&recipient as &dyn age::Recipient))
+                .map_err(age_error)?;
+        let mut reader = BufReader::new(File::open(&from)?);
+        let mut writer = encryptor.wrap_output(BufWriter::new(File::create(&to)?))?;
+        io::copy(&mut reader, &mut writer)?;
+        writer.finish()?.flush()
+    })
+    .await?
+}
+
+/// Decrypt `from` to `to` with the secret key in `key`, an age identity file
+/// or an OpenPGP secret key without passphrase.
+pub(super) async fn decrypt(from: PathBuf, to: PathBuf, key: PathBuf) -> io::Result<()> {
+    let is_age = {
+        let from = from.clone();
+        tokio::task::spawn_blocking(move || is_age(&from)).await??
+    };
+    if !is_age {
+        return gpg_decrypt(&from, &to, &key).await;
+    }
+    tokio::task::spawn_blocking(move || {
+        let identities = age::IdentityFile::from_file(key.to_string_lossy().into_owned())?
+            .into_identities()
+            .map_err(age_error)?;
+        let decryptor =
+            age::Decryptor::new_buffered(BufReader::new(File::open(&from)?)).map_err(age_error)?;
+        let mut reader = decryptor
+            .decrypt(identities.iter().map(|i| i.as_ref() as &dyn age::Identity))
+            .map_err(age_error)?;
+        let mut writer = BufWriter::new(create_private(&to)?);
+        io::copy(&mut reader, &mut writer)?;
+        writer.flush()
+    })
+    .await?
+}
+
+/// Decrypt with GPG, importing `key` in a keyring of its own.
+async fn gpg_decrypt(from: &Path, to: &Path, key: &Path) -> io::Result<()> {
+    static COUNT: AtomicU64 = AtomicU64::new(0);
+    let count = COUNT.fetch_add(1, Ordering::Relaxed);
+    let home = std::env::temp_dir().join(format!("pipeline-gpg-{}-{count}", std::process::id()));
+    std::fs::create_dir_all(&home)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&home, std::fs::Permissions::from_mode(0o700))?;
+    }
+    let gpg = || {
+        let mut gpg = Command::new("gpg");
+        gpg.arg("--homedir").arg(&home).args(["--batch", "--yes"]);
+        gpg
+    };
+    let mut import = gpg();
+    import.stdout(Stdio::null()).arg("--import").arg(key);
+    let result = match run_gpg(import).await {
+        Ok(()) => match create_private(to) {
+            // Plaintext goes through stdout so that gpg never creates `to`
+            Ok(out) => {
+                let mut decrypt = gpg();
+                decrypt.stdout(out).arg("--decrypt").arg(from);
+                run_gpg(decrypt).await
+            }
+            Err(err) => Err(err),
+        },
+        Err(err) => Err(err),
+    };
+    if let Err(err) = std::fs::remove_dir_all(&home) {
+        warn!("failed to remove temporary keyring {home:?}: {err}");
+    }
+    result
+}
+
+async fn run_gpg(mut gpg: Command) -> io::Result<()> {
+    let output = gpg
+        .stdin(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()?
+        .wait_with_output()
+        .await?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::other(format!(
+            "gpg failed with status {:?}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use age::secrecy::ExposeSecret;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn age_round_trip() {
+        let dir = std::env::temp_dir().join(format!("pipeline-age-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let identity = age::x25519::Identity::generate();
+        let key = dir.join("identity.txt");
+        std::fs::write(&key, identity.to_string().expose_secret()).unwrap();
+        let plain = dir.join("file.dat");
+        std::fs::write(&plain, b"some content").unwrap();
+
+        let sealed = dir.join("file.dat.age");
+        let recipient = identity.to_public().to_string();
+        encrypt(plain.clone(), sealed.clone(), recipient)
+            .await
+            .unwrap();
+        let opened = dir.join("opened.dat");
+        decrypt(sealed, opened.clone(), key).await.unwrap();
+        assert_eq!(std::fs::read(&opened).unwrap(), b"some content");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&opened).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
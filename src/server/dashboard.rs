@@ -0,0 +1,131 @@
+use std::{
+    io,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use log::{debug, info, warn};
+use serde::Serialize;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::server::{
+    Config,
+    database::{Database, ProcessStatus},
+};
+
+static DASHBOARD_HTML: &str = include_str!("dashboard.html");
+
+/// Days of arrivals shown in the per-client throughput chart.
+const ARRIVAL_DAYS: u64 = 14;
+
+/// Longest request accepted, dashboard requests are only a few headers.
+const MAX_REQUEST_BYTES: usize = 8192;
+
+/// Time given to a client to send its request before the connection is dropped.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Serve the dashboard at `dashboard_address`, if set.
+pub(super) async fn serve(config: Arc<Config>, db: Database) -> io::Result<()> {
+    let Some(address) = &config.dashboard_address else {
+        return std::future::pending().await;
+    };
+    let listener = TcpListener::bind(address).await?;
+    info!("serving dashboard on http://{}", listener.local_addr()?);
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                // The dashboard is optional, it should not stop the server.
+                warn!("failed to accept dashboard connection: {err}");
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        let db = db.clone();
+        tokio::spawn(async move {
+            if let Err(err) = respond(stream, &db).await {
+                debug!("failed to answer dashboard request from {addr:?}: {err}");
+            }
+        });
+    }
+}
+
+/// Path requested by an HTTP GET request, or `None` for other methods.
+fn requested_path(request: &str) -> Option<&str> {
+    let mut words = request.lines().next()?.split_whitespace();
+    match (words.next(), words.next()) {
+        (Some("GET"), Some(target)) => Some(target.split('?').next().unwrap_or(target)),
+        _ => None,
+    }
+}
+
+async fn read_request(stream: &mut TcpStream) -> io::Result<String> {
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 || request.len() + n > MAX_REQUEST_BYTES {
+            break;
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+    Ok(String::from_utf8_lossy(&request).into_owned())
+}
+
+fn json<T: Serialize>(value: &T) -> io::Result<(&'static str, &'static str, Vec<u8>)> {
+    let body = serde_json::to_vec(value)?;
+    Ok(("200 OK", "application/json", body))
+}
+
+async fn respond(mut stream: TcpStream, db: &Database) -> io::Result<()> {
+    let request = tokio::time::timeout(REQUEST_TIMEOUT, read_request(&mut stream))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "no request received in time"))??;
+    let (status, content_type, body) = match requested_path(&request) {
+        None => (
+            "405 Method Not Allowed",
+            "text/plain",
+            b"only GET is supported\n".to_vec(),
+        ),
+        Some("/") => ("200 OK", "text/html; charset=utf-8", DASHBOARD_HTML.into()),
+        Some("/api/snapshot") => json(&db.snapshot().await.map_err(io::Error::other)?)?,
+        Some("/api/queue") => {
+            let queued = db.tasks_with_status(ProcessStatus::Queued).await;
+            json(&queued.map_err(io::Error::other)?)?
+        }
+        Some("/api/arrivals") => {
+            let since = SystemTime::now() - Duration::from_secs(ARRIVAL_DAYS * 24 * 3600);
+            let since = chrono::DateTime::<chrono::Utc>::from(since).format("%Y-%m-%d");
+            let arrivals = db.daily_arrivals(&since.to_string()).await;
+            json(&arrivals.map_err(io::Error::other)?)?
+        }
+        Some(path) => {
+            debug!("dashboard has no page {path:?}");
+            ("404 Not Found", "text/plain", b"not found\n".to_vec())
+        }
+    };
+    let header = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\n\
+        Cache-Control: no-store\r\nConnection: close\r\n\r\n",
+        body.len()
+    );
+    stream.write_all(header.as_bytes()).await?;
+    stream.write_all(&body).await?;
+    stream.shutdown().await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_request_path() {
+        let request = "GET /api/queue?t=1 HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        assert_eq!(requested_path(request), Some("/api/queue"));
+        assert_eq!(requested_path("POST / HTTP/1.1\r\n\r\n"), None);
+        assert_eq!(requested_path(""), None);
+    }
+}
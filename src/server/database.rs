@@ -1,20 +1,50 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    io,
+    path::Path,
+    time::Duration,
+};
+
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value as JsonValue};
 use sqlx::{
-    Pool, Result, Sqlite,
+    AssertSqlSafe, Pool, Result, Sqlite, SqliteConnection,
     prelude::{FromRow, Type},
-    sqlite::{SqliteConnectOptions, SqliteJournalMode, SqliteLockingMode, SqlitePoolOptions},
+    sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions},
 };
 use tabled::Tabled;
 
-use crate::{FileSpec, cli::MarkStatus, hashing::FileDigest};
+use crate::{
+    FileSpec,
+    cli::MarkStatus,
+    hashing::{FileDigest, HashMode},
+    server::{DatabaseConfig, processing::StepError},
+};
 
 static DB_FILENAME: &str = ".pipeline_server.db";
 
-#[derive(Serialize, Deserialize, Copy, Clone, Type, Debug)]
-pub(super) enum ProcessStatus {
+/// Row of a table, see [`Database::export_table`].
+pub(super) type JsonObject = Map<String, JsonValue>;
+
+/// Actor recorded in the audit log for actions taken by the server itself.
+pub(super) static SERVER_ACTOR: &str = "server";
+
+#[derive(clap::ValueEnum, Serialize, Deserialize, Copy, Clone, PartialEq, Eq, Type, Debug)]
+pub(crate) enum ProcessStatus {
+    /// Announced by the client, not sent yet.
     AwaitFromClient,
+    /// Being copied by the client to the incoming directory.
+    Receiving,
+    /// Arrived, its hash is being checked against the announced one.
+    Verifying,
+    /// Not delivered within `await_ttl_secs` of its announcement.
+    Expired,
+    /// Arrived and verified, waiting for a processing slot.
+    Queued,
     Processing,
     Failed,
+    /// Failed too many times, not retried anymore.
+    Abandoned,
     Done,
     ToPrune,
 }
@@ -24,6 +54,7 @@ impl From<MarkStatus> for ProcessStatus {
         match value {
             MarkStatus::Done => ProcessStatus::Done,
             MarkStatus::Failed => ProcessStatus::Failed,
+            MarkStatus::Abandoned => ProcessStatus::Abandoned,
             MarkStatus::ToPrune => ProcessStatus::ToPrune,
         }
     }
@@ -31,7 +62,7 @@ impl From<MarkStatus> for ProcessStatus {
 
 #[derive(FromRow, Tabled, Serialize, Deserialize)]
 pub(super) struct FileInPipeline {
-    hash: String,
+    pub(super) hash: String,
     full_hash: bool,
     client: String,
     date_utc: String,
@@ -39,16 +70,225 @@ pub(super) struct FileInPipeline {
     file_name: String,
     processing: String,
     #[tabled(format = "{:?}")]
+    pub(super) status: ProcessStatus,
+    size_bytes: i64,
+    modified_utc: String,
+    /// Client metadata as a JSON object.
+    metadata: String,
+    companion: String,
+    /// Whether a shallow hash also samples the middle and tail of the file.
+    sampled: bool,
+    /// Number of bytes per sample of a shallow hash.
+    sample_bytes: i64,
+    /// Processing attempts since the file arrived or was last marked.
+    attempts: i64,
+    /// Whether the file is kept in the pipeline regardless of its status.
+    pinned: bool,
+    /// Tags attached to the file as a JSON array.
+    tags: String,
+    /// Path of the file relative to the incoming directory, empty until it is
+    /// claimed, see [`Database::set_storage_path`].
+    #[serde(default)]
+    #[tabled(skip)]
+    pub(super) storage_path: String,
+    /// Whether the stored file is encrypted, see [`Database::set_encrypted`].
+    #[serde(default)]
+    #[sqlx(default)]
+    #[tabled(skip)]
+    pub(super) encrypted: bool,
+    /// Duration of the last completed processing attempt, only filled by
+    /// [`Database::content`].
+    #[sqlx(default)]
+    #[tabled(display = "display_option")]
+    processing_secs: Option<i64>,
+}
+
+fn display_option(value: &Option<i64>) -> String {
+    value.map(|value| value.to_string()).unwrap_or_default()
+}
+
+impl FileInPipeline {
+    pub(super) fn tag_list(&self) -> Vec<String> {
+        serde_json::from_str(&self.tags).unwrap_or_default()
+    }
+
+    pub(super) fn has_tag(&self, tag: &str) -> bool {
+        self.tag_list().iter().any(|t| t == tag)
+    }
+}
+
+/// Restricts the files considered for pruning.
+#[derive(Default)]
+pub(crate) struct PruneFilter {
+    pub(crate) client: Option<String>,
+    /// Only files announced at least this long ago.
+    pub(crate) older_than: Option<Duration>,
+    /// Also consider pinned files and members of batches not processed yet,
+    /// which are otherwise never pruned.
+    pub(crate) include_pinned: bool,
+    /// Only files with this tag.
+    pub(crate) tag: Option<String>,
+}
+
+/// File that would be pruned, see [`Database::prune_candidates`].
+#[derive(FromRow)]
+pub(super) struct PruneCandidate {
+    pub(super) hash: String,
+    pub(super) client: String,
+    pub(super) size_bytes: i64,
+    /// Time elapsed since the file was announced.
+    pub(super) age_secs: i64,
+}
+
+/// Event in the history of a file, see [`Database::history`].
+#[derive(FromRow, Tabled, Serialize)]
+pub(super) struct AuditEntry {
+    date_utc: String,
+    actor: String,
+    event: String,
+}
+
+/// Processing attempt of a file, see [`Database::attempts`].
+#[derive(FromRow, Tabled, Serialize)]
+pub(super) struct Attempt {
+    start_utc: String,
+    end_utc: String,
+    duration_secs: String,
+    /// Duration in seconds of each step that ran, as a JSON array.
+    step_secs: String,
+    failed_step: String,
+    exit_code: String,
+    error: String,
+}
+
+/// File matching a search, see [`Database::search`].
+#[derive(FromRow, Tabled)]
+pub(super) struct SearchHit {
+    hash: String,
+    client: String,
+    path: String,
+    file_name: String,
+    #[tabled(format = "{:?}")]
     status: ProcessStatus,
+    tags: String,
+}
+
+/// Processing client known to the server, see [`Database::clients`].
+#[derive(FromRow)]
+pub(super) struct ClientInfo {
+    pub(super) name: String,
+    pub(super) address: String,
+    pub(super) connected: bool,
+    pub(super) connected_utc: String,
+    pub(super) last_seen_utc: String,
+    pub(super) last_delivery_utc: Option<String>,
+    /// Seconds since the last delivery.
+    pub(super) silent_secs: Option<i64>,
+}
+
+/// Files that arrived from a client on a given day, see
+/// [`Database::daily_arrivals`].
+#[derive(FromRow, Serialize)]
+pub(super) struct DailyArrivals {
+    pub(super) day: String,
+    pub(super) client: String,
+    pub(super) files: i64,
+    pub(super) size_bytes: i64,
+}
+
+/// Processing attempts of a group, see [`Database::processing_stats`].
+#[derive(FromRow)]
+pub(super) struct ProcessingStats {
+    pub(super) processing: String,
+    pub(super) attempts: i64,
+    pub(super) failures: i64,
+    /// Average duration of successful attempts.
+    pub(super) avg_secs: Option<f64>,
+}
+
+/// Failures of a processing step, see [`Database::step_failures`].
+#[derive(FromRow)]
+pub(super) struct StepFailures {
+    pub(super) processing: String,
+    pub(super) step: i64,
+    pub(super) failures: i64,
+}
+
+/// Client that sent a file, see [`Database::origins`].
+#[derive(FromRow, Serialize)]
+pub(super) struct Origin {
+    client: String,
+    path: String,
+    file_name: String,
+    /// Name of the companion sent along, empty if none.
+    companion: String,
+    date_utc: String,
+}
+
+/// Origin of a file sent along with a companion, see
+/// [`Database::companion_origins`].
+#[derive(FromRow)]
+pub(super) struct CompanionOrigin {
+    pub(super) hash: String,
+    client: String,
+    path: String,
+    file_name: String,
+    companion: String,
+    /// Storage path of the file, see [`FileInPipeline::storage_path`].
+    pub(super) storage_path: String,
+}
+
+impl CompanionOrigin {
+    /// `file` as sent from this origin, which locates its companion.
+    pub(super) fn spec_of(&self, file: &FileSpec) -> FileSpec {
+        FileSpec {
+            client: self.client.clone(),
+            path: self.path.clone(),
+            filename: self.file_name.clone(),
+            companion: Some(self.companion.clone()),
+            ..file.clone()
+        }
+    }
+}
+
+/// Overview of the pipeline activity, displayed by `server top`.
+#[derive(Serialize, Deserialize)]
+pub(super) struct Snapshot {
+    /// Number of files per status.
+    pub(super) counts: Vec<(String, i64)>,
+    pub(super) processing: Vec<ProcessingEntry>,
+    pub(super) recent_failures: Vec<FailureEntry>,
+    /// Number of files announced per client in the last hour.
+    pub(super) throughput: Vec<(String, i64)>,
+}
+
+#[derive(FromRow, Serialize, Deserialize)]
+pub(super) struct ProcessingEntry {
+    pub(super) hash: String,
+    pub(super) client: String,
+    pub(super) file_name: String,
+    pub(super) elapsed_secs: i64,
+}
+
+#[derive(FromRow, Serialize, Deserialize)]
+pub(super) struct FailureEntry {
+    pub(super) hash: String,
+    pub(super) client: String,
+    pub(super) file_name: String,
+    pub(super) date_utc: String,
+    pub(super) error: String,
 }
 
 impl From<FileInPipeline> for FileSpec {
     fn from(value: FileInPipeline) -> Self {
         let hash = value.hash;
+        let sample_bytes = value.sample_bytes as u64;
         let sha256_digest = if value.full_hash {
             FileDigest::Full(hash)
+        } else if value.sampled {
+            FileDigest::Sampled { hash, sample_bytes }
         } else {
-            FileDigest::Shallow(hash)
+            FileDigest::Shallow { hash, sample_bytes }
         };
         Self {
             client: value.client,
@@ -56,43 +296,137 @@ impl From<FileInPipeline> for FileSpec {
             filename: value.file_name,
             processing: value.processing,
             sha256_digest,
+            size_bytes: value.size_bytes as u64,
+            modified_utc: value.modified_utc,
+            metadata: serde_json::from_str(&value.metadata).unwrap_or_default(),
+            companion: Some(value.companion).filter(|c| !c.is_empty()),
         }
     }
 }
 
+impl ProcessStatus {
+    /// Whether the file has been announced but has not fully arrived yet.
+    /// Expired files are awaited again if they are announced anew.
+    pub(super) fn awaits_arrival(self) -> bool {
+        matches!(
+            self,
+            ProcessStatus::AwaitFromClient
+                | ProcessStatus::Receiving
+                | ProcessStatus::Verifying
+                | ProcessStatus::Expired
+        )
+    }
+}
+
 impl AsRef<str> for ProcessStatus {
     fn as_ref(&self) -> &str {
         match self {
             ProcessStatus::AwaitFromClient => "AwaitFromClient",
+            ProcessStatus::Receiving => "Receiving",
+            ProcessStatus::Verifying => "Verifying",
+            ProcessStatus::Expired => "Expired",
+            ProcessStatus::Queued => "Queued",
             ProcessStatus::Processing => "Processing",
             ProcessStatus::Failed => "Failed",
+            ProcessStatus::Abandoned => "Abandoned",
             ProcessStatus::Done => "Done",
             ProcessStatus::ToPrune => "ToPrune",
         }
     }
 }
 
+/// Add a column to a table created by an older version of pipeline.
+async fn add_column_if_missing(
+    pool: &Pool<Sqlite>,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> Result<()> {
+    let exists: bool =
+        sqlx::query_scalar("SELECT EXISTS(SELECT 1 FROM pragma_table_info($1) WHERE name = $2);")
+            .bind(table)
+            .bind(column)
+            .fetch_one(pool)
+            .await?;
+    if !exists {
+        // Only called with static table and column definitions.
+        let query = AssertSqlSafe(format!(
+            "ALTER TABLE {table} ADD COLUMN {column} {definition};"
+        ));
+        sqlx::query(query).execute(pool).await?;
+    }
+    Ok(())
+}
+
 #[derive(Clone)]
 pub(super) struct Database(Pool<Sqlite>);
 
 impl Database {
-    pub(super) async fn create_if_missing(wal: bool) -> Result<Self> {
-        let journal_mode = if wal {
+    pub(super) async fn create_if_missing(config: &DatabaseConfig) -> Result<Self> {
+        Self::open(config, true).await
+    }
+
+    /// Open the database in the current directory, failing if there is none
+    /// rather than creating an empty one.
+    pub(super) async fn open_existing(config: &DatabaseConfig) -> Result<Self> {
+        if !Path::new(DB_FILENAME).exists() {
+            return Err(sqlx::Error::Io(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no database `{DB_FILENAME}` in the current directory"),
+            )));
+        }
+        Self::open(config, false).await
+    }
+
+    async fn open(config: &DatabaseConfig, create: bool) -> Result<Self> {
+        let journal_mode = if config.wal {
             SqliteJournalMode::Wal
         } else {
             SqliteJournalMode::Truncate
         };
         let pool = SqlitePoolOptions::new()
-            .max_connections(1)
+            .max_connections(config.max_connections)
             .connect_with(
                 SqliteConnectOptions::new()
                     .filename(DB_FILENAME)
-                    .locking_mode(SqliteLockingMode::Exclusive)
                     .journal_mode(journal_mode)
+                    .busy_timeout(Duration::from_secs(config.busy_timeout_secs))
+                    .create_if_missing(create),
+            )
+            .await?;
+        Self::init(pool).await
+    }
+
+    /// Database only held in memory, for tests.
+    #[cfg(test)]
+    pub(super) async fn in_memory() -> Result<Self> {
+        // The database vanishes with its single connection.
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .idle_timeout(None)
+            .max_lifetime(None)
+            .connect("sqlite::memory:")
+            .await?;
+        Self::init(pool).await
+    }
+
+    /// Database stored at `path`, for tests that need it on disk, such as
+    /// backups which `VACUUM INTO` keeps in memory for in-memory databases.
+    #[cfg(test)]
+    pub(super) async fn at_path(path: &Path) -> Result<Self> {
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect_with(
+                SqliteConnectOptions::new()
+                    .filename(path)
                     .create_if_missing(true),
             )
             .await?;
+        Self::init(pool).await
+    }
 
+    /// Create missing tables and columns.
+    async fn init(pool: Pool<Sqlite>) -> Result<Self> {
         sqlx::query(
             "CREATE TABLE IF NOT EXISTS files_in_pipeline (
                 hash TEXT PRIMARY KEY,
@@ -102,92 +436,1764 @@ impl Database {
                 path TEXT NOT NULL,
                 file_name TEXT NOT NULL,
                 processing TEXT NOT NULL,
-                status TEXT NOT NULL
+                status TEXT NOT NULL,
+                size_bytes INTEGER NOT NULL DEFAULT 0,
+                modified_utc TEXT NOT NULL DEFAULT '',
+                metadata TEXT NOT NULL DEFAULT '{}',
+                companion TEXT NOT NULL DEFAULT '',
+                sampled INTEGER NOT NULL DEFAULT 0,
+                sample_bytes INTEGER NOT NULL DEFAULT 1048576
+            ) STRICT;",
+        )
+        .execute(&pool)
+        .await?;
+        add_column_if_missing(
+            &pool,
+            "files_in_pipeline",
+            "size_bytes",
+            "INTEGER NOT NULL DEFAULT 0",
+        )
+        .await?;
+        add_column_if_missing(
+            &pool,
+            "files_in_pipeline",
+            "modified_utc",
+            "TEXT NOT NULL DEFAULT ''",
+        )
+        .await?;
+        add_column_if_missing(
+            &pool,
+            "files_in_pipeline",
+            "metadata",
+            "TEXT NOT NULL DEFAULT '{}'",
+        )
+        .await?;
+        add_column_if_missing(
+            &pool,
+            "files_in_pipeline",
+            "companion",
+            "TEXT NOT NULL DEFAULT ''",
+        )
+        .await?;
+        add_column_if_missing(
+            &pool,
+            "files_in_pipeline",
+            "sampled",
+            "INTEGER NOT NULL DEFAULT 0",
+        )
+        .await?;
+        add_column_if_missing(
+            &pool,
+            "files_in_pipeline",
+            "sample_bytes",
+            "INTEGER NOT NULL DEFAULT 1048576",
+        )
+        .await?;
+        add_column_if_missing(
+            &pool,
+            "files_in_pipeline",
+            "attempts",
+            "INTEGER NOT NULL DEFAULT 0",
+        )
+        .await?;
+        add_column_if_missing(
+            &pool,
+            "files_in_pipeline",
+            "pinned",
+            "INTEGER NOT NULL DEFAULT 0",
+        )
+        .await?;
+        add_column_if_missing(
+            &pool,
+            "files_in_pipeline",
+            "tags",
+            "TEXT NOT NULL DEFAULT '[]'",
+        )
+        .await?;
+        add_column_if_missing(
+            &pool,
+            "files_in_pipeline",
+            "storage_path",
+            "TEXT NOT NULL DEFAULT ''",
+        )
+        .await?;
+        add_column_if_missing(
+            &pool,
+            "files_in_pipeline",
+            "encrypted",
+            "INTEGER NOT NULL DEFAULT 0",
+        )
+        .await?;
+
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS file_origins (
+                hash TEXT NOT NULL,
+                client TEXT NOT NULL,
+                path TEXT NOT NULL,
+                file_name TEXT NOT NULL,
+                date_utc TEXT NOT NULL,
+                PRIMARY KEY (hash, client, path, file_name)
+            ) STRICT;",
+        )
+        .execute(&pool)
+        .await?;
+        add_column_if_missing(
+            &pool,
+            "file_origins",
+            "companion",
+            "TEXT NOT NULL DEFAULT ''",
+        )
+        .await?;
+
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS audit (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                hash TEXT NOT NULL,
+                date_utc TEXT NOT NULL,
+                actor TEXT NOT NULL,
+                event TEXT NOT NULL
+            ) STRICT;",
+        )
+        .execute(&pool)
+        .await?;
+        sqlx::query("CREATE INDEX IF NOT EXISTS audit_hash ON audit (hash);")
+            .execute(&pool)
+            .await?;
+        sqlx::query("CREATE INDEX IF NOT EXISTS audit_date ON audit (date_utc);")
+            .execute(&pool)
+            .await?;
+
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS attempts (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                hash TEXT NOT NULL,
+                start_utc TEXT NOT NULL,
+                end_utc TEXT,
+                failed_step INTEGER,
+                exit_code INTEGER,
+                error TEXT,
+                step_secs TEXT
+            ) STRICT;",
+        )
+        .execute(&pool)
+        .await?;
+        add_column_if_missing(&pool, "attempts", "step_secs", "TEXT").await?;
+
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS batches (
+                processing TEXT NOT NULL,
+                name TEXT NOT NULL,
+                status TEXT NOT NULL,
+                PRIMARY KEY (processing, name)
+            ) STRICT;",
+        )
+        .execute(&pool)
+        .await?;
+
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS batch_members (
+                processing TEXT NOT NULL,
+                name TEXT NOT NULL,
+                hash TEXT NOT NULL,
+                PRIMARY KEY (processing, name, hash)
+            ) STRICT;",
+        )
+        .execute(&pool)
+        .await?;
+
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS arrivals (
+                hash TEXT NOT NULL,
+                client TEXT NOT NULL,
+                processing TEXT NOT NULL,
+                size_bytes INTEGER NOT NULL,
+                date_utc TEXT NOT NULL
+            ) STRICT;",
+        )
+        .execute(&pool)
+        .await?;
+        sqlx::query("CREATE INDEX IF NOT EXISTS arrivals_hash ON arrivals (hash, client);")
+            .execute(&pool)
+            .await?;
+
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS clients (
+                name TEXT PRIMARY KEY,
+                address TEXT NOT NULL,
+                connections INTEGER NOT NULL,
+                connected_utc TEXT NOT NULL,
+                last_seen_utc TEXT NOT NULL
+            ) STRICT;",
+        )
+        .execute(&pool)
+        .await?;
+
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS archived (
+                hash TEXT PRIMARY KEY,
+                file TEXT NOT NULL,
+                location TEXT NOT NULL,
+                date_utc TEXT NOT NULL
+            ) STRICT;",
+        )
+        .execute(&pool)
+        .await?;
+
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS requests (
+                client TEXT NOT NULL,
+                hash TEXT NOT NULL,
+                last_id INTEGER NOT NULL,
+                PRIMARY KEY (client, hash)
             ) STRICT;",
         )
         .execute(&pool)
         .await?;
 
+        track_changes(&pool).await?;
         Ok(Self(pool))
     }
 
-    pub(super) async fn tasks_with_status(
+    /// Add a member to a batch, returning whether this completes the batch.
+    ///
+    /// A batch is only reported complete once, at which point it is marked
+    /// as `Processing`.
+    pub(super) async fn add_to_batch(
         &self,
-        status: ProcessStatus,
-    ) -> Result<Vec<FileInPipeline>> {
-        sqlx::query_as("SELECT * FROM files_in_pipeline WHERE status = $1;")
-            .bind(status.as_ref())
-            .fetch_all(&self.0)
-            .await
+        processing: &str,
+        name: &str,
+        hash: &str,
+        size: u32,
+    ) -> Result<bool> {
+        let mut tx = self.0.begin().await?;
+        sqlx::query(
+            "INSERT OR IGNORE INTO batches (processing, name, status)
+            VALUES ($1, $2, 'AwaitFromClient');",
+        )
+        .bind(processing)
+        .bind(name)
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query(
+            "INSERT OR IGNORE INTO batch_members (processing, name, hash)
+            VALUES ($1, $2, $3);",
+        )
+        .bind(processing)
+        .bind(name)
+        .bind(hash)
+        .execute(&mut *tx)
+        .await?;
+        let started = sqlx::query(
+            "UPDATE batches SET status = 'Processing'
+            WHERE processing = $1 AND name = $2 AND status = 'AwaitFromClient'
+                AND (SELECT COUNT(*) FROM batch_members
+                    WHERE processing = $1 AND name = $2) >= $3;",
+        )
+        .bind(processing)
+        .bind(name)
+        .bind(size)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+        tx.commit().await?;
+        Ok(started > 0)
     }
 
-    pub(super) async fn status(&self, hash: &str) -> Result<ProcessStatus> {
-        sqlx::query_scalar("SELECT status FROM files_in_pipeline WHERE hash = $1;")
-            .bind(hash)
-            .fetch_one(&self.0)
-            .await
+    pub(super) async fn batch_members(
+        &self,
+        processing: &str,
+        name: &str,
+    ) -> Result<Vec<FileInPipeline>> {
+        sqlx::query_as(
+            "SELECT f.* FROM files_in_pipeline f
+            JOIN batch_members b ON b.hash = f.hash
+            WHERE b.processing = $1 AND b.name = $2
+            ORDER BY f.path, f.file_name;",
+        )
+        .bind(processing)
+        .bind(name)
+        .fetch_all(&self.0)
+        .await
     }
 
-    pub(super) async fn contains(&self, hash: &str) -> Result<bool> {
-        sqlx::query_scalar("SELECT EXISTS(SELECT 1 FROM files_in_pipeline WHERE hash = $1);")
-            .bind(hash)
-            .fetch_one(&self.0)
+    /// Batches of all groups with `status`, as group and batch names.
+    pub(super) async fn batches_with_status(
+        &self,
+        status: ProcessStatus,
+    ) -> Result<Vec<(String, String)>> {
+        sqlx::query_as("SELECT processing, name FROM batches WHERE status = $1;")
+            .bind(status.as_ref())
+            .fetch_all(&self.0)
             .await
     }
 
-    pub(super) async fn insert_new(&self, file: &FileSpec) -> Result<()> {
-        sqlx::query(
-            "INSERT INTO files_in_pipeline
-            (hash, full_hash, client, date_utc, path, file_name, processing, status)
-            VALUES ($1, $2, $3, datetime('now'), $4, $5, $6, $7);",
+    /// Mark a failed batch as `Processing` again, returning whether it was
+    /// failed.
+    pub(super) async fn retry_batch(&self, processing: &str, name: &str) -> Result<bool> {
+        let retried = sqlx::query(
+            "UPDATE batches SET status = 'Processing'
+            WHERE processing = $1 AND name = $2 AND status = 'Failed';",
         )
-        .bind(file.hash())
-        .bind(file.sha256_digest.is_full())
-        .bind(&file.client)
-        .bind(&file.path)
-        .bind(&file.filename)
-        .bind(&file.processing)
-        .bind(ProcessStatus::AwaitFromClient.as_ref())
+        .bind(processing)
+        .bind(name)
         .execute(&self.0)
-        .await?;
+        .await?
+        .rows_affected();
+        Ok(retried > 0)
+    }
+
+    pub(super) async fn update_batch_status(
+        &self,
+        processing: &str,
+        name: &str,
+        status: ProcessStatus,
+    ) -> Result<()> {
+        sqlx::query("UPDATE batches SET status = $3 WHERE processing = $1 AND name = $2;")
+            .bind(processing)
+            .bind(name)
+            .bind(status.as_ref())
+            .execute(&self.0)
+            .await?;
         Ok(())
     }
 
-    pub(super) async fn update_status(&self, hash: &str, status: ProcessStatus) -> Result<()> {
-        sqlx::query(
-            "UPDATE files_in_pipeline
-            SET date_utc = datetime('now'), status = $2
-            WHERE hash = $1;",
+    /// Record the start of a processing attempt, returning its id.
+    pub(super) async fn start_attempt(&self, hash: &str) -> Result<i64> {
+        sqlx::query("UPDATE files_in_pipeline SET attempts = attempts + 1 WHERE hash = $1;")
+            .bind(hash)
+            .execute(&self.0)
+            .await?;
+        sqlx::query_scalar(
+            "INSERT INTO attempts (hash, start_utc)
+            VALUES ($1, datetime('now'))
+            RETURNING id;",
         )
         .bind(hash)
-        .bind(status.as_ref())
-        .execute(&self.0)
-        .await?;
-        Ok(())
+        .fetch_one(&self.0)
+        .await
     }
 
-    pub(super) async fn mark_done_to_prune(&self) -> Result<()> {
+    pub(super) async fn end_attempt(
+        &self,
+        id: i64,
+        failure: Option<&StepError>,
+        step_secs: &[f64],
+    ) -> Result<()> {
+        // Millisecond precision is plenty to compare step durations.
+        let step_secs: Vec<_> = step_secs
+            .iter()
+            .map(|secs| (secs * 1000.0).round() / 1000.0)
+            .collect();
         sqlx::query(
-            "UPDATE files_in_pipeline
-            SET status = 'ToPrune'
-            WHERE status = 'Done';",
+            "UPDATE attempts
+            SET end_utc = datetime('now'), failed_step = $2, exit_code = $3, error = $4,
+                step_secs = $5
+            WHERE id = $1;",
         )
+        .bind(id)
+        .bind(failure.map(|f| f.step as i64))
+        .bind(failure.and_then(|f| f.exit_code))
+        .bind(failure.map(|f| f.error.to_string()))
+        .bind(serde_json::to_string(&step_secs).expect("durations should serialize"))
         .execute(&self.0)
         .await?;
         Ok(())
     }
 
-    pub(super) async fn remove(&self, hash: &str) -> Result<()> {
-        sqlx::query("DELETE FROM files_in_pipeline WHERE hash = $1;")
+    /// Number of processing attempts since the counter was last reset.
+    pub(super) async fn attempt_count(&self, hash: &str) -> Result<u32> {
+        sqlx::query_scalar("SELECT attempts FROM files_in_pipeline WHERE hash = $1;")
+            .bind(hash)
+            .fetch_one(&self.0)
+            .await
+    }
+
+    pub(super) async fn reset_attempt_count(&self, hash: &str) -> Result<()> {
+        sqlx::query("UPDATE files_in_pipeline SET attempts = 0 WHERE hash = $1;")
             .bind(hash)
             .execute(&self.0)
             .await?;
         Ok(())
     }
 
-    pub(super) async fn content(&self) -> Result<Vec<FileInPipeline>> {
-        sqlx::query_as("SELECT * FROM files_in_pipeline;")
+    pub(super) async fn attempts(&self, hash: &str) -> Result<Vec<Attempt>> {
+        sqlx::query_as(
+            "SELECT start_utc,
+                COALESCE(end_utc, '') AS end_utc,
+                COALESCE(CAST(unixepoch(end_utc) - unixepoch(start_utc) AS TEXT), '')
+                    AS duration_secs,
+                COALESCE(step_secs, '') AS step_secs,
+                COALESCE(CAST(failed_step AS TEXT), '') AS failed_step,
+                COALESCE(CAST(exit_code AS TEXT), '') AS exit_code,
+                COALESCE(error, '') AS error
+            FROM attempts WHERE hash = $1 ORDER BY id;",
+        )
+        .bind(hash)
+        .fetch_all(&self.0)
+        .await
+    }
+
+    /// Append an event to the audit log, which is never pruned.
+    pub(super) async fn audit(&self, hash: &str, actor: &str, event: &str) -> Result<()> {
+        let mut conn = self.0.acquire().await?;
+        audit_in(&mut conn, hash, actor, event).await
+    }
+
+    pub(super) async fn history(&self, hash: &str) -> Result<Vec<AuditEntry>> {
+        sqlx::query_as("SELECT date_utc, actor, event FROM audit WHERE hash = $1 ORDER BY id;")
+            .bind(hash)
             .fetch_all(&self.0)
             .await
     }
+
+    pub(super) async fn tasks_with_status(
+        &self,
+        status: ProcessStatus,
+    ) -> Result<Vec<FileInPipeline>> {
+        sqlx::query_as("SELECT * FROM files_in_pipeline WHERE status = $1;")
+            .bind(status.as_ref())
+            .fetch_all(&self.0)
+            .await
+    }
+
+    /// Files without a recorded size, such as those announced before sizes
+    /// were recorded.
+    pub(super) async fn unsized_files(&self) -> Result<Vec<FileInPipeline>> {
+        sqlx::query_as("SELECT * FROM files_in_pipeline WHERE size_bytes = 0;")
+            .fetch_all(&self.0)
+            .await
+    }
+
+    /// Files whose storage path was never recorded, announced by an older
+    /// version of pipeline.
+    pub(super) async fn unclaimed_files(&self) -> Result<Vec<FileInPipeline>> {
+        sqlx::query_as("SELECT * FROM files_in_pipeline WHERE storage_path = '';")
+            .fetch_all(&self.0)
+            .await
+    }
+
+    /// Path of the file relative to the incoming directory, recorded when it
+    /// was claimed. This is `None` if the file is unknown or not claimed yet.
+    pub(super) async fn storage_path(&self, hash: &str) -> Result<Option<String>> {
+        let path: Option<String> =
+            sqlx::query_scalar("SELECT storage_path FROM files_in_pipeline WHERE hash = $1;")
+                .bind(hash)
+                .fetch_optional(&self.0)
+                .await?;
+        Ok(path.filter(|path| !path.is_empty()))
+    }
+
+    /// Record where the file is stored, relative to the incoming directory.
+    pub(super) async fn set_storage_path(&self, hash: &str, path: &str) -> Result<()> {
+        sqlx::query("UPDATE files_in_pipeline SET storage_path = $2 WHERE hash = $1;")
+            .bind(hash)
+            .bind(path)
+            .execute(&self.0)
+            .await?;
+        Ok(())
+    }
+
+    /// Record that the stored file of `hash` is encrypted, failing if there is
+    /// no such file.
+    pub(super) async fn set_encrypted(&self, hash: &str) -> Result<()> {
+        let updated = sqlx::query("UPDATE files_in_pipeline SET encrypted = 1 WHERE hash = $1;")
+            .bind(hash)
+            .execute(&self.0)
+            .await?
+            .rows_affected();
+        if updated == 0 {
+            return Err(sqlx::Error::RowNotFound);
+        }
+        Ok(())
+    }
+
+    /// Whether the stored file of `hash` is encrypted, false if there is no
+    /// such file.
+    pub(super) async fn is_encrypted(&self, hash: &str) -> Result<bool> {
+        let encrypted =
+            sqlx::query_scalar("SELECT encrypted FROM files_in_pipeline WHERE hash = $1;")
+                .bind(hash)
+                .fetch_optional(&self.0)
+                .await?;
+        Ok(encrypted.unwrap_or(false))
+    }
+
+    pub(super) async fn set_size(&self, hash: &str, size_bytes: u64) -> Result<()> {
+        sqlx::query("UPDATE files_in_pipeline SET size_bytes = $2 WHERE hash = $1;")
+            .bind(hash)
+            .bind(size_bytes as i64)
+            .execute(&self.0)
+            .await?;
+        Ok(())
+    }
+
+    pub(super) async fn tasks_to_prune(
+        &self,
+        status: ProcessStatus,
+        filter: &PruneFilter,
+    ) -> Result<Vec<FileInPipeline>> {
+        sqlx::query_as(
+            "SELECT * FROM files_in_pipeline
+            WHERE status = $1 AND ($2 IS NULL OR client = $2)
+                AND ($3 IS NULL OR unixepoch(date_utc) <= unixepoch('now') - $3)
+                AND ($4 OR (NOT pinned AND NOT EXISTS (
+                    SELECT 1 FROM batch_members m JOIN batches b
+                        ON b.processing = m.processing AND b.name = m.name
+                    WHERE m.hash = files_in_pipeline.hash AND b.status != 'Done'
+                )))
+                AND ($5 IS NULL OR EXISTS (SELECT 1 FROM json_each(tags) WHERE value = $5));",
+        )
+        .bind(status.as_ref())
+        .bind(&filter.client)
+        .bind(filter.older_than.map(|d| d.as_secs() as i64))
+        .bind(filter.include_pinned)
+        .bind(&filter.tag)
+        .fetch_all(&self.0)
+        .await
+    }
+
+    /// Files with any of `statuses`, the least recently announced first.
+    pub(super) async fn oldest_tasks(
+        &self,
+        statuses: &[ProcessStatus],
+        filter: &PruneFilter,
+    ) -> Result<Vec<FileInPipeline>> {
+        let statuses: Vec<&str> = statuses.iter().map(AsRef::as_ref).collect();
+        let statuses = serde_json::to_string(&statuses).expect("statuses should serialize");
+        sqlx::query_as(
+            "SELECT * FROM files_in_pipeline
+            WHERE status IN (SELECT value FROM json_each($1)) AND ($2 IS NULL OR client = $2)
+                AND ($3 IS NULL OR unixepoch(date_utc) <= unixepoch('now') - $3)
+                AND ($4 OR (NOT pinned AND NOT EXISTS (
+                    SELECT 1 FROM batch_members m JOIN batches b
+                        ON b.processing = m.processing AND b.name = m.name
+                    WHERE m.hash = files_in_pipeline.hash AND b.status != 'Done'
+                )))
+                AND ($5 IS NULL OR EXISTS (SELECT 1 FROM json_each(tags) WHERE value = $5))
+            ORDER BY date_utc;",
+        )
+        .bind(statuses)
+        .bind(&filter.client)
+        .bind(filter.older_than.map(|d| d.as_secs() as i64))
+        .bind(filter.include_pinned)
+        .bind(&filter.tag)
+        .fetch_all(&self.0)
+        .await
+    }
+
+    pub(super) async fn prune_candidates(
+        &self,
+        status: ProcessStatus,
+        filter: &PruneFilter,
+    ) -> Result<Vec<PruneCandidate>> {
+        sqlx::query_as(
+            "SELECT hash, client, size_bytes,
+                unixepoch('now') - unixepoch(date_utc) AS age_secs
+            FROM files_in_pipeline
+            WHERE status = $1 AND ($2 IS NULL OR client = $2)
+                AND ($3 IS NULL OR unixepoch(date_utc) <= unixepoch('now') - $3)
+                AND ($4 OR (NOT pinned AND NOT EXISTS (
+                    SELECT 1 FROM batch_members m JOIN batches b
+                        ON b.processing = m.processing AND b.name = m.name
+                    WHERE m.hash = files_in_pipeline.hash AND b.status != 'Done'
+                )))
+                AND ($5 IS NULL OR EXISTS (SELECT 1 FROM json_each(tags) WHERE value = $5))
+            ORDER BY date_utc;",
+        )
+        .bind(status.as_ref())
+        .bind(&filter.client)
+        .bind(filter.older_than.map(|d| d.as_secs() as i64))
+        .bind(filter.include_pinned)
+        .bind(&filter.tag)
+        .fetch_all(&self.0)
+        .await
+    }
+
+    pub(super) async fn status(&self, hash: &str) -> Result<ProcessStatus> {
+        sqlx::query_scalar("SELECT status FROM files_in_pipeline WHERE hash = $1;")
+            .bind(hash)
+            .fetch_one(&self.0)
+            .await
+    }
+
+    /// Statuses of the files among `hashes` that are in the pipeline.
+    pub(super) async fn statuses(&self, hashes: &[&str]) -> Result<HashMap<String, ProcessStatus>> {
+        let hashes = serde_json::to_string(hashes).expect("hashes should serialize");
+        let rows: Vec<(String, ProcessStatus)> = sqlx::query_as(
+            "SELECT hash, status FROM files_in_pipeline
+            WHERE hash IN (SELECT value FROM json_each($1));",
+        )
+        .bind(hashes)
+        .fetch_all(&self.0)
+        .await?;
+        Ok(rows.into_iter().collect())
+    }
+
+    /// Client that announced the file first, with the path and name of the
+    /// file on that client.
+    pub(super) async fn origin_of(&self, hash: &str) -> Result<(String, String, String)> {
+        sqlx::query_as("SELECT client, path, file_name FROM files_in_pipeline WHERE hash = $1;")
+            .bind(hash)
+            .fetch_one(&self.0)
+            .await
+    }
+
+    /// Clients and paths from which `hash` was sent, in order of arrival.
+    pub(super) async fn origins(&self, hash: &str) -> Result<Vec<Origin>> {
+        sqlx::query_as(
+            "SELECT client, path, file_name, companion, date_utc FROM file_origins
+            WHERE hash = $1 ORDER BY date_utc;",
+        )
+        .bind(hash)
+        .fetch_all(&self.0)
+        .await
+    }
+
+    /// Origins of `hash`, or of all files if `None`, that sent a companion.
+    pub(super) async fn companion_origins(
+        &self,
+        hash: Option<&str>,
+    ) -> Result<Vec<CompanionOrigin>> {
+        sqlx::query_as(
+            "SELECT o.hash, o.client, o.path, o.file_name, o.companion, f.storage_path
+            FROM file_origins o JOIN files_in_pipeline f ON f.hash = o.hash
+            WHERE o.companion != '' AND f.storage_path != '' AND ($1 IS NULL OR o.hash = $1);",
+        )
+        .bind(hash)
+        .fetch_all(&self.0)
+        .await
+    }
+
+    pub(super) async fn contains(&self, hash: &str) -> Result<bool> {
+        sqlx::query_scalar("SELECT EXISTS(SELECT 1 FROM files_in_pipeline WHERE hash = $1);")
+            .bind(hash)
+            .fetch_one(&self.0)
+            .await
+    }
+
+    /// Insert a newly announced file stored at `storage_path`, unless the
+    /// files of its client would then exceed `quota` bytes. This returns
+    /// whether the file was inserted.
+    pub(super) async fn insert_new(
+        &self,
+        file: &FileSpec,
+        storage_path: &str,
+        quota: Option<u64>,
+    ) -> Result<bool> {
+        // Taking the write lock upfront so that concurrent announcements
+        // cannot both fit in the remaining quota.
+        let mut tx = self.0.begin_with("BEGIN IMMEDIATE;").await?;
+        if let Some(quota) = quota {
+            let usage: i64 = sqlx::query_scalar(
+                "SELECT COALESCE(SUM(size_bytes), 0) FROM files_in_pipeline WHERE client = $1;",
+            )
+            .bind(&file.client)
+            .fetch_one(&mut *tx)
+            .await?;
+            if usage as u64 + file.size_bytes > quota {
+                return Ok(false);
+            }
+        }
+        sqlx::query(
+            "INSERT INTO files_in_pipeline
+            (hash, full_hash, client, date_utc, path, file_name, processing, status,
+                size_bytes, modified_utc, metadata, companion, sampled, sample_bytes,
+                storage_path)
+            VALUES ($1, $2, $3, datetime('now'), $4, $5, $6, $7, $8, $9, $10, $11, $12, $13,
+                $14);",
+        )
+        .bind(file.hash())
+        .bind(file.sha256_digest.is_full())
+        .bind(&file.client)
+        .bind(&file.path)
+        .bind(&file.filename)
+        .bind(&file.processing)
+        .bind(ProcessStatus::AwaitFromClient.as_ref())
+        .bind(file.size_bytes as i64)
+        .bind(&file.modified_utc)
+        .bind(serde_json::to_string(&file.metadata).expect("metadata should serialize"))
+        .bind(file.companion.as_deref().unwrap_or_default())
+        .bind(matches!(file.sha256_digest.mode(), HashMode::Sampled(_)))
+        .bind(file.sha256_digest.sample_bytes().unwrap_or_default() as i64)
+        .bind(storage_path)
+        .execute(&mut *tx)
+        .await?;
+        audit_in(
+            &mut tx,
+            file.hash(),
+            &file.client,
+            "announced, status AwaitFromClient",
+        )
+        .await?;
+        sqlx::query(
+            "INSERT OR IGNORE INTO file_origins
+            (hash, client, path, file_name, companion, date_utc)
+            VALUES ($1, $2, $3, $4, $5, datetime('now'));",
+        )
+        .bind(file.hash())
+        .bind(&file.client)
+        .bind(&file.path)
+        .bind(&file.filename)
+        .bind(file.companion.as_deref().unwrap_or_default())
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(true)
+    }
+
+    /// Whether the same client already sent `file` from the same path.
+    pub(super) async fn has_origin(&self, file: &FileSpec) -> Result<bool> {
+        sqlx::query_scalar(
+            "SELECT EXISTS (
+                SELECT 1 FROM file_origins
+                WHERE hash = $1 AND client = $2 AND path = $3 AND file_name = $4
+            );",
+        )
+        .bind(file.hash())
+        .bind(&file.client)
+        .bind(&file.path)
+        .bind(&file.filename)
+        .fetch_one(&self.0)
+        .await
+    }
+
+    /// Record request `id` of `client` about `hash`, returning whether it is
+    /// more recent than those already handled. Clients number requests in
+    /// increasing order, older ones are duplicates delivered again.
+    pub(super) async fn claim_request(&self, client: &str, hash: &str, id: u64) -> Result<bool> {
+        let claimed = sqlx::query(
+            "INSERT INTO requests (client, hash, last_id) VALUES ($1, $2, $3)
+            ON CONFLICT (client, hash) DO UPDATE SET last_id = excluded.last_id
+            WHERE excluded.last_id > requests.last_id;",
+        )
+        .bind(client)
+        .bind(hash)
+        .bind(id as i64)
+        .execute(&self.0)
+        .await?
+        .rows_affected();
+        Ok(claimed > 0)
+    }
+
+    /// Record that a client sent a file, possibly with content already in the pipeline.
+    pub(super) async fn add_origin(&self, file: &FileSpec) -> Result<()> {
+        sqlx::query(
+            "INSERT OR IGNORE INTO file_origins
+            (hash, client, path, file_name, companion, date_utc)
+            VALUES ($1, $2, $3, $4, $5, datetime('now'));",
+        )
+        .bind(file.hash())
+        .bind(&file.client)
+        .bind(&file.path)
+        .bind(&file.filename)
+        .bind(file.companion.as_deref().unwrap_or_default())
+        .execute(&self.0)
+        .await?;
+        Ok(())
+    }
+
+    /// Record that `file` arrived from its client and was verified, once per
+    /// client. Arrivals are kept for statistics after files are pruned.
+    pub(super) async fn record_arrival(&self, file: &FileSpec) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO arrivals (hash, client, processing, size_bytes, date_utc)
+            SELECT $1, $2, $3, $4, datetime('now')
+            WHERE NOT EXISTS (SELECT 1 FROM arrivals WHERE hash = $1 AND client = $2);",
+        )
+        .bind(file.hash())
+        .bind(&file.client)
+        .bind(&file.processing)
+        .bind(file.size_bytes as i64)
+        .execute(&self.0)
+        .await?;
+        Ok(())
+    }
+
+    pub(super) async fn update_status(
+        &self,
+        hash: &str,
+        status: ProcessStatus,
+        actor: &str,
+    ) -> Result<()> {
+        let mut tx = self.0.begin().await?;
+        let updated = sqlx::query(
+            "UPDATE files_in_pipeline
+            SET date_utc = datetime('now'), status = $2
+            WHERE hash = $1;",
+        )
+        .bind(hash)
+        .bind(status.as_ref())
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+        if updated == 0 {
+            return Ok(());
+        }
+        audit_in(&mut tx, hash, actor, &format!("status {}", status.as_ref())).await?;
+        tx.commit().await
+    }
+
+    /// Hand the file over to the processing group `processing`.
+    pub(super) async fn route(&self, hash: &str, processing: &str, actor: &str) -> Result<()> {
+        let mut tx = self.0.begin().await?;
+        let updated = sqlx::query("UPDATE files_in_pipeline SET processing = $2 WHERE hash = $1;")
+            .bind(hash)
+            .bind(processing)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+        if updated > 0 {
+            audit_in(&mut tx, hash, actor, &format!("routed to {processing}")).await?;
+        }
+        tx.commit().await
+    }
+
+    pub(super) async fn set_pinned(&self, hash: &str, pinned: bool, actor: &str) -> Result<()> {
+        let mut tx = self.0.begin().await?;
+        let updated = sqlx::query("UPDATE files_in_pipeline SET pinned = $2 WHERE hash = $1;")
+            .bind(hash)
+            .bind(pinned)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+        if updated > 0 {
+            let event = if pinned { "pinned" } else { "unpinned" };
+            audit_in(&mut tx, hash, actor, event).await?;
+        }
+        tx.commit().await
+    }
+
+    /// Attach `tag` to a file, returning whether it was not attached yet.
+    pub(super) async fn tag(&self, hash: &str, tag: &str, actor: &str) -> Result<bool> {
+        let mut tx = self.0.begin().await?;
+        let tagged = sqlx::query(
+            "UPDATE files_in_pipeline SET tags = json_insert(tags, '$[#]', $2)
+            WHERE hash = $1 AND NOT EXISTS (SELECT 1 FROM json_each(tags) WHERE value = $2);",
+        )
+        .bind(hash)
+        .bind(tag)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected()
+            > 0;
+        if tagged {
+            audit_in(&mut tx, hash, actor, &format!("tagged {tag}")).await?;
+        }
+        tx.commit().await?;
+        Ok(tagged)
+    }
+
+    /// Detach `tag` from a file, returning whether it was attached.
+    pub(super) async fn untag(&self, hash: &str, tag: &str, actor: &str) -> Result<bool> {
+        let mut tx = self.0.begin().await?;
+        let untagged = sqlx::query(
+            "UPDATE files_in_pipeline
+            SET tags = (SELECT json_group_array(value) FROM json_each(tags) WHERE value != $2)
+            WHERE hash = $1 AND EXISTS (SELECT 1 FROM json_each(tags) WHERE value = $2);",
+        )
+        .bind(hash)
+        .bind(tag)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected()
+            > 0;
+        if untagged {
+            audit_in(&mut tx, hash, actor, &format!("untagged {tag}")).await?;
+        }
+        tx.commit().await?;
+        Ok(untagged)
+    }
+
+    /// Failed and abandoned files, optionally only `hash` or those with `tag`.
+    pub(super) async fn retry_candidates(
+        &self,
+        hash: Option<&str>,
+        tag: Option<&str>,
+    ) -> Result<Vec<String>> {
+        sqlx::query_scalar(
+            "SELECT hash FROM files_in_pipeline
+            WHERE status IN ('Failed', 'Abandoned') AND ($1 IS NULL OR hash = $1)
+                AND ($2 IS NULL OR EXISTS (SELECT 1 FROM json_each(tags) WHERE value = $2));",
+        )
+        .bind(hash)
+        .bind(tag)
+        .fetch_all(&self.0)
+        .await
+    }
+
+    pub(super) async fn mark_done_to_prune(&self, actor: &str) -> Result<()> {
+        let mut tx = self.0.begin().await?;
+        sqlx::query(
+            "INSERT INTO audit (hash, date_utc, actor, event)
+            SELECT hash, datetime('now'), $1, 'status ToPrune'
+            FROM files_in_pipeline WHERE status = 'Done';",
+        )
+        .bind(actor)
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query(
+            "UPDATE files_in_pipeline
+            SET status = 'ToPrune'
+            WHERE status = 'Done';",
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await
+    }
+
+    pub(super) async fn remove(&self, hash: &str) -> Result<()> {
+        let mut tx = self.0.begin().await?;
+        let removed = sqlx::query("DELETE FROM files_in_pipeline WHERE hash = $1;")
+            .bind(hash)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+        sqlx::query("DELETE FROM file_origins WHERE hash = $1;")
+            .bind(hash)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("DELETE FROM requests WHERE hash = $1;")
+            .bind(hash)
+            .execute(&mut *tx)
+            .await?;
+        if removed > 0 {
+            audit_in(&mut tx, hash, SERVER_ACTOR, "pruned").await?;
+        }
+        tx.commit().await
+    }
+
+    /// Record that `file` was pruned by moving it to `location`.
+    pub(super) async fn archive(&self, file: &FileSpec, location: &str) -> Result<()> {
+        let mut tx = self.0.begin().await?;
+        sqlx::query(
+            "INSERT OR REPLACE INTO archived (hash, file, location, date_utc)
+            VALUES ($1, $2, $3, datetime('now'));",
+        )
+        .bind(file.hash())
+        .bind(serde_json::to_string(file).expect("file spec should serialize"))
+        .bind(location)
+        .execute(&mut *tx)
+        .await?;
+        audit_in(
+            &mut tx,
+            file.hash(),
+            SERVER_ACTOR,
+            &format!("archived to {location}"),
+        )
+        .await?;
+        tx.commit().await
+    }
+
+    /// Archived file with `hash` and its location, if any.
+    pub(super) async fn archived(&self, hash: &str) -> Result<Option<(FileSpec, String)>> {
+        let archived: Option<(String, String)> =
+            sqlx::query_as("SELECT file, location FROM archived WHERE hash = $1;")
+                .bind(hash)
+                .fetch_optional(&self.0)
+                .await?;
+        archived
+            .map(|(file, location)| {
+                let file =
+                    serde_json::from_str(&file).map_err(|err| sqlx::Error::Decode(err.into()))?;
+                Ok((file, location))
+            })
+            .transpose()
+    }
+
+    pub(super) async fn unarchive(&self, hash: &str) -> Result<()> {
+        sqlx::query("DELETE FROM archived WHERE hash = $1;")
+            .bind(hash)
+            .execute(&self.0)
+            .await?;
+        Ok(())
+    }
+
+    pub(super) async fn count(&self) -> Result<i64> {
+        sqlx::query_scalar("SELECT COUNT(*) FROM files_in_pipeline;")
+            .fetch_one(&self.0)
+            .await
+    }
+
+    pub(super) async fn content(&self) -> Result<Vec<FileInPipeline>> {
+        sqlx::query_as(
+            "SELECT *, (
+                SELECT unixepoch(end_utc) - unixepoch(start_utc) FROM attempts
+                WHERE attempts.hash = files_in_pipeline.hash AND end_utc IS NOT NULL
+                ORDER BY id DESC LIMIT 1
+            ) AS processing_secs
+            FROM files_in_pipeline;",
+        )
+        .fetch_all(&self.0)
+        .await
+    }
+
+    /// Up to `limit` files with a hash greater than `after`, ordered by hash,
+    /// to go through the table without loading it whole.
+    pub(super) async fn content_page(
+        &self,
+        after: &str,
+        limit: u32,
+    ) -> Result<Vec<FileInPipeline>> {
+        sqlx::query_as(
+            "SELECT * FROM files_in_pipeline
+            WHERE hash > $1 ORDER BY hash LIMIT $2;",
+        )
+        .bind(after)
+        .bind(limit)
+        .fetch_all(&self.0)
+        .await
+    }
+
+    pub(super) async fn file(&self, hash: &str) -> Result<Option<FileInPipeline>> {
+        sqlx::query_as("SELECT * FROM files_in_pipeline WHERE hash = $1;")
+            .bind(hash)
+            .fetch_optional(&self.0)
+            .await
+    }
+
+    /// Files whose name, path, client or tags match the `LIKE` pattern, with
+    /// `\` as escape character.
+    pub(super) async fn search(&self, pattern: &str, limit: u32) -> Result<Vec<SearchHit>> {
+        sqlx::query_as(
+            "SELECT hash, client, path, file_name, status, tags FROM files_in_pipeline
+            WHERE file_name LIKE $1 ESCAPE '\\' OR path LIKE $1 ESCAPE '\\'
+                OR client LIKE $1 ESCAPE '\\'
+                OR EXISTS (SELECT 1 FROM json_each(tags) WHERE value LIKE $1 ESCAPE '\\')
+            ORDER BY date_utc DESC
+            LIMIT $2;",
+        )
+        .bind(pattern)
+        .bind(limit)
+        .fetch_all(&self.0)
+        .await
+    }
+
+    /// Record a new connection of processing client `name` from `address`.
+    pub(super) async fn client_connected(&self, name: &str, address: &str) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO clients (name, address, connections, connected_utc, last_seen_utc)
+            VALUES ($1, $2, 1, datetime('now'), datetime('now'))
+            ON CONFLICT (name) DO UPDATE SET address = $2, connections = connections + 1,
+                connected_utc = datetime('now'), last_seen_utc = datetime('now');",
+        )
+        .bind(name)
+        .bind(address)
+        .execute(&self.0)
+        .await?;
+        Ok(())
+    }
+
+    /// Record that processing client `name` is still connected.
+    pub(super) async fn client_seen(&self, name: &str) -> Result<()> {
+        sqlx::query("UPDATE clients SET last_seen_utc = datetime('now') WHERE name = $1;")
+            .bind(name)
+            .execute(&self.0)
+            .await?;
+        Ok(())
+    }
+
+    /// Record the end of a connection of processing client `name`.
+    pub(super) async fn client_disconnected(&self, name: &str) -> Result<()> {
+        sqlx::query(
+            "UPDATE clients SET connections = MAX(connections - 1, 0),
+                last_seen_utc = datetime('now')
+            WHERE name = $1;",
+        )
+        .bind(name)
+        .execute(&self.0)
+        .await?;
+        Ok(())
+    }
+
+    /// Forget connections of a previous run of the server.
+    pub(super) async fn reset_client_connections(&self) -> Result<()> {
+        sqlx::query("UPDATE clients SET connections = 0;")
+            .execute(&self.0)
+            .await?;
+        Ok(())
+    }
+
+    /// Processing clients and when they last delivered a file.
+    pub(super) async fn clients(&self) -> Result<Vec<ClientInfo>> {
+        sqlx::query_as(
+            "SELECT name, address, connections > 0 AS connected, connected_utc, last_seen_utc,
+                last_delivery_utc,
+                unixepoch('now') - unixepoch(last_delivery_utc) AS silent_secs
+            FROM clients LEFT JOIN (
+                SELECT client AS name, MAX(date_utc) AS last_delivery_utc
+                FROM arrivals GROUP BY client
+            ) USING (name)
+            ORDER BY name;",
+        )
+        .fetch_all(&self.0)
+        .await
+    }
+
+    /// Files and bytes received per day and client since `date_utc`.
+    pub(super) async fn daily_arrivals(&self, date_utc: &str) -> Result<Vec<DailyArrivals>> {
+        sqlx::query_as(
+            "SELECT date(date_utc) AS day, client, COUNT(*) AS files, SUM(size_bytes) AS size_bytes
+            FROM arrivals WHERE date_utc >= $1
+            GROUP BY day, client ORDER BY day, client;",
+        )
+        .bind(date_utc)
+        .fetch_all(&self.0)
+        .await
+    }
+
+    /// Completed processing attempts per group started since `date_utc`.
+    pub(super) async fn processing_stats(&self, date_utc: &str) -> Result<Vec<ProcessingStats>> {
+        sqlx::query_as(
+            "SELECT COALESCE(processing, '?') AS processing, COUNT(*) AS attempts,
+                SUM(failed_step IS NOT NULL) AS failures,
+                AVG(CASE WHEN failed_step IS NULL
+                    THEN unixepoch(end_utc) - unixepoch(start_utc) END) AS avg_secs
+            FROM attempts LEFT JOIN (
+                SELECT hash, MAX(processing) AS processing FROM arrivals GROUP BY hash
+            ) USING (hash)
+            WHERE end_utc IS NOT NULL AND start_utc >= $1
+            GROUP BY processing ORDER BY processing;",
+        )
+        .bind(date_utc)
+        .fetch_all(&self.0)
+        .await
+    }
+
+    /// Failed processing attempts per group and step started since `date_utc`.
+    pub(super) async fn step_failures(&self, date_utc: &str) -> Result<Vec<StepFailures>> {
+        sqlx::query_as(
+            "SELECT COALESCE(processing, '?') AS processing, failed_step AS step,
+                COUNT(*) AS failures
+            FROM attempts LEFT JOIN (
+                SELECT hash, MAX(processing) AS processing FROM arrivals GROUP BY hash
+            ) USING (hash)
+            WHERE failed_step IS NOT NULL AND start_utc >= $1
+            GROUP BY processing, failed_step ORDER BY processing, failed_step;",
+        )
+        .bind(date_utc)
+        .fetch_all(&self.0)
+        .await
+    }
+
+    /// Files announced at or after `date_utc`, formatted as in the database.
+    pub(super) async fn announced_since(&self, date_utc: &str) -> Result<Vec<FileInPipeline>> {
+        sqlx::query_as("SELECT * FROM files_in_pipeline WHERE date_utc >= $1 ORDER BY date_utc;")
+            .bind(date_utc)
+            .fetch_all(&self.0)
+            .await
+    }
+
+    pub(super) async fn snapshot(&self) -> Result<Snapshot> {
+        let counts = sqlx::query_as(
+            "SELECT status, COUNT(*) FROM files_in_pipeline
+            GROUP BY status ORDER BY status;",
+        )
+        .fetch_all(&self.0)
+        .await?;
+        let processing = sqlx::query_as(
+            "SELECT hash, client, file_name,
+                CAST((julianday('now') - julianday(date_utc)) * 86400 AS INTEGER) AS elapsed_secs
+            FROM files_in_pipeline WHERE status = 'Processing'
+            ORDER BY date_utc;",
+        )
+        .fetch_all(&self.0)
+        .await?;
+        let recent_failures = sqlx::query_as(
+            "SELECT f.hash, f.client, f.file_name, f.date_utc,
+                COALESCE((SELECT a.error FROM attempts a WHERE a.hash = f.hash
+                    ORDER BY a.id DESC LIMIT 1), '') AS error
+            FROM files_in_pipeline f WHERE f.status = 'Failed'
+            ORDER BY f.date_utc DESC LIMIT 20;",
+        )
+        .fetch_all(&self.0)
+        .await?;
+        let throughput = sqlx::query_as(
+            "SELECT actor, COUNT(*) FROM audit
+            WHERE event LIKE 'announced%' AND date_utc > datetime('now', '-1 hour')
+            GROUP BY actor ORDER BY actor;",
+        )
+        .fetch_all(&self.0)
+        .await?;
+        Ok(Snapshot {
+            counts,
+            processing,
+            recent_failures,
+            throughput,
+        })
+    }
+
+    /// Messages of `PRAGMA integrity_check`, a single "ok" if no problem was found.
+    pub(super) async fn integrity_check(&self) -> Result<Vec<String>> {
+        sqlx::query_scalar("PRAGMA integrity_check;")
+            .fetch_all(&self.0)
+            .await
+    }
+
+    pub(super) async fn vacuum(&self) -> Result<()> {
+        sqlx::query("VACUUM;").execute(&self.0).await?;
+        Ok(())
+    }
+
+    /// Close all connections to the database.
+    pub(super) async fn close(self) {
+        self.0.close().await;
+    }
+
+    /// Write a consistent copy of the database to `path`, which must not
+    /// exist. This is safe while the server writes to the database.
+    pub(super) async fn backup_to(&self, path: &Path) -> Result<()> {
+        sqlx::query("VACUUM INTO $1;")
+            .bind(path.to_string_lossy())
+            .execute(&self.0)
+            .await?;
+        Ok(())
+    }
+
+    /// Checkpoint and truncate the WAL file, this is a no-op if WAL is disabled.
+    pub(super) async fn checkpoint(&self) -> Result<()> {
+        sqlx::query("PRAGMA wal_checkpoint(TRUNCATE);")
+            .execute(&self.0)
+            .await?;
+        Ok(())
+    }
+
+    /// Size of the database in bytes.
+    pub(super) async fn size(&self) -> Result<i64> {
+        sqlx::query_scalar(
+            "SELECT page_count * page_size FROM pragma_page_count(), pragma_page_size();",
+        )
+        .fetch_one(&self.0)
+        .await
+    }
+
+    pub(super) async fn tables(&self) -> Result<Vec<String>> {
+        sqlx::query_scalar(
+            "SELECT name FROM sqlite_schema
+            WHERE type = 'table' AND name NOT LIKE 'sqlite_%' ORDER BY name;",
+        )
+        .fetch_all(&self.0)
+        .await
+    }
+
+    pub(super) async fn columns(&self, table: &str) -> Result<Vec<String>> {
+        sqlx::query_scalar("SELECT name FROM pragma_table_info($1) ORDER BY cid;")
+            .bind(table)
+            .fetch_all(&self.0)
+            .await
+    }
+
+    /// Rows of `table` as JSON objects, in insertion order.
+    pub(super) async fn export_table(&self, table: &str) -> Result<Vec<JsonObject>> {
+        let fields: Vec<_> = self
+            .columns(table)
+            .await?
+            .iter()
+            .map(|column| format!("'{column}', \"{column}\""))
+            .collect();
+        // Table and column names come from the schema itself.
+        let query = AssertSqlSafe(format!(
+            "SELECT json_object({}) FROM \"{table}\" ORDER BY rowid;",
+            fields.join(", ")
+        ));
+        let rows: Vec<String> = sqlx::query_scalar(query).fetch_all(&self.0).await?;
+        Ok(rows
+            .iter()
+            .map(|row| serde_json::from_str(row).expect("SQLite should produce valid JSON"))
+            .collect())
+    }
+
+    /// Insert the rows of each table of `content`, as exported by
+    /// [`Database::export_table`], in a single transaction so that a failed
+    /// import leaves the database untouched. Rows that conflict with existing
+    /// ones are skipped. Returns false without importing anything if the
+    /// database already contains files.
+    ///
+    /// The caller must check that the tables of `content` and the keys of
+    /// their rows are tables and columns of the database.
+    pub(super) async fn import_tables(
+        &self,
+        content: &BTreeMap<String, Vec<JsonObject>>,
+    ) -> Result<bool> {
+        let mut tx = self.0.begin_with("BEGIN IMMEDIATE;").await?;
+        let files: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM files_in_pipeline;")
+            .fetch_one(&mut *tx)
+            .await?;
+        if files > 0 {
+            return Ok(false);
+        }
+        for (table, rows) in content {
+            insert_rows(&mut tx, table, rows, "IGNORE").await?;
+        }
+        tx.commit().await?;
+        Ok(true)
+    }
+
+    /// Files changed after the change `after`, oldest change first and at most
+    /// `limit` of them, see [`track_changes`].
+    pub(super) async fn changes_after(&self, after: i64, limit: u32) -> Result<Vec<(i64, String)>> {
+        sqlx::query_as("SELECT id, hash FROM changes WHERE id > $1 ORDER BY id LIMIT $2;")
+            .bind(after)
+            .bind(limit)
+            .fetch_all(&self.0)
+            .await
+    }
+
+    /// Rows about `hashes` in each table with a `hash` column, and the batches
+    /// they are members of, to apply them to another database with
+    /// [`Database::replace_rows`].
+    pub(super) async fn rows_of(
+        &self,
+        hashes: &[String],
+    ) -> Result<BTreeMap<String, Vec<JsonObject>>> {
+        let hashes = JsonValue::from(hashes).to_string();
+        let mut content = BTreeMap::new();
+        for table in hashed_tables(&self.0).await? {
+            let filter = "hash IN (SELECT value FROM json_each($1))";
+            let rows = self.rows_where(&table, filter, &hashes).await?;
+            content.insert(table, rows);
+        }
+        let filter = "(processing, name) IN (SELECT processing, name FROM batch_members
+            WHERE hash IN (SELECT value FROM json_each($1)))";
+        let batches = self.rows_where("batches", filter, &hashes).await?;
+        content.insert("batches".to_owned(), batches);
+        Ok(content)
+    }
+
+    /// Rows of `table` matching `filter`, which takes `param` as `$1`.
+    async fn rows_where(&self, table: &str, filter: &str, param: &str) -> Result<Vec<JsonObject>> {
+        let fields: Vec<_> = self
+            .columns(table)
+            .await?
+            .iter()
+            .map(|column| format!("'{column}', \"{column}\""))
+            .collect();
+        // Table and column names come from the schema itself, filters are
+        // static.
+        let query = AssertSqlSafe(format!(
+            "SELECT json_object({}) FROM \"{table}\" WHERE {filter} ORDER BY rowid;",
+            fields.join(", ")
+        ));
+        let rows: Vec<String> = sqlx::query_scalar(query)
+            .bind(param)
+            .fetch_all(&self.0)
+            .await?;
+        Ok(rows
+            .iter()
+            .map(|row| serde_json::from_str(row).expect("SQLite should produce valid JSON"))
+            .collect())
+    }
+
+    /// Replace the rows about `hashes` with `content`, as returned by
+    /// [`Database::rows_of`] on another database, in a single transaction.
+    /// Rows of batches are updated rather than removed.
+    ///
+    /// The caller must check that the tables of `content` and the keys of
+    /// their rows are tables and columns of the database.
+    pub(super) async fn replace_rows(
+        &self,
+        hashes: &[String],
+        content: &BTreeMap<String, Vec<JsonObject>>,
+    ) -> Result<()> {
+        let hashes = JsonValue::from(hashes).to_string();
+        let tables = hashed_tables(&self.0).await?;
+        let mut tx = self.0.begin_with("BEGIN IMMEDIATE;").await?;
+        for table in &tables {
+            // Table names come from the schema itself.
+            let query = AssertSqlSafe(format!(
+                "DELETE FROM \"{table}\" WHERE hash IN (SELECT value FROM json_each($1));"
+            ));
+            sqlx::query(query).bind(&hashes).execute(&mut *tx).await?;
+        }
+        for (table, rows) in content {
+            insert_rows(&mut tx, table, rows, "REPLACE").await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Number of rows in each table.
+    pub(super) async fn table_sizes(&self) -> Result<Vec<(String, i64)>> {
+        let tables = self.tables().await?;
+        let mut sizes = Vec::with_capacity(tables.len());
+        for table in tables {
+            // Table names come from the schema itself.
+            let query = AssertSqlSafe(format!("SELECT COUNT(*) FROM \"{table}\";"));
+            let n = sqlx::query_scalar(query).fetch_one(&self.0).await?;
+            sizes.push((table, n));
+        }
+        Ok(sizes)
+    }
+}
+
+/// Insert `rows` in `table` through `conn`, resolving conflicts with existing
+/// rows with `on_conflict`, `IGNORE` or `REPLACE`. See
+/// [`Database::import_tables`] and [`Database::replace_rows`].
+async fn insert_rows(
+    conn: &mut SqliteConnection,
+    table: &str,
+    rows: &[JsonObject],
+    on_conflict: &str,
+) -> Result<()> {
+    for row in rows {
+        let columns: Vec<_> = row.keys().map(|column| format!("\"{column}\"")).collect();
+        let params: Vec<_> = (1..=row.len()).map(|i| format!("${i}")).collect();
+        let query = AssertSqlSafe(format!(
+            "INSERT OR {on_conflict} INTO \"{table}\" ({}) VALUES ({});",
+            columns.join(", "),
+            params.join(", ")
+        ));
+        let mut query = sqlx::query(query);
+        for value in row.values() {
+            query = match value {
+                JsonValue::Null => query.bind(None::<i64>),
+                JsonValue::Bool(b) => query.bind(*b),
+                JsonValue::Number(n) => match n.as_i64() {
+                    Some(n) => query.bind(n),
+                    None => query.bind(n.as_f64()),
+                },
+                JsonValue::String(s) => query.bind(s.as_str()),
+                value => query.bind(value.to_string()),
+            };
+        }
+        query.execute(&mut *conn).await?;
+    }
+    Ok(())
+}
+
+/// Tables with a `hash` column, whose changes are tracked.
+async fn hashed_tables(pool: &Pool<Sqlite>) -> Result<Vec<String>> {
+    sqlx::query_scalar(
+        "SELECT m.name FROM sqlite_schema m, pragma_table_info(m.name) c
+        WHERE m.type = 'table' AND c.name = 'hash' AND m.name != 'changes'
+        ORDER BY m.name;",
+    )
+    .fetch_all(pool)
+    .await
+}
+
+/// Record the files whose rows change in the `changes` table, the latest
+/// change of each file only, so that standby servers can replicate the
+/// database incrementally. Triggers on every table with a `hash` column keep
+/// it up to date, and an update of a batch counts as a change of its members.
+/// When the table is created, all files known to the database are recorded.
+async fn track_changes(pool: &Pool<Sqlite>) -> Result<()> {
+    let created: bool = sqlx::query_scalar(
+        "SELECT NOT EXISTS(SELECT 1 FROM sqlite_schema WHERE type = 'table' AND name = 'changes');",
+    )
+    .fetch_one(pool)
+    .await?;
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS changes (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            hash TEXT NOT NULL UNIQUE
+        ) STRICT;",
+    )
+    .execute(pool)
+    .await?;
+    if created {
+        // The audit log also covers files pruned since.
+        sqlx::query(
+            "INSERT INTO changes (hash)
+            SELECT hash FROM audit UNION SELECT hash FROM files_in_pipeline;",
+        )
+        .execute(pool)
+        .await?;
+    }
+
+    // Deleting then inserting moves the file after other changes. `INSERT OR
+    // REPLACE` would do the same, but a conflict clause of the statement
+    // firing the trigger would override it.
+    for table in hashed_tables(pool).await? {
+        for (event, row) in [("INSERT", "NEW"), ("UPDATE", "NEW"), ("DELETE", "OLD")] {
+            // Table names come from the schema itself.
+            let query = AssertSqlSafe(format!(
+                "CREATE TRIGGER IF NOT EXISTS \"{table}_{}_change\" AFTER {event} ON \"{table}\"
+                BEGIN
+                    DELETE FROM changes WHERE hash = {row}.hash;
+                    INSERT INTO changes (hash) VALUES ({row}.hash);
+                END;",
+                event.to_lowercase()
+            ));
+            sqlx::query(query).execute(pool).await?;
+        }
+    }
+    sqlx::query(
+        "CREATE TRIGGER IF NOT EXISTS batches_update_change AFTER UPDATE ON batches
+        BEGIN
+            DELETE FROM changes WHERE hash IN (SELECT hash FROM batch_members
+                WHERE processing = NEW.processing AND name = NEW.name);
+            INSERT INTO changes (hash) SELECT hash FROM batch_members
+                WHERE processing = NEW.processing AND name = NEW.name;
+        END;",
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Append `event` to the audit log through `conn`, usually the transaction
+/// making the change it records.
+async fn audit_in(conn: &mut SqliteConnection, hash: &str, actor: &str, event: &str) -> Result<()> {
+    sqlx::query(
+        "INSERT INTO audit (hash, date_utc, actor, event)
+        VALUES ($1, datetime('now'), $2, $3);",
+    )
+    .bind(hash)
+    .bind(actor)
+    .bind(event)
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    fn announced(hash: &str, client: &str, size_bytes: u64) -> FileSpec {
+        FileSpec {
+            client: client.to_owned(),
+            path: String::new(),
+            filename: format!("{hash}.dat"),
+            processing: "main".to_owned(),
+            sha256_digest: FileDigest::Full(hash.to_owned()),
+            size_bytes,
+            modified_utc: String::new(),
+            metadata: BTreeMap::new(),
+            companion: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn content_by_pages() {
+        let db = Database::in_memory().await.unwrap();
+        for hash in ["c", "a", "b"] {
+            db.insert_new(&announced(hash, "lab", 1), "", None)
+                .await
+                .unwrap();
+        }
+        let first = db.content_page("", 2).await.unwrap();
+        let hashes: Vec<_> = first.iter().map(|row| row.hash.as_str()).collect();
+        assert_eq!(hashes, ["a", "b"]);
+        let second = db.content_page("b", 2).await.unwrap();
+        assert_eq!(second.len(), 1);
+        assert_eq!(second[0].hash, "c");
+        assert!(db.content_page("c", 2).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn insert_within_quota() {
+        let db = Database::in_memory().await.unwrap();
+        let quota = Some(100);
+        assert!(
+            db.insert_new(&announced("a", "lab", 60), "", quota)
+                .await
+                .unwrap()
+        );
+        assert!(
+            !db.insert_new(&announced("b", "lab", 50), "", quota)
+                .await
+                .unwrap()
+        );
+        assert!(
+            db.insert_new(&announced("c", "lab", 40), "", quota)
+                .await
+                .unwrap()
+        );
+        assert!(
+            db.insert_new(&announced("d", "other", 90), "", quota)
+                .await
+                .unwrap()
+        );
+        assert!(
+            db.insert_new(&announced("e", "lab", 500), "", None)
+                .await
+                .unwrap()
+        );
+        assert!(!db.contains("b").await.unwrap());
+        assert_eq!(db.count().await.unwrap(), 4);
+        assert_eq!(db.history("b").await.unwrap().len(), 0);
+        assert_eq!(db.origins("a").await.unwrap().len(), 1);
+    }
+
+    fn events(history: &[AuditEntry]) -> Vec<&str> {
+        history.iter().map(|entry| entry.event.as_str()).collect()
+    }
+
+    #[tokio::test]
+    async fn audit_records_changes() {
+        let db = Database::in_memory().await.unwrap();
+        db.insert_new(&announced("a", "lab", 10), "", None)
+            .await
+            .unwrap();
+        db.update_status("a", ProcessStatus::Queued, SERVER_ACTOR)
+            .await
+            .unwrap();
+        db.set_pinned("a", true, "admin").await.unwrap();
+        assert!(db.tag("a", "calib", "admin").await.unwrap());
+        assert!(!db.tag("a", "calib", "admin").await.unwrap());
+        db.remove("a").await.unwrap();
+        assert_eq!(
+            events(&db.history("a").await.unwrap()),
+            [
+                "announced, status AwaitFromClient",
+                "status Queued",
+                "pinned",
+                "tagged calib",
+                "pruned",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn audit_skips_unknown_files() {
+        let db = Database::in_memory().await.unwrap();
+        db.update_status("a", ProcessStatus::Queued, SERVER_ACTOR)
+            .await
+            .unwrap();
+        db.set_pinned("a", true, "admin").await.unwrap();
+        db.route("a", "other", SERVER_ACTOR).await.unwrap();
+        db.remove("a").await.unwrap();
+        assert!(db.history("a").await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn arrivals_are_recorded_once_per_client() {
+        let db = Database::in_memory().await.unwrap();
+        let file = announced("a", "lab", 10);
+        db.insert_new(&file, "", None).await.unwrap();
+        for status in [
+            ProcessStatus::Queued,
+            ProcessStatus::Failed,
+            ProcessStatus::Queued,
+        ] {
+            db.update_status("a", status, SERVER_ACTOR).await.unwrap();
+        }
+        db.record_arrival(&file).await.unwrap();
+        db.record_arrival(&file).await.unwrap();
+        db.record_arrival(&announced("a", "other", 10))
+            .await
+            .unwrap();
+        let arrivals = db.daily_arrivals("").await.unwrap();
+        let per_client: Vec<_> = arrivals
+            .iter()
+            .map(|day| (day.client.as_str(), day.files))
+            .collect();
+        assert_eq!(per_client, [("lab", 1), ("other", 1)]);
+    }
+
+    #[tokio::test]
+    async fn failed_import_changes_nothing() {
+        let db = Database::in_memory().await.unwrap();
+        let row = |json: serde_json::Value| json.as_object().unwrap().clone();
+        let arrival = row(serde_json::json!({
+            "hash": "a", "client": "lab", "processing": "main",
+            "size_bytes": 1, "date_utc": "2024-01-01 00:00:00",
+        }));
+        let content = BTreeMap::from([
+            ("arrivals".to_owned(), vec![arrival]),
+            (
+                "files_in_pipeline".to_owned(),
+                vec![row(serde_json::json!({"bogus": 1}))],
+            ),
+        ]);
+        assert!(db.import_tables(&content).await.is_err());
+        assert!(db.daily_arrivals("").await.unwrap().is_empty());
+
+        db.insert_new(&announced("b", "lab", 1), "", None)
+            .await
+            .unwrap();
+        let content = BTreeMap::from([("arrivals".to_owned(), content["arrivals"].clone())]);
+        assert!(!db.import_tables(&content).await.unwrap());
+        assert!(db.daily_arrivals("").await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn duplicate_requests_are_refused() {
+        let db = Database::in_memory().await.unwrap();
+        assert!(db.claim_request("lab", "a", 5).await.unwrap());
+        assert!(!db.claim_request("lab", "a", 5).await.unwrap());
+        assert!(!db.claim_request("lab", "a", 3).await.unwrap());
+        assert!(db.claim_request("other", "a", 3).await.unwrap());
+        assert!(db.claim_request("lab", "b", 1).await.unwrap());
+        assert!(db.claim_request("lab", "a", 6).await.unwrap());
+        db.remove("a").await.unwrap();
+        assert!(db.claim_request("lab", "a", 2).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn open_batch_members_are_not_pruned() {
+        let db = Database::in_memory().await.unwrap();
+        for hash in ["a", "b"] {
+            db.insert_new(&announced(hash, "lab", 10), "", None)
+                .await
+                .unwrap();
+            db.update_status(hash, ProcessStatus::Done, SERVER_ACTOR)
+                .await
+                .unwrap();
+        }
+        assert!(!db.add_to_batch("main", "run1", "a", 2).await.unwrap());
+        let candidates = |db: Database| async move {
+            let filter = PruneFilter::default();
+            let candidates = db.prune_candidates(ProcessStatus::Done, &filter).await;
+            let candidates = candidates.unwrap().into_iter().map(|c| c.hash);
+            candidates.collect::<Vec<_>>()
+        };
+        assert_eq!(candidates(db.clone()).await, ["b"]);
+
+        assert!(db.add_to_batch("main", "run1", "b", 2).await.unwrap());
+        assert!(candidates(db.clone()).await.is_empty());
+        assert!(!db.retry_batch("main", "run1").await.unwrap());
+        db.update_batch_status("main", "run1", ProcessStatus::Failed)
+            .await
+            .unwrap();
+        assert!(db.retry_batch("main", "run1").await.unwrap());
+        db.update_batch_status("main", "run1", ProcessStatus::Done)
+            .await
+            .unwrap();
+        assert_eq!(candidates(db).await.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn track_latest_change_of_files() {
+        let db = Database::in_memory().await.unwrap();
+        for hash in ["a", "b", "c"] {
+            db.insert_new(&announced(hash, "lab", 10), "", None)
+                .await
+                .unwrap();
+        }
+        let changed = |after| {
+            let db = db.clone();
+            async move {
+                let changes = db.changes_after(after, 10).await.unwrap();
+                changes
+                    .into_iter()
+                    .map(|(_, hash)| hash)
+                    .collect::<Vec<_>>()
+            }
+        };
+        assert_eq!(changed(0).await, ["a", "b", "c"]);
+        let (last, _) = *db.changes_after(0, 10).await.unwrap().last().unwrap();
+
+        db.update_status("a", ProcessStatus::Done, SERVER_ACTOR)
+            .await
+            .unwrap();
+        db.remove("b").await.unwrap();
+        assert_eq!(changed(0).await, ["c", "a", "b"]);
+        assert_eq!(changed(last).await, ["a", "b"]);
+
+        let (last, _) = *db.changes_after(0, 10).await.unwrap().last().unwrap();
+        db.add_to_batch("main", "run1", "c", 2).await.unwrap();
+        db.update_batch_status("main", "run1", ProcessStatus::Failed)
+            .await
+            .unwrap();
+        assert_eq!(changed(last).await, ["c"]);
+        let rows = db.rows_of(&["c".to_owned()]).await.unwrap();
+        assert_eq!(rows["batches"].len(), 1);
+        assert_eq!(rows["batches"][0]["status"], "Failed");
+        assert_eq!(rows["files_in_pipeline"].len(), 1);
+        assert!(!rows.contains_key("changes"));
+    }
 }
@@ -0,0 +1,140 @@
+use std::{
+    collections::BTreeMap,
+    io::{self, Write},
+    path::Path,
+    time::Instant,
+};
+
+use crate::{
+    FileSpec, encode_name, format_utc,
+    hashing::{FileDigest, HashMode},
+    server::{
+        Config,
+        scheduler::{Pools, Resources},
+    },
+};
+
+/// Print the steps the processing `group` would run on the local file
+/// `path`, as if sent by `client`, and run them on a copy of the file if
+/// `execute` is set, without asking for confirmation if it is `Some(true)`.
+pub(crate) async fn main(
+    config: Config,
+    path: &Path,
+    group: Option<String>,
+    client: String,
+    metadata: BTreeMap<String, String>,
+    execute: Option<bool>,
+) -> io::Result<()> {
+    let group = match group {
+        Some(group) => group,
+        None if config.processing.len() == 1 => config.processing.keys().next().unwrap().clone(),
+        None => {
+            let mut groups: Vec<_> = config.processing.keys().map(String::as_str).collect();
+            groups.sort_unstable();
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("choose a group with `--group` among {}", groups.join(", ")),
+            ));
+        }
+    };
+    let Some(processing) = config.processing.get(&group) else {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("unknown processing group `{group}`"),
+        ));
+    };
+
+    let stat = path.metadata()?;
+    let spec = FileSpec {
+        client,
+        path: String::new(),
+        filename: encode_name(path.file_name().unwrap_or_default()),
+        processing: group,
+        sha256_digest: FileDigest::new(
+            path,
+            HashMode::Full,
+            config.hash_algorithm,
+            config.read_options().unmapped(),
+        )?,
+        size_bytes: stat.len(),
+        modified_utc: format_utc(stat.modified()?),
+        metadata,
+        companion: None,
+    };
+    println!(
+        "{path:?} from client {}, {} bytes, hash {}",
+        spec.client,
+        spec.size_bytes,
+        spec.hash()
+    );
+    let steps = processing.processing.describe(&spec, path);
+    for (i, step) in steps.iter().enumerate() {
+        println!("step {}: {step}", i + 1);
+    }
+    let Some(confirmed) = execute else {
+        return Ok(());
+    };
+
+    // Steps may modify or delete the file they process.
+    let dir = std::env::temp_dir().join(format!("pipeline-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+    let copy = dir.join(path.file_name().unwrap_or_default());
+    std::fs::copy(path, &copy)?;
+    println!("steps run on the copy {copy:?}:");
+    for (i, step) in processing
+        .processing
+        .describe(&spec, &copy)
+        .iter()
+        .enumerate()
+    {
+        println!("step {}: {step}", i + 1);
+    }
+    println!(
+        "warning: only `{{server_path}}` refers to the copy, other paths, commands, \
+         requests and manifests take effect as configured"
+    );
+    if !confirmed && !confirm("run these steps?")? {
+        if let Err(err) = std::fs::remove_dir_all(&dir) {
+            println!("failed to remove {dir:?}: {err}");
+        }
+        return Ok(());
+    }
+    let pools = Pools::new(&config.concurrency.resources);
+    let mut resources = Resources::new(&pools, None);
+    let mut step_secs = Vec::new();
+    let started = Instant::now();
+    let result = processing
+        .processing
+        .run_at(&spec, &copy, &config, &mut resources, &mut step_secs)
+        .await;
+    if let Err(err) = std::fs::remove_dir_all(&dir) {
+        println!("failed to remove {dir:?}: {err}");
+    }
+    for (i, secs) in step_secs.iter().enumerate() {
+        println!("step {} ran for {secs:.2}s", i + 1);
+    }
+    let secs = started.elapsed().as_secs_f64();
+    match result {
+        Ok(None) => {
+            println!("all {} steps succeeded in {secs:.2}s", steps.len());
+            Ok(())
+        }
+        Ok(Some(group)) => {
+            println!(
+                "step {} routed the file to group {group} in {secs:.2}s",
+                step_secs.len()
+            );
+            Ok(())
+        }
+        Err(err) => Err(io::Error::other(format!("processing failed at {err}"))),
+    }
+}
+
+/// Ask `question` on the terminal, only a `y` or `yes` answer confirms.
+fn confirm(question: &str) -> io::Result<bool> {
+    print!("{question} [y/N] ");
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
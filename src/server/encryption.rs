@@ -0,0 +1,356 @@
+use std::{
+    fs::File,
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use log::{debug, warn};
+use ring::{
+    aead::{AES_256_GCM, Aad, LessSafeKey, NONCE_LEN, Nonce, UnboundKey},
+    hkdf,
+    rand::{SecureRandom, SystemRandom},
+};
+use serde::Deserialize;
+use zeroize::Zeroizing;
+
+use crate::server::{Config, database::Database};
+
+/// Encryption of the blobs stored in the incoming directory.
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone)]
+pub(super) struct EncryptionConfig {
+    /// File holding the 256-bit key, hex-encoded.
+    key_file: PathBuf,
+    /// Directory where blobs are decrypted for processing.
+    #[serde(default = "std::env::temp_dir")]
+    pub(super) decrypt_directory: PathBuf,
+}
+
+/// Start of encrypted blobs, followed by the salt of their key.
+const MAGIC: &[u8; 8] = b"PLENC002";
+/// Each blob has its own key, derived from the configured one with HKDF and a
+/// random salt, so that nonces only need to be unique within a blob.
+const SALT_LEN: usize = 32;
+/// Context of the derivation of blob keys.
+const KEY_INFO: &[u8] = b"pipeline blob key";
+const CHUNK_LEN: usize = 1 << 20;
+const TAG_LEN: usize = 16;
+
+impl EncryptionConfig {
+    /// The configured key, from which the key of each blob is derived.
+    pub(super) fn key(&self) -> io::Result<Zeroizing<Vec<u8>>> {
+        let content = Zeroizing::new(std::fs::read_to_string(&self.key_file)?);
+        match hex::decode(content.trim()) {
+            Ok(bytes) if bytes.len() == AES_256_GCM.key_len() => Ok(Zeroizing::new(bytes)),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{:?} should hold a hex-encoded 256-bit key", self.key_file),
+            )),
+        }
+    }
+}
+
+/// Key of the blob with `salt`, derived from the configured `key`.
+fn blob_key(key: &[u8], salt: &[u8; SALT_LEN]) -> io::Result<LessSafeKey> {
+    let prk = hkdf::Salt::new(hkdf::HKDF_SHA256, salt).extract(key);
+    let okm = prk
+        .expand(&[KEY_INFO], &AES_256_GCM)
+        .map_err(|_| io::Error::other("failed to derive blob key"))?;
+    Ok(LessSafeKey::new(UnboundKey::from(okm)))
+}
+
+/// Nonces are the index of the chunk and whether it is the last.
+fn nonce(index: u32, last: bool) -> Nonce {
+    let mut nonce = [0; NONCE_LEN];
+    nonce[NONCE_LEN - 5..NONCE_LEN - 1].copy_from_slice(&index.to_be_bytes());
+    nonce[NONCE_LEN - 1] = last.into();
+    Nonce::assume_unique_for_key(nonce)
+}
+
+/// Read up to `buf.len()` bytes, fewer only at the end of `reader`.
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..])? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    Ok(filled)
+}
+
+/// Size of the encrypted blob of a file of `size` bytes. Chunks are full
+/// except the last one, possibly empty.
+pub(super) fn encrypted_len(size: u64) -> u64 {
+    let chunks = size / CHUNK_LEN as u64 + 1;
+    (MAGIC.len() + SALT_LEN) as u64 + size + chunks * TAG_LEN as u64
+}
+
+fn encrypt<R: Read, W: Write>(key: &[u8], reader: &mut R, writer: &mut W) -> io::Result<()> {
+    let mut salt = [0; SALT_LEN];
+    SystemRandom::new()
+        .fill(&mut salt)
+        .map_err(|_| io::Error::other("failed to generate salt"))?;
+    let key = blob_key(key, &salt)?;
+    writer.write_all(MAGIC)?;
+    writer.write_all(&salt)?;
+    let mut chunk = vec![0; CHUNK_LEN];
+    for index in 0.. {
+        let len = read_full(reader, &mut chunk)?;
+        let last = len < CHUNK_LEN;
+        let mut sealed = chunk[..len].to_vec();
+        key.seal_in_place_append_tag(nonce(index, last), Aad::empty(), &mut sealed)
+            .map_err(|_| io::Error::other("failed to encrypt chunk"))?;
+        writer.write_all(&sealed)?;
+        if last {
+            break;
+        }
+    }
+    Ok(())
+}
+
+fn decrypt<R: Read, W: Write>(key: &[u8], reader: &mut R, writer: &mut W) -> io::Result<()> {
+    let invalid = |what: &str| io::Error::new(io::ErrorKind::InvalidData, what.to_owned());
+    let mut header = [0; MAGIC.len() + SALT_LEN];
+    if read_full(reader, &mut header)? < header.len() || &header[..MAGIC.len()] != MAGIC {
+        return Err(invalid("not an encrypted blob"));
+    }
+    let salt = header[MAGIC.len()..]
+        .try_into()
+        .expect("salt has the right length");
+    let key = blob_key(key, &salt)?;
+    let mut chunk = vec![0; CHUNK_LEN + TAG_LEN];
+    for index in 0.. {
+        let len = read_full(reader, &mut chunk)?;
+        let last = len < chunk.len();
+        let plain = key
+            .open_in_place(nonce(index, last), Aad::empty(), &mut chunk[..len])
+            .map_err(|_| invalid("encrypted blob is corrupted, truncated or has another key"))?;
+        writer.write_all(plain)?;
+        if last {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Where the blob at `path` is encrypted before replacing it.
+fn part_path(path: &Path) -> PathBuf {
+    let mut part = path.as_os_str().to_owned();
+    part.push(".enc.part");
+    part.into()
+}
+
+/// Encrypt the blob at `from` to `to`.
+fn encrypt_file(conf: &EncryptionConfig, from: &Path, to: &Path) -> io::Result<()> {
+    let key = conf.key()?;
+    let mut reader = io::BufReader::new(File::open(from)?);
+    let mut writer = io::BufWriter::new(File::create(to)?);
+    let written = encrypt(&key, &mut reader, &mut writer)
+        .and_then(|()| writer.into_inner().map_err(io::Error::from))
+        .and_then(|file| file.sync_all());
+    if written.is_err() {
+        let _ = std::fs::remove_file(to);
+    }
+    written
+}
+
+fn decrypt_file(conf: &EncryptionConfig, from: &Path, to: &Path) -> io::Result<()> {
+    let key = conf.key()?;
+    let mut reader = io::BufReader::new(File::open(from)?);
+    let mut writer = io::BufWriter::new(File::create(to)?);
+    let written = decrypt(&key, &mut reader, &mut writer).and_then(|()| writer.flush());
+    if written.is_err() {
+        let _ = std::fs::remove_file(to);
+    }
+    written
+}
+
+/// Encrypt the blob of `hash` at `path` if encryption is enabled and it is
+/// not encrypted yet, logging failures as the blob stays usable.
+pub(super) async fn encrypt_at_rest(config: &Config, db: &Database, hash: &str, path: PathBuf) {
+    let Some(conf) = config.encryption.clone() else {
+        return;
+    };
+    if let Err(err) = encrypt_in_place(conf, db, hash, &path).await {
+        warn!("failed to encrypt {path:?}: {err}");
+    }
+}
+
+/// The blob is recorded as encrypted before the encrypted copy replaces it, so
+/// that it is never encrypted twice. A copy left by an interruption in between
+/// replaces the blob on the next call.
+async fn encrypt_in_place(
+    conf: EncryptionConfig,
+    db: &Database,
+    hash: &str,
+    path: &Path,
+) -> io::Result<()> {
+    let part = part_path(path);
+    if db.is_encrypted(hash).await.map_err(io::Error::other)? {
+        if tokio::fs::try_exists(&part).await? {
+            tokio::fs::rename(&part, path).await?;
+        }
+        return Ok(());
+    }
+    let (from, to) = (path.to_owned(), part.clone());
+    tokio::task::spawn_blocking(move || encrypt_file(&conf, &from, &to)).await??;
+    if let Err(err) = db.set_encrypted(hash).await {
+        let _ = tokio::fs::remove_file(&part).await;
+        return Err(io::Error::other(err));
+    }
+    tokio::fs::rename(&part, path).await
+}
+
+/// Plaintext of a stored blob: the blob itself, or a decrypted copy removed
+/// when dropped.
+pub(super) struct Plaintext {
+    path: PathBuf,
+    /// Directory holding the decrypted copy.
+    temporary: Option<PathBuf>,
+}
+
+impl Plaintext {
+    pub(super) fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for Plaintext {
+    fn drop(&mut self) {
+        if let Some(dir) = &self.temporary
+            && let Err(err) = std::fs::remove_dir_all(dir)
+        {
+            warn!("failed to remove decrypted copy {:?}: {err}", self.path);
+        }
+    }
+}
+
+fn plaintext_blocking(
+    conf: Option<&EncryptionConfig>,
+    path: PathBuf,
+    encrypted: bool,
+) -> io::Result<Plaintext> {
+    if !encrypted {
+        return Ok(Plaintext {
+            path,
+            temporary: None,
+        });
+    }
+    let conf = conf.ok_or_else(|| {
+        io::Error::other(format!(
+            "{path:?} is encrypted but no `[encryption]` is set"
+        ))
+    })?;
+    // Decrypted copies keep the name of the blob, their extension may matter
+    // to processing.
+    static COUNT: AtomicU64 = AtomicU64::new(0);
+    let count = COUNT.fetch_add(1, Ordering::Relaxed);
+    let dir = conf
+        .decrypt_directory
+        .join(format!("pipeline-plain-{}-{count}", std::process::id()));
+    std::fs::create_dir_all(&conf.decrypt_directory)?;
+    let mut builder = std::fs::DirBuilder::new();
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    // Only the server may read decrypted copies.
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+        builder.mode(0o700);
+        options.mode(0o600);
+    }
+    builder.create(&dir)?;
+    let plain = Plaintext {
+        path: dir.join(path.file_name().unwrap_or_default()),
+        temporary: Some(dir),
+    };
+    debug!("decrypting {path:?} to {:?}", plain.path);
+    options.open(&plain.path)?;
+    decrypt_file(conf, &path, &plain.path)?;
+    Ok(plain)
+}
+
+/// Plaintext of the blob of `hash` at `path`, decrypting it if needed.
+pub(super) async fn plaintext(
+    config: &Config,
+    db: &Database,
+    hash: &str,
+    path: PathBuf,
+) -> io::Result<Plaintext> {
+    let encrypted = db.is_encrypted(hash).await.map_err(io::Error::other)?;
+    let conf = config.encryption.clone();
+    tokio::task::spawn_blocking(move || plaintext_blocking(conf.as_ref(), path, encrypted)).await?
+}
+
+/// Write the plaintext of the blob at `from` to `to`.
+pub(super) async fn decrypt_to(config: &Config, from: PathBuf, to: PathBuf) -> io::Result<()> {
+    let conf = config.encryption.clone().ok_or_else(|| {
+        io::Error::other(format!(
+            "{from:?} is encrypted but no `[encryption]` is set"
+        ))
+    })?;
+    tokio::task::spawn_blocking(move || decrypt_file(&conf, &from, &to)).await?
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn encryption_round_trip() {
+        let key = [7; 32];
+        for size in [0, 10, CHUNK_LEN, 2 * CHUNK_LEN + 3] {
+            let content: Vec<u8> = (0..size).map(|i| i as u8).collect();
+            let mut sealed = Vec::new();
+            encrypt(&key, &mut content.as_slice(), &mut sealed).unwrap();
+            assert_eq!(sealed.len() as u64, encrypted_len(size as u64));
+            let mut opened = Vec::new();
+            decrypt(&key, &mut sealed.as_slice(), &mut opened).unwrap();
+            assert_eq!(opened, content);
+
+            let truncated = &sealed[..sealed.len() - TAG_LEN - 1];
+            assert!(decrypt(&key, &mut &truncated[..], &mut Vec::new()).is_err());
+        }
+    }
+
+    #[test]
+    fn blobs_have_their_own_key() {
+        let key = [7; 32];
+        let seal = || {
+            let mut sealed = Vec::new();
+            encrypt(&key, &mut &b"same content"[..], &mut sealed).unwrap();
+            sealed
+        };
+        let (first, second) = (seal(), seal());
+        let header = MAGIC.len() + SALT_LEN;
+        assert_ne!(first[MAGIC.len()..header], second[MAGIC.len()..header]);
+        assert_ne!(first[header..], second[header..]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn decrypted_copies_are_private() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = std::env::temp_dir().join(format!("pipeline-plain-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let key_file = dir.join("blob.key");
+        std::fs::write(&key_file, "07".repeat(32)).unwrap();
+        let conf = EncryptionConfig {
+            key_file,
+            decrypt_directory: dir.join("plain"),
+        };
+        let blob = dir.join("blob.txt");
+        std::fs::write(&blob, "secret").unwrap();
+        let sealed = dir.join("blob.sealed");
+        encrypt_file(&conf, &blob, &sealed).unwrap();
+
+        let plain = plaintext_blocking(Some(&conf), sealed, true).unwrap();
+        assert_eq!(std::fs::read(plain.path()).unwrap(), b"secret");
+        let mode = |path: &Path| path.metadata().unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(plain.path()), 0o600);
+        assert_eq!(mode(plain.path().parent().unwrap()), 0o700);
+        drop(plain);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
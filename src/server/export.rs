@@ -0,0 +1,137 @@
+use std::{
+    collections::BTreeMap,
+    io::{self, Write},
+    path::Path,
+};
+
+use serde_json::Value as JsonValue;
+
+use crate::server::{
+    Config,
+    database::{Database, JsonObject},
+};
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub(crate) enum ExportFormat {
+    /// All tables (or the one given) as a JSON object mapping table names to
+    /// their rows, which `db import` reads back
+    Json,
+    /// A single table, `files_in_pipeline` unless another one is given
+    Csv,
+}
+
+/// Print the content of the database on stdout.
+pub(crate) async fn export(
+    config: Config,
+    format: ExportFormat,
+    table: Option<String>,
+) -> io::Result<()> {
+    let db = Database::open_existing(&config.database)
+        .await
+        .map_err(io::Error::other)?;
+    let tables = db.tables().await.map_err(io::Error::other)?;
+    if let Some(table) = &table
+        && !tables.contains(table)
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("unknown table {table}, expected one of {tables:?}"),
+        ));
+    }
+
+    let mut out = io::stdout().lock();
+    match format {
+        ExportFormat::Json => {
+            let mut content = BTreeMap::new();
+            for table in table.map_or(tables, |table| vec![table]) {
+                let rows = db.export_table(&table).await.map_err(io::Error::other)?;
+                content.insert(table, rows);
+            }
+            serde_json::to_writer_pretty(&mut out, &content)?;
+            writeln!(out)?;
+        }
+        ExportFormat::Csv => {
+            let table = table.unwrap_or_else(|| "files_in_pipeline".to_owned());
+            let columns = db.columns(&table).await.map_err(io::Error::other)?;
+            let rows = db.export_table(&table).await.map_err(io::Error::other)?;
+            writeln!(out, "{}", columns.join(","))?;
+            for row in rows {
+                let fields: Vec<_> = columns
+                    .iter()
+                    .map(|column| csv_field(row.get(column).unwrap_or(&JsonValue::Null)))
+                    .collect();
+                writeln!(out, "{}", fields.join(","))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Field of a CSV record, quoted if needed.
+fn csv_field(value: &JsonValue) -> String {
+    let text = match value {
+        JsonValue::Null => return String::new(),
+        JsonValue::String(s) => s.clone(),
+        value => value.to_string(),
+    };
+    if text.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text
+    }
+}
+
+/// Load a JSON export into the database, which should not contain any file yet.
+pub(crate) async fn import(config: Config, path: &Path) -> io::Result<()> {
+    let content: BTreeMap<String, Vec<JsonObject>> =
+        serde_json::from_slice(&std::fs::read(path)?).map_err(io::Error::other)?;
+    let db = Database::open_existing(&config.database)
+        .await
+        .map_err(io::Error::other)?;
+
+    let tables = db.tables().await.map_err(io::Error::other)?;
+    for (table, rows) in &content {
+        if !tables.contains(table) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown table {table}"),
+            ));
+        }
+        let columns = db.columns(table).await.map_err(io::Error::other)?;
+        if let Some(column) = rows
+            .iter()
+            .flat_map(|row| row.keys())
+            .find(|column| !columns.contains(column))
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown column {column} in table {table}"),
+            ));
+        }
+    }
+    if !db.import_tables(&content).await.map_err(io::Error::other)? {
+        return Err(io::Error::other(
+            "database already contains files, import into an empty database",
+        ));
+    }
+    for (table, rows) in &content {
+        println!("{table}: imported {} rows", rows.len());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn csv_fields() {
+        assert_eq!(csv_field(&JsonValue::Null), "");
+        assert_eq!(csv_field(&JsonValue::from(42)), "42");
+        assert_eq!(csv_field(&JsonValue::from("a/b")), "a/b");
+        assert_eq!(
+            csv_field(&JsonValue::from(r#"{"a":"1","b":"2"}"#)),
+            r#""{""a"":""1"",""b"":""2""}""#
+        );
+    }
+}
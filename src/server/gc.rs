@@ -0,0 +1,127 @@
+use std::{
+    collections::HashSet,
+    io,
+    path::{Path, PathBuf},
+};
+
+use tokio::net::TcpListener;
+
+use crate::{
+    FileSpec, format_size,
+    server::{
+        Config,
+        database::Database,
+        verify::{expected_paths, files_in},
+    },
+};
+
+/// Number of files read from the database at once.
+const PAGE_SIZE: u32 = 1000;
+
+/// What is done with files in the incoming directory that are not in the
+/// database.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Collect {
+    /// Only list them.
+    List,
+    /// Move them to this directory.
+    Quarantine(PathBuf),
+    /// Remove them.
+    Delete,
+}
+
+/// Remove files in the incoming directory that are not in the database, or
+/// move them to a quarantine directory, e.g. files left over by a crash.
+/// Files are only listed unless told otherwise.
+pub(crate) async fn main(config: Config, collect: Collect) -> io::Result<()> {
+    let db = Database::open_existing(&config.database)
+        .await
+        .map_err(io::Error::other)?;
+    // Files arriving while the server runs are not in the database yet.
+    if let Err(err) = TcpListener::bind(&config.server.address).await
+        && err.kind() == io::ErrorKind::AddrInUse
+    {
+        return Err(io::Error::other(format!(
+            "{} is in use, stop the server before collecting files",
+            config.server.address
+        )));
+    }
+
+    let present = files_in(&config.incoming_directory)?;
+    let origins = db.companion_origins(None).await.map_err(io::Error::other)?;
+    let mut expected = HashSet::new();
+    let mut after = String::new();
+    loop {
+        let page = db
+            .content_page(&after, PAGE_SIZE)
+            .await
+            .map_err(io::Error::other)?;
+        let Some(last) = page.last() else {
+            break;
+        };
+        after = last.hash.clone();
+        let files: Vec<_> = page
+            .into_iter()
+            .map(|row| {
+                let path = config.stored_path(&row.storage_path);
+                (FileSpec::from(row), path)
+            })
+            .collect();
+        let stored = files.iter().map(|(spec, path)| (spec, path.as_path()));
+        expected.extend(expected_paths(&config, stored, &origins));
+    }
+    // Likely the wrong database, every file would be collected.
+    if after.is_empty() {
+        return Err(io::Error::other(
+            "the database has no files, refusing to collect the incoming directory",
+        ));
+    }
+
+    let mut nfiles = 0;
+    let mut total_size = 0;
+    for orphan in present.into_iter().filter(|path| !expected.contains(path)) {
+        let size = orphan.metadata()?.len();
+        match &collect {
+            Collect::List => println!("would collect {orphan:?} ({})", format_size(size)),
+            Collect::Quarantine(quarantine) => {
+                let relative = orphan
+                    .strip_prefix(&config.incoming_directory)
+                    .expect("orphan should be in incoming directory");
+                let destination = quarantine.join(relative);
+                move_file(&orphan, &destination)?;
+                println!("moved {orphan:?} to {destination:?}");
+            }
+            Collect::Delete => {
+                std::fs::remove_file(&orphan)?;
+                println!("removed {orphan:?}");
+            }
+        }
+        nfiles += 1;
+        total_size += size;
+    }
+    let action = match collect {
+        Collect::List => "would collect",
+        Collect::Quarantine(_) => "quarantined",
+        Collect::Delete => "removed",
+    };
+    println!(
+        "{action} {nfiles} orphan files ({})",
+        format_size(total_size)
+    );
+    if collect == Collect::List && nfiles > 0 {
+        println!("pass `--delete` or `--quarantine` to collect them");
+    }
+    Ok(())
+}
+
+/// Move `from` to `to`, copying it if they are on different filesystems.
+pub(super) fn move_file(from: &Path, to: &Path) -> io::Result<()> {
+    if let Some(parent) = to.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    if std::fs::rename(from, to).is_err() {
+        std::fs::copy(from, to)?;
+        std::fs::remove_file(from)?;
+    }
+    Ok(())
+}
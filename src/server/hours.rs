@@ -0,0 +1,134 @@
+use std::time::Duration;
+
+use chrono::{Local, NaiveTime, TimeDelta};
+use log::debug;
+use serde::Deserialize;
+
+/// Daily window of local time, written `"HH:MM-HH:MM"` in the configuration.
+/// It wraps around midnight if it ends before it starts, e.g. `"20:00-07:00"`.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub(super) struct TimeWindow {
+    start: NaiveTime,
+    end: NaiveTime,
+}
+
+impl TryFrom<String> for TimeWindow {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let invalid = || format!("invalid time window {value:?}, expected \"HH:MM-HH:MM\"");
+        let (start, end) = value.split_once('-').ok_or_else(invalid)?;
+        let parse = |s: &str| NaiveTime::parse_from_str(s.trim(), "%H:%M").map_err(|_| invalid());
+        let window = Self {
+            start: parse(start)?,
+            end: parse(end)?,
+        };
+        if window.start == window.end {
+            return Err(format!("time window {value:?} is empty"));
+        }
+        Ok(window)
+    }
+}
+
+impl TimeWindow {
+    fn contains(&self, time: NaiveTime) -> bool {
+        if self.start < self.end {
+            self.start <= time && time < self.end
+        } else {
+            self.start <= time || time < self.end
+        }
+    }
+
+    /// Time from `time` until the window closes, assuming it is open.
+    fn closes_in(&self, time: NaiveTime) -> TimeDelta {
+        let delta = self.end - time;
+        if delta <= TimeDelta::zero() {
+            delta + TimeDelta::days(1)
+        } else {
+            delta
+        }
+    }
+
+    /// Time from `time` until the window next opens.
+    fn opens_in(&self, time: NaiveTime) -> TimeDelta {
+        let delta = self.start - time;
+        if delta < TimeDelta::zero() {
+            delta + TimeDelta::days(1)
+        } else {
+            delta
+        }
+    }
+}
+
+/// Time from `time` until one of `windows` opens, `None` if one is open or
+/// there are no windows at all.
+fn closed_for(windows: &[TimeWindow], time: NaiveTime) -> Option<TimeDelta> {
+    if windows.iter().any(|w| w.contains(time)) {
+        return None;
+    }
+    windows.iter().map(|w| w.opens_in(time)).min()
+}
+
+/// Time from `time` until the open windows among `windows` close, `None` if
+/// none is open.
+fn open_for(windows: &[TimeWindow], time: NaiveTime) -> Option<TimeDelta> {
+    windows
+        .iter()
+        .filter(|w| w.contains(time))
+        .map(|w| w.closes_in(time))
+        .max()
+}
+
+/// Whether `windows` allow processing now, always the case without windows.
+pub(super) fn is_open(windows: &[TimeWindow]) -> bool {
+    closed_for(windows, Local::now().time()).is_none()
+}
+
+/// Wait until one of `windows` is open.
+pub(super) async fn wait_until_open(windows: &[TimeWindow]) {
+    while let Some(delta) = closed_for(windows, Local::now().time()) {
+        let delay = delta.to_std().unwrap_or_default();
+        debug!("outside of processing hours, waiting {}s", delay.as_secs());
+        // Time is checked again once the window opens, in case the clock
+        // changed in the meantime.
+        tokio::time::sleep(delay + Duration::from_secs(1)).await;
+    }
+}
+
+/// Wait until all of `windows` are closed, forever if there are none.
+pub(super) async fn wait_until_closed(windows: &[TimeWindow]) {
+    if windows.is_empty() {
+        return std::future::pending().await;
+    }
+    while let Some(delta) = open_for(windows, Local::now().time()) {
+        let delay = delta.to_std().unwrap_or_default();
+        // Another window may still be open at that time.
+        tokio::time::sleep(delay + Duration::from_secs(1)).await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn at(hour: u32, min: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(hour, min, 0).unwrap()
+    }
+
+    #[test]
+    fn overnight_window() {
+        let night = TimeWindow::try_from("20:00-07:00".to_owned()).unwrap();
+        assert_eq!(closed_for(&[night], at(23, 0)), None);
+        assert_eq!(closed_for(&[night], at(6, 59)), None);
+        assert_eq!(
+            closed_for(&[night], at(19, 30)),
+            Some(TimeDelta::minutes(30))
+        );
+        assert_eq!(closed_for(&[], at(12, 0)), None);
+        assert_eq!(open_for(&[night], at(6, 30)), Some(TimeDelta::minutes(30)));
+        assert_eq!(open_for(&[night], at(12, 0)), None);
+        assert!(TimeWindow::try_from("20:00".to_owned()).is_err());
+        assert!(TimeWindow::try_from("07:00-07:00".to_owned()).is_err());
+    }
+}
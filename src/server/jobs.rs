@@ -0,0 +1,364 @@
+use std::{
+    io,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use chrono::{Datelike, Local, NaiveDateTime, TimeDelta, Timelike};
+use futures_util::future::join_all;
+use log::{debug, info, warn};
+use serde::Deserialize;
+
+use crate::{
+    cli::MarkStatus,
+    format_utc,
+    server::{
+        Config,
+        clean::clean_tasks_with_status,
+        database::{Database, ProcessStatus, PruneFilter},
+        maintenance, prune_once, verify,
+    },
+};
+
+/// Maintenance job run by the server on a schedule.
+#[derive(Deserialize, Debug, PartialEq, Eq)]
+pub(super) struct ScheduledJob {
+    schedule: Schedule,
+    #[serde(flatten)]
+    job: Job,
+}
+
+#[derive(Deserialize, Debug, PartialEq, Eq)]
+#[serde(tag = "job", rename_all = "snake_case")]
+enum Job {
+    /// Prune `ToPrune` tasks, as done every `prune_every_secs`.
+    Prune,
+    /// Prune tasks with any of `statuses` whose status last changed at least
+    /// `older_than_secs` ago.
+    Retention {
+        #[serde(default = "default_retention_statuses")]
+        statuses: Vec<MarkStatus>,
+        older_than_secs: u64,
+    },
+    /// Back up the database to `backup_directory`.
+    Backup,
+    /// Re-hash received files, as `pipeline server verify`.
+    Verify,
+    /// Log files received and processing attempts since the previous run.
+    Stats,
+}
+
+fn default_retention_statuses() -> Vec<MarkStatus> {
+    vec![MarkStatus::Done]
+}
+
+impl Job {
+    /// Run the job, `last_run` is when it previously ran.
+    async fn run(&self, config: &Arc<Config>, db: &Database, last_run: SystemTime) {
+        match self {
+            Job::Prune => prune_once(config, db).await,
+            Job::Retention {
+                statuses,
+                older_than_secs,
+            } => {
+                let filter = PruneFilter {
+                    older_than: Some(Duration::from_secs(*older_than_secs)),
+                    ..PruneFilter::default()
+                };
+                for &status in statuses {
+                    let status = ProcessStatus::from(status);
+                    let summary =
+                        clean_tasks_with_status(config.clone(), db.clone(), status, &filter).await;
+                    info!("retention of {status:?} files: {summary}");
+                }
+            }
+            Job::Backup => {
+                let conf = &config.database;
+                match std::fs::create_dir_all(&conf.backup_directory) {
+                    Ok(()) => maintenance::backup_and_rotate(conf, db).await,
+                    Err(err) => warn!("failed to create {:?}: {err}", conf.backup_directory),
+                }
+            }
+            Job::Verify => match verify::verify(config, db, &config.incoming_directory).await {
+                Ok((verified, problems)) => {
+                    for problem in &problems {
+                        warn!("verification: {problem}");
+                    }
+                    info!(
+                        "verified {verified} files, found {} problems",
+                        problems.len()
+                    );
+                }
+                Err(err) => warn!("failed to verify incoming directory: {err}"),
+            },
+            Job::Stats => {
+                if let Err(err) = log_stats(db, &format_utc(last_run)).await {
+                    warn!("failed to read stats from db: {err}");
+                }
+            }
+        }
+    }
+}
+
+async fn log_stats(db: &Database, since: &str) -> sqlx::Result<()> {
+    for arrivals in db.daily_arrivals(since).await? {
+        info!(
+            "stats since {since}: {} received {} files ({} bytes) on {}",
+            arrivals.client, arrivals.files, arrivals.size_bytes, arrivals.day
+        );
+    }
+    for stats in db.processing_stats(since).await? {
+        info!(
+            "stats since {since}: {} processing attempts in group {}, {} failed",
+            stats.attempts, stats.processing, stats.failures
+        );
+    }
+    Ok(())
+}
+
+/// Run the scheduled `jobs` of the configuration, each waiting for the
+/// previous run to complete before scheduling the next one.
+pub(super) async fn run_scheduled(config: Arc<Config>, db: Database) -> io::Result<()> {
+    join_all(config.jobs.iter().map(|job| run_job(job, &config, &db))).await;
+    // No job left to run, which should not stop the server.
+    std::future::pending().await
+}
+
+async fn run_job(job: &ScheduledJob, config: &Arc<Config>, db: &Database) {
+    let mut last_run = SystemTime::now();
+    loop {
+        let now = Local::now().naive_local();
+        let Some(next) = job.schedule.next_after(now) else {
+            warn!("{:?} job never runs again", job.job);
+            return;
+        };
+        debug!("next {:?} job at {next}", job.job);
+        tokio::time::sleep((next - now).to_std().unwrap_or_default()).await;
+        info!("running scheduled {:?} job", job.job);
+        let started = SystemTime::now();
+        job.job.run(config, db, last_run).await;
+        last_run = started;
+    }
+}
+
+/// Cron-like schedule in local time, written `"minute hour day month weekday"`
+/// in the configuration. Each field is `*`, a value, a range `a-b`, a step
+/// `*/n` or `a-b/n`, or a comma-separated list of those. Weekdays range from 0
+/// (Sunday) to 6, with 7 also being Sunday. Like cron, a time matches if
+/// either day or weekday matches when both are restricted. `@hourly`,
+/// `@daily`, `@weekly` and `@monthly` are also accepted.
+#[derive(Deserialize, Debug, PartialEq, Eq)]
+#[serde(try_from = "String")]
+struct Schedule {
+    /// Bit sets of the allowed values of each field.
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    any_day: bool,
+    any_weekday: bool,
+}
+
+/// Bit set of the values allowed by a cron field ranging from `min` to `max`.
+fn parse_field(field: &str, min: u32, max: u32) -> Result<u64, String> {
+    let invalid = || format!("invalid field {field:?}, expected values in {min}-{max}");
+    let value = |s: &str| {
+        s.parse::<u32>()
+            .ok()
+            .filter(|v| (min..=max).contains(v))
+            .ok_or_else(invalid)
+    };
+    let mut set = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>().map_err(|_| invalid())?),
+            None => (part, 1),
+        };
+        let (start, end) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((start, end)) => (value(start)?, value(end)?),
+            None if step > 1 => (value(range)?, max),
+            None => (value(range)?, value(range)?),
+        };
+        if step == 0 || start > end {
+            return Err(invalid());
+        }
+        for v in (start..=end).step_by(step as usize) {
+            set |= 1 << v;
+        }
+    }
+    Ok(set)
+}
+
+impl TryFrom<String> for Schedule {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let expr = match value.as_str() {
+            "@hourly" => "0 * * * *",
+            "@daily" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            "@monthly" => "0 0 1 * *",
+            expr => expr,
+        };
+        let fields: Vec<_> = expr.split_whitespace().collect();
+        let [minutes, hours, days, months, weekdays] = fields[..] else {
+            return Err(format!(
+                "invalid schedule {value:?}, expected \"minute hour day month weekday\""
+            ));
+        };
+        let mut weekday_set = parse_field(weekdays, 0, 7)?;
+        if weekday_set & (1 << 7) != 0 {
+            weekday_set |= 1;
+        }
+        let schedule = Self {
+            minutes: parse_field(minutes, 0, 59)?,
+            hours: parse_field(hours, 0, 23)?,
+            days: parse_field(days, 1, 31)?,
+            months: parse_field(months, 1, 12)?,
+            weekdays: weekday_set,
+            any_day: days == "*",
+            any_weekday: weekdays == "*",
+        };
+        let start = NaiveDateTime::default();
+        if schedule.next_after(start).is_none() {
+            return Err(format!("schedule {value:?} never matches"));
+        }
+        Ok(schedule)
+    }
+}
+
+impl Schedule {
+    fn matches_day(&self, time: NaiveDateTime) -> bool {
+        let day = self.days & (1 << time.day()) != 0;
+        let weekday = self.weekdays & (1 << time.weekday().num_days_from_sunday()) != 0;
+        let day = match (self.any_day, self.any_weekday) {
+            (false, false) => day || weekday,
+            _ => day && weekday,
+        };
+        day && self.months & (1 << time.month()) != 0
+    }
+
+    /// First matching minute strictly after `time`, `None` if there is none
+    /// within four years.
+    fn next_after(&self, time: NaiveDateTime) -> Option<NaiveDateTime> {
+        let mut time = time.with_second(0)?.with_nanosecond(0)? + TimeDelta::minutes(1);
+        let limit = time + TimeDelta::days(4 * 366);
+        while time < limit {
+            if !self.matches_day(time) {
+                time = time.date().succ_opt()?.into();
+            } else if self.hours & (1 << time.hour()) == 0 {
+                time = time.with_minute(0)? + TimeDelta::hours(1);
+            } else if self.minutes & (1 << time.minute()) == 0 {
+                time += TimeDelta::minutes(1);
+            } else {
+                return Some(time);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::server::test::{config_in, stored_file};
+
+    fn schedule(expr: &str) -> Result<Schedule, String> {
+        Schedule::try_from(expr.to_owned())
+    }
+
+    fn at(s: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M").unwrap()
+    }
+
+    #[test]
+    fn next_scheduled_time() {
+        let nightly = schedule("30 2 * * *").unwrap();
+        let next = nightly.next_after(at("2025-01-31 12:00"));
+        assert_eq!(next, Some(at("2025-02-01 02:30")));
+
+        let weekdays = schedule("*/15 8-9 * * 1-5").unwrap();
+        let next = weekdays.next_after(at("2025-02-01 12:00"));
+        assert_eq!(next, Some(at("2025-02-03 08:00")), "saturday to monday");
+        let next = weekdays.next_after(at("2025-02-03 08:00"));
+        assert_eq!(next, Some(at("2025-02-03 08:15")));
+
+        let next = schedule("@monthly")
+            .unwrap()
+            .next_after(at("2025-02-03 08:00"));
+        assert_eq!(next, Some(at("2025-03-01 00:00")));
+
+        assert!(schedule("0 0 31 2 *").is_err());
+        assert!(schedule("60 * * * *").is_err());
+        assert!(schedule("* * *").is_err());
+    }
+
+    #[tokio::test]
+    async fn retention_prunes_old_files_with_statuses() {
+        let (config, dir) = config_in(
+            "retention",
+            &[(
+                "jobs = [\n",
+                "jobs = [
+    { schedule = \"@daily\", job = \"retention\", older_than_secs = 3600 },
+    { schedule = \"@daily\", job = \"retention\", older_than_secs = 0 },
+",
+            )],
+        );
+        let config = Arc::new(config);
+        let db = Database::in_memory().await.unwrap();
+        for (name, status) in [
+            ("done.dat", ProcessStatus::Done),
+            ("queued.dat", ProcessStatus::Queued),
+        ] {
+            let file = stored_file(&config, name, name);
+            db.insert_new(&file, name, None).await.unwrap();
+            db.update_status(file.hash(), status, "test").await.unwrap();
+        }
+        let files = || async { db.content().await.unwrap().len() };
+
+        let [recent, any_age] = &config.jobs[..] else {
+            panic!("expected two jobs, got {:?}", config.jobs);
+        };
+        recent.job.run(&config, &db, SystemTime::now()).await;
+        assert_eq!(files().await, 2);
+        any_age.job.run(&config, &db, SystemTime::now()).await;
+        assert_eq!(files().await, 1);
+        assert!(!dir.join("done.dat").exists());
+        assert!(dir.join("queued.dat").exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn backup_job_backs_up_the_database() {
+        let (config, dir) = config_in(
+            "backup-job",
+            &[
+                (
+                    "jobs = [\n",
+                    "jobs = [\n    { schedule = \"30 2 * * *\", job = \"backup\" },\n",
+                ),
+                ("\"./server/backups\"", "\"./server/buckets/backups\""),
+            ],
+        );
+        let config = Arc::new(config);
+        let db = Database::at_path(&dir.join("pipeline.db")).await.unwrap();
+        let file = stored_file(&config, "file.dat", "content");
+        db.insert_new(&file, "file.dat", None).await.unwrap();
+
+        config.jobs[0]
+            .job
+            .run(&config, &db, SystemTime::now())
+            .await;
+        let backups: Vec<_> = std::fs::read_dir(dir.join("backups"))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(backups.len(), 1);
+        let backup = Database::at_path(&backups[0].path()).await.unwrap();
+        assert_eq!(backup.content().await.unwrap().len(), 1);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
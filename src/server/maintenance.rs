@@ -0,0 +1,136 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use log::{info, warn};
+use tokio::time::MissedTickBehavior;
+
+use crate::{
+    format_size,
+    server::{Config, DatabaseConfig, database::Database},
+};
+
+const BACKUP_PREFIX: &str = "pipeline-server-";
+const BACKUP_EXTENSION: &str = "db";
+
+pub(crate) async fn main(config: Config) -> io::Result<()> {
+    let db = Database::open_existing(&config.database)
+        .await
+        .map_err(io::Error::other)?;
+
+    let problems = db.integrity_check().await.map_err(io::Error::other)?;
+    if problems != ["ok"] {
+        for problem in &problems {
+            eprintln!("- {problem}");
+        }
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "database integrity check failed, not attempting further maintenance",
+        ));
+    }
+    println!("integrity check: ok");
+
+    let size_before = db.size().await.map_err(io::Error::other)?;
+    db.checkpoint().await.map_err(io::Error::other)?;
+    db.vacuum().await.map_err(io::Error::other)?;
+    let size_after = db.size().await.map_err(io::Error::other)?;
+    println!(
+        "vacuum: {} -> {}",
+        format_size(size_before as u64),
+        format_size(size_after as u64)
+    );
+
+    for (table, rows) in db.table_sizes().await.map_err(io::Error::other)? {
+        println!("{table}: {rows} rows");
+    }
+    Ok(())
+}
+
+/// Back up the database to `dest`, or to a new timestamped file if `dest` is
+/// a directory.
+pub(crate) async fn backup(config: Config, dest: &Path) -> io::Result<()> {
+    let db = Database::open_existing(&config.database)
+        .await
+        .map_err(io::Error::other)?;
+    let dest = if dest.is_dir() {
+        backup_path(dest)
+    } else {
+        dest.to_owned()
+    };
+    db.backup_to(&dest).await.map_err(io::Error::other)?;
+    println!(
+        "backed up database to {dest:?} ({})",
+        format_size(dest.metadata()?.len())
+    );
+    Ok(())
+}
+
+/// Timestamped backup file in `directory`, named so that sorting backups by
+/// name sorts them by date.
+fn backup_path(directory: &Path) -> PathBuf {
+    let date = chrono::DateTime::<chrono::Utc>::from(SystemTime::now());
+    directory.join(format!(
+        "{BACKUP_PREFIX}{}.{BACKUP_EXTENSION}",
+        date.format("%Y%m%dT%H%M%SZ")
+    ))
+}
+
+/// Back up the database every `backup_every_secs`, only keeping the
+/// `backup_keep` most recent backups.
+pub(super) async fn backup_periodically(config: Arc<Config>, db: Database) -> io::Result<()> {
+    let conf = &config.database;
+    if conf.backup_every_secs == 0 {
+        return std::future::pending().await;
+    }
+    std::fs::create_dir_all(&conf.backup_directory)?;
+    let mut interval = tokio::time::interval(Duration::from_secs(conf.backup_every_secs));
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    // The first tick completes immediately, skip it to not back up the
+    // database at each restart.
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        backup_and_rotate(conf, &db).await;
+    }
+}
+
+/// Back up the database to `backup_directory`, only keeping the `backup_keep`
+/// most recent backups.
+pub(super) async fn backup_and_rotate(conf: &DatabaseConfig, db: &Database) {
+    let dest = backup_path(&conf.backup_directory);
+    match db.backup_to(&dest).await {
+        Ok(()) => info!("backed up database to {dest:?}"),
+        Err(err) => {
+            warn!("failed to back up database to {dest:?}: {err}");
+            return;
+        }
+    }
+    if let Err(err) = remove_old_backups(&conf.backup_directory, conf.backup_keep) {
+        warn!("failed to remove old database backups: {err}");
+    }
+}
+
+fn remove_old_backups(directory: &Path, keep: usize) -> io::Result<()> {
+    let mut backups = Vec::new();
+    for entry in std::fs::read_dir(directory)? {
+        let path = entry?.path();
+        let is_backup = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.starts_with(BACKUP_PREFIX))
+            && path.extension().is_some_and(|ext| ext == BACKUP_EXTENSION);
+        if is_backup {
+            backups.push(path);
+        }
+    }
+    backups.sort();
+    let nremove = backups.len().saturating_sub(keep);
+    for path in &backups[..nremove] {
+        info!("removing old database backup {path:?}");
+        std::fs::remove_file(path)?;
+    }
+    Ok(())
+}
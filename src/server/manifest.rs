@@ -0,0 +1,114 @@
+use std::{io, time::SystemTime};
+
+use hmac::{Hmac, KeyInit, Mac};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::server::{
+    Config,
+    database::{Attempt, AuditEntry, Database, FileInPipeline, Origin},
+};
+
+/// Files included in a manifest.
+pub(crate) enum Selection {
+    Hash(String),
+    /// Files announced at or after this date, formatted as in the database.
+    Since(String),
+}
+
+#[derive(Serialize)]
+struct Manifest {
+    generated_utc: String,
+    pipeline_version: &'static str,
+    files: Vec<FileRecord>,
+}
+
+/// Chain of custody of a file, from its announcement to its processing.
+#[derive(Serialize)]
+struct FileRecord {
+    hash: String,
+    /// Current state of the file, `None` once it is pruned from the database.
+    file: Option<FileInPipeline>,
+    origins: Vec<Origin>,
+    history: Vec<AuditEntry>,
+    attempts: Vec<Attempt>,
+}
+
+#[derive(Serialize)]
+struct SignedManifest {
+    manifest: Manifest,
+    /// SHA-256 of the compact JSON serialization of `manifest`, with keys in
+    /// the order of this document.
+    sha256: String,
+    /// HMAC-SHA256 of the same serialization keyed with `manifest_key_file`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hmac_sha256: Option<String>,
+}
+
+/// Print the manifest of the selected files on stdout.
+pub(crate) async fn main(config: Config, selection: Selection) -> io::Result<()> {
+    let db = Database::open_existing(&config.database)
+        .await
+        .map_err(io::Error::other)?;
+
+    let files = match selection {
+        Selection::Hash(hash) => {
+            let file = db.file(&hash).await.map_err(io::Error::other)?;
+            vec![(hash, file)]
+        }
+        Selection::Since(date) => db
+            .announced_since(&date)
+            .await
+            .map_err(io::Error::other)?
+            .into_iter()
+            .map(|file| (file.hash.clone(), Some(file)))
+            .collect(),
+    };
+
+    let mut records = Vec::with_capacity(files.len());
+    for (hash, file) in files {
+        let history = db.history(&hash).await.map_err(io::Error::other)?;
+        if file.is_none() && history.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no record of {hash}"),
+            ));
+        }
+        records.push(FileRecord {
+            origins: db.origins(&hash).await.map_err(io::Error::other)?,
+            attempts: db.attempts(&hash).await.map_err(io::Error::other)?,
+            hash,
+            file,
+            history,
+        });
+    }
+
+    let manifest = Manifest {
+        generated_utc: chrono::DateTime::<chrono::Utc>::from(SystemTime::now())
+            .format("%Y-%m-%d %H:%M:%S")
+            .to_string(),
+        pipeline_version: env!("CARGO_PKG_VERSION"),
+        files: records,
+    };
+    let content = serde_json::to_vec(&manifest).map_err(io::Error::other)?;
+    let hmac_sha256 = match &config.manifest_key_file {
+        Some(key_file) => {
+            let key = std::fs::read(key_file)?;
+            let mut mac = Hmac::<Sha256>::new_from_slice(key.trim_ascii_end())
+                .expect("HMAC accepts keys of any size");
+            mac.update(&content);
+            Some(hex::encode(mac.finalize().into_bytes()))
+        }
+        None => None,
+    };
+    let signed = SignedManifest {
+        manifest,
+        sha256: hex::encode(Sha256::digest(&content)),
+        hmac_sha256,
+    };
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&signed).map_err(io::Error::other)?
+    );
+    Ok(())
+}
@@ -1,30 +1,67 @@
 use std::{
+    collections::{BTreeMap, HashMap},
     ffi::{OsStr, OsString},
+    fmt::{self, Write as _},
     fs,
-    path::PathBuf,
+    io::Write,
+    path::{Path, PathBuf},
+    process::ExitStatus,
+    sync::Arc,
+    time::{Duration, Instant},
 };
 
+use futures_util::future::BoxFuture;
 use log::warn;
 use serde::Deserialize;
 use tokio::{io, process::Command};
 
 use crate::{
-    FileSpec, custom_serde, replace_os_strings,
-    server::{Config, Database, ProcessStatus},
+    FileSpec, custom_serde,
+    hashing::{FileDigest, HashAlgorithm, HashMode, ReadOptions},
+    replace_os_strings,
+    server::{
+        Config, Database, ProcessStatus, companion_path_of, crypt, encryption,
+        scheduler::Resources, spawn::SpawnOptions,
+    },
 };
 
+/// Longest time an `HttpPost` step waits for the request to complete.
+const HTTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Placeholders available in processing steps.
+pub(super) const PLACEHOLDERS: [&str; 7] = [
+    "{hash}",
+    "{server_path}",
+    "{companion_path}",
+    "{client_name}",
+    "{client_relative_directory}",
+    "{client_file_stem}",
+    "{client_file_name}",
+];
+
 struct Replacements<'a> {
     file: &'a FileSpec,
     server_path: PathBuf,
+    /// Empty if the file has no companion.
+    companion_path: PathBuf,
     rel_dir: PathBuf,
+    /// `{meta.key}` placeholders and their values.
+    metadata: Vec<(String, &'a str)>,
 }
 
 impl<'a> Replacements<'a> {
-    fn new(file: &'a FileSpec, config: &Config) -> Self {
+    /// Replacements for `file` stored at `server_path`.
+    fn new(file: &'a FileSpec, server_path: &Path) -> Self {
         Self {
             file,
-            server_path: config.path_of(file),
+            server_path: server_path.to_owned(),
+            companion_path: companion_path_of(server_path, file).unwrap_or_default(),
             rel_dir: file.relative_directory(),
+            metadata: file
+                .metadata
+                .iter()
+                .map(|(key, value)| (format!("{{meta.{key}}}"), value.as_str()))
+                .collect(),
         }
     }
 
@@ -32,31 +69,306 @@ impl<'a> Replacements<'a> {
         [
             ("{hash}", self.file.hash().as_ref()),
             ("{server_path}", self.server_path.as_os_str()),
+            ("{companion_path}", self.companion_path.as_os_str()),
             ("{client_name}", self.file.client.as_ref()),
             ("{client_relative_directory}", self.rel_dir.as_os_str()),
             ("{client_file_stem}", self.file.file_stem()),
             ("{client_file_name}", self.file.filename.as_ref()),
         ]
         .into_iter()
+        .chain(
+            self.metadata
+                .iter()
+                .map(|(key, value)| (key.as_str(), value.as_ref())),
+        )
     }
 
     fn apply_to(&'a self, s: &str) -> OsString {
         replace_os_strings(s, self.iter())
     }
+
+    /// Replace placeholders in `s` by their values escaped to fit in JSON
+    /// strings.
+    fn apply_to_json(&'a self, s: &str) -> String {
+        let escaped: Vec<_> = self
+            .iter()
+            .map(|(key, value)| {
+                let quoted = serde_json::Value::from(value.to_string_lossy()).to_string();
+                (key, OsString::from(&quoted[1..quoted.len() - 1]))
+            })
+            .collect();
+        let escaped = escaped.iter().map(|(key, value)| (*key, value.as_os_str()));
+        replace_os_strings(s, escaped)
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    /// Replace placeholders in `s` by their percent-encoded values, so that
+    /// they fit in any component of a URL.
+    fn apply_to_url(&'a self, s: &str) -> String {
+        let encoded: Vec<_> = self
+            .iter()
+            .map(|(key, value)| {
+                let mut encoded = String::new();
+                for &byte in value.as_encoded_bytes() {
+                    if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+                        encoded.push(byte.into());
+                    } else {
+                        write!(encoded, "%{byte:02X}").expect("writing to a string cannot fail");
+                    }
+                }
+                (key, OsString::from(encoded))
+            })
+            .collect();
+        let encoded = encoded.iter().map(|(key, value)| (*key, value.as_os_str()));
+        replace_os_strings(s, encoded)
+            .to_string_lossy()
+            .into_owned()
+    }
+}
+
+/// Custom processing step, for applications embedding pipeline.
+///
+/// Steps are registered with [`ServerBuilder::step`](crate::ServerBuilder::step)
+/// and referred to as `{ plugin = "name" }` in the `processing` configuration.
+pub trait ProcessingStep: Send + Sync {
+    /// Name under which the step is referred to in the configuration.
+    fn name(&self) -> &str;
+
+    /// Run the step on a file, failing the processing on error.
+    fn run<'a>(&'a self, ctx: &'a StepContext<'a>) -> BoxFuture<'a, io::Result<()>>;
+}
+
+/// Information about the file being processed, available to a [`ProcessingStep`].
+pub struct StepContext<'a> {
+    rep: &'a Replacements<'a>,
+}
+
+impl StepContext<'_> {
+    /// Unique hash identifying the file.
+    pub fn hash(&self) -> &str {
+        self.rep.file.hash()
+    }
+
+    /// Path of the file on the server.
+    pub fn server_path(&self) -> &Path {
+        &self.rep.server_path
+    }
+
+    /// Value of a placeholder, e.g. `placeholder("client_name")`.
+    pub fn placeholder(&self, name: &str) -> Option<&OsStr> {
+        self.rep
+            .iter()
+            .find(|(key, _)| key.strip_prefix('{').and_then(|k| k.strip_suffix('}')) == Some(name))
+            .map(|(_, value)| value)
+    }
+
+    /// Replace all placeholders in `template`.
+    pub fn expand(&self, template: &str) -> OsString {
+        self.rep.apply_to(template)
+    }
+}
+
+/// Custom processing steps, indexed by name.
+#[derive(Clone, Default)]
+pub(super) struct Plugins(HashMap<String, Arc<dyn ProcessingStep>>);
+
+impl Plugins {
+    pub(super) fn register(&mut self, step: Arc<dyn ProcessingStep>) {
+        self.0.insert(step.name().to_owned(), step);
+    }
+
+    pub(super) fn contains(&self, name: &str) -> bool {
+        self.0.contains_key(name)
+    }
+}
+
+impl fmt::Debug for Plugins {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.0.keys()).finish()
+    }
+}
+
+impl PartialEq for Plugins {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.len() == other.0.len() && self.0.keys().all(|k| other.0.contains_key(k))
+    }
 }
 
+impl Eq for Plugins {}
+
 #[derive(Deserialize, Debug, PartialEq, Eq)]
 #[serde(untagged)]
 enum Step {
-    Mkdir { create_directory: String },
-    DeleteFile { delete_file: String },
-    DeleteDirectory { delete_directory: String },
+    /// Step holding the given amount of each resource while it runs.
+    WithResources {
+        run: Box<Step>,
+        resources: BTreeMap<String, u32>,
+    },
+    Mkdir {
+        create_directory: String,
+    },
+    DeleteFile {
+        delete_file: String,
+    },
+    DeleteDirectory {
+        delete_directory: String,
+    },
+    Plugin {
+        plugin: String,
+    },
+    Lua {
+        lua_script: String,
+    },
+    /// Encrypt a file for `recipient`, an age public key or a GPG key.
+    Encrypt {
+        encrypt: String,
+        recipient: String,
+        to: String,
+    },
+    /// Decrypt an age or OpenPGP file with the secret key in the file `key`.
+    Decrypt {
+        decrypt: String,
+        key: String,
+        to: String,
+    },
+    /// Send a POST request to `url` with `body_template` as JSON body.
+    HttpPost {
+        url: String,
+        body_template: String,
+    },
+    /// Append the SHA-256 of the file to the manifest of the day in the
+    /// `checksum_manifest` directory, listed under `name`.
+    ChecksumManifest {
+        checksum_manifest: String,
+        name: Option<String>,
+    },
+    /// External command run as another user or with a lower priority.
+    CommandWith {
+        #[serde(deserialize_with = "custom_serde::vec_at_least_one")]
+        command: Vec<String>,
+        #[serde(flatten)]
+        options: SpawnOptions,
+    },
     ExternalCommand(#[serde(deserialize_with = "custom_serde::vec_at_least_one")] Vec<String>),
 }
 
 impl Step {
-    async fn run(&self, rep: &Replacements<'_>) -> io::Result<()> {
+    /// Strings of this step in which placeholders are replaced.
+    fn templates(&self) -> Vec<&str> {
         match self {
+            Step::WithResources { run, .. } => run.templates(),
+            Step::Mkdir { create_directory } => vec![create_directory],
+            Step::DeleteFile { delete_file } => vec![delete_file],
+            Step::DeleteDirectory { delete_directory } => vec![delete_directory],
+            Step::Plugin { .. } => Vec::new(),
+            Step::Lua { lua_script } => vec![lua_script],
+            Step::Encrypt {
+                encrypt,
+                recipient,
+                to,
+            } => vec![encrypt, recipient, to],
+            Step::Decrypt { decrypt, key, to } => vec![decrypt, key, to],
+            Step::HttpPost { url, body_template } => vec![url, body_template],
+            Step::ChecksumManifest {
+                checksum_manifest,
+                name,
+            } => [Some(checksum_manifest), name.as_ref()]
+                .into_iter()
+                .flatten()
+                .map(String::as_str)
+                .collect(),
+            Step::CommandWith { command, .. } => command.iter().map(String::as_str).collect(),
+            Step::ExternalCommand(segments) => segments.iter().map(String::as_str).collect(),
+        }
+    }
+
+    /// Description of the step run on the file of `rep`, with placeholders
+    /// replaced.
+    fn describe(&self, rep: &Replacements<'_>) -> String {
+        let expanded = |templates: &[&String]| {
+            let expanded: Vec<_> = templates
+                .iter()
+                .map(|template| format!("{:?}", rep.apply_to(template)))
+                .collect();
+            expanded.join(" ")
+        };
+        match self.inner() {
+            Step::WithResources { .. } => unreachable!("inner step holds no resources"),
+            Step::Mkdir { create_directory } => {
+                format!("create directory {}", expanded(&[create_directory]))
+            }
+            Step::DeleteFile { delete_file } => format!("delete file {}", expanded(&[delete_file])),
+            Step::DeleteDirectory { delete_directory } => {
+                format!("delete directory {}", expanded(&[delete_directory]))
+            }
+            Step::Plugin { plugin } => format!("plugin step `{plugin}`"),
+            Step::Lua { lua_script } => format!("Lua script {}", expanded(&[lua_script])),
+            Step::Encrypt {
+                encrypt,
+                recipient,
+                to,
+            } => format!(
+                "encrypt {} for {} to {}",
+                expanded(&[encrypt]),
+                expanded(&[recipient]),
+                expanded(&[to])
+            ),
+            Step::Decrypt { decrypt, key, to } => format!(
+                "decrypt {} with {} to {}",
+                expanded(&[decrypt]),
+                expanded(&[key]),
+                expanded(&[to])
+            ),
+            Step::HttpPost { url, body_template } => format!(
+                "POST {} {}",
+                rep.apply_to_url(url),
+                rep.apply_to_json(body_template)
+            ),
+            Step::ChecksumManifest {
+                checksum_manifest, ..
+            } => format!("checksum manifest in {}", expanded(&[checksum_manifest])),
+            Step::CommandWith { command, .. } => expanded(&command.iter().collect::<Vec<_>>()),
+            Step::ExternalCommand(segments) => expanded(&segments.iter().collect::<Vec<_>>()),
+        }
+    }
+
+    /// Name of the log file of the output of this step, for external
+    /// commands.
+    fn log_name(&self) -> Option<String> {
+        let program = match self.inner() {
+            Step::CommandWith { command, .. } => &command[0],
+            Step::ExternalCommand(segments) => &segments[0],
+            _ => return None,
+        };
+        let program = Path::new(program).file_name().unwrap_or_default();
+        Some(format!("{}.log", program.to_string_lossy()))
+    }
+
+    /// Step run once its resources are acquired.
+    fn inner(&self) -> &Step {
+        match self {
+            Step::WithResources { run, .. } => run.inner(),
+            _ => self,
+        }
+    }
+
+    /// Run the step, external commands writing their output to `log` if set.
+    async fn run(
+        &self,
+        rep: &Replacements<'_>,
+        plugins: &Plugins,
+        resources: &mut Resources<'_>,
+        log: Option<&Path>,
+    ) -> io::Result<Flow> {
+        match self {
+            Step::WithResources {
+                run,
+                resources: required,
+            } => {
+                let _permits = resources.acquire(required).await?;
+                return Box::pin(run.run(rep, plugins, resources, log)).await;
+            }
             Step::Mkdir { create_directory } => {
                 let dir = rep.apply_to(create_directory);
                 fs::create_dir_all(dir)
@@ -69,21 +381,283 @@ impl Step {
                 let path = rep.apply_to(delete_directory);
                 fs::remove_dir_all(path)
             }
+            Step::Plugin { plugin } => match plugins.0.get(plugin) {
+                Some(step) => step.run(&StepContext { rep }).await,
+                None => Err(io::Error::other(format!("unknown plugin step `{plugin}`"))),
+            },
+            Step::Lua { lua_script } => return run_lua(rep.apply_to(lua_script), rep).await,
+            Step::Encrypt {
+                encrypt,
+                recipient,
+                to,
+            } => {
+                let recipient = rep.apply_to(recipient).to_string_lossy().into_owned();
+                let (from, to) = (rep.apply_to(encrypt), rep.apply_to(to));
+                crypt::encrypt(from.into(), to.into(), recipient).await
+            }
+            Step::Decrypt { decrypt, key, to } => {
+                let (from, to) = (rep.apply_to(decrypt), rep.apply_to(to));
+                crypt::decrypt(from.into(), to.into(), rep.apply_to(key).into()).await
+            }
+            Step::HttpPost { url, body_template } => {
+                let url = rep.apply_to_url(url);
+                let body = rep.apply_to_json(body_template);
+                http_post(url, body).await
+            }
+            Step::ChecksumManifest {
+                checksum_manifest,
+                name,
+            } => {
+                let name = match name {
+                    Some(name) => rep.apply_to(name).to_string_lossy().into_owned(),
+                    None => rep.file.relative_path().to_string_lossy().into_owned(),
+                };
+                let dir = PathBuf::from(rep.apply_to(checksum_manifest));
+                append_to_manifest(&dir, &name, rep.file, rep.server_path.clone()).await
+            }
+            Step::CommandWith { command, options } => run_command(command, options, rep, log).await,
             Step::ExternalCommand(segments) => {
-                let mut processing = Command::new(&segments[0])
-                    .args(segments[1..].iter().map(|a| rep.apply_to(a)))
-                    .spawn()?;
-
-                match processing.wait().await {
-                    Ok(status) if status.success() => Ok(()),
-                    Ok(status) => Err(io::Error::other(format!("failed with status {status:?}"))),
-                    Err(err) => Err(err),
-                }
+                run_command(segments, &SpawnOptions::default(), rep, log).await
             }
-        }
+        }?;
+        Ok(Flow::Next)
+    }
+}
+
+/// How processing goes on after a step succeeded.
+#[derive(Debug, PartialEq, Eq)]
+enum Flow {
+    Next,
+    /// Skip the remaining steps and hand the file over to this group, only
+    /// done by Lua scripts.
+    #[cfg_attr(not(feature = "lua"), expect(dead_code))]
+    RouteTo(String),
+}
+
+async fn run_command(
+    segments: &[String],
+    options: &SpawnOptions,
+    rep: &Replacements<'_>,
+    log: Option<&Path>,
+) -> io::Result<()> {
+    let args: Vec<_> = segments[1..].iter().map(|a| rep.apply_to(a)).collect();
+    let log = match log {
+        Some(path) => Some(open_step_log(path, &segments[0], &args)?),
+        None => None,
+    };
+    let mut processing = options.spawn(&segments[0], args, log)?;
+
+    match processing.wait().await {
+        Ok(status) if status.success() => Ok(()),
+        Ok(status) => Err(io::Error::other(CommandFailed(status))),
+        Err(err) => Err(err),
+    }
+}
+
+/// Open the log file at `path` for appending, starting with a line recording
+/// when and which command runs, as a file may be processed several times.
+fn open_step_log(path: &Path, program: &str, args: &[OsString]) -> io::Result<fs::File> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut log = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    let mut header = format!(
+        "==> {} UTC: {program}",
+        chrono::Utc::now().format("%Y-%m-%d %H:%M:%S")
+    );
+    for arg in args {
+        header.push(' ');
+        header.push_str(&arg.to_string_lossy());
+    }
+    header.push('\n');
+    log.write_all(header.as_bytes())?;
+    Ok(log)
+}
+
+/// Remove the logs of the steps run on the file `hash`, once it leaves the
+/// pipeline.
+pub(super) async fn remove_step_logs(config: &Config, hash: &str) {
+    let Some(dir) = &config.step_logs_directory else {
+        return;
+    };
+    match tokio::fs::remove_dir_all(dir.join(hash)).await {
+        Ok(()) => {}
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+        Err(err) => warn!("failed to remove step logs of {hash}: {err}"),
+    }
+}
+
+/// Send `body` to `url`, failing on statuses other than 2xx.
+async fn http_post(url: String, body: String) -> io::Result<()> {
+    if let Err(err) = serde_json::from_str::<serde_json::Value>(&body) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("request body is not valid JSON: {err}"),
+        ));
+    }
+    tokio::task::spawn_blocking(move || {
+        let agent: ureq::Agent = ureq::Agent::config_builder()
+            .timeout_global(Some(HTTP_TIMEOUT))
+            .build()
+            .into();
+        agent
+            .post(&url)
+            .header("Content-Type", "application/json")
+            .send(body)
+            .map_err(|err| io::Error::other(format!("POST to {url} failed: {err}")))?;
+        Ok(())
+    })
+    .await?
+}
+
+/// Append the SHA-256 of the file at `path` to the manifest of the day in
+/// `dir`, in the format of `sha256sum`, and the client, size and dates of
+/// `file` to its `.details` sidecar.
+async fn append_to_manifest(
+    dir: &Path,
+    name: &str,
+    file: &FileSpec,
+    path: PathBuf,
+) -> io::Result<()> {
+    let digest = tokio::task::spawn_blocking(move || {
+        FileDigest::new(
+            &path,
+            HashMode::Full,
+            HashAlgorithm::Sha256,
+            ReadOptions::default(),
+        )
+    })
+    .await??;
+    let now = chrono::Utc::now();
+    let (entry, details) = manifest_entries(
+        name,
+        digest.hash(),
+        file,
+        &now.format("%Y-%m-%d %H:%M:%S").to_string(),
+    );
+    fs::create_dir_all(dir)?;
+    let manifest = dir.join(format!("{}.sha256", now.format("%Y-%m-%d")));
+    let mut sidecar = manifest.clone().into_os_string();
+    sidecar.push(".details");
+    // Single writes so that concurrent processing does not interleave
+    // entries.
+    for (path, line) in [
+        (manifest.as_os_str(), entry),
+        (sidecar.as_os_str(), details),
+    ] {
+        fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?
+            .write_all(line.as_bytes())?;
+    }
+    Ok(())
+}
+
+/// Line of the manifest listing the file `name` with `sha256`, and the
+/// tab-separated line with its details. Like `sha256sum`, names with
+/// backslashes or newlines are escaped and their manifest line starts with a
+/// backslash, tabs are also escaped in details.
+fn manifest_entries(
+    name: &str,
+    sha256: &str,
+    file: &FileSpec,
+    processed_utc: &str,
+) -> (String, String) {
+    let escaped = name
+        .replace('\\', "\\\\")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r");
+    let prefix = if escaped != name { "\\" } else { "" };
+    let entry = format!("{prefix}{sha256}  {escaped}\n");
+    let details = format!(
+        "{}\t{}\t{}\t{}\t{processed_utc}\n",
+        escaped.replace('\t', "\\t"),
+        file.client,
+        file.size_bytes,
+        file.modified_utc,
+    );
+    (entry, details)
+}
+
+/// Error of an external command exiting unsuccessfully.
+#[derive(Debug)]
+struct CommandFailed(ExitStatus);
+
+impl fmt::Display for CommandFailed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "failed with status {:?}", self.0)
+    }
+}
+
+impl std::error::Error for CommandFailed {}
+
+/// Failure of one of the steps of a processing.
+pub(super) struct StepError {
+    /// Index of the failed step, starting at 1, 0 if the file could not be
+    /// decrypted.
+    pub(super) step: usize,
+    /// Exit code if the step is an external command that exited unsuccessfully.
+    pub(super) exit_code: Option<i32>,
+    pub(super) error: io::Error,
+}
+
+impl fmt::Display for StepError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "step {}: {}", self.step, self.error)
     }
 }
 
+/// Run a Lua script, with placeholder values available in the `file` table.
+///
+/// The step fails if the script raises an error or returns `false`. If it
+/// returns a string, the file is routed to the processing group of that name.
+#[cfg(feature = "lua")]
+async fn run_lua(script: OsString, rep: &Replacements<'_>) -> io::Result<Flow> {
+    let fields: Vec<(String, Vec<u8>)> = rep
+        .iter()
+        .map(|(key, value)| {
+            let key = key.trim_start_matches('{').trim_end_matches('}');
+            (key.to_owned(), value.as_encoded_bytes().to_owned())
+        })
+        .collect();
+    // Scripts may be slow, they must not hold up other files.
+    tokio::task::spawn_blocking(move || {
+        let to_io = |err: mlua::Error| io::Error::other(err.to_string());
+        let code = fs::read_to_string(&script)?;
+        let lua = mlua::Lua::new();
+        let file = lua.create_table().map_err(to_io)?;
+        for (key, value) in fields {
+            let value = lua.create_string(value).map_err(to_io)?;
+            file.set(key, value).map_err(to_io)?;
+        }
+        lua.globals().set("file", file).map_err(to_io)?;
+        let result: mlua::Value = lua
+            .load(code)
+            .set_name(script.to_string_lossy())
+            .eval()
+            .map_err(to_io)?;
+        match result {
+            mlua::Value::Boolean(false) => Err(io::Error::other("script returned false")),
+            mlua::Value::String(group) => {
+                Ok(Flow::RouteTo(group.to_str().map_err(to_io)?.to_owned()))
+            }
+            _ => Ok(Flow::Next),
+        }
+    })
+    .await?
+}
+
+#[cfg(not(feature = "lua"))]
+async fn run_lua(_script: OsString, _rep: &Replacements<'_>) -> io::Result<Flow> {
+    Err(io::Error::other(
+        "pipeline was built without the `lua` feature",
+    ))
+}
+
 #[derive(Deserialize, Debug, PartialEq, Eq)]
 enum InnerProc {
     #[serde(rename = "pass")]
@@ -97,6 +671,96 @@ enum InnerProc {
 #[derive(Deserialize, Debug, PartialEq, Eq)]
 pub(super) struct Processing(InnerProc);
 
+/// Placeholders available in the batch processing command.
+pub(super) const BATCH_PLACEHOLDERS: [&str; 2] = ["{batch_name}", "{batch_members}"];
+
+/// Processing run once all members of a batch have been processed individually.
+#[derive(Deserialize, Debug, PartialEq, Eq)]
+pub(super) struct Batch {
+    /// Name of the batch a file belongs to, with the same placeholders as
+    /// `processing`.
+    pub(super) key: String,
+    /// Number of files completing a batch.
+    pub(super) size: u32,
+    #[serde(deserialize_with = "custom_serde::vec_at_least_one")]
+    pub(super) processing: Vec<String>,
+}
+
+impl Batch {
+    pub(super) fn name_of(&self, file: &FileSpec, server_path: &Path) -> String {
+        let rep = Replacements::new(file, server_path);
+        rep.apply_to(&self.key).to_string_lossy().into_owned()
+    }
+
+    /// Run the batch command, where an argument `{batch_members}` is expanded
+    /// to the server paths of all members.
+    pub(super) async fn run(&self, name: &str, members: &[PathBuf]) -> io::Result<()> {
+        let mut args = Vec::with_capacity(self.processing.len() + members.len());
+        for arg in &self.processing[1..] {
+            if arg == "{batch_members}" {
+                args.extend(members.iter().map(|m| m.as_os_str().to_owned()));
+            } else {
+                let name = OsStr::new(name);
+                args.push(replace_os_strings(
+                    arg,
+                    [("{batch_name}", name)].into_iter(),
+                ));
+            }
+        }
+        let status = Command::new(&self.processing[0])
+            .args(args)
+            .spawn()?
+            .wait()
+            .await?;
+        if status.success() {
+            Ok(())
+        } else {
+            Err(io::Error::other(CommandFailed(status)))
+        }
+    }
+}
+
+/// Files produced by the processing of another group, fed to the group
+/// declaring them once that processing succeeds.
+#[derive(Deserialize, Debug, PartialEq, Eq)]
+pub(super) struct ChainedFrom {
+    /// Group whose processing produces the files.
+    pub(super) group: String,
+    /// Paths of the files produced by the processing of a file, with the same
+    /// placeholders as `processing`. Directories stand for all the files they
+    /// contain.
+    #[serde(deserialize_with = "custom_serde::vec_at_least_one")]
+    pub(super) outputs: Vec<String>,
+}
+
+impl ChainedFrom {
+    /// Files produced by the processing of `file`, stored at `server_path`,
+    /// that exist.
+    pub(super) fn output_paths(&self, file: &FileSpec, server_path: &Path) -> Vec<PathBuf> {
+        let rep = Replacements::new(file, server_path);
+        let mut paths = Vec::new();
+        for output in &self.outputs {
+            let path = PathBuf::from(rep.apply_to(output));
+            if !path.is_dir() {
+                paths.push(path);
+                continue;
+            }
+            match fs::read_dir(&path) {
+                Ok(entries) => paths.extend(
+                    entries
+                        .filter_map(Result::ok)
+                        .filter(|entry| entry.file_type().is_ok_and(|t| t.is_file()))
+                        .map(|entry| entry.path()),
+                ),
+                Err(err) => warn!("cannot list outputs in {path:?}: {err}"),
+            }
+        }
+        paths.retain(|path| path.is_file());
+        paths.sort();
+        paths
+    }
+}
+
 #[derive(Deserialize, Debug, PartialEq, Eq)]
 pub(super) enum AfterProcessing {
     #[serde(rename = "pass")]
@@ -114,9 +778,17 @@ pub(super) enum StatusAfterProcessing {
 }
 
 impl AfterProcessing {
+    pub(super) fn templates(&self) -> Vec<&str> {
+        match self {
+            AfterProcessing::Pass | AfterProcessing::MarkAs { .. } => Vec::new(),
+            AfterProcessing::MoveAndPrune { move_to_and_prune } => vec![move_to_and_prune],
+        }
+    }
+
     pub(super) async fn run(
         &self,
         spec: &FileSpec,
+        server_path: &Path,
         config: &Config,
         db: &Database,
     ) -> Option<ProcessStatus> {
@@ -127,28 +799,64 @@ impl AfterProcessing {
                 StatusAfterProcessing::ToPrune => Some(ProcessStatus::ToPrune),
             },
             AfterProcessing::MoveAndPrune { move_to_and_prune } => {
-                let rep = Replacements::new(spec, config);
+                let rep = Replacements::new(spec, server_path);
                 let dest = rep.apply_to(move_to_and_prune);
-                match fs::rename(&rep.server_path, &dest) {
-                    Ok(()) => match db.remove(spec.hash()).await {
-                        Ok(()) => None,
-                        Err(err) => {
-                            warn!("error when removing {spec:?} from db: {err}");
-                            Some(ProcessStatus::ToPrune)
-                        }
-                    },
-                    Err(err) if err.kind() == io::ErrorKind::CrossesDevices => {
-                        match fs::copy(&rep.server_path, &dest) {
-                            Ok(_) => Some(ProcessStatus::ToPrune),
-                            Err(err) => {
+                // The file is moved first, nothing is moved if it fails and
+                // processing can be retried.
+                let pruned_later = if db.is_encrypted(spec.hash()).await.unwrap_or(false) {
+                    // The plaintext is moved out, the blob is pruned later.
+                    let from = rep.server_path.clone();
+                    if let Err(err) =
+                        encryption::decrypt_to(config, from, dest.clone().into()).await
+                    {
+                        warn!("failed decrypting {spec:?}: {err:?}");
+                        return Some(ProcessStatus::Failed);
+                    }
+                    true
+                } else {
+                    match fs::rename(&rep.server_path, &dest) {
+                        Ok(()) => false,
+                        Err(err) if err.kind() == io::ErrorKind::CrossesDevices => {
+                            if let Err(err) = fs::copy(&rep.server_path, &dest) {
                                 warn!("failed copying {spec:?}: {err:?}");
-                                Some(ProcessStatus::Failed)
+                                return Some(ProcessStatus::Failed);
                             }
+                            true
+                        }
+                        Err(err) => {
+                            warn!("failed moving {spec:?}: {err:?}");
+                            return Some(ProcessStatus::Failed);
                         }
                     }
+                };
+                if let Some(ext) = spec.companion_extension() {
+                    let mut companion_dest = dest;
+                    companion_dest.push(format!(".{ext}"));
+                    let moved = fs::rename(&rep.companion_path, &companion_dest).or_else(|_| {
+                        fs::copy(&rep.companion_path, &companion_dest)?;
+                        fs::remove_file(&rep.companion_path)
+                    });
+                    if let Err(err) = moved {
+                        // Kept in the pipeline until pruned explicitly rather
+                        // than losing the companion.
+                        warn!(
+                            "failed moving companion of {spec:?}, it stays at {:?}: {err:?}",
+                            rep.companion_path
+                        );
+                        return Some(ProcessStatus::Done);
+                    }
+                }
+                if pruned_later {
+                    return Some(ProcessStatus::ToPrune);
+                }
+                match db.remove(spec.hash()).await {
+                    Ok(()) => {
+                        remove_step_logs(config, spec.hash()).await;
+                        None
+                    }
                     Err(err) => {
-                        warn!("failed moving {spec:?}: {err:?}");
-                        Some(ProcessStatus::Failed)
+                        warn!("error when removing {spec:?} from db: {err}");
+                        Some(ProcessStatus::ToPrune)
                     }
                 }
             }
@@ -157,20 +865,260 @@ impl AfterProcessing {
 }
 
 impl Processing {
-    pub(super) async fn run(&self, file: &FileSpec, config: &Config) -> io::Result<()> {
+    fn steps(&self) -> &[Step] {
         match &self.0 {
-            InnerProc::One(step) => {
-                let rep = Replacements::new(file, config);
-                step.run(&rep).await
-            }
-            InnerProc::List(steps) => {
-                let rep = Replacements::new(file, config);
-                for step in steps {
-                    step.run(&rep).await?;
+            InnerProc::One(step) => std::slice::from_ref(step),
+            InnerProc::List(steps) => steps,
+            InnerProc::Pass => &[],
+        }
+    }
+
+    pub(super) fn templates(&self) -> Vec<&str> {
+        self.steps().iter().flat_map(Step::templates).collect()
+    }
+
+    /// Names of plugin steps used by this processing.
+    pub(super) fn plugins(&self) -> impl Iterator<Item = &str> {
+        self.steps().iter().filter_map(|step| match step.inner() {
+            Step::Plugin { plugin } => Some(plugin.as_str()),
+            _ => None,
+        })
+    }
+
+    pub(super) fn uses_lua(&self) -> bool {
+        self.steps()
+            .iter()
+            .any(|step| matches!(step.inner(), Step::Lua { .. }))
+    }
+
+    /// Spawn options of the external commands of this processing.
+    pub(super) fn spawn_options(&self) -> impl Iterator<Item = &SpawnOptions> {
+        self.steps().iter().filter_map(|step| match step.inner() {
+            Step::CommandWith { options, .. } => Some(options),
+            _ => None,
+        })
+    }
+
+    /// Resources required by steps of this processing, and their amount.
+    pub(super) fn resources(&self) -> impl Iterator<Item = (&str, u32)> {
+        self.steps()
+            .iter()
+            .filter_map(|step| match step {
+                Step::WithResources { resources, .. } => Some(resources),
+                _ => None,
+            })
+            .flatten()
+            .map(|(name, &amount)| (name.as_str(), amount))
+    }
+
+    /// Descriptions of the steps run on `file` stored at `server_path`, with
+    /// placeholders replaced.
+    pub(super) fn describe(&self, file: &FileSpec, server_path: &Path) -> Vec<String> {
+        let rep = Replacements::new(file, server_path);
+        self.steps()
+            .iter()
+            .map(|step| step.describe(&rep))
+            .collect()
+    }
+
+    /// Run the steps in order until one fails, pushing the duration in
+    /// seconds of each step that ran to `step_secs`, including the wait for
+    /// its resources.
+    ///
+    /// This returns the group the file is routed to if a step skipped the
+    /// remaining ones to hand it over to another group.
+    pub(super) async fn run(
+        &self,
+        file: &FileSpec,
+        server_path: &Path,
+        config: &Config,
+        db: &Database,
+        resources: &mut Resources<'_>,
+        step_secs: &mut Vec<f64>,
+    ) -> Result<Option<String>, StepError> {
+        let plaintext = encryption::plaintext(config, db, file.hash(), server_path.to_owned())
+            .await
+            .map_err(|error| StepError {
+                step: 0,
+                exit_code: None,
+                error,
+            })?;
+        let mut rep = Replacements::new(file, server_path);
+        rep.server_path = plaintext.path().to_owned();
+        self.run_steps(&rep, config, resources, step_secs).await
+    }
+
+    /// Run the steps as [`Processing::run`] on the file at `path`, e.g. a
+    /// copy of the file stored on the server.
+    pub(super) async fn run_at(
+        &self,
+        file: &FileSpec,
+        path: &Path,
+        config: &Config,
+        resources: &mut Resources<'_>,
+        step_secs: &mut Vec<f64>,
+    ) -> Result<Option<String>, StepError> {
+        let rep = Replacements::new(file, path);
+        self.run_steps(&rep, config, resources, step_secs).await
+    }
+
+    async fn run_steps(
+        &self,
+        rep: &Replacements<'_>,
+        config: &Config,
+        resources: &mut Resources<'_>,
+        step_secs: &mut Vec<f64>,
+    ) -> Result<Option<String>, StepError> {
+        let file = rep.file;
+        for (i, step) in self.steps().iter().enumerate() {
+            let started = Instant::now();
+            let log = config
+                .step_logs_directory
+                .as_ref()
+                .zip(step.log_name())
+                .map(|(dir, name)| dir.join(file.hash()).join(format!("{}-{name}", i + 1)));
+            let result = step
+                .run(rep, &config.plugins, resources, log.as_deref())
+                .await;
+            step_secs.push(started.elapsed().as_secs_f64());
+            match result {
+                Ok(Flow::Next) => {}
+                Ok(Flow::RouteTo(group)) => return Ok(Some(group)),
+                Err(error) => {
+                    let exit_code = error
+                        .get_ref()
+                        .and_then(|e| e.downcast_ref::<CommandFailed>())
+                        .and_then(|e| e.0.code());
+                    return Err(StepError {
+                        step: i + 1,
+                        exit_code,
+                        error,
+                    });
                 }
-                Ok(())
             }
-            InnerProc::Pass => Ok(()),
         }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn spec() -> FileSpec {
+        FileSpec {
+            client: "lab".to_owned(),
+            path: "runs/1".to_owned(),
+            filename: "scan.dat".to_owned(),
+            processing: "main".to_owned(),
+            sha256_digest: FileDigest::Full("0123abcd".to_owned()),
+            size_bytes: 42,
+            modified_utc: "2024-01-01 00:00:00".to_owned(),
+            metadata: BTreeMap::new(),
+            companion: None,
+        }
+    }
+
+    #[test]
+    fn manifest_entries_format() {
+        let file = spec();
+        let now = "2024-01-02 03:04:05";
+        assert_eq!(
+            manifest_entries("runs/1/scan.dat", "ab12", &file, now),
+            (
+                "ab12  runs/1/scan.dat\n".to_owned(),
+                "runs/1/scan.dat\tlab\t42\t2024-01-01 00:00:00\t2024-01-02 03:04:05\n".to_owned(),
+            )
+        );
+        let (entry, details) = manifest_entries("a\\b\nc\td", "ab12", &file, now);
+        assert_eq!(entry, "\\ab12  a\\\\b\\nc\td\n");
+        assert!(details.starts_with("a\\\\b\\nc\\td\tlab\t"));
+        assert_eq!(details.lines().count(), 1);
+    }
+
+    fn replacements(file: &FileSpec) -> Replacements<'_> {
+        Replacements {
+            file,
+            server_path: PathBuf::from("/srv/incoming/scan.dat"),
+            companion_path: PathBuf::new(),
+            rel_dir: file.relative_directory(),
+            metadata: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn placeholders_in_json_body() {
+        let mut file = spec();
+        file.filename = "say \"hi\"\\\n.dat".to_owned();
+        let rep = replacements(&file);
+        let body = rep.apply_to_json(r#"{"hash": "{hash}", "name": "{client_file_name}"}"#);
+        assert_eq!(
+            body,
+            r#"{"hash": "0123abcd", "name": "say \"hi\"\\\n.dat"}"#
+        );
+        let value: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(value["name"], file.filename);
+    }
+
+    #[test]
+    fn placeholders_in_url() {
+        let mut file = spec();
+        file.filename = "a b&c=d/é.dat".to_owned();
+        let rep = replacements(&file);
+        assert_eq!(
+            rep.apply_to_url("https://lims.example.org/files/{hash}?name={client_file_name}"),
+            "https://lims.example.org/files/0123abcd?name=a%20b%26c%3Dd%2F%C3%A9.dat"
+        );
+        assert_eq!(
+            rep.apply_to_url("https://example.org{server_path}"),
+            "https://example.org%2Fsrv%2Fincoming%2Fscan.dat"
+        );
+    }
+
+    #[cfg(feature = "lua")]
+    #[tokio::test]
+    async fn lua_script_routes_file() {
+        let script = std::env::temp_dir().join(format!("pipeline-{}.lua", std::process::id()));
+        fs::write(
+            &script,
+            r#"
+            if file.client_name ~= "lab" then return false end
+            if file.client_relative_directory == "runs/1" then return "archive" end
+            return true
+            "#,
+        )
+        .unwrap();
+        let file = spec();
+        let routed = run_lua(script.clone().into(), &replacements(&file)).await;
+        assert_eq!(routed.unwrap(), Flow::RouteTo("archive".to_owned()));
+
+        let other = FileSpec {
+            path: "runs/2".to_owned(),
+            ..spec()
+        };
+        let next = run_lua(script.clone().into(), &replacements(&other)).await;
+        assert_eq!(next.unwrap(), Flow::Next);
+
+        let foreign = FileSpec {
+            client: "other".to_owned(),
+            ..spec()
+        };
+        assert!(
+            run_lua(script.clone().into(), &replacements(&foreign))
+                .await
+                .is_err()
+        );
+        fs::remove_file(script).unwrap();
+    }
+
+    #[cfg(not(feature = "lua"))]
+    #[tokio::test]
+    async fn lua_script_needs_feature() {
+        let file = spec();
+        assert!(
+            run_lua("route.lua".into(), &replacements(&file))
+                .await
+                .is_err()
+        );
     }
 }
@@ -1,37 +1,59 @@
-use std::io;
+use std::{io, net::SocketAddr};
 
-use log::warn;
+use log::{info, warn};
 use serde::Deserialize;
 use tabled::{Table, settings::Style};
 use tokio::net::TcpStream;
 
 use crate::{
-    cli::MarkStatus,
-    framed_io::json_channel,
+    cli::Mark,
+    framed_io::{default_max_frame_length, json_channel},
     handshake::{self, RequestPayload},
-    server::{Database, database::FileInPipeline},
+    server::{
+        Database,
+        control::{self, State},
+        database::{FileInPipeline, ProcessStatus, Snapshot},
+    },
     server_route::ServerRoute,
 };
 use futures_util::{TryStreamExt, sink::SinkExt};
 
 #[derive(Clone)]
 pub(crate) enum Query {
-    Mark { hash: String, status: MarkStatus },
-    List,
+    Mark {
+        hash: String,
+        mark: Mark,
+    },
+    List {
+        status: Option<ProcessStatus>,
+        tag: Option<String>,
+    },
+    Retry {
+        hash: Option<String>,
+        tag: Option<String>,
+    },
     PruneDone,
     Status,
+    Control(control::Command),
 }
 
 impl Query {
-    async fn get_response(&self, stream: TcpStream) -> io::Result<()> {
+    async fn get_response(&self, stream: TcpStream, max_frame_length: usize) -> io::Result<()> {
         match self {
             Query::Mark { .. } => Ok(()),
-            Query::List => {
-                let (mut from_server, _) = json_channel::<Vec<FileInPipeline>, (), _, _, _>(stream);
-                let content = from_server
+            Query::List { status, tag } => {
+                let (mut from_server, _) =
+                    json_channel::<Vec<FileInPipeline>, (), _, _, _>(stream, max_frame_length);
+                let mut content = from_server
                     .try_next()
                     .await?
                     .expect("should have exactly one answer");
+                if let Some(status) = status {
+                    content.retain(|file| file.status == *status);
+                }
+                if let Some(tag) = tag {
+                    content.retain(|file| file.has_tag(tag));
+                }
                 let mut table = Table::new(&content);
                 table.with(
                     Style::markdown()
@@ -42,9 +64,19 @@ impl Query {
                 println!("{table}");
                 Ok(())
             }
-            Query::PruneDone => Ok(()),
-            Query::Status => {
+            Query::Retry { .. } | Query::PruneDone => Ok(()),
+            Query::Status | Query::Control(_) => {
+                let (mut from_server, _) =
+                    json_channel::<State, (), _, _, _>(stream, max_frame_length);
+                let state = from_server.try_next().await?.ok_or_else(|| match self {
+                    Query::Control(_) => io::Error::other(
+                        "server closed connection, control commands are only accepted from \
+                            the server host",
+                    ),
+                    _ => io::Error::other("server closed connection"),
+                })?;
                 println!("pipeline server is online");
+                println!("{state}");
                 Ok(())
             }
         }
@@ -54,10 +86,12 @@ impl Query {
 impl From<Query> for RequestPayload {
     fn from(value: Query) -> Self {
         match value {
-            Query::Mark { hash, status } => RequestPayload::Mark { hash, status },
-            Query::List => RequestPayload::List,
+            Query::Mark { hash, mark } => RequestPayload::Mark { hash, mark },
+            Query::List { .. } => RequestPayload::List,
+            Query::Retry { hash, tag } => RequestPayload::Retry { hash, tag },
             Query::PruneDone => RequestPayload::PruneDone,
             Query::Status => RequestPayload::Status,
+            Query::Control(command) => RequestPayload::Control(command),
         }
     }
 }
@@ -66,6 +100,18 @@ impl From<Query> for RequestPayload {
 #[derive(Deserialize, Debug)]
 pub(crate) struct QueryConfig {
     server: ServerRoute,
+    #[serde(default = "default_max_frame_length")]
+    max_frame_length: usize,
+}
+
+impl QueryConfig {
+    /// Configuration to reach the server at `address` without a tunnel.
+    pub(crate) fn direct(address: String) -> Self {
+        Self {
+            server: ServerRoute::Direct { address },
+            max_frame_length: default_max_frame_length(),
+        }
+    }
 }
 
 pub(crate) static QUERY_TOML_CONF: &str = include_str!("query.toml");
@@ -76,28 +122,107 @@ pub(crate) async fn main(config: QueryConfig, query: Query) -> io::Result<()> {
     if !handshake::client_side(&mut stream, payload).await? {
         return Err(io::Error::other("handshake failed"));
     }
-    query.get_response(stream).await
+    query.get_response(stream, config.max_frame_length).await
 }
 
 pub(super) async fn process_mark_query(
     db: Database,
     hash: String,
-    status: MarkStatus,
+    mark: Mark,
+    addr: SocketAddr,
 ) -> io::Result<()> {
-    while let Err(err) = db.update_status(&hash, status.into()).await {
+    let actor = format!("mark query from {addr}");
+    let status = match mark {
+        Mark::Status(status) => status,
+        Mark::Pin | Mark::Unpin => {
+            let pinned = matches!(mark, Mark::Pin);
+            while let Err(err) = db.set_pinned(&hash, pinned, &actor).await {
+                warn!("error pinning {hash}: {err}");
+            }
+            return Ok(());
+        }
+    };
+    while let Err(err) = db.update_status(&hash, status.into(), &actor).await {
         warn!("error updating status for {hash}: {err}");
     }
+    while let Err(err) = db.reset_attempt_count(&hash).await {
+        warn!("error resetting attempt count for {hash}: {err}");
+    }
+    Ok(())
+}
+
+pub(super) async fn process_retry_query(
+    db: Database,
+    hash: Option<String>,
+    tag: Option<String>,
+    addr: SocketAddr,
+) -> io::Result<()> {
+    let actor = format!("retry query from {addr}");
+    let hashes = db
+        .retry_candidates(hash.as_deref(), tag.as_deref())
+        .await
+        .map_err(io::Error::other)?;
+    info!("retrying {} failed or abandoned files", hashes.len());
+    for hash in hashes {
+        while let Err(err) = db.update_status(&hash, ProcessStatus::Failed, &actor).await {
+            warn!("error updating status for {hash}: {err}");
+        }
+        while let Err(err) = db.reset_attempt_count(&hash).await {
+            warn!("error resetting attempt count for {hash}: {err}");
+        }
+    }
     Ok(())
 }
 
-pub(super) async fn process_list_query(stream: TcpStream, db: Database) -> io::Result<()> {
+pub(super) async fn process_list_query(
+    stream: TcpStream,
+    db: Database,
+    max_frame_length: usize,
+) -> io::Result<()> {
     let content = db.content().await.unwrap();
-    let (_, mut to_client) = json_channel::<(), Vec<FileInPipeline>, _, _, _>(stream);
+    let (_, mut to_client) =
+        json_channel::<(), Vec<FileInPipeline>, _, _, _>(stream, max_frame_length);
     to_client.send(content).await
 }
 
-pub(super) async fn process_prune_done_query(db: Database) -> io::Result<()> {
-    if let Err(err) = db.mark_done_to_prune().await {
+/// Fetch an overview of the pipeline activity from the server.
+pub(super) async fn fetch_snapshot(config: &QueryConfig) -> io::Result<Snapshot> {
+    let mut stream = config.server.connect().await;
+    if !handshake::client_side(&mut stream, RequestPayload::Top).await? {
+        return Err(io::Error::other("handshake failed"));
+    }
+    let (mut from_server, _) =
+        json_channel::<Snapshot, (), _, _, _>(stream, config.max_frame_length);
+    from_server
+        .try_next()
+        .await?
+        .ok_or_else(|| io::Error::other("server closed connection"))
+}
+
+pub(super) async fn process_top_query(
+    stream: TcpStream,
+    db: Database,
+    max_frame_length: usize,
+) -> io::Result<()> {
+    let snapshot = db.snapshot().await.map_err(io::Error::other)?;
+    let (_, mut to_client) = json_channel::<(), Snapshot, _, _, _>(stream, max_frame_length);
+    to_client.send(snapshot).await
+}
+
+/// Send the runtime state of the server, in answer to status and control
+/// queries.
+pub(super) async fn send_state(
+    stream: TcpStream,
+    state: State,
+    max_frame_length: usize,
+) -> io::Result<()> {
+    let (_, mut to_client) = json_channel::<(), State, _, _, _>(stream, max_frame_length);
+    to_client.send(state).await
+}
+
+pub(super) async fn process_prune_done_query(db: Database, addr: SocketAddr) -> io::Result<()> {
+    let actor = format!("prune-done query from {addr}");
+    if let Err(err) = db.mark_done_to_prune(&actor).await {
         warn!("error marking 'done' tasks to prune: {err}");
     }
     Ok(())
@@ -0,0 +1,461 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+    time::MissedTickBehavior,
+};
+
+use crate::{
+    FileSpec,
+    framed_io::{DEFAULT_MAX_FRAME_LENGTH, read_json_frame, write_json_frame},
+    handshake::{self, RequestPayload},
+    server::{
+        Config, companion_path_of,
+        database::{Database, JsonObject, ProcessStatus, SERVER_ACTOR},
+        encryption::encrypted_len,
+        verify::expected_paths,
+    },
+};
+
+/// Files whose rows are sent at once, to keep replies within a frame.
+const CHANGES_PER_REPLY: u32 = 256;
+
+/// Request of a standby server to its primary.
+#[derive(Serialize, Deserialize, Debug)]
+enum StandbyRequest {
+    /// Rows of the files changed after the change `after`.
+    Changes { after: i64 },
+    /// Received file with `hash`, or its companion.
+    File { hash: String, companion: bool },
+}
+
+/// Answer of the primary to a [`StandbyRequest`].
+#[derive(Serialize, Deserialize, Debug)]
+enum PrimaryReply {
+    /// Rows about `hashes`, as of the change `last`. No hash means that the
+    /// standby is up to date.
+    Changes {
+        last: i64,
+        hashes: Vec<String>,
+        rows: BTreeMap<String, Vec<JsonObject>>,
+    },
+    /// Followed by `size_bytes` bytes of content.
+    Content {
+        size_bytes: u64,
+    },
+    Missing,
+}
+
+/// Answer requests of the standby server at the other end of `stream`.
+pub(super) async fn serve_standby(
+    mut stream: TcpStream,
+    config: &Config,
+    db: &Database,
+) -> io::Result<()> {
+    let (mut reader, mut writer) = stream.split();
+    while let Some(request) = read_json_frame(&mut reader, config.max_frame_length).await? {
+        debug!("standby requested {request:?}");
+        match request {
+            StandbyRequest::Changes { after } => {
+                let changes = db
+                    .changes_after(after, CHANGES_PER_REPLY)
+                    .await
+                    .map_err(io::Error::other)?;
+                let last = changes.last().map_or(after, |(id, _)| *id);
+                let hashes: Vec<_> = changes.into_iter().map(|(_, hash)| hash).collect();
+                let rows = db.rows_of(&hashes).await.map_err(io::Error::other)?;
+                let reply = PrimaryReply::Changes { last, hashes, rows };
+                write_json_frame(&mut writer, &reply).await?;
+            }
+            StandbyRequest::File { hash, companion } => {
+                let file = db.file(&hash).await.map_err(io::Error::other)?;
+                let path = file.and_then(|file| {
+                    let path = config.stored_path(&file.storage_path);
+                    if companion {
+                        companion_path_of(&path, &FileSpec::from(file))
+                    } else {
+                        Some(path)
+                    }
+                });
+                match path {
+                    Some(path) => send_file(&mut writer, &path).await?,
+                    None => write_json_frame(&mut writer, &PrimaryReply::Missing).await?,
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn send_file<W: AsyncWrite + Unpin>(writer: &mut W, path: &Path) -> io::Result<()> {
+    let file = match tokio::fs::File::open(path).await {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            return write_json_frame(writer, &PrimaryReply::Missing).await;
+        }
+        Err(err) => return Err(err),
+    };
+    let size_bytes = file.metadata().await?.len();
+    write_json_frame(writer, &PrimaryReply::Content { size_bytes }).await?;
+    let sent = tokio::io::copy(&mut file.take(size_bytes), writer).await?;
+    if sent < size_bytes {
+        // The standby cannot tell where the content ends anymore.
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("{path:?} shrank while being sent"),
+        ));
+    }
+    writer.flush().await
+}
+
+/// Send `request` to the primary and read its reply.
+async fn request<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    request: &StandbyRequest,
+) -> io::Result<PrimaryReply>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    write_json_frame(writer, request).await?;
+    read_json_frame(reader, DEFAULT_MAX_FRAME_LENGTH)
+        .await?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "primary closed connection"))
+}
+
+/// Request `what` from the primary and write it to `dest`, returning whether
+/// the primary had it.
+async fn fetch<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    what: StandbyRequest,
+    dest: &Path,
+) -> io::Result<bool>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let PrimaryReply::Content { size_bytes } = request(reader, writer, &what).await? else {
+        return Ok(false);
+    };
+    if let Some(parent) = dest.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    let mut part = dest.as_os_str().to_owned();
+    part.push(".part");
+    let mut file = tokio::fs::File::create(&part).await?;
+    let received = tokio::io::copy(&mut (&mut *reader).take(size_bytes), &mut file).await?;
+    if received < size_bytes {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "primary closed connection while sending content",
+        ));
+    }
+    file.sync_all().await?;
+    tokio::fs::rename(&part, dest).await?;
+    Ok(true)
+}
+
+/// Mirror the database and received files of the primary server set in the
+/// `[replication]` section until stopped, so that this server can take over
+/// if the primary fails.
+pub(crate) async fn standby(config: Config) -> io::Result<()> {
+    let Some(primary) = &config.replication.primary else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "no primary server to replicate in the `[replication]` section",
+        ));
+    };
+    loop {
+        match follow(&config, primary).await {
+            Ok(()) => warn!("primary server {primary} refused replication"),
+            Err(err) => warn!("replication of {primary} interrupted: {err}"),
+        }
+        tokio::time::sleep(Duration::from_secs(config.replication.every_secs)).await;
+    }
+}
+
+/// Replicate the changes of `primary` every `every_secs` as long as the
+/// connection lasts. All files are replicated again after connecting, which
+/// catches up with changes missed while disconnected.
+async fn follow(config: &Config, primary: &str) -> io::Result<()> {
+    let mut stream = TcpStream::connect(primary).await?;
+    if !handshake::client_side(&mut stream, RequestPayload::Standby).await? {
+        return Ok(());
+    }
+    info!("replicating primary server {primary}");
+    let db = Database::create_if_missing(&config.database)
+        .await
+        .map_err(io::Error::other)?;
+    let (mut reader, mut writer) = stream.split();
+    let every = Duration::from_secs(config.replication.every_secs);
+    let mut interval = tokio::time::interval(every);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut last_change = 0;
+    let replicated = loop {
+        interval.tick().await;
+        if let Err(err) = catch_up(config, &db, &mut reader, &mut writer, &mut last_change).await {
+            break Err(err);
+        }
+    };
+    db.close().await;
+    replicated
+}
+
+/// Apply the changes of the primary made after `last_change`, which is updated
+/// to the last change applied.
+async fn catch_up<R, W>(
+    config: &Config,
+    db: &Database,
+    reader: &mut R,
+    writer: &mut W,
+    last_change: &mut i64,
+) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let (mut changed, mut fetched, mut removed) = (0, 0, 0);
+    loop {
+        let what = StandbyRequest::Changes {
+            after: *last_change,
+        };
+        let PrimaryReply::Changes { last, hashes, rows } = request(reader, writer, &what).await?
+        else {
+            return Err(io::Error::other("primary sent no changes"));
+        };
+        if hashes.is_empty() {
+            break;
+        }
+        check_rows(db, &rows).await?;
+        let before = stored_paths(config, db, &hashes).await?;
+        db.replace_rows(&hashes, &rows)
+            .await
+            .map_err(io::Error::other)?;
+        let (f, r) = mirror_files(config, db, reader, writer, &hashes, before).await?;
+        changed += hashes.len();
+        fetched += f;
+        removed += r;
+        *last_change = last;
+    }
+    if changed > 0 {
+        info!("replicated {changed} changed files, fetched {fetched} and removed {removed} files");
+    }
+    Ok(())
+}
+
+/// Check that `rows` sent by the primary only have tables and columns of `db`.
+async fn check_rows(db: &Database, rows: &BTreeMap<String, Vec<JsonObject>>) -> io::Result<()> {
+    let tables = db.tables().await.map_err(io::Error::other)?;
+    for (table, rows) in rows {
+        if !tables.contains(table) {
+            return Err(io::Error::other(format!(
+                "primary sent rows of unknown table {table:?}"
+            )));
+        }
+        let columns = db.columns(table).await.map_err(io::Error::other)?;
+        if let Some(column) = rows
+            .iter()
+            .flat_map(|row| row.keys())
+            .find(|c| !columns.contains(c))
+        {
+            return Err(io::Error::other(format!(
+                "primary sent unknown column {column:?} of {table:?}"
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Paths of the files with `hashes` and of their companions in the incoming
+/// directory, according to `db`.
+async fn stored_paths(
+    config: &Config,
+    db: &Database,
+    hashes: &[String],
+) -> io::Result<HashSet<PathBuf>> {
+    let mut files = Vec::new();
+    let mut origins = Vec::new();
+    for hash in hashes {
+        if let Some(row) = db.file(hash).await.map_err(io::Error::other)? {
+            let path = config.stored_path(&row.storage_path);
+            files.push((FileSpec::from(row), path));
+        }
+        origins.extend(
+            db.companion_origins(Some(hash))
+                .await
+                .map_err(io::Error::other)?,
+        );
+    }
+    let files = files.iter().map(|(spec, path)| (spec, path.as_path()));
+    Ok(expected_paths(config, files, &origins))
+}
+
+/// Fetch the files with `hashes` missing from the incoming directory and
+/// remove those of the paths `before` they changed that are not used anymore,
+/// returning the number of files fetched and removed.
+async fn mirror_files<R, W>(
+    config: &Config,
+    db: &Database,
+    reader: &mut R,
+    writer: &mut W,
+    hashes: &[String],
+    before: HashSet<PathBuf>,
+) -> io::Result<(usize, usize)>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut fetched = 0;
+    for hash in hashes {
+        let Some(row) = db.file(hash).await.map_err(io::Error::other)? else {
+            continue;
+        };
+        let (status, encrypted) = (row.status, row.encrypted);
+        let path = config.stored_path(&row.storage_path);
+        let spec = FileSpec::from(row);
+        // Processing was interrupted if this server takes over.
+        if matches!(status, ProcessStatus::Processing)
+            && let Err(err) = db
+                .update_status(hash, ProcessStatus::Failed, SERVER_ACTOR)
+                .await
+        {
+            warn!("failed to mark {spec:?} as failed: {err}");
+        }
+        if status.awaits_arrival() {
+            continue;
+        }
+        // The blob is fetched again once the primary encrypted it.
+        let stored_len = if encrypted {
+            encrypted_len(spec.size_bytes)
+        } else {
+            spec.size_bytes
+        };
+        if !path.metadata().is_ok_and(|meta| meta.len() == stored_len) {
+            let what = StandbyRequest::File {
+                hash: hash.clone(),
+                companion: false,
+            };
+            if fetch(reader, writer, what, &path).await? {
+                fetched += 1;
+            } else {
+                warn!("primary does not have {spec:?} anymore");
+            }
+        }
+        if let Some(companion) = companion_path_of(&path, &spec)
+            && !companion.exists()
+        {
+            let what = StandbyRequest::File {
+                hash: hash.clone(),
+                companion: true,
+            };
+            if fetch(reader, writer, what, &companion).await? {
+                fetched += 1;
+            }
+        }
+    }
+
+    let after = stored_paths(config, db, hashes).await?;
+    let mut removed = 0;
+    for orphan in before.difference(&after).filter(|path| path.exists()) {
+        debug!("removing {orphan:?}, not in the pipeline of the primary");
+        std::fs::remove_file(orphan)?;
+        removed += 1;
+    }
+    Ok((fetched, removed))
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::server::test::{config_in, stored_file};
+
+    #[tokio::test]
+    async fn standby_mirrors_changes_of_primary() {
+        let (primary, primary_dir) = config_in("primary", &[]);
+        let (standby, standby_dir) = config_in("standby", &[]);
+        let primary_db = Database::in_memory().await.unwrap();
+        let standby_db = Database::in_memory().await.unwrap();
+        let mut hashes = Vec::new();
+        for (name, status) in [
+            ("done.dat", ProcessStatus::Done),
+            ("processing.dat", ProcessStatus::Processing),
+        ] {
+            let file = stored_file(&primary, name, name);
+            primary_db.insert_new(&file, name, None).await.unwrap();
+            primary_db
+                .update_status(file.hash(), status, "test")
+                .await
+                .unwrap();
+            hashes.push(file.hash().to_owned());
+        }
+        // Replicated before, then pruned by the primary.
+        let pruned = stored_file(&standby, "pruned.dat", "pruned");
+        for db in [&primary_db, &standby_db] {
+            db.insert_new(&pruned, "pruned.dat", None).await.unwrap();
+        }
+        primary_db.remove(pruned.hash()).await.unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut stream = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (served, _) = listener.accept().await.unwrap();
+        let serving =
+            tokio::spawn(async move { serve_standby(served, &primary, &primary_db).await });
+        let mut last_change = 0;
+        {
+            let (mut reader, mut writer) = stream.split();
+            catch_up(
+                &standby,
+                &standby_db,
+                &mut reader,
+                &mut writer,
+                &mut last_change,
+            )
+            .await
+            .unwrap();
+            let caught_up = last_change;
+            assert!(caught_up > 0);
+            // Nothing changed since.
+            catch_up(
+                &standby,
+                &standby_db,
+                &mut reader,
+                &mut writer,
+                &mut last_change,
+            )
+            .await
+            .unwrap();
+            assert_eq!(last_change, caught_up);
+        }
+        drop(stream);
+        serving.await.unwrap().unwrap();
+
+        let content = |name| std::fs::read_to_string(standby_dir.join(name)).unwrap();
+        assert_eq!(content("done.dat"), "done.dat");
+        assert_eq!(content("processing.dat"), "processing.dat");
+        assert!(!standby_dir.join("pruned.dat").exists());
+        assert!(!standby_db.contains(pruned.hash()).await.unwrap());
+        // Processing on the primary is interrupted if the standby takes over.
+        let mut statuses = Vec::new();
+        for hash in &hashes {
+            statuses.push(standby_db.file(hash).await.unwrap().unwrap().status);
+        }
+        assert_eq!(statuses, [ProcessStatus::Done, ProcessStatus::Failed]);
+        assert!(!standby_db.history(&hashes[0]).await.unwrap().is_empty());
+        std::fs::remove_dir_all(primary_dir).unwrap();
+        std::fs::remove_dir_all(standby_dir).unwrap();
+    }
+}
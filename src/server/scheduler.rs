@@ -0,0 +1,336 @@
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap},
+    io,
+    sync::{Arc, Mutex},
+};
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore, oneshot};
+
+/// Processing slots, handed to waiting files in decreasing order of priority
+/// and then in order of request, unlike a semaphore which is first come,
+/// first served.
+///
+/// With fair sharing, files of equal priority are instead handed slots in
+/// turn across clients, those with the fewest slots in use first and then
+/// those served the least recently, so that a burst of files from one client
+/// does not hold the others back.
+#[derive(Clone)]
+pub(super) struct Scheduler(Arc<Mutex<Slots>>);
+
+struct Slots {
+    available: usize,
+    fair_share: bool,
+    waiting: Vec<Waiter>,
+    /// Number of requests so far, ordering waiters of equal priority.
+    requests: u64,
+    /// Number of slots granted so far, to tell which client was served last.
+    grants: u64,
+    clients: HashMap<String, ClientShare>,
+}
+
+#[derive(Default)]
+struct ClientShare {
+    in_use: usize,
+    last_grant: u64,
+}
+
+struct Waiter {
+    priority: i64,
+    client: String,
+    request: u64,
+    wake: oneshot::Sender<()>,
+}
+
+impl Slots {
+    /// Remove the waiter to serve next.
+    fn next_waiter(&mut self) -> Option<Waiter> {
+        let (index, _) = self.waiting.iter().enumerate().max_by_key(|(_, w)| {
+            let share = match self.clients.get(&w.client) {
+                Some(share) if self.fair_share => (share.in_use, share.last_grant),
+                _ => (0, 0),
+            };
+            (w.priority, Reverse(share), Reverse(w.request))
+        })?;
+        Some(self.waiting.swap_remove(index))
+    }
+
+    fn grant(&mut self, client: &str) {
+        self.grants += 1;
+        let share = self.clients.entry(client.to_owned()).or_default();
+        share.in_use += 1;
+        share.last_grant = self.grants;
+    }
+
+    fn ungrant(&mut self, client: &str) {
+        if let Some(share) = self.clients.get_mut(client) {
+            share.in_use -= 1;
+        }
+    }
+}
+
+/// Processing slot, released when dropped.
+pub(super) struct Slot {
+    scheduler: Scheduler,
+    priority: i64,
+    client: String,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.scheduler.release(&self.client);
+    }
+}
+
+/// Request for a slot, which hands the slot over again if dropped after it
+/// was granted.
+struct Pending<'a> {
+    scheduler: &'a Scheduler,
+    client: &'a str,
+    granted: oneshot::Receiver<()>,
+}
+
+impl Drop for Pending<'_> {
+    fn drop(&mut self) {
+        if self.granted.try_recv().is_ok() {
+            self.scheduler.release(self.client);
+        }
+    }
+}
+
+impl Scheduler {
+    pub(super) fn new(slots: usize, fair_share: bool) -> Self {
+        Self(Arc::new(Mutex::new(Slots {
+            available: slots,
+            fair_share,
+            waiting: Vec::new(),
+            requests: 0,
+            grants: 0,
+            clients: HashMap::new(),
+        })))
+    }
+
+    /// Wait for a slot for a file of `client`, served before requests of
+    /// lower `priority`.
+    pub(super) async fn acquire(&self, priority: i64, client: &str) -> Slot {
+        let slot = || Slot {
+            scheduler: self.clone(),
+            priority,
+            client: client.to_owned(),
+        };
+        let granted = {
+            let mut slots = self.0.lock().unwrap();
+            if slots.available > 0 {
+                slots.available -= 1;
+                slots.grant(client);
+                return slot();
+            }
+            let (wake, granted) = oneshot::channel();
+            slots.requests += 1;
+            let request = slots.requests;
+            slots.waiting.push(Waiter {
+                priority,
+                client: client.to_owned(),
+                request,
+                wake,
+            });
+            granted
+        };
+        let mut pending = Pending {
+            scheduler: self,
+            client,
+            granted,
+        };
+        // Waiters are only dropped once woken up.
+        (&mut pending.granted)
+            .await
+            .expect("waiter should be woken up");
+        slot()
+    }
+
+    /// Release the slot of `client`, handing it over to the next waiter still
+    /// waiting, if any.
+    fn release(&self, client: &str) {
+        let mut slots = self.0.lock().unwrap();
+        slots.ungrant(client);
+        loop {
+            let Some(waiter) = slots.next_waiter() else {
+                slots.available += 1;
+                return;
+            };
+            slots.grant(&waiter.client);
+            if waiter.wake.send(()).is_ok() {
+                return;
+            }
+            slots.ungrant(&waiter.client);
+        }
+    }
+}
+
+/// Named pools of resources, e.g. GPUs, that processing steps hold while
+/// they run.
+#[derive(Clone, Default)]
+pub(super) struct Pools(HashMap<String, (u32, Arc<Semaphore>)>);
+
+impl Pools {
+    pub(super) fn new(sizes: &HashMap<String, u32>) -> Self {
+        let pools = sizes
+            .iter()
+            .map(|(name, &size)| {
+                let sem = Arc::new(Semaphore::new(size as usize));
+                (name.clone(), (size, sem))
+            })
+            .collect();
+        Self(pools)
+    }
+
+    /// Semaphore of the resource `name`, checking that `amount` of it can
+    /// ever be acquired.
+    fn pool(&self, name: &str, amount: u32) -> io::Result<&Arc<Semaphore>> {
+        let Some((size, sem)) = self.0.get(name) else {
+            return Err(io::Error::other(format!("unknown resource `{name}`")));
+        };
+        if amount > *size {
+            return Err(io::Error::other(format!(
+                "requires {amount} `{name}` out of {size}"
+            )));
+        }
+        Ok(sem)
+    }
+
+    /// The `required` amount of each resource if all are free.
+    fn try_acquire(
+        &self,
+        required: &BTreeMap<String, u32>,
+    ) -> io::Result<Option<Vec<OwnedSemaphorePermit>>> {
+        let mut permits = Vec::with_capacity(required.len());
+        for (name, &amount) in required {
+            match self
+                .pool(name, amount)?
+                .clone()
+                .try_acquire_many_owned(amount)
+            {
+                Ok(permit) => permits.push(permit),
+                Err(_) => return Ok(None),
+            }
+        }
+        Ok(Some(permits))
+    }
+
+    /// Wait for the `required` amount of each resource.
+    ///
+    /// Resources are acquired in order of name so that steps requiring
+    /// several of them cannot deadlock each other.
+    async fn acquire(
+        &self,
+        required: &BTreeMap<String, u32>,
+    ) -> io::Result<Vec<OwnedSemaphorePermit>> {
+        let mut permits = Vec::with_capacity(required.len());
+        for (name, &amount) in required {
+            let sem = self.pool(name, amount)?.clone();
+            permits.push(sem.acquire_many_owned(amount).await.unwrap());
+        }
+        Ok(permits)
+    }
+}
+
+/// Resources available to a file being processed: the pools shared by all
+/// files and the processing slot of the file, if any.
+pub(super) struct Resources<'a> {
+    pools: &'a Pools,
+    slot: Option<Slot>,
+}
+
+impl<'a> Resources<'a> {
+    pub(super) fn new(pools: &'a Pools, slot: Option<Slot>) -> Self {
+        Self { pools, slot }
+    }
+
+    /// Wait for the `required` amount of each resource. The processing slot
+    /// is handed back while waiting, so that files waiting for busy resources
+    /// do not keep others from being processed, and acquired again after.
+    pub(super) async fn acquire(
+        &mut self,
+        required: &BTreeMap<String, u32>,
+    ) -> io::Result<Vec<OwnedSemaphorePermit>> {
+        if let Some(permits) = self.pools.try_acquire(required)? {
+            return Ok(permits);
+        }
+        let Some(slot) = self.slot.take() else {
+            return self.pools.acquire(required).await;
+        };
+        let (scheduler, priority, client) =
+            (slot.scheduler.clone(), slot.priority, slot.client.clone());
+        drop(slot);
+        let permits = self.pools.acquire(required).await;
+        self.slot = Some(scheduler.acquire(priority, &client).await);
+        permits
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Order in which `requests` are served by a single slot held by `a`.
+    async fn served(fair_share: bool, requests: &[(&'static str, i64, &str)]) -> Vec<&'static str> {
+        let scheduler = Scheduler::new(1, fair_share);
+        let slot = scheduler.acquire(0, "a").await;
+        let (order_tx, mut order) = tokio::sync::mpsc::unbounded_channel();
+        for &(name, priority, client) in requests {
+            let scheduler = scheduler.clone();
+            let order_tx = order_tx.clone();
+            let client = client.to_owned();
+            tokio::spawn(async move {
+                let _slot = scheduler.acquire(priority, &client).await;
+                order_tx.send(name).unwrap();
+            });
+            // Let the task register before the next one.
+            tokio::task::yield_now().await;
+        }
+        drop(order_tx);
+        drop(slot);
+        let mut served = Vec::new();
+        while let Some(name) = order.recv().await {
+            served.push(name);
+        }
+        served
+    }
+
+    #[tokio::test]
+    async fn serves_higher_priority_first() {
+        let requests = [("low", 0, "a"), ("high", 10, "a"), ("low again", 0, "a")];
+        assert_eq!(served(false, &requests).await, ["high", "low", "low again"]);
+    }
+
+    #[tokio::test]
+    async fn shares_slots_across_clients() {
+        let requests = [("a1", 0, "a"), ("a2", 0, "a"), ("b1", 0, "b")];
+        assert_eq!(served(false, &requests).await, ["a1", "a2", "b1"]);
+        assert_eq!(served(true, &requests).await, ["b1", "a1", "a2"]);
+    }
+
+    #[tokio::test]
+    async fn slot_is_handed_back_while_waiting_for_resources() {
+        let scheduler = Scheduler::new(1, false);
+        let pools = Pools::new(&HashMap::from([("gpu".to_owned(), 1)]));
+        let required = BTreeMap::from([("gpu".to_owned(), 1)]);
+        let gpu = pools.try_acquire(&required).unwrap().unwrap();
+
+        let slot = scheduler.acquire(0, "a").await;
+        let waiting = {
+            let pools = pools.clone();
+            let required = required.clone();
+            tokio::spawn(async move {
+                let mut resources = Resources::new(&pools, Some(slot));
+                resources.acquire(&required).await.unwrap();
+                resources.slot.is_some()
+            })
+        };
+        // Only granted once the file waiting for the GPU handed its slot back.
+        let other = scheduler.acquire(0, "b").await;
+        drop(gpu);
+        drop(other);
+        assert!(waiting.await.unwrap());
+    }
+}
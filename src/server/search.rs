@@ -0,0 +1,67 @@
+use std::io;
+
+use tabled::{Table, settings::Style};
+
+use crate::server::{Config, database::Database};
+
+/// Print the files whose name, client path, client name or tags contain
+/// `pattern`, where `*` matches any sequence of characters and `?` any single
+/// character. Matching is case-insensitive for ASCII letters.
+pub(crate) async fn main(config: Config, pattern: &str, limit: u32) -> io::Result<()> {
+    let db = Database::open_existing(&config.database)
+        .await
+        .map_err(io::Error::other)?;
+
+    let hits = db
+        .search(&like_pattern(pattern), limit)
+        .await
+        .map_err(io::Error::other)?;
+    if hits.is_empty() {
+        println!("no file matches {pattern:?}");
+        return Ok(());
+    }
+    let nhits = hits.len();
+    let mut table = Table::new(hits);
+    table.with(
+        Style::markdown()
+            .remove_vertical()
+            .remove_left()
+            .remove_right(),
+    );
+    println!("{table}");
+    if nhits as u32 == limit {
+        println!("\nonly showing the {limit} most recent matches");
+    }
+    Ok(())
+}
+
+/// `LIKE` pattern matching strings containing `pattern`, with `\` as escape.
+fn like_pattern(pattern: &str) -> String {
+    let mut like = String::with_capacity(pattern.len() + 2);
+    like.push('%');
+    for c in pattern.chars() {
+        match c {
+            '%' | '_' | '\\' => {
+                like.push('\\');
+                like.push(c);
+            }
+            '*' => like.push('%'),
+            '?' => like.push('_'),
+            c => like.push(c),
+        }
+    }
+    like.push('%');
+    like
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn like_patterns() {
+        assert_eq!(like_pattern("grid_3"), "%grid\\_3%");
+        assert_eq!(like_pattern("FoilHole*.tif?"), "%FoilHole%.tif_%");
+        assert_eq!(like_pattern("100%"), "%100\\%%");
+    }
+}
@@ -0,0 +1,307 @@
+use std::{ffi::OsString, fs::File, io, path::PathBuf, process::Stdio};
+
+use serde::Deserialize;
+use tokio::process::{Child, Command};
+
+/// How external commands of a processing step are spawned.
+#[derive(Deserialize, Debug, PartialEq, Eq, Default)]
+pub(super) struct SpawnOptions {
+    /// Account running the command, by name.
+    pub(super) user: Option<String>,
+    /// Niceness of the command, from -20 (highest priority) to 19.
+    pub(super) nice: Option<i32>,
+    /// I/O scheduling class and level of the command.
+    ionice: Option<IoPriority>,
+    /// CPU time the command may use, in percent of one CPU.
+    pub(super) cpu_percent: Option<u32>,
+    /// Memory above which the command is killed.
+    pub(super) memory_max_bytes: Option<u64>,
+}
+
+/// I/O scheduling of a command, written `"idle"`, `"best-effort"` or
+/// `"realtime"` in the configuration, optionally followed by a level from 0
+/// (highest priority) to 7, e.g. `"best-effort:7"`.
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(try_from = "String")]
+struct IoPriority {
+    class: u8,
+    level: u8,
+}
+
+impl TryFrom<String> for IoPriority {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let invalid = || {
+            format!(
+                "invalid ionice {value:?}, expected \"idle\", \"best-effort\" or \"realtime\" \
+                 optionally followed by a level, e.g. \"best-effort:7\""
+            )
+        };
+        let (class, level) = match value.split_once(':') {
+            Some((class, level)) => (class, Some(level)),
+            None => (value.as_str(), None),
+        };
+        // Classes as numbered by the kernel.
+        let class = match class {
+            "realtime" => 1,
+            "best-effort" => 2,
+            "idle" => 3,
+            _ => return Err(invalid()),
+        };
+        let level = match level {
+            Some(level) => level
+                .parse()
+                .ok()
+                .filter(|level| *level <= 7)
+                .ok_or_else(invalid)?,
+            None => 4,
+        };
+        Ok(Self { class, level })
+    }
+}
+
+impl SpawnOptions {
+    fn has_limits(&self) -> bool {
+        self.cpu_percent.is_some() || self.memory_max_bytes.is_some()
+    }
+
+    /// Spawn `program` with `args` and these options, its output going to
+    /// `log` if set. Commands with CPU or memory limits run in a transient
+    /// systemd scope holding their cgroup.
+    pub(super) fn spawn(
+        &self,
+        program: &str,
+        args: impl IntoIterator<Item = OsString>,
+        log: Option<File>,
+    ) -> io::Result<Child> {
+        if !self.has_limits() {
+            let mut command = Command::new(program);
+            command.args(args);
+            self.apply(&mut command, true)?;
+            redirect_output(&mut command, log)?;
+            return command.spawn();
+        }
+        if cfg!(not(target_os = "linux")) {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "`cpu_percent` and `memory_max_bytes` are only supported on Linux",
+            ));
+        }
+        #[cfg(unix)]
+        // SAFETY: `geteuid` has no preconditions.
+        if unsafe { libc::geteuid() } != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "`cpu_percent` and `memory_max_bytes` need the server to run as root, \
+                 as only root may create the systemd scope limiting the command",
+            ));
+        }
+        let mut scope = Command::new("systemd-run");
+        scope.args(["--scope", "--quiet", "--collect"]);
+        if let Some(percent) = self.cpu_percent {
+            scope.arg(format!("--property=CPUQuota={percent}%"));
+        }
+        if let Some(bytes) = self.memory_max_bytes {
+            scope.arg(format!("--property=MemoryMax={bytes}"));
+        }
+        // Only root may create the scope, which then switches user.
+        if let Some(user) = &self.user {
+            scope.arg(format!("--uid={user}"));
+        }
+        scope.arg("--").arg(program).args(args);
+        self.apply(&mut scope, false)?;
+        redirect_output(&mut scope, log)?;
+        scope.spawn().map_err(|err| match err.kind() {
+            io::ErrorKind::NotFound => io::Error::new(
+                io::ErrorKind::NotFound,
+                "`systemd-run` is needed to limit CPU or memory, with the server \
+                 running as root, but was not found",
+            ),
+            _ => err,
+        })
+    }
+
+    /// Set up `command` to be spawned with these options, switching to
+    /// `user` if `switch_user`. The environment of the command is that of
+    /// `user` in any case.
+    #[cfg(unix)]
+    fn apply(&self, command: &mut Command, switch_user: bool) -> io::Result<()> {
+        if cfg!(not(target_os = "linux")) && self.ionice.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "ionice is only supported on Linux",
+            ));
+        }
+        let account = match &self.user {
+            Some(user) => {
+                let account = lookup_user(user)?;
+                command
+                    .env("HOME", &account.home)
+                    .env("USER", user)
+                    .env("LOGNAME", user);
+                Some(account).filter(|_| switch_user)
+            }
+            None => None,
+        };
+        if self.nice.is_none() && self.ionice.is_none() && account.is_none() {
+            return Ok(());
+        }
+        let (nice, ionice) = (self.nice, self.ionice);
+        // SAFETY: the closure only makes system calls, which are safe to make
+        // between fork and exec. Supplementary groups are looked up before
+        // forking, as `initgroups` would read the group database in the child.
+        unsafe {
+            command.pre_exec(move || {
+                // Priorities are set first, only root may raise them.
+                if let Some(nice) = nice
+                    && libc::setpriority(libc::PRIO_PROCESS, 0, nice) != 0
+                {
+                    return Err(io::Error::last_os_error());
+                }
+                #[cfg(target_os = "linux")]
+                if let Some(IoPriority { class, level }) = ionice {
+                    const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+                    let priority = libc::c_int::from(class) << 13 | libc::c_int::from(level);
+                    if libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, priority) != 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+                if let Some(account) = &account
+                    && (libc::setgroups(account.groups.len() as _, account.groups.as_ptr()) != 0
+                        || libc::setgid(account.gid) != 0
+                        || libc::setuid(account.uid) != 0)
+                {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            })
+        };
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    fn apply(&self, _command: &mut Command, _switch_user: bool) -> io::Result<()> {
+        if *self == Self::default() {
+            Ok(())
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "`user`, `nice` and `ionice` are only supported on Unix",
+            ))
+        }
+    }
+}
+
+fn redirect_output(command: &mut Command, log: Option<File>) -> io::Result<()> {
+    if let Some(log) = log {
+        command
+            .stdout(Stdio::from(log.try_clone()?))
+            .stderr(Stdio::from(log));
+    }
+    Ok(())
+}
+
+/// Account a command runs under.
+pub(super) struct Account {
+    uid: u32,
+    gid: u32,
+    /// Supplementary groups, as set by `initgroups`.
+    groups: Vec<u32>,
+    home: PathBuf,
+}
+
+/// User and group ids, groups and home directory of the account `user`.
+#[cfg(unix)]
+pub(super) fn lookup_user(user: &str) -> io::Result<Account> {
+    use std::os::unix::ffi::OsStrExt;
+    let name = std::ffi::CString::new(user)?;
+    // SAFETY: `passwd` is plain data, valid when zeroed.
+    let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buf = vec![0; 1 << 14];
+    let mut found = std::ptr::null_mut();
+    // SAFETY: all pointers are valid for the duration of the call, strings
+    // of the entry are written to `buf` which outlives `passwd`.
+    let code = unsafe {
+        libc::getpwnam_r(
+            name.as_ptr(),
+            &mut passwd,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut found,
+        )
+    };
+    if found.is_null() {
+        return Err(match code {
+            0 => io::Error::new(io::ErrorKind::NotFound, format!("unknown user `{user}`")),
+            code => io::Error::from_raw_os_error(code),
+        });
+    }
+    // SAFETY: `pw_dir` points to a nul-terminated string in `buf`.
+    let home = unsafe { std::ffi::CStr::from_ptr(passwd.pw_dir) };
+    let home = PathBuf::from(std::ffi::OsStr::from_bytes(home.to_bytes()));
+
+    let mut groups: Vec<libc::gid_t> = vec![0; 64];
+    loop {
+        let mut count = groups.len() as libc::c_int;
+        // SAFETY: `groups` holds `count` elements.
+        let found = unsafe {
+            libc::getgrouplist(
+                name.as_ptr(),
+                passwd.pw_gid as _,
+                groups.as_mut_ptr().cast(),
+                &mut count,
+            )
+        };
+        if found >= 0 {
+            groups.truncate(count as usize);
+            break;
+        }
+        if groups.len() >= 1 << 16 {
+            return Err(io::Error::other(format!("`{user}` is in too many groups")));
+        }
+        groups.resize(groups.len() * 2, 0);
+    }
+    Ok(Account {
+        uid: passwd.pw_uid,
+        gid: passwd.pw_gid,
+        groups,
+        home,
+    })
+}
+
+#[cfg(not(unix))]
+pub(super) fn lookup_user(_user: &str) -> io::Result<Account> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "`user` is only supported on Unix",
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn ionice(value: &str) -> Result<IoPriority, String> {
+        IoPriority::try_from(value.to_owned())
+    }
+
+    #[test]
+    fn parse_ionice() {
+        assert_eq!(ionice("idle"), Ok(IoPriority { class: 3, level: 4 }));
+        let best_effort = ionice("best-effort:7");
+        assert_eq!(best_effort, Ok(IoPriority { class: 2, level: 7 }));
+        assert!(ionice("realtime:8").is_err());
+        assert!(ionice("lowest").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn lookup_root_account() {
+        let root = lookup_user("root").unwrap();
+        assert_eq!((root.uid, root.gid), (0, 0));
+        assert!(root.groups.contains(&0));
+        assert!(root.home.is_absolute());
+        assert!(lookup_user("no-such-user-of-pipeline").is_err());
+    }
+}
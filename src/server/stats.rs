@@ -0,0 +1,110 @@
+use std::io;
+
+use tabled::{Table, Tabled, settings::Style};
+
+use crate::{
+    format_size,
+    server::{Config, database::Database},
+};
+
+#[derive(Tabled)]
+struct ArrivalRow {
+    day: String,
+    client: String,
+    files: i64,
+    size: String,
+}
+
+#[derive(Tabled)]
+struct ProcessingRow {
+    processing: String,
+    attempts: i64,
+    failures: i64,
+    failure_rate: String,
+    avg_duration: String,
+}
+
+#[derive(Tabled)]
+struct StepRow {
+    processing: String,
+    step: i64,
+    failures: i64,
+    /// Share of the attempts of the group failing at this step.
+    failure_rate: String,
+}
+
+fn percent(part: i64, total: i64) -> String {
+    if total == 0 {
+        return "-".to_owned();
+    }
+    format!("{:.1}%", 100.0 * part as f64 / total as f64)
+}
+
+/// Report files and bytes received per day and client, as well as the
+/// duration and failures of processing attempts since `since`, formatted as
+/// dates in the database.
+pub(crate) async fn main(config: Config, since: &str) -> io::Result<()> {
+    let db = Database::open_existing(&config.database)
+        .await
+        .map_err(io::Error::other)?;
+
+    let arrivals = db.daily_arrivals(since).await.map_err(io::Error::other)?;
+    let total_files: i64 = arrivals.iter().map(|a| a.files).sum();
+    let total_bytes: i64 = arrivals.iter().map(|a| a.size_bytes).sum();
+    println!("files received:");
+    print_table(Table::new(arrivals.into_iter().map(|a| ArrivalRow {
+        day: a.day,
+        client: a.client,
+        files: a.files,
+        size: format_size(a.size_bytes as u64),
+    })));
+    println!(
+        "total: {total_files} files ({})\n",
+        format_size(total_bytes as u64)
+    );
+
+    let processing = db.processing_stats(since).await.map_err(io::Error::other)?;
+    let failures = db.step_failures(since).await.map_err(io::Error::other)?;
+    let attempts_of = |group: &str| {
+        processing
+            .iter()
+            .find(|p| p.processing == group)
+            .map_or(0, |p| p.attempts)
+    };
+    let steps: Vec<_> = failures
+        .into_iter()
+        .map(|f| StepRow {
+            failure_rate: percent(f.failures, attempts_of(&f.processing)),
+            processing: f.processing,
+            step: f.step,
+            failures: f.failures,
+        })
+        .collect();
+    println!("processing attempts:");
+    print_table(Table::new(processing.into_iter().map(|p| {
+        ProcessingRow {
+            failure_rate: percent(p.failures, p.attempts),
+            avg_duration: p
+                .avg_secs
+                .map_or_else(|| "-".to_owned(), |secs| format!("{secs:.1} s")),
+            processing: p.processing,
+            attempts: p.attempts,
+            failures: p.failures,
+        }
+    })));
+    if !steps.is_empty() {
+        println!("\nfailures per step:");
+        print_table(Table::new(steps));
+    }
+    Ok(())
+}
+
+fn print_table(mut table: Table) {
+    table.with(
+        Style::markdown()
+            .remove_vertical()
+            .remove_left()
+            .remove_right(),
+    );
+    println!("{table}");
+}
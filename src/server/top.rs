@@ -0,0 +1,156 @@
+use std::{io, time::Duration};
+
+use ratatui::{
+    Frame,
+    crossterm::event::{self, Event, KeyCode, KeyEventKind},
+    layout::{Constraint, Layout},
+    style::Style,
+    widgets::{Block, Row, Table},
+};
+
+use crate::server::{
+    database::Snapshot,
+    query::{QueryConfig, fetch_snapshot},
+};
+
+const REFRESH_EVERY: Duration = Duration::from_secs(2);
+
+pub(crate) async fn main(config: QueryConfig) -> io::Result<()> {
+    // Log lines would garble the terminal, only keep errors.
+    log::set_max_level(log::LevelFilter::Error);
+
+    let mut terminal = ratatui::init();
+    let res = async {
+        loop {
+            let snapshot = fetch_snapshot(&config).await?;
+            terminal.draw(|frame| draw(frame, &snapshot))?;
+            if tokio::task::spawn_blocking(wait_for_quit).await?? {
+                break Ok(());
+            }
+        }
+    }
+    .await;
+    ratatui::restore();
+    res
+}
+
+/// Wait for a key press until the next refresh, returning whether to quit.
+fn wait_for_quit() -> io::Result<bool> {
+    if event::poll(REFRESH_EVERY)?
+        && let Event::Key(key) = event::read()?
+        && key.kind == KeyEventKind::Press
+    {
+        return Ok(matches!(key.code, KeyCode::Char('q') | KeyCode::Esc));
+    }
+    Ok(false)
+}
+
+fn format_elapsed(secs: i64) -> String {
+    format!("{}:{:02}:{:02}", secs / 3600, (secs / 60) % 60, secs % 60)
+}
+
+fn table<'a>(
+    title: &'a str,
+    header: Row<'a>,
+    rows: Vec<Row<'a>>,
+    widths: &[Constraint],
+) -> Table<'a> {
+    Table::new(rows, widths)
+        .header(header.style(Style::new().bold()))
+        .block(Block::bordered().title(title))
+}
+
+fn draw(frame: &mut Frame, snapshot: &Snapshot) {
+    let [top, processing, failures] = Layout::vertical([
+        Constraint::Length(8),
+        Constraint::Fill(1),
+        Constraint::Fill(1),
+    ])
+    .areas(frame.area());
+    let [counts, throughput] =
+        Layout::horizontal([Constraint::Fill(1), Constraint::Fill(1)]).areas(top);
+
+    let rows = snapshot
+        .counts
+        .iter()
+        .map(|(status, n)| Row::new([status.clone(), n.to_string()]))
+        .collect();
+    frame.render_widget(
+        table(
+            "Files per status (q to quit)",
+            Row::new(["status", "files"]),
+            rows,
+            &[Constraint::Fill(1), Constraint::Length(10)],
+        ),
+        counts,
+    );
+
+    let rows = snapshot
+        .throughput
+        .iter()
+        .map(|(client, n)| Row::new([client.clone(), n.to_string()]))
+        .collect();
+    frame.render_widget(
+        table(
+            "Files received in the last hour",
+            Row::new(["client", "files"]),
+            rows,
+            &[Constraint::Fill(1), Constraint::Length(10)],
+        ),
+        throughput,
+    );
+
+    let rows = snapshot
+        .processing
+        .iter()
+        .map(|p| {
+            Row::new([
+                format_elapsed(p.elapsed_secs),
+                p.client.clone(),
+                p.file_name.clone(),
+                p.hash.clone(),
+            ])
+        })
+        .collect();
+    frame.render_widget(
+        table(
+            "Processing",
+            Row::new(["elapsed", "client", "file", "hash"]),
+            rows,
+            &[
+                Constraint::Length(10),
+                Constraint::Length(16),
+                Constraint::Fill(1),
+                Constraint::Fill(1),
+            ],
+        ),
+        processing,
+    );
+
+    let rows = snapshot
+        .recent_failures
+        .iter()
+        .map(|f| {
+            Row::new([
+                f.date_utc.clone(),
+                f.client.clone(),
+                f.file_name.clone(),
+                f.error.clone(),
+            ])
+        })
+        .collect();
+    frame.render_widget(
+        table(
+            "Recent failures",
+            Row::new(["date (UTC)", "client", "file", "error"]),
+            rows,
+            &[
+                Constraint::Length(20),
+                Constraint::Length(16),
+                Constraint::Fill(1),
+                Constraint::Fill(2),
+            ],
+        ),
+        failures,
+    );
+}
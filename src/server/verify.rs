@@ -0,0 +1,260 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    path::{Path, PathBuf},
+};
+
+use walkdir::WalkDir;
+
+use crate::{
+    FileSpec,
+    hashing::FileDigest,
+    server::{
+        Config, companion_path_of,
+        database::{CompanionOrigin, Database, ProcessStatus},
+        encryption,
+        processing::AfterProcessing,
+    },
+};
+
+/// Number of files read from the database at once.
+const PAGE_SIZE: u32 = 1000;
+
+/// Re-hash the files of the pipeline stored in `directory` of the incoming
+/// directory, all if `None`, reporting missing files, digest mismatches and
+/// files unknown to the database.
+pub(crate) async fn main(config: Config, directory: Option<PathBuf>) -> io::Result<()> {
+    let db = Database::open_existing(&config.database)
+        .await
+        .map_err(io::Error::other)?;
+    let root = match &directory {
+        Some(directory) => config.incoming_path(directory),
+        None => config.incoming_directory.clone(),
+    };
+
+    let (verified, problems) = verify(&config, &db, &root).await?;
+    for problem in &problems {
+        println!("{problem}");
+    }
+    println!(
+        "verified {verified} files, found {} problems",
+        problems.len()
+    );
+    if !problems.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "verification of incoming directory failed",
+        ));
+    }
+    Ok(())
+}
+
+/// Re-hash the files of the pipeline stored in `root`, returning the number
+/// of files verified and a description of each problem found.
+pub(super) async fn verify(
+    config: &Config,
+    db: &Database,
+    root: &Path,
+) -> io::Result<(usize, Vec<String>)> {
+    let present = files_in(root)?;
+    let origins = db.companion_origins(None).await.map_err(io::Error::other)?;
+    let mut expected = HashSet::new();
+    let mut verified = 0;
+    let mut problems = Vec::new();
+    let mut after = String::new();
+    loop {
+        let page = db
+            .content_page(&after, PAGE_SIZE)
+            .await
+            .map_err(io::Error::other)?;
+        let Some(last) = page.last() else {
+            break;
+        };
+        after = last.hash.clone();
+        let files: Vec<_> = page
+            .into_iter()
+            .map(|row| {
+                let path = config.stored_path(&row.storage_path);
+                (row.status, FileSpec::from(row), path)
+            })
+            .collect();
+        let stored = files.iter().map(|(_, spec, path)| (spec, path.as_path()));
+        expected.extend(expected_paths(config, stored, &origins));
+        for (status, spec, path) in files {
+            if verify_file(config, db, root, status, &spec, &path, &mut problems).await? {
+                verified += 1;
+            }
+        }
+    }
+
+    problems.extend(
+        present
+            .into_iter()
+            .filter(|path| !expected.contains(path))
+            .map(|orphan| format!("orphan: {orphan:?} is not in the database")),
+    );
+    Ok((verified, problems))
+}
+
+/// Whether `spec` is moved out of the pipeline after being processed, it is
+/// then missing from the incoming directory while still `Done`.
+fn moved_away(config: &Config, spec: &FileSpec) -> bool {
+    config
+        .processing
+        .get(&spec.processing)
+        .is_some_and(|group| matches!(group.after_processing, AfterProcessing::MoveAndPrune { .. }))
+}
+
+/// Re-hash `spec` stored at `path` if it is in `root`, recording problems.
+/// Returns whether the file was verified.
+async fn verify_file(
+    config: &Config,
+    db: &Database,
+    root: &Path,
+    status: ProcessStatus,
+    spec: &FileSpec,
+    path: &Path,
+    problems: &mut Vec<String>,
+) -> io::Result<bool> {
+    // Files being pruned may already be gone, e.g. moved by
+    // `move_to_and_prune`.
+    if !path.starts_with(root) || status.awaits_arrival() || status == ProcessStatus::ToPrune {
+        return Ok(false);
+    }
+    let may_be_moved = status == ProcessStatus::Done && moved_away(config, spec);
+    let companion = companion_path_of(path, spec);
+    let hash = spec.hash();
+    let algorithm = config.hash_algorithm;
+    let read = config.read_options();
+    let digest = match encryption::plaintext(config, db, hash, path.to_owned()).await {
+        Ok(plain) => {
+            let spec = spec.clone();
+            tokio::task::spawn_blocking(move || {
+                FileDigest::with_spec(plain.path(), &spec, algorithm, read)
+            })
+            .await?
+        }
+        Err(err) => Err(err),
+    };
+    let verified = match digest {
+        Ok(digest) if digest == spec.sha256_digest => true,
+        Ok(digest) => {
+            problems.push(format!(
+                "mismatch: {path:?} has hash {} instead of {hash}",
+                digest.hash()
+            ));
+            false
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            if !may_be_moved {
+                problems.push(format!(
+                    "missing: {hash} ({status:?}) should be at {path:?}"
+                ));
+            }
+            return Ok(false);
+        }
+        Err(err) => {
+            problems.push(format!("unreadable: {path:?}: {err}"));
+            false
+        }
+    };
+    if let Some(companion) = companion
+        && !companion.exists()
+    {
+        problems.push(format!(
+            "missing: companion of {hash} should be at {companion:?}"
+        ));
+    }
+    Ok(verified)
+}
+
+/// Files in `root`. This should be listed before reading the database, files
+/// arriving meanwhile are then known to the database as they are recorded
+/// before being sent.
+pub(super) fn files_in(root: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in WalkDir::new(root) {
+        let entry = entry?;
+        if entry.file_type().is_file() {
+            files.push(entry.into_path());
+        }
+    }
+    Ok(files)
+}
+
+/// Paths of `files` stored in the incoming directory, with those of the
+/// companions sent from each of their `origins`.
+pub(super) fn expected_paths<'a>(
+    config: &Config,
+    files: impl IntoIterator<Item = (&'a FileSpec, &'a Path)>,
+    origins: &[CompanionOrigin],
+) -> HashSet<PathBuf> {
+    let mut specs: HashMap<&str, &FileSpec> = HashMap::new();
+    let mut paths = HashSet::new();
+    for (spec, path) in files {
+        specs.insert(spec.hash(), spec);
+        paths.insert(path.to_owned());
+        paths.extend(companion_path_of(path, spec));
+    }
+    paths.extend(origins.iter().filter_map(|origin| {
+        let spec = specs.get(origin.hash.as_str())?;
+        let path = config.stored_path(&origin.storage_path);
+        companion_path_of(&path, &origin.spec_of(spec))
+    }));
+    paths
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        hashing::{HashAlgorithm, HashMode, ReadOptions},
+        server::DEFAULT_TOML_CONF,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn skip_files_being_pruned() {
+        let dir = std::env::temp_dir().join(format!("pipeline-verify-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let toml = DEFAULT_TOML_CONF.replace("./server/buckets", &dir.to_string_lossy());
+        let config: Config = toml::from_str(&toml).unwrap();
+        let db = Database::in_memory().await.unwrap();
+        let spec = |name: &str| {
+            let path = dir.join(name);
+            std::fs::write(&path, name).unwrap();
+            let sha256 = HashAlgorithm::Sha256;
+            let digest =
+                FileDigest::new(&path, HashMode::Full, sha256, ReadOptions::default()).unwrap();
+            FileSpec {
+                client: "lab".to_owned(),
+                path: String::new(),
+                filename: name.to_owned(),
+                processing: "main".to_owned(),
+                sha256_digest: digest,
+                size_bytes: name.len() as u64,
+                modified_utc: String::new(),
+                metadata: Default::default(),
+                companion: None,
+            }
+        };
+        for (name, status) in [
+            ("kept.dat", ProcessStatus::Done),
+            ("pruned.dat", ProcessStatus::ToPrune),
+            ("lost.dat", ProcessStatus::Queued),
+        ] {
+            let spec = spec(name);
+            db.insert_new(&spec, name, None).await.unwrap();
+            db.update_status(spec.hash(), status, "test").await.unwrap();
+        }
+        std::fs::remove_file(dir.join("pruned.dat")).unwrap();
+        std::fs::remove_file(dir.join("lost.dat")).unwrap();
+
+        let (verified, problems) = verify(&config, &db, &dir).await.unwrap();
+        assert_eq!(verified, 1);
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].starts_with("missing:"));
+        assert!(problems[0].contains("lost.dat"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
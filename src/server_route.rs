@@ -1,45 +1,82 @@
-use std::path::PathBuf;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 use std::{collections::HashSet, sync::Arc};
 
-use log::{info, warn};
+use log::{debug, info, warn};
 use russh::keys::agent::client::AgentClient;
+use russh::keys::known_hosts::known_host_keys_path;
+use russh::keys::{PrivateKeyWithHashAlg, load_secret_key};
 use russh::{
     client::{self as ssh_client, Handle},
     keys::{PublicKey, ssh_key::public::KeyData},
 };
 use serde::Deserialize;
 use tokio::net::TcpStream;
-use tokio::sync::oneshot;
+use tokio::sync::{Mutex, OnceCell};
 
 use tokio::net::TcpListener;
 use zeroize::Zeroize;
 
+use crate::custom_serde::Secret;
+
+/// Time allowed to establish a connection, e.g. to each SSH host of a tunnel.
+pub(crate) const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
 /// Configuration to connect to server.
 #[derive(Deserialize, Debug)]
 #[serde(untagged)]
 pub(crate) enum ServerRoute {
     Direct { address: String },
-    SshTunnel(SshTunnelConfig),
+    SshTunnel(Box<SshTunnelConfig>),
 }
 
 #[derive(Deserialize, Debug, Clone)]
 pub(crate) struct SshTunnelConfig {
-    ssh_host: String,
-    ssh_port: u16,
-    ssh_auth: SshAuth,
+    #[serde(flatten)]
+    host: SshHop,
+    /// SSH hosts to go through, in order, before reaching `host`.
+    #[serde(default)]
+    jump_hosts: Vec<SshHop>,
     keepalive_every_secs: u64,
     server_addr_from_host: String,
     server_port_from_host: u16,
+    /// Local address of the tunnel, set up on first connection.
+    #[serde(skip)]
+    tunnel_addr: OnceCell<SocketAddr>,
+}
+
+/// One SSH host on the way to the pipeline server.
+#[derive(Deserialize, Debug, Clone)]
+struct SshHop {
+    ssh_host: String,
+    ssh_port: u16,
+    ssh_auth: SshAuth,
+    #[serde(default)]
     accepted_ssh_keys: Vec<PublicKey>,
+    known_hosts_file: Option<PathBuf>,
 }
 
 #[derive(Deserialize, Debug, Clone)]
 #[serde(tag = "method", rename_all = "kebab-case")]
 enum SshAuth {
-    None { user: String },
-    Password { user: String },
-    Key { user: String, public_key: PathBuf },
+    None {
+        user: String,
+    },
+    Password {
+        user: String,
+        password: Option<Secret>,
+    },
+    Key {
+        user: String,
+        public_key: PathBuf,
+    },
+    PrivateKeyFile {
+        user: String,
+        private_key: PathBuf,
+        passphrase: Option<Secret>,
+    },
 }
 
 struct Client {
@@ -47,9 +84,29 @@ struct Client {
 }
 
 impl Client {
-    fn from_openssh_keys(keys: &[PublicKey]) -> Client {
-        let accepted_keys = keys.iter().map(KeyData::from).collect();
-        Client { accepted_keys }
+    fn from_config(conf: &SshHop) -> io::Result<Client> {
+        let mut accepted_keys: HashSet<KeyData> =
+            conf.accepted_ssh_keys.iter().map(KeyData::from).collect();
+        if let Some(path) = &conf.known_hosts_file {
+            let path = expand_home(path);
+            let known = known_host_keys_path(&conf.ssh_host, conf.ssh_port, &path)
+                .map_err(io::Error::other)?;
+            if known.is_empty() {
+                warn!("no key for {} found in {path:?}", conf.ssh_host);
+            }
+            accepted_keys.extend(known.iter().map(|(_, key)| KeyData::from(key)));
+        }
+        Ok(Client { accepted_keys })
+    }
+}
+
+/// Expand a leading `~` to the home directory of the current user.
+fn expand_home(path: &Path) -> PathBuf {
+    match path.strip_prefix("~") {
+        Ok(rest) => std::env::home_dir()
+            .expect("cannot determine home directory")
+            .join(rest),
+        Err(_) => path.to_owned(),
     }
 }
 
@@ -71,32 +128,108 @@ impl ssh_client::Handler for Client {
     }
 }
 
-async fn create_session(client: Client, conf: &SshTunnelConfig) -> Handle<Client> {
+/// SSH session to the last host, keeping alive the sessions to jump hosts.
+struct TunnelSession {
+    target: Handle<Client>,
+    _jumps: Vec<Handle<Client>>,
+}
+
+/// Run `future`, failing if it takes longer than [`CONNECT_TIMEOUT`].
+pub(crate) async fn with_timeout<T, E>(
+    what: &str,
+    future: impl Future<Output = Result<T, E>>,
+) -> io::Result<T>
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    match tokio::time::timeout(CONNECT_TIMEOUT, future).await {
+        Ok(res) => res.map_err(|err| io::Error::other(format!("{what} failed: {}", err.into()))),
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("{what} timed out after {}s", CONNECT_TIMEOUT.as_secs()),
+        )),
+    }
+}
+
+async fn create_session(conf: &SshTunnelConfig) -> io::Result<TunnelSession> {
     let ssh_config = Arc::new(ssh_client::Config {
         keepalive_interval: Some(Duration::from_secs(conf.keepalive_every_secs)),
         ..Default::default()
     });
 
-    let mut ssh_session =
-        ssh_client::connect(ssh_config, (conf.ssh_host.as_str(), conf.ssh_port), client)
-            .await
-            .expect("Connection to SSH host failed");
+    let mut hops = conf.jump_hosts.iter().chain([&conf.host]);
+    let first = hops.next().expect("there is at least one ssh host");
+
+    let mut ssh_session = with_timeout(
+        "connection to SSH host",
+        ssh_client::connect(
+            ssh_config.clone(),
+            (first.ssh_host.as_str(), first.ssh_port),
+            Client::from_config(first)?,
+        ),
+    )
+    .await?;
+    authenticate(&mut ssh_session, &first.ssh_auth).await?;
+
+    let mut jumps = Vec::with_capacity(conf.jump_hosts.len());
+    for hop in hops {
+        info!("jumping to ssh host {}:{}", hop.ssh_host, hop.ssh_port);
+        let channel = with_timeout(
+            "connection to next SSH host",
+            ssh_session.channel_open_direct_tcpip(
+                hop.ssh_host.clone(),
+                u32::from(hop.ssh_port),
+                "127.0.0.1",
+                0,
+            ),
+        )
+        .await?;
+        let mut next_session = with_timeout(
+            "connection to SSH host",
+            ssh_client::connect_stream(
+                ssh_config.clone(),
+                channel.into_stream(),
+                Client::from_config(hop)?,
+            ),
+        )
+        .await?;
+        authenticate(&mut next_session, &hop.ssh_auth).await?;
+        jumps.push(ssh_session);
+        ssh_session = next_session;
+    }
+
+    Ok(TunnelSession {
+        target: ssh_session,
+        _jumps: jumps,
+    })
+}
 
-    let auth_result = match &conf.ssh_auth {
+async fn authenticate(ssh_session: &mut Handle<Client>, ssh_auth: &SshAuth) -> io::Result<()> {
+    let auth_result = match ssh_auth {
         SshAuth::None { user } => {
             info!("authenticate as {user} with `none` auth");
-            ssh_session
-                .authenticate_none(user)
-                .await
-                .expect("Failed to authenticate")
+            with_timeout("authentication", ssh_session.authenticate_none(user)).await?
         }
-        SshAuth::Password { user } => {
+        SshAuth::Password { user, password } => {
             info!("authenticate as {user} with password");
-            let mut pwd = rpassword::prompt_password(format!("password for {user}:"))
-                .expect("Failed to read password");
-            let auth_result = ssh_session.authenticate_password(user, &pwd).await;
-            pwd.zeroize();
-            auth_result.expect("Failed to authenticate")
+            let auth_result = if let Some(password) = password {
+                with_timeout(
+                    "authentication",
+                    ssh_session.authenticate_password(user, password.expose()),
+                )
+                .await
+            } else {
+                let mut pwd = rpassword::prompt_password(format!("password for {user}:"))
+                    .expect("Failed to read password");
+                let auth_result = with_timeout(
+                    "authentication",
+                    ssh_session.authenticate_password(user, &pwd),
+                )
+                .await;
+                pwd.zeroize();
+                auth_result
+            };
+            auth_result?
         }
         SshAuth::Key { user, public_key } => {
             info!("authenticate as {user} with key");
@@ -110,20 +243,129 @@ async fn create_session(client: Client, conf: &SshTunnelConfig) -> Handle<Client
                 }}
             };
             let mut agent = agent.expect("Failed to connect to SSH agent");
-            ssh_session
-                .authenticate_publickey_with(user, public_key, None, &mut agent)
-                .await
-                .expect("Failed to authenticate")
+            with_timeout(
+                "authentication",
+                ssh_session.authenticate_publickey_with(user, public_key, None, &mut agent),
+            )
+            .await?
+        }
+        SshAuth::PrivateKeyFile {
+            user,
+            private_key,
+            passphrase,
+        } => {
+            info!("authenticate as {user} with private key file {private_key:?}");
+            let private_key = expand_home(private_key);
+            let key =
+                match load_secret_key(&private_key, passphrase.as_ref().map(Secret::expose)) {
+                    Err(russh::keys::Error::KeyIsEncrypted) => {
+                        let mut passphrase =
+                            rpassword::prompt_password(format!("passphrase for {private_key:?}:"))
+                                .expect("Failed to read passphrase");
+                        let key = load_secret_key(&private_key, Some(&passphrase));
+                        passphrase.zeroize();
+                        key
+                    }
+                    key => key,
+                }
+                .map_err(io::Error::other)?;
+            let hash_alg = with_timeout(
+                "negotiation of hash algorithm",
+                ssh_session.best_supported_rsa_hash(),
+            )
+            .await?
+            .flatten();
+            with_timeout(
+                "authentication",
+                ssh_session.authenticate_publickey(
+                    user,
+                    PrivateKeyWithHashAlg::new(Arc::new(key), hash_alg),
+                ),
+            )
+            .await?
         }
     };
 
     assert!(auth_result.success(), "Denied authentication");
+    Ok(())
+}
+
+/// SSH session of a tunnel, set up again whenever it is lost.
+struct Tunnel {
+    conf: SshTunnelConfig,
+    session: Mutex<Option<Arc<TunnelSession>>>,
+}
+
+impl Tunnel {
+    /// Current session, connecting again if there is none or it was closed.
+    async fn session(&self) -> io::Result<Arc<TunnelSession>> {
+        let mut session = self.session.lock().await;
+        if let Some(session) = session.as_ref().filter(|s| !s.target.is_closed()) {
+            return Ok(session.clone());
+        }
+        *session = None;
+        let new_session = Arc::new(create_session(&self.conf).await?);
+        *session = Some(new_session.clone());
+        Ok(new_session)
+    }
 
-    ssh_session
+    /// Drop `failed` so that the next connection sets up a new session.
+    async fn discard(&self, failed: &Arc<TunnelSession>) {
+        let mut session = self.session.lock().await;
+        if session.as_ref().is_some_and(|s| Arc::ptr_eq(s, failed)) {
+            *session = None;
+        }
+    }
 }
 
-/// Setup the SSH tunnel.
-async fn setup_tunnel(conf: SshTunnelConfig) -> TcpStream {
+/// Open a forwarding channel through the SSH session for one local connection.
+async fn forward_connection(
+    tunnel: Arc<Tunnel>,
+    mut local_socket: TcpStream,
+    local_addr: SocketAddr,
+) {
+    let conf = &tunnel.conf;
+    let ssh_channel = loop {
+        let ssh_session = match tunnel.session().await {
+            Ok(ssh_session) => ssh_session,
+            Err(err) => {
+                warn!("cannot set up SSH session, will retry in 3s: {err}");
+                tokio::time::sleep(Duration::from_secs(3)).await;
+                continue;
+            }
+        };
+        let channel = with_timeout(
+            "opening SSH forwarding channel",
+            ssh_session.target.channel_open_direct_tcpip(
+                conf.server_addr_from_host.clone(),
+                u32::from(conf.server_port_from_host),
+                local_addr.ip().to_string(),
+                u32::from(local_addr.port()),
+            ),
+        )
+        .await;
+        match channel {
+            Ok(channel) => break channel,
+            Err(err) => {
+                warn!("cannot open SSH forwarding channel, will reconnect in 3s: {err}");
+                tunnel.discard(&ssh_session).await;
+                tokio::time::sleep(Duration::from_secs(3)).await;
+            }
+        }
+    };
+
+    let mut ssh_stream = ssh_channel.into_stream();
+
+    if let Err(err) = tokio::io::copy_bidirectional(&mut local_socket, &mut ssh_stream).await {
+        warn!("copy error between local socket and SSH stream: {err}");
+    }
+}
+
+/// Setup the SSH tunnel, returning the local address it listens on.
+///
+/// Each connection accepted on the local address is forwarded through its own
+/// channel of a single SSH session, which is set up again if it fails.
+async fn setup_tunnel(conf: SshTunnelConfig) -> SocketAddr {
     let local_listener = TcpListener::bind("127.0.0.1:0")
         .await
         .expect("Cannot bind local port");
@@ -131,52 +373,33 @@ async fn setup_tunnel(conf: SshTunnelConfig) -> TcpStream {
 
     info!(
         "setting up ssh tunnel {local_addr} -> {}:{} -> {}:{}",
-        conf.ssh_host, conf.ssh_port, conf.server_addr_from_host, conf.server_port_from_host,
+        conf.host.ssh_host,
+        conf.host.ssh_port,
+        conf.server_addr_from_host,
+        conf.server_port_from_host,
     );
 
-    let (tx_tunnel, rx_tunnel) = oneshot::channel();
+    let tunnel = Arc::new(Tunnel {
+        conf,
+        session: Mutex::new(None),
+    });
+    if let Err(err) = tunnel.session().await {
+        warn!("cannot set up SSH session, will retry on connection: {err}");
+    }
 
     tokio::spawn(async move {
-        let stream = TcpStream::connect(local_addr)
-            .await
-            .expect("failed to connect to local socket");
-
-        let (mut local_socket, _) = local_listener
-            .accept()
-            .await
-            .expect("failed to accept local listener");
-
-        let ssh_client = Client::from_openssh_keys(&conf.accepted_ssh_keys);
-        let ssh_session = create_session(ssh_client, &conf).await;
-
-        let ssh_channel = loop {
-            let channel = ssh_session
-                .channel_open_direct_tcpip(
-                    conf.server_addr_from_host.clone(),
-                    u32::from(conf.server_port_from_host),
-                    local_addr.ip().to_string(),
-                    u32::from(local_addr.port()),
-                )
-                .await;
-            match channel {
-                Ok(channel) => break channel,
-                Err(err) => {
-                    warn!("cannot open SSH forwarding channel, will retry in 3s: {err}");
-                    tokio::time::sleep(Duration::from_secs(3)).await;
+        loop {
+            match local_listener.accept().await {
+                Ok((local_socket, addr)) => {
+                    debug!("forwarding connection from {addr} through ssh tunnel");
+                    tokio::spawn(forward_connection(tunnel.clone(), local_socket, local_addr));
                 }
+                Err(err) => warn!("failed to accept connection on local listener: {err}"),
             }
-        };
-
-        let mut ssh_stream = ssh_channel.into_stream();
-
-        tx_tunnel.send(stream).expect("rx_tunnel has been dropped");
-
-        tokio::io::copy_bidirectional(&mut local_socket, &mut ssh_stream)
-            .await
-            .expect("Copy error between local socket and SSH stream");
+        }
     });
 
-    rx_tunnel.await.expect("tx_tunnel has been dropped")
+    local_addr
 }
 
 impl ServerRoute {
@@ -197,7 +420,13 @@ impl ServerRoute {
                 stream
             }
             Self::SshTunnel(conf) => {
-                let stream = setup_tunnel(conf.clone()).await;
+                let local_addr = *conf
+                    .tunnel_addr
+                    .get_or_init(|| setup_tunnel((**conf).clone()))
+                    .await;
+                let stream = TcpStream::connect(local_addr)
+                    .await
+                    .expect("failed to connect to local end of ssh tunnel");
                 info!("connected to server via SSH tunnel");
                 stream
             }
@@ -0,0 +1,50 @@
+use std::{future::Future, io, time::Duration};
+
+use log::{debug, warn};
+
+/// Signal systemd that the service finished starting up, for units of
+/// `Type=notify`. This is a no-op when not run by systemd.
+pub(crate) fn notify_ready() {
+    #[cfg(unix)]
+    if let Err(err) = sd_notify::notify(false, &[sd_notify::NotifyState::Ready]) {
+        warn!("failed to notify systemd: {err}");
+    }
+}
+
+/// Feed the systemd watchdog as long as `is_healthy` resolves to true.
+///
+/// This never returns, and is pending forever if the watchdog is disabled.
+pub(crate) async fn watchdog<F, Fut>(is_healthy: F) -> io::Result<()>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = bool>,
+{
+    let Some(period) = watchdog_period() else {
+        return std::future::pending().await;
+    };
+    let mut interval = tokio::time::interval(period / 2);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        if is_healthy().await {
+            debug!("feeding systemd watchdog");
+            #[cfg(unix)]
+            if let Err(err) = sd_notify::notify(false, &[sd_notify::NotifyState::Watchdog]) {
+                warn!("failed to notify systemd watchdog: {err}");
+            }
+        } else {
+            warn!("health check failed, not feeding systemd watchdog");
+        }
+    }
+}
+
+fn watchdog_period() -> Option<Duration> {
+    #[cfg(unix)]
+    {
+        let mut usec = 0;
+        if sd_notify::watchdog_enabled(false, &mut usec) {
+            return Some(Duration::from_micros(usec));
+        }
+    }
+    None
+}
@@ -0,0 +1,152 @@
+use std::{
+    ffi::OsString,
+    io,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+    time::Duration,
+};
+
+use log::error;
+use windows_service::{
+    define_windows_service,
+    service::{
+        ServiceAccess, ServiceControl, ServiceControlAccept, ServiceErrorControl, ServiceExitCode,
+        ServiceInfo, ServiceStartType, ServiceState, ServiceStatus, ServiceType,
+    },
+    service_control_handler::{self, ServiceControlHandlerResult},
+    service_dispatcher,
+    service_manager::{ServiceManager, ServiceManagerAccess},
+};
+
+use crate::{cli::read_conf_and_chdir, client, server};
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum ServiceKind {
+    Client,
+    Server,
+}
+
+impl ServiceKind {
+    fn subcommand(self) -> &'static str {
+        match self {
+            ServiceKind::Client => "client",
+            ServiceKind::Server => "server",
+        }
+    }
+}
+
+struct ServiceSpec {
+    kind: ServiceKind,
+    name: String,
+    config: PathBuf,
+}
+
+/// Service to run, set before handing control to the service dispatcher.
+static SERVICE: OnceLock<ServiceSpec> = OnceLock::new();
+
+/// Register a service starting pipeline with the given configuration file.
+pub(crate) fn install(kind: ServiceKind, name: &str, config: &Path) -> io::Result<()> {
+    let config = config.canonicalize()?;
+    let manager = ServiceManager::local_computer(
+        None::<&str>,
+        ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE,
+    )
+    .map_err(io::Error::other)?;
+    let info = ServiceInfo {
+        name: name.into(),
+        display_name: format!("pipeline {} ({name})", kind.subcommand()).into(),
+        service_type: ServiceType::OWN_PROCESS,
+        start_type: ServiceStartType::AutoStart,
+        error_control: ServiceErrorControl::Normal,
+        executable_path: std::env::current_exe()?,
+        launch_arguments: vec![
+            kind.subcommand().into(),
+            "run-as-service".into(),
+            "--name".into(),
+            name.into(),
+            config.into_os_string(),
+        ],
+        dependencies: vec![],
+        account_name: None,
+        account_password: None,
+    };
+    let service = manager
+        .create_service(&info, ServiceAccess::CHANGE_CONFIG)
+        .map_err(io::Error::other)?;
+    service
+        .set_description(format!("Processing pipeline {}", kind.subcommand()))
+        .map_err(io::Error::other)?;
+    println!("installed service {name}");
+    Ok(())
+}
+
+/// Run as a service, this should only be called by the service manager.
+pub(crate) fn run(kind: ServiceKind, name: String, config: PathBuf) -> io::Result<()> {
+    let spec = ServiceSpec { kind, name, config };
+    let name = spec.name.clone();
+    if SERVICE.set(spec).is_err() {
+        return Err(io::Error::other("service is already running"));
+    }
+    service_dispatcher::start(name, ffi_service_main).map_err(io::Error::other)
+}
+
+define_windows_service!(ffi_service_main, service_main);
+
+fn service_main(_arguments: Vec<OsString>) {
+    if let Err(err) = run_service() {
+        error!("service failed: {err}");
+    }
+}
+
+fn run_service() -> io::Result<()> {
+    let spec = SERVICE
+        .get()
+        .expect("service should be set before dispatching");
+
+    let (stop_tx, mut stop_rx) = tokio::sync::mpsc::unbounded_channel();
+    let event_handler = move |event| match event {
+        ServiceControl::Stop => {
+            _ = stop_tx.send(());
+            ServiceControlHandlerResult::NoError
+        }
+        ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+        _ => ServiceControlHandlerResult::NotImplemented,
+    };
+    let status_handle =
+        service_control_handler::register(&spec.name, event_handler).map_err(io::Error::other)?;
+    let set_state = |current_state, controls_accepted, code| {
+        status_handle
+            .set_service_status(ServiceStatus {
+                service_type: ServiceType::OWN_PROCESS,
+                current_state,
+                controls_accepted,
+                exit_code: ServiceExitCode::Win32(code),
+                checkpoint: 0,
+                wait_hint: Duration::default(),
+                process_id: None,
+            })
+            .map_err(io::Error::other)
+    };
+
+    set_state(ServiceState::Running, ServiceControlAccept::STOP, 0)?;
+
+    let runtime = tokio::runtime::Runtime::new()?;
+    let res = runtime.block_on(async {
+        let run = async {
+            match spec.kind {
+                ServiceKind::Client => {
+                    client::main(read_conf_and_chdir(&spec.config)?, false).await
+                }
+                ServiceKind::Server => server::main(read_conf_and_chdir(&spec.config)?).await,
+            }
+        };
+        tokio::select!(
+            res = run => res,
+            _ = stop_rx.recv() => Ok(()),
+        )
+    });
+
+    let code = if res.is_ok() { 0 } else { 1 };
+    set_state(ServiceState::Stopped, ServiceControlAccept::empty(), code)?;
+    res
+}